@@ -62,8 +62,37 @@
       };
 
       nixosModule = { config, lib, pkgs, ... }:
-        let cfg = config.services.hetzner-private-dns-sync;
-        in {
+        let
+          cfg = config.services.hetzner-private-dns-sync;
+
+          optionalFlag = flag: value: lib.optionals (value != null) [ flag (toString value) ];
+          args = [
+            "--dns-provider"
+            cfg.dnsProvider
+            "--private-network-name"
+            cfg.privateNetworkName
+            "--zone-name"
+            cfg.zoneName
+            "--output-format"
+            cfg.outputFormat
+          ]
+          ++ lib.optionals (cfg.dnsProvider == "rfc2136") [
+            "--server-address"
+            cfg.serverAddress
+            "--tsig-fudge"
+            (toString cfg.tsigFudge)
+          ]
+          ++ optionalFlag "--tsig-key-path" cfg.tsigKeyPath
+          ++ optionalFlag "--tsig-key-name" cfg.tsigKeyName
+          ++ optionalFlag "--powerdns-api-url" cfg.powerdnsApiUrl
+          ++ optionalFlag "--report-file" cfg.reportFile
+          ++ optionalFlag "--metrics-file" cfg.metricsFile
+          ++ optionalFlag "--heartbeat-file" cfg.heartbeatFile
+          ++ optionalFlag "--ping-url" cfg.pingUrl
+          ++ lib.optionals cfg.verify [ "--verify" "--verify-timeout" (toString cfg.verifyTimeout) ]
+          ++ cfg.extraArgs;
+        in
+        {
           options.services.hetzner-private-dns-sync = {
             enable = lib.mkEnableOption "Enables the hetzner-private-dns-sync service";
 
@@ -73,14 +102,34 @@
               description = "The package to use for hetzner-private-dns-sync";
             };
 
+            dnsProvider = lib.mkOption {
+              type = lib.types.enum [ "rfc2136" "hetzner-dns" "powerdns" "cloudflare" ];
+              default = "rfc2136";
+              description = "How records get to the zones. \"rfc2136\" sends dynamic updates to the DNS server at serverAddress, signed with the TSIG key. The others write them through the API of Hetzner DNS, PowerDNS or Cloudflare, and need the token or key of the API in the environment file (HETZNER_PRIVATE_DNS_SYNC_HETZNER_DNS_API_TOKEN, HETZNER_PRIVATE_DNS_SYNC_POWERDNS_API_KEY or HETZNER_PRIVATE_DNS_SYNC_CLOUDFLARE_API_TOKEN).";
+            };
+
             tsigKeyPath = lib.mkOption {
-              type = lib.types.path;
-              description = "The path to the TSIG key used to communicate with the DNS server.";
+              type = lib.types.nullOr lib.types.path;
+              default = null;
+              description = "The path to the TSIG key used to communicate with the DNS server. Needed with the rfc2136 provider.";
             };
 
             tsigKeyName = lib.mkOption {
-              type = lib.types.str;
-              description = "The name of the TSIG key used to communicate with the DNS server.";
+              type = lib.types.nullOr lib.types.str;
+              default = null;
+              description = "The name of the TSIG key used to communicate with the DNS server. Needed with the rfc2136 provider.";
+            };
+
+            tsigFudge = lib.mkOption {
+              type = lib.types.ints.between 1 3600;
+              default = 60;
+              description = "Number of seconds the DNS server may find the time in our TSIG signatures to be off from its own clock. Only zone transfers, PTR records and removals from shared names use it, other updates are always signed with 60 seconds.";
+            };
+
+            powerdnsApiUrl = lib.mkOption {
+              type = lib.types.nullOr lib.types.str;
+              default = null;
+              description = "URL of the PowerDNS Authoritative API, like \"http://127.0.0.1:8081\". Needed with the powerdns provider.";
             };
 
             serverAddress = lib.mkOption {
@@ -91,7 +140,7 @@
 
             environmentFilePath = lib.mkOption {
               type = lib.types.path;
-              description = "Path to a file with environment variables to further configure the software. Currently, the only required environment variable is HCLOUD_API_TOKEN.";
+              description = "Path to a file with environment variables to further configure the software. HCLOUD_API_TOKEN is always required, along with the token or key of the API of dnsProvider. Any flag can be set here too, as HETZNER_PRIVATE_DNS_SYNC_ followed by its name (e.g. HETZNER_PRIVATE_DNS_SYNC_RECORD_TTL).";
             };
 
             privateNetworkName = lib.mkOption {
@@ -103,16 +152,79 @@
               type = lib.types.str;
               description = "Name of the DNS zone to update. This will be the same as the internal domain you want to use.";
             };
+
+            outputFormat = lib.mkOption {
+              type = lib.types.enum [ "human" "json" "quiet" ];
+              default = "human";
+              description = "How much gets printed, and in which form. \"json\" only logs warnings and errors, and prints a JSON summary of each sync.";
+            };
+
+            reportFile = lib.mkOption {
+              type = lib.types.nullOr lib.types.str;
+              default = null;
+              example = "/var/lib/hetzner-private-dns-sync/report.json";
+              description = "Path of a file to write a JSON report of each sync to, for auditing. It has to be somewhere the service can write to, like its state directory.";
+            };
+
+            metricsFile = lib.mkOption {
+              type = lib.types.nullOr lib.types.str;
+              default = null;
+              example = "/var/lib/prometheus-node-exporter-text-files/hetzner-private-dns-sync.prom";
+              description = "Path of a file to write metrics of each sync to, in the Prometheus text format, for the textfile collector of node_exporter.";
+            };
+
+            heartbeatFile = lib.mkOption {
+              type = lib.types.nullOr lib.types.str;
+              default = null;
+              example = "/var/lib/hetzner-private-dns-sync/heartbeat";
+              description = "Path of a file to write the time of the last successful sync to, so monitoring can alert when it gets too old.";
+            };
+
+            pingUrl = lib.mkOption {
+              type = lib.types.nullOr lib.types.str;
+              default = null;
+              description = "URL of a dead man's switch monitor (like healthchecks.io) to ping after each sync. Failed syncs ping the URL with \"/fail\" appended.";
+            };
+
+            verify = lib.mkOption {
+              type = lib.types.bool;
+              default = false;
+              description = "Whether to poll the DNS servers after each change until they answer with the new record, and log how long that took.";
+            };
+
+            verifyTimeout = lib.mkOption {
+              type = lib.types.ints.positive;
+              default = 30;
+              description = "Number of seconds to wait for a record to show up with verify before giving up on it.";
+            };
+
+            extraArgs = lib.mkOption {
+              type = lib.types.listOf lib.types.str;
+              default = [ ];
+              example = [ "--record-ttl" "300" ];
+              description = "Extra flags to pass to hetzner-private-dns-sync. See its --help for all of them.";
+            };
           };
 
           config = lib.mkIf cfg.enable {
+            assertions = [
+              {
+                assertion = cfg.dnsProvider != "rfc2136" || (cfg.tsigKeyPath != null && cfg.tsigKeyName != null);
+                message = "services.hetzner-private-dns-sync needs tsigKeyPath and tsigKeyName with the rfc2136 provider.";
+              }
+              {
+                assertion = cfg.dnsProvider != "powerdns" || cfg.powerdnsApiUrl != null;
+                message = "services.hetzner-private-dns-sync needs powerdnsApiUrl with the powerdns provider.";
+              }
+            ];
+
             systemd.services.hetzner-private-dns-sync = {
               description = "Runs hetzner-private-dns-sync once to sync server IPs and hostnames into a DNS server.";
 
               serviceConfig = {
                 Type = "oneshot";
                 EnvironmentFile = cfg.environmentFilePath;
-                ExecStart = "${lib.getExe cfg.package} --tsig-key-path ${cfg.tsigKeyPath} --tsig-key-name ${cfg.tsigKeyName} --server-address ${cfg.serverAddress} --private-network-name ${cfg.privateNetworkName} --zone-name ${cfg.zoneName}";
+                ExecStart = "${lib.getExe cfg.package} ${lib.escapeShellArgs args}";
                 DynamicUser = true;
                 User = "hetzner-private-dns-sync";
                 StateDirectory = "hetzner-private-dns-sync";
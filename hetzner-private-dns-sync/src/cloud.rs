@@ -0,0 +1,1226 @@
+//! Reading the servers of the private network from Hetzner Cloud.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
+
+use clap::ValueEnum;
+use hcloud::{
+    apis::{
+        configuration::Configuration,
+        networks_api::{self, GetNetworkParams, ListNetworksParams},
+        servers_api::{self, GetServerParams},
+        ResponseContent,
+    },
+    models::{GetNetworkResponse, Network},
+};
+use ipnet::IpNet;
+
+use crate::{
+    hcloud_error, is_valid_dns_name, normalise_name, parse_address, record_id, state::open_state,
+    AliasIpRecords, Args, Error, OnAmbiguousNetwork, OnInvalidHostname, Result, Server,
+    ServerRecordType, Service, CNAME_LABEL, GROUP_LABEL, MAX_LABEL_LENGTH,
+    METADATA_INSTANCE_ID_URL, RECORD_TYPES_LABEL, SRV_LABEL, TTL_LABEL,
+};
+
+/// Information about a server retrieved from the Hetzner API, before we decide how it'll look like in DNS.
+#[derive(Clone, Debug)]
+pub(crate) struct HCloudServer {
+    pub(crate) id: i64,
+    pub(crate) name: String,
+    // Servers can be attached to the network for a moment before they get an IP in it, when they're still being provisioned.
+    pub(crate) ip_address: Option<String>,
+    // Additional IPs the server has in the network, sorted so they're published in the same order across runs.
+    pub(crate) alias_ips: Vec<String>,
+    // The server's public IPv6 network, like "2001:db8::/64". Servers can be created without one.
+    pub(crate) public_ipv6_network: Option<String>,
+    pub(crate) public_ip_address: Option<String>,
+    // Like "fsn1-dc14" and "fsn1".
+    pub(crate) datacenter: String,
+    pub(crate) location: String,
+    pub(crate) labels: HashMap<String, String>,
+    pub(crate) project: Option<String>,
+    // Of the network the server was found in.
+    pub(crate) network_id: i64,
+}
+
+/// What a sync needs from the Hetzner API. `HCloudWrapper` talks to the real API, and tests can put something that records the calls in its place.
+pub(crate) trait CloudBackend {
+    fn forget_network(&mut self);
+    async fn network_id(&mut self) -> Result<i64>;
+    async fn server_ids(&mut self) -> Result<Vec<i64>>;
+    async fn hydrate_server_list(&mut self, server_ids: Vec<i64>) -> Result<Vec<HCloudServer>>;
+}
+
+#[derive(Debug)]
+struct HCloudWrapper {
+    configuration: Configuration,
+    network_name: String,
+    on_ambiguous_network: OnAmbiguousNetwork,
+    // ID of the network resolved in a previous run. If set, we'll get the network by its ID instead of looking it up by name.
+    cached_network_id: Option<i64>,
+
+    // Quick cache to avoid getting the network multiple times.
+    network_info: Option<Network>,
+    // Name of the --hcloud-project this is for, if it isn't for the project of --hcloud-api-token.
+    project: Option<String>,
+}
+
+impl HCloudWrapper {
+    fn new(
+        api_token: String,
+        network_name: String,
+        on_ambiguous_network: OnAmbiguousNetwork,
+        cached_network_id: Option<i64>,
+    ) -> Self {
+        let mut configuration = Configuration::new();
+        configuration.bearer_access_token = Some(api_token);
+
+        Self {
+            configuration,
+            network_name,
+            on_ambiguous_network,
+            cached_network_id,
+
+            network_info: None,
+            project: None,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn retrieve_network(&mut self) -> Result<()> {
+        if self.network_info.is_some() {
+            return Ok(());
+        }
+
+        tracing::debug!("Networking info wasn't retrieved yet. Will do that now.");
+
+        if let Some(network_id) = self.cached_network_id {
+            match networks_api::get_network(
+                &self.configuration,
+                GetNetworkParams { id: network_id },
+            )
+            .await
+            {
+                Ok(GetNetworkResponse {
+                    network: Some(network),
+                }) => {
+                    self.network_info = Some(*network);
+                    return Ok(());
+                }
+                Ok(GetNetworkResponse { network: None }) => {
+                    tracing::warn!(network_id, "The Hetzner API didn't return the network with the ID we resolved previously. Will look it up by name instead.");
+                }
+                Err(hcloud::apis::Error::ResponseError(ResponseContent { status, .. }))
+                    if status.as_u16() == 404 =>
+                {
+                    tracing::warn!(network_id, "The network with the ID we resolved previously doesn't exist anymore. Will look it up by name instead.");
+                }
+                Err(e) => {
+                    return Err(hcloud_error(
+                        format!("unable to get network with id {}.", network_id),
+                        e,
+                    ))
+                }
+            }
+        }
+
+        let networks = networks_api::list_networks(
+            &self.configuration,
+            ListNetworksParams {
+                name: Some(self.network_name.clone()),
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(|e| hcloud_error("unable to list networks.".to_string(), e))?;
+
+        if networks.networks.is_empty() {
+            return Err(Error::HCloud(format!(
+                "Private network with name '{}' not found on the Hetzner account!",
+                self.network_name
+            )));
+        }
+
+        if networks.networks.len() > 1 {
+            let network_ids: Vec<i64> = networks.networks.iter().map(|n| n.id).collect();
+            if self.on_ambiguous_network == OnAmbiguousNetwork::Error {
+                return Err(Error::HCloud(format!(
+                    "More than one private network with name '{}' found on the Hetzner account (ids {:?})! Pass --on-ambiguous-network first to use the first one anyway.",
+                    self.network_name, network_ids
+                )));
+            }
+
+            tracing::warn!(
+                ?network_ids,
+                "More than one network retrieved from the Hetzner API! Will proceed with the first one."
+            );
+        }
+
+        self.network_info = Some(networks.networks.first().unwrap().clone());
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn network(&mut self) -> Result<Network> {
+        self.retrieve_network().await?;
+        Ok(self.network_info.clone().unwrap())
+    }
+}
+
+impl CloudBackend for HCloudWrapper {
+    /// Drops what we know about the network, so it's retrieved again the next time we need it. We keep its ID so the next retrieval can still go straight to it.
+    fn forget_network(&mut self) {
+        if let Some(network) = self.network_info.take() {
+            self.cached_network_id = Some(network.id);
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn network_id(&mut self) -> Result<i64> {
+        self.retrieve_network().await?;
+        Ok(self.network_info.as_ref().unwrap().id)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn server_ids(&mut self) -> Result<Vec<i64>> {
+        self.retrieve_network().await?;
+        Ok(self.network_info.as_ref().unwrap().servers.clone())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn hydrate_server_list(&mut self, server_ids: Vec<i64>) -> Result<Vec<HCloudServer>> {
+        self.retrieve_network().await?;
+
+        let network_id = self.network_info.as_ref().unwrap().id;
+        let mut hydrated_servers = Vec::with_capacity(server_ids.len());
+
+        for server_id in server_ids {
+            let server_info =
+                servers_api::get_server(&self.configuration, GetServerParams { id: server_id })
+                    .await
+                    .map_err(|e| {
+                        hcloud_error(
+                            format!(
+                                "unable to get information for server with id {}.",
+                                server_id
+                            ),
+                            e,
+                        )
+                    })?;
+
+            if let Some(server_info) = server_info.server {
+                let private_net = server_info
+                    .private_net
+                    .iter()
+                    .find(|n| n.network.is_some_and(|nid| nid == network_id))
+                    .ok_or_else(|| {
+                        Error::HCloud(format!(
+                            "Server with id {} doesn't have a network with id {} attached to it!",
+                            server_id, network_id
+                        ))
+                    })?;
+                // A server can only be attached once to a network, so there's a single entry for it. The server's record always has the entry's main IP and never one of its alias IPs, whatever order the API lists those in, so the record doesn't flip between addresses across runs.
+                let mut alias_ips = private_net.alias_ips.clone().unwrap_or_default();
+                alias_ips.retain(|ip| !ip.is_empty());
+                alias_ips.sort_by_key(|ip| ip.parse::<IpAddr>().ok());
+                let current_server = HCloudServer {
+                    id: server_id,
+                    ip_address: private_net.ip.clone().filter(|ip| !ip.is_empty()),
+                    alias_ips,
+                    public_ipv6_network: server_info
+                        .public_net
+                        .ipv6
+                        .map(|ipv6| ipv6.ip)
+                        .filter(|ip| !ip.is_empty()),
+                    public_ip_address: server_info
+                        .public_net
+                        .ipv4
+                        .map(|ipv4| ipv4.ip)
+                        .filter(|ip| !ip.is_empty()),
+                    datacenter: server_info.datacenter.name.clone(),
+                    location: server_info.datacenter.location.name.clone(),
+                    name: server_info.name,
+                    labels: server_info.labels,
+                    project: self.project.clone(),
+                    network_id,
+                };
+
+                hydrated_servers.push(current_server);
+            } else {
+                return Err(Error::HCloud(format!(
+                    "Couldn't get information for server with id {}!",
+                    server_id
+                )));
+            }
+        }
+
+        Ok(hydrated_servers)
+    }
+}
+
+/// The networks of the project of --hcloud-api-token and of every --hcloud-project, as if all their servers were in a single network.
+#[derive(Debug)]
+pub(crate) struct HCloudProjects {
+    main: HCloudWrapper,
+    others: Vec<HCloudWrapper>,
+}
+
+impl HCloudProjects {
+    /// `cached_network_id` is only for the network of --hcloud-api-token, the networks of the other projects are always looked up by name.
+    pub(crate) fn new(args: &Args, cached_network_id: Option<i64>) -> Result<Self> {
+        let mut others = Vec::with_capacity(args.hcloud_project.len());
+        for project in &args.hcloud_project {
+            others.push(HCloudWrapper {
+                project: Some(project.name.clone()),
+                ..HCloudWrapper::new(
+                    read_hcloud_api_token(&project.api_token_file)?,
+                    args.private_network_name.clone(),
+                    args.on_ambiguous_network,
+                    None,
+                )
+            });
+        }
+
+        Ok(Self {
+            main: HCloudWrapper::new(
+                args.hcloud_api_token.clone(),
+                args.private_network_name.clone(),
+                args.on_ambiguous_network,
+                cached_network_id,
+            ),
+            others,
+        })
+    }
+
+    fn projects(&mut self) -> impl Iterator<Item = &mut HCloudWrapper> {
+        std::iter::once(&mut self.main).chain(self.others.iter_mut())
+    }
+}
+
+impl CloudBackend for HCloudProjects {
+    fn forget_network(&mut self) {
+        for project in self.projects() {
+            project.forget_network();
+        }
+    }
+
+    /// Only the network of --hcloud-api-token is remembered in the state, so that's the one we tell apart.
+    async fn network_id(&mut self) -> Result<i64> {
+        self.main.network_id().await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn server_ids(&mut self) -> Result<Vec<i64>> {
+        let mut server_ids = Vec::new();
+        let mut seen_server_ids = HashSet::new();
+        for project in self.projects() {
+            for server_id in project.server_ids().await? {
+                if !seen_server_ids.insert(server_id) {
+                    return Err(Error::HCloud(format!(
+                        "Server with id {} is in the network of more than one Hetzner project!",
+                        server_id
+                    )));
+                }
+                server_ids.push(server_id);
+            }
+        }
+
+        Ok(server_ids)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn hydrate_server_list(&mut self, server_ids: Vec<i64>) -> Result<Vec<HCloudServer>> {
+        // Each project can only get the servers in its own network.
+        let mut remaining_server_ids = server_ids;
+        let mut hydrated_servers = Vec::with_capacity(remaining_server_ids.len());
+        for project in &mut self.others {
+            let project_server_ids: HashSet<i64> =
+                project.server_ids().await?.into_iter().collect();
+            let (of_project, others): (Vec<i64>, Vec<i64>) = remaining_server_ids
+                .into_iter()
+                .partition(|id| project_server_ids.contains(id));
+            remaining_server_ids = others;
+            hydrated_servers.extend(project.hydrate_server_list(of_project).await?);
+        }
+        hydrated_servers.extend(self.main.hydrate_server_list(remaining_server_ids).await?);
+
+        Ok(hydrated_servers)
+    }
+}
+
+/// Reads the API token of a --hcloud-project. Whitespace around it (like the newline at the end of the file) isn't part of the token.
+pub(crate) fn read_hcloud_api_token(path: &std::path::Path) -> Result<String> {
+    std::fs::read_to_string(path)
+        .map(|api_token| api_token.trim().to_string())
+        .map_err(|e| {
+            Error::Config(format!(
+                "unable to read the Hetzner API token at {}. {}",
+                path.display(),
+                e
+            ))
+        })
+}
+
+/// Turns the information we got from the Hetzner API into the server entry we'll publish in DNS.
+pub(crate) fn server_from_hcloud(
+    hcloud_server: HCloudServer,
+    ip_address: String,
+    args: &Args,
+) -> Result<Server> {
+    let zone_name = &args.zone_name;
+    let subdomain = args.network_subdomain.as_deref();
+    let zone = match args
+        .zone_per_label
+        .as_ref()
+        .and_then(|label| hcloud_server.labels.get(label))
+    {
+        Some(label_value) if label_value.is_empty() => {
+            return Err(Error::HCloud(format!(
+                "Server with id {} has an empty value for the label used to pick its zone!",
+                hcloud_server.id
+            )))
+        }
+        Some(label_value) => format!("{}.{}", label_value, zone_name),
+        None => zone_name.to_string(),
+    };
+
+    // Label values can't end with a dot, so the target is always taken as a name relative to the zone the server is in.
+    let cname = match hcloud_server.labels.get(CNAME_LABEL) {
+        Some(target) if target.is_empty() => {
+            return Err(Error::HCloud(format!(
+                "Server with id {} has an empty value for the {} label!",
+                hcloud_server.id, CNAME_LABEL
+            )))
+        }
+        Some(target) => Some(match subdomain {
+            Some(subdomain) => format!("{}.{}.{}.", target, subdomain, zone),
+            None => format!("{}.{}.", target, zone),
+        }),
+        None => None,
+    };
+
+    // The target of an SRV record can't be a CNAME.
+    let services = match hcloud_server.labels.get(SRV_LABEL) {
+        Some(label_value) if cname.is_none() => {
+            Service::parse_label(hcloud_server.id, label_value)?
+        }
+        _ => Vec::new(),
+    };
+
+    // Groups are next to the servers in them, under the same subdomain.
+    let groups = match cname {
+        Some(_) => Vec::new(),
+        None => names_in_label(&hcloud_server, GROUP_LABEL)?
+            .into_iter()
+            .map(|group| args.record_label(&group))
+            .map(|group| match subdomain {
+                Some(subdomain) => format!("{}.{}", group, subdomain),
+                None => group,
+            })
+            .collect(),
+    };
+
+    let metadata = match cname {
+        Some(_) => None,
+        None => server_metadata(&hcloud_server, &args.metadata_field)?,
+    };
+
+    let ttl = match hcloud_server.labels.get(TTL_LABEL) {
+        Some(label_value) => label_value.parse().map_err(|e| {
+            Error::HCloud(format!(
+                "Server with id {} has an invalid TTL '{}' in its {} label. {}",
+                hcloud_server.id, label_value, TTL_LABEL, e
+            ))
+        })?,
+        None => args.record_ttl,
+    };
+
+    // An empty name stays empty, so it's caught just like it is without a subdomain.
+    let label = hostname_to_dns_label(hcloud_server.id, &hcloud_server.name)?;
+    let label = match args.on_invalid_hostname {
+        OnInvalidHostname::Sanitize => sanitize_hostname(hcloud_server.id, &label),
+        _ => label,
+    };
+    let label = match label.trim() {
+        "" => label,
+        _ => args.record_label(&label),
+    };
+    let label = match &args.record_name_template {
+        Some(template) if !label.trim().is_empty() => {
+            record_name_from_template(template, &hcloud_server, &label)?
+        }
+        _ => label,
+    };
+    let hostname = match subdomain {
+        Some(subdomain) if !label.trim().is_empty() => format!("{}.{}", label, subdomain),
+        _ => label,
+    };
+
+    Ok(Server {
+        id: hcloud_server.id,
+        ip_address,
+        ipv6_address: None,
+        public_ip_address: None,
+        public_zone: None,
+        services,
+        groups,
+        metadata,
+        wildcard: false,
+        shared_name: false,
+        no_a_record: false,
+        no_ptr_record: false,
+        hostname,
+        zone,
+        cname,
+        ttl,
+        project: hcloud_server.project,
+        network_id: Some(hcloud_server.network_id),
+    })
+}
+
+/// Hetzner gives every server a whole IPv6 network and configures its first address on the server, which is the one we publish.
+pub(crate) fn first_address_of(server_id: i64, network: &str) -> Result<String> {
+    let network: ipnet::Ipv6Net = parse_address(server_id, "public IPv6 network", network)?;
+
+    Ok(std::net::Ipv6Addr::from(u128::from(network.network()) + 1).to_string())
+}
+
+/// The names in one of the server's labels that take a list of names, like the alias label.
+pub(crate) fn names_in_label(hcloud_server: &HCloudServer, label: &str) -> Result<Vec<String>> {
+    let Some(label_value) = hcloud_server.labels.get(label) else {
+        return Ok(Vec::new());
+    };
+
+    let names: Vec<String> = label_value
+        .split([',', '.'])
+        .map(|name| name.to_ascii_lowercase())
+        .collect();
+    if let Some(name) = names.iter().find(|name| !is_valid_dns_name(name)) {
+        return Err(Error::HCloud(format!(
+            "Server with id {} has the name '{}' in its {} label, which isn't a valid DNS label!",
+            hcloud_server.id, name, label
+        )));
+    }
+
+    Ok(names)
+}
+
+/// The types of records the server's names get, from its record types label or --record-types. Every type, if neither of them says otherwise.
+pub(crate) fn record_types_of(
+    hcloud_server: &HCloudServer,
+    args: &Args,
+) -> Result<Vec<ServerRecordType>> {
+    let record_types = match hcloud_server.labels.get(RECORD_TYPES_LABEL) {
+        Some(label_value) => label_value
+            .split([',', '.'])
+            .map(|record_type| {
+                <ServerRecordType as ValueEnum>::from_str(record_type, true).map_err(|_| {
+                    Error::HCloud(format!(
+                        "Server with id {} has '{}' in its {} label, which isn't a record type we can limit it to! The types are \"a\", \"aaaa\" and \"ptr\".",
+                        hcloud_server.id, record_type, RECORD_TYPES_LABEL
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => args.record_types.clone(),
+    };
+
+    match record_types.is_empty() {
+        true => Ok(ServerRecordType::value_variants().to_vec()),
+        false => Ok(record_types),
+    }
+}
+
+/// The CNAME we publish for an alias of a server. It's in the same zone (and under the same --network-subdomain) as the server's record, and gets the same --record-prefix and --record-suffix.
+pub(crate) fn alias_server(server: &Server, alias: &str, args: &Args) -> Server {
+    let alias = args.record_label(alias);
+    let hostname = match &args.network_subdomain {
+        Some(subdomain) => format!("{}.{}", alias, subdomain),
+        None => alias,
+    };
+
+    Server {
+        id: record_id(&format!(
+            "alias {}.{}",
+            hostname,
+            normalise_name(&server.zone)
+        )),
+        ip_address: String::new(),
+        hostname,
+        zone: server.zone.clone(),
+        cname: Some(format!("{}.", server.fqdn().trim_end_matches('.'))),
+        ttl: server.ttl,
+        project: server.project.clone(),
+        network_id: server.network_id,
+        ..Default::default()
+    }
+}
+
+/// The A records we publish for the alias IPs of a server, with --alias-ip-records. Each of them is kept in the state like a server of its own, so it's removed once the server loses the alias IP. With "same-name", the server shares its name with them. They're numbered in the order of their addresses, the same one `hydrate_server_list` puts them in.
+pub(crate) fn alias_ip_servers(
+    server: &mut Server,
+    alias_ips: &[String],
+    args: &Args,
+) -> Vec<Server> {
+    if args.alias_ip_records == AliasIpRecords::None
+        || server.cname.is_some()
+        || alias_ips.is_empty()
+    {
+        return Vec::new();
+    }
+
+    let same_name = args.alias_ip_records == AliasIpRecords::SameName;
+    server.shared_name |= same_name;
+    let mut alias_ips = alias_ips.to_vec();
+    alias_ips.sort_by_key(|ip| ip.parse::<IpAddr>().ok());
+    alias_ips
+        .iter()
+        .enumerate()
+        .map(|(position, alias_ip)| Server {
+            id: record_id(&format!("alias ip {} {}", server.id, alias_ip)),
+            ip_address: alias_ip.clone(),
+            shared_name: same_name,
+            hostname: if same_name {
+                server.hostname.clone()
+            } else {
+                with_first_label_suffix(&server.hostname, &format!("-alias{}", position + 1))
+            },
+            zone: server.zone.clone(),
+            ttl: server.ttl,
+            project: server.project.clone(),
+            network_id: server.network_id,
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Adds `suffix` to the end of the first label of `hostname`, so it stays under the same subdomain.
+pub(crate) fn with_first_label_suffix(hostname: &str, suffix: &str) -> String {
+    match hostname.split_once('.') {
+        Some((label, rest)) => format!("{}{}.{}", label, suffix, rest),
+        None => format!("{}{}", hostname, suffix),
+    }
+}
+
+pub(crate) const RECORD_NAME_TEMPLATE_VARIABLES: [&str; 4] =
+    ["hostname", "id", "datacenter", "location"];
+
+/// Replaces every "{variable}" in the template with what `value` gives for it. A variable without a value is an error, and so are braces that don't enclose a variable.
+pub(crate) fn fill_template(
+    template: &str,
+    value: impl Fn(&str) -> Option<String>,
+) -> std::result::Result<String, String> {
+    let template = template.strip_suffix(".{zone}").unwrap_or(template);
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        filled.push_str(&rest[..start]);
+        let Some(end) = rest[start..]
+            .find('}')
+            .filter(|_| rest[start..].starts_with('{'))
+        else {
+            return Err(format!("'{}' has an unmatched brace.", template));
+        };
+        let variable = &rest[start + 1..start + end];
+        match value(variable) {
+            Some(variable_value) => filled.push_str(&variable_value),
+            None if variable == "zone" => {
+                return Err("\"{zone}\" can only be at the end, after a dot.".to_string())
+            }
+            None if variable.starts_with("label.") => {
+                return Err(format!(
+                    "there's no label \"{}\" to fill in.",
+                    &variable["label.".len()..]
+                ))
+            }
+            None => return Err(format!("there's no \"{{{}}}\" to fill in.", variable)),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    filled.push_str(rest);
+
+    Ok(filled)
+}
+
+/// The name of a server's record from --record-name-template, relative to its zone.
+fn record_name_from_template(
+    template: &str,
+    hcloud_server: &HCloudServer,
+    hostname: &str,
+) -> Result<String> {
+    fill_template(template, |variable| {
+        server_variable(hcloud_server, hostname, variable)
+    })
+    .map(|name| name.to_ascii_lowercase())
+    .map_err(|e| {
+        Error::HCloud(format!(
+            "Couldn't make the name of the record of the server with id {} from the record name template. {}",
+            hcloud_server.id, e
+        ))
+    })
+}
+
+/// What a variable of --record-name-template or a --metadata-field stands for, for a server. `None` for a label the server doesn't have.
+fn server_variable(hcloud_server: &HCloudServer, hostname: &str, variable: &str) -> Option<String> {
+    match variable {
+        "hostname" => Some(hostname.to_string()),
+        "id" => Some(hcloud_server.id.to_string()),
+        "datacenter" => Some(hcloud_server.datacenter.clone()),
+        "location" => Some(hcloud_server.location.clone()),
+        variable => variable
+            .strip_prefix("label.")
+            .and_then(|key| hcloud_server.labels.get(key))
+            .cloned(),
+    }
+}
+
+/// The content of the TXT record with the --metadata-field of the server, if it has any of them.
+fn server_metadata(hcloud_server: &HCloudServer, fields: &[String]) -> Result<Option<String>> {
+    let metadata = fields
+        .iter()
+        .filter_map(|field| {
+            server_variable(hcloud_server, &hcloud_server.name, field).map(|value| {
+                let key = field.strip_prefix("label.").unwrap_or(field);
+                format!("{}={}", key, value)
+            })
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    if metadata.len() > 255 {
+        return Err(Error::HCloud(format!(
+            "Server with id {} would get a TXT record with its metadata of {} characters, but it can be at most 255!",
+            hcloud_server.id,
+            metadata.len()
+        )));
+    }
+
+    Ok(Some(metadata).filter(|metadata| !metadata.is_empty()))
+}
+
+/// Turns a server name into the label we use in its record. Names with non-ASCII characters get encoded with IDNA (punycode), so they become valid A-labels like "xn--...". Other names are left as they are.
+fn hostname_to_dns_label(server_id: i64, name: &str) -> Result<String> {
+    if name.is_ascii() {
+        return Ok(name.to_string());
+    }
+
+    let encoded_name = idna::domain_to_ascii(name).map_err(|e| {
+        Error::HCloud(format!(
+            "Server with id {} has the name '{}', which can't be encoded with IDNA. {}",
+            server_id, name, e
+        ))
+    })?;
+    tracing::debug!(
+        server_id,
+        name,
+        encoded_name,
+        "Encoded a non-ASCII server name with IDNA."
+    );
+
+    Ok(encoded_name)
+}
+
+/// Turns a name DNS can't take into one it can, for --on-invalid-hostname=sanitize. Dots stay as they are, and whatever is between them is turned into a valid label, or left out if nothing of it is left.
+fn sanitize_hostname(server_id: i64, name: &str) -> String {
+    let labels: Vec<String> = name
+        .split('.')
+        .map(|label| {
+            let label: String = label
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | '0'..='9' | '-' => c,
+                    'A'..='Z' => c.to_ascii_lowercase(),
+                    _ => '-',
+                })
+                .collect();
+            let label = label.trim_matches('-');
+            if label.len() > MAX_LABEL_LENGTH {
+                let truncated = label[..MAX_LABEL_LENGTH].trim_end_matches('-');
+                tracing::warn!(
+                    server_id,
+                    label,
+                    truncated,
+                    "Cut a part of the server's name that was too long for DNS."
+                );
+                truncated.to_string()
+            } else {
+                label.to_string()
+            }
+        })
+        .filter(|label| !label.is_empty())
+        .collect();
+    let sanitized = labels.join(".");
+    if sanitized != name {
+        tracing::debug!(
+            server_id,
+            name,
+            sanitized,
+            "Sanitized a server name that isn't valid in DNS."
+        );
+    }
+
+    sanitized
+}
+
+/// Figures out the ID of the server we're running on, if we were told to exclude it.
+pub(crate) async fn self_server_id(args: &Args) -> Result<Option<i64>> {
+    if !args.exclude_self {
+        return Ok(None);
+    }
+
+    if let Some(server_id) = args.self_server_id {
+        return Ok(Some(server_id));
+    }
+
+    tracing::debug!("Getting the ID of the server we're running on from the metadata service.");
+
+    let metadata_error = |e: String| {
+        Error::HCloud(format!("unable to get the ID of the server we're running on from the metadata service. If it isn't available, pass --self-server-id instead. {}", e))
+    };
+    let response = reqwest::Client::new()
+        .get(METADATA_INSTANCE_ID_URL)
+        .timeout(std::time::Duration::from_secs(5))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| metadata_error(e.to_string()))?;
+    let server_id = response
+        .text()
+        .await
+        .map_err(|e| metadata_error(e.to_string()))?
+        .trim()
+        .parse()
+        .map_err(|e: std::num::ParseIntError| metadata_error(e.to_string()))?;
+
+    tracing::info!(server_id, "Will exclude the server we're running on.");
+    Ok(Some(server_id))
+}
+
+/// The private network as the Hetzner API describes it, and how each server in it fits in.
+#[derive(Debug)]
+pub struct NetworkInfo {
+    pub id: i64,
+    pub name: String,
+    pub ip_range: String,
+    pub subnets: Vec<SubnetInfo>,
+    pub routes: Vec<RouteInfo>,
+    pub servers: Vec<ServerNetworkInfo>,
+}
+
+#[derive(Debug)]
+pub struct SubnetInfo {
+    pub ip_range: Option<String>,
+    pub gateway: String,
+    pub network_zone: String,
+}
+
+#[derive(Debug)]
+pub struct RouteInfo {
+    pub destination: String,
+    pub gateway: String,
+}
+
+#[derive(Debug)]
+pub struct ServerNetworkInfo {
+    pub id: i64,
+    pub name: String,
+    /// `None` if the server doesn't have an IP in the network yet.
+    pub ip_address: Option<String>,
+    /// IP range of the subnet the server's IP belongs to.
+    pub subnet: Option<String>,
+    pub in_published_subnets: bool,
+}
+
+/// Gets the private network and the IP of every server in it, without changing anything.
+pub async fn network_info(args: &Args) -> Result<NetworkInfo> {
+    let current_state = open_state(args, true)?;
+    let mut hcloud = HCloudWrapper::new(
+        args.hcloud_api_token.clone(),
+        args.private_network_name.clone(),
+        args.on_ambiguous_network,
+        if current_state.private_network_name == args.private_network_name {
+            current_state.private_network_id
+        } else {
+            None
+        },
+    );
+
+    describe_network(&mut hcloud, args).await
+}
+
+async fn describe_network(hcloud: &mut HCloudWrapper, args: &Args) -> Result<NetworkInfo> {
+    let network = hcloud.network().await?;
+    let subnets: Vec<SubnetInfo> = network
+        .subnets
+        .iter()
+        .map(|s| SubnetInfo {
+            ip_range: s.ip_range.clone(),
+            gateway: s.gateway.clone(),
+            network_zone: s.network_zone.clone(),
+        })
+        .collect();
+
+    let mut servers = Vec::with_capacity(network.servers.len());
+    for hcloud_server in hcloud.hydrate_server_list(network.servers.clone()).await? {
+        let ip: Option<IpAddr> = hcloud_server
+            .ip_address
+            .as_deref()
+            .and_then(|ip| ip.parse().ok());
+        let subnet = ip.and_then(|ip| {
+            subnets
+                .iter()
+                .filter_map(|s| s.ip_range.as_deref())
+                .find(|range| {
+                    range
+                        .parse::<IpNet>()
+                        .is_ok_and(|range| range.contains(&ip))
+                })
+                .map(str::to_string)
+        });
+        let in_published_subnets = ip.is_some_and(|ip| {
+            args.publish_subnet.is_empty() || args.publish_subnet.iter().any(|s| s.contains(&ip))
+        });
+
+        servers.push(ServerNetworkInfo {
+            id: hcloud_server.id,
+            name: hcloud_server.name,
+            ip_address: hcloud_server.ip_address,
+            subnet,
+            in_published_subnets,
+        });
+    }
+
+    Ok(NetworkInfo {
+        id: network.id,
+        name: network.name,
+        ip_range: network.ip_range,
+        subnets,
+        routes: network
+            .routes
+            .into_iter()
+            .map(|r| RouteInfo {
+                destination: r.destination,
+                gateway: r.gateway,
+            })
+            .collect(),
+        servers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing;
+
+    fn network(id: i64) -> serde_json::Value {
+        json!({
+            "id": id,
+            "name": "test",
+            "ip_range": "10.0.0.0/16",
+            "subnets": [],
+            "routes": [],
+            "servers": [],
+            "load_balancers": [],
+            "protection": {"delete": false},
+            "labels": {},
+            "created": "2024-01-01T00:00:00+00:00",
+            "expose_routes_to_vswitch": false,
+        })
+    }
+
+    /// A server as the Hetzner API describes it, attached to the networks in `private_net`.
+    fn server(id: i64, private_net: serde_json::Value) -> serde_json::Value {
+        json!({
+            "id": id,
+            "name": format!("server{}", id),
+            "status": "running",
+            "created": "2024-01-01T00:00:00+00:00",
+            "public_net": {"ipv4": null, "ipv6": null, "floating_ips": []},
+            "private_net": private_net,
+            "server_type": {
+                "id": 1,
+                "name": "cx22",
+                "description": "CX22",
+                "cores": 2,
+                "memory": 4.0,
+                "disk": 40.0,
+                "deprecated": null,
+                "prices": [],
+                "storage_type": "local",
+                "cpu_type": "shared",
+                "architecture": "x86",
+                "included_traffic": null,
+            },
+            "datacenter": {
+                "id": 1,
+                "name": "fsn1-dc14",
+                "description": "Falkenstein 1 DC14",
+                "location": {
+                    "id": 1,
+                    "name": "fsn1",
+                    "description": "Falkenstein DC Park 1",
+                    "country": "DE",
+                    "city": "Falkenstein",
+                    "latitude": 50.47612,
+                    "longitude": 12.370071,
+                    "network_zone": "eu-central",
+                },
+                "server_types": {"available": [], "available_for_migration": [], "supported": []},
+            },
+            "image": null,
+            "iso": null,
+            "rescue_enabled": false,
+            "locked": false,
+            "backup_window": null,
+            "outgoing_traffic": null,
+            "ingoing_traffic": null,
+            "included_traffic": null,
+            "protection": {"delete": false, "rebuild": false},
+            "labels": {},
+            "volumes": [],
+            "load_balancers": [],
+            "primary_disk_size": 40,
+            "placement_group": null,
+        })
+    }
+
+    /// A client of the API at `url` for the network named "test".
+    fn hcloud(url: &str, cached_network_id: Option<i64>) -> HCloudWrapper {
+        let mut hcloud = HCloudWrapper::new(
+            "test".to_string(),
+            "test".to_string(),
+            OnAmbiguousNetwork::Error,
+            cached_network_id,
+        );
+        hcloud.configuration.base_path = url.to_string();
+        hcloud
+    }
+
+    #[tokio::test]
+    async fn the_network_is_got_by_the_id_resolved_before() {
+        let (url, requests) = testing::http_server(|request, _| match request {
+            "GET /networks/7" => (200, json!({ "network": network(7) }).to_string()),
+            "GET /networks?name=test" => (
+                200,
+                json!({
+                    "networks": [network(9)],
+                    "meta": {"pagination": {
+                        "page": 1,
+                        "per_page": 25,
+                        "previous_page": null,
+                        "next_page": null,
+                        "last_page": 1,
+                        "total_entries": 1,
+                    }},
+                })
+                .to_string(),
+            ),
+            _ => (
+                404,
+                json!({"error": {"code": "not_found", "message": "not found"}}).to_string(),
+            ),
+        })
+        .await;
+        let take_requests = || std::mem::take(&mut *requests.lock().unwrap());
+
+        assert_eq!(hcloud(&url, None).network_id().await.unwrap(), 9);
+        assert_eq!(take_requests(), ["GET /networks?name=test"]);
+
+        let mut with_id = hcloud(&url, Some(7));
+        assert_eq!(with_id.network_id().await.unwrap(), 7);
+        assert_eq!(take_requests(), ["GET /networks/7"]);
+
+        // Forgetting the network keeps its ID for the next time.
+        with_id.forget_network();
+        assert_eq!(with_id.network_id().await.unwrap(), 7);
+        assert_eq!(take_requests(), ["GET /networks/7"]);
+
+        // A network that's gone is looked up by name again.
+        assert_eq!(hcloud(&url, Some(8)).network_id().await.unwrap(), 9);
+        assert_eq!(
+            take_requests(),
+            ["GET /networks/8", "GET /networks?name=test"]
+        );
+    }
+
+    #[tokio::test]
+    async fn servers_without_an_ip_in_the_network_yet_are_kept_apart_from_detached_ones() {
+        let (url, _) = testing::http_server(|request, _| {
+            let private_net = match request {
+                "GET /networks/7" => return (200, json!({ "network": network(7) }).to_string()),
+                "GET /servers/1" => json!([{"network": 7, "ip": "10.0.0.2", "alias_ips": []}]),
+                "GET /servers/2" => json!([{"network": 7, "alias_ips": []}]),
+                "GET /servers/3" => json!([{"network": 8, "ip": "10.1.0.2", "alias_ips": []}]),
+                _ => return (404, "{}".to_string()),
+            };
+            let id = request.rsplit('/').next().unwrap().parse().unwrap();
+            (
+                200,
+                json!({ "server": server(id, private_net) }).to_string(),
+            )
+        })
+        .await;
+        let mut hcloud = hcloud(&url, Some(7));
+
+        let servers = hcloud.hydrate_server_list(vec![1, 2]).await.unwrap();
+        let ip_addresses: Vec<(i64, Option<String>)> =
+            servers.into_iter().map(|s| (s.id, s.ip_address)).collect();
+        assert_eq!(ip_addresses, [(1, Some("10.0.0.2".to_string())), (2, None)]);
+
+        assert!(matches!(
+            hcloud.hydrate_server_list(vec![1, 3]).await,
+            Err(Error::HCloud(_))
+        ));
+    }
+
+    #[test]
+    fn non_ascii_names_are_encoded_with_idna() {
+        for (name, encoded) in [
+            ("büro", "xn--bro-hoa"),
+            ("München", "xn--mnchen-3ya"),
+            ("日本.web", "xn--wgv71a.web"),
+            ("web-1", "web-1"),
+        ] {
+            let label = hostname_to_dns_label(1, name).unwrap();
+            assert_eq!(label, encoded);
+            assert_eq!(
+                idna::domain_to_unicode(&label).0,
+                name.to_lowercase(),
+                "{} doesn't decode back",
+                label
+            );
+        }
+
+        // The state keeps the encoded name, so the records are removed under it too.
+        let server = server_from_hcloud(
+            testing::hcloud_server(1, "büro", "10.0.0.2"),
+            "10.0.0.2".to_string(),
+            &testing::args(&[]),
+        )
+        .unwrap();
+        assert_eq!(server.fqdn(), "xn--bro-hoa.example.com");
+    }
+
+    #[tokio::test]
+    async fn network_info_finds_the_subnet_of_every_server() {
+        let (url, _) = testing::http_server(|request, _| {
+            let private_net = match request {
+                "GET /networks/7" => {
+                    let mut network = network(7);
+                    network["subnets"] = json!([
+                        {"type": "cloud", "network_zone": "eu-central", "gateway": "10.0.0.1", "ip_range": "10.0.0.0/24"},
+                        {"type": "cloud", "network_zone": "us-east", "gateway": "10.0.1.1", "ip_range": "10.0.1.0/24"},
+                    ]);
+                    network["routes"] = json!([{"destination": "10.100.0.0/16", "gateway": "10.0.0.5"}]);
+                    network["servers"] = json!([1, 2, 3]);
+                    return (200, json!({ "network": network }).to_string());
+                }
+                "GET /servers/1" => json!([{"network": 7, "ip": "10.0.0.2", "alias_ips": []}]),
+                "GET /servers/2" => json!([{"network": 7, "ip": "10.0.1.2", "alias_ips": []}]),
+                "GET /servers/3" => json!([{"network": 7, "alias_ips": []}]),
+                _ => return (404, "{}".to_string()),
+            };
+            let id = request.rsplit('/').next().unwrap().parse().unwrap();
+            (
+                200,
+                json!({ "server": server(id, private_net) }).to_string(),
+            )
+        })
+        .await;
+
+        let info = describe_network(
+            &mut hcloud(&url, Some(7)),
+            &testing::args(&["--publish-subnet", "10.0.1.0/24"]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            info.subnets
+                .iter()
+                .map(|s| (s.ip_range.as_deref(), s.gateway.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                (Some("10.0.0.0/24"), "10.0.0.1"),
+                (Some("10.0.1.0/24"), "10.0.1.1")
+            ]
+        );
+        assert_eq!(info.routes[0].destination, "10.100.0.0/16");
+        assert_eq!(
+            info.servers
+                .iter()
+                .map(|s| (
+                    s.id,
+                    s.ip_address.as_deref(),
+                    s.subnet.as_deref(),
+                    s.in_published_subnets
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (1, Some("10.0.0.2"), Some("10.0.0.0/24"), false),
+                (2, Some("10.0.1.2"), Some("10.0.1.0/24"), true),
+                (3, None, None, false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn several_networks_with_the_name_are_an_error_unless_the_first_one_is_wanted() {
+        let (url, _) = testing::http_server(|request, _| match request {
+            "GET /networks?name=test" => (
+                200,
+                json!({
+                    "networks": [network(9), network(10)],
+                    "meta": {"pagination": {
+                        "page": 1,
+                        "per_page": 25,
+                        "previous_page": null,
+                        "next_page": null,
+                        "last_page": 1,
+                        "total_entries": 2,
+                    }},
+                })
+                .to_string(),
+            ),
+            _ => (404, "{}".to_string()),
+        })
+        .await;
+
+        let Err(Error::HCloud(message)) = hcloud(&url, None).network_id().await else {
+            panic!("one of several networks with the name was picked");
+        };
+        assert!(message.contains("[9, 10]"), "{}", message);
+
+        let mut hcloud = hcloud(&url, None);
+        hcloud.on_ambiguous_network = OnAmbiguousNetwork::First;
+        assert_eq!(hcloud.network_id().await.unwrap(), 9);
+    }
+
+    #[test]
+    fn alias_ip_records_do_not_depend_on_the_order_of_the_alias_ips() {
+        let web = Server {
+            id: 1,
+            ip_address: "10.0.0.2".to_string(),
+            hostname: "web".to_string(),
+            zone: "example.com".to_string(),
+            ..Default::default()
+        };
+        let alias_ips = ["10.0.0.10", "10.0.0.9", "10.0.1.1"].map(String::from);
+        for alias_ip_records in ["same-name", "numbered"] {
+            let args = testing::args(&["--alias-ip-records", alias_ip_records]);
+            let expected = alias_ip_servers(&mut web.clone(), &alias_ips, &args);
+            assert_eq!(expected.len(), 3);
+
+            let mut permuted = alias_ips.clone();
+            for _ in 0..alias_ips.len() {
+                permuted.rotate_left(1);
+                for permuted in [permuted.to_vec(), permuted.iter().rev().cloned().collect()] {
+                    let servers = alias_ip_servers(&mut web.clone(), &permuted, &args);
+                    assert_eq!(servers, expected, "{alias_ip_records} {permuted:?}");
+                }
+            }
+        }
+    }
+}
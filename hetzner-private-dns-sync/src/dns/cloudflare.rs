@@ -0,0 +1,546 @@
+//! The Cloudflare API, with --dns-provider=cloudflare.
+
+use std::{collections::HashMap, fmt::Debug, net::SocketAddr, time::Duration};
+
+use serde::Deserialize;
+
+use super::{
+    api_add_server, api_remove_server, same_record_value, send_api_request, verify_propagation,
+    DnsBackend, RateLimiter, RecordApi, ZoneRecord,
+};
+use crate::{normalise_name, Error, Result, Server};
+
+/// Base URL of the Cloudflare API, for --dns-provider=cloudflare.
+const CLOUDFLARE_API_URL: &str = "https://api.cloudflare.com/client/v4";
+
+/// Every answer of the Cloudflare API is wrapped in one of these.
+#[derive(Deserialize)]
+struct CloudflareResponse<T> {
+    result: T,
+    #[serde(default)]
+    result_info: Option<CloudflareResultInfo>,
+}
+
+#[derive(Deserialize)]
+struct CloudflareResultInfo {
+    page: u32,
+    total_pages: u32,
+}
+
+#[derive(Deserialize)]
+struct CloudflareZone {
+    id: String,
+    name: String,
+}
+
+/// A record as the Cloudflare API has it.
+#[derive(Clone, Debug, Deserialize)]
+struct CloudflareRecord {
+    id: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    // Fully-qualified, without the trailing dot.
+    name: String,
+    // For SRV records, everything but the priority.
+    content: String,
+    #[serde(default)]
+    priority: Option<u16>,
+    // 1 means the TTL is chosen by Cloudflare.
+    ttl: u32,
+}
+
+impl CloudflareRecord {
+    /// The value of the record the way a zone file has it.
+    fn value(&self) -> String {
+        match (self.record_type.as_str(), self.priority) {
+            ("SRV", Some(priority)) => {
+                format!("{} {}.", priority, self.content.trim_end_matches('.'))
+            }
+            ("CNAME", _) => format!("{}.", self.content.trim_end_matches('.')),
+            _ => self.content.clone(),
+        }
+    }
+}
+
+/// Writes records through the Cloudflare API, with --dns-provider=cloudflare. Records are never proxied, since they point to private addresses. Cloudflare doesn't take TTLs under 60 seconds (30 on Enterprise plans).
+pub(crate) struct CloudflareWrapper {
+    client: reqwest::Client,
+    // `CLOUDFLARE_API_URL`, except in tests.
+    api_url: String,
+    api_token: String,
+    rate_limiter: Option<RateLimiter>,
+    record_comment: Option<String>,
+    // IDs of the zones we know, by their normalised name.
+    zone_ids: tokio::sync::Mutex<HashMap<String, String>>,
+}
+
+// Keeps the API token out of the logs.
+impl Debug for CloudflareWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CloudflareWrapper {{}}")
+    }
+}
+
+impl CloudflareWrapper {
+    /// With a `zone_id`, `zone_name` is never looked up, so the token only needs access to that zone's records.
+    pub(crate) fn new(
+        api_token: String,
+        zone_name: &str,
+        zone_id: Option<String>,
+        operation_timeout: Option<Duration>,
+        rate_limit: Option<u32>,
+        record_comment: Option<String>,
+    ) -> Result<Self> {
+        let mut client = reqwest::Client::builder();
+        if let Some(operation_timeout) = operation_timeout {
+            client = client.timeout(operation_timeout);
+        }
+        let client = client.build().map_err(|e| {
+            Error::Config(format!(
+                "unable to set up a client for the Cloudflare API. {}",
+                e
+            ))
+        })?;
+
+        Ok(Self {
+            client,
+            api_url: CLOUDFLARE_API_URL.to_string(),
+            api_token,
+            rate_limiter: rate_limit.map(RateLimiter::new),
+            record_comment,
+            zone_ids: tokio::sync::Mutex::new(
+                zone_id
+                    .into_iter()
+                    .map(|zone_id| (normalise_name(zone_name), zone_id))
+                    .collect(),
+            ),
+        })
+    }
+
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<Vec<u8>> {
+        let url = format!("{}{}", self.api_url, path);
+        let body = body.map(|body| body.to_string());
+        let (status, body) = send_api_request("Cloudflare API", self.rate_limiter.as_ref(), || {
+            let request = self
+                .client
+                .request(method.clone(), &url)
+                .bearer_auth(&self.api_token);
+            match &body {
+                Some(body) => request
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body.clone()),
+                None => request,
+            }
+        })
+        .await?;
+        if !status.is_success() {
+            return Err(Error::Dns(format!(
+                "the Cloudflare API answered with {}. {}",
+                status,
+                String::from_utf8_lossy(&body).trim()
+            )));
+        }
+
+        Ok(body)
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+    ) -> Result<CloudflareResponse<T>> {
+        let body = self.request(reqwest::Method::GET, path, None).await?;
+        serde_json::from_slice(&body).map_err(|e| {
+            Error::Dns(format!(
+                "unable to parse the answer of the Cloudflare API. {}",
+                e
+            ))
+        })
+    }
+
+    async fn zone_id(&self, zone_name: &str) -> Result<String> {
+        let zone_name = normalise_name(zone_name);
+        if let Some(zone_id) = self.zone_ids.lock().await.get(&zone_name) {
+            return Ok(zone_id.clone());
+        }
+
+        let zones: CloudflareResponse<Vec<CloudflareZone>> =
+            self.get(&format!("/zones?name={}", zone_name)).await?;
+        let zone_id = zones
+            .result
+            .into_iter()
+            .find(|zone| normalise_name(&zone.name) == zone_name)
+            .map(|zone| zone.id)
+            .ok_or_else(|| {
+                Error::Dns(format!(
+                    "the zone {} isn't in the Cloudflare account of the API token.",
+                    zone_name
+                ))
+            })?;
+        self.zone_ids
+            .lock()
+            .await
+            .insert(zone_name, zone_id.clone());
+
+        Ok(zone_id)
+    }
+
+    async fn records(&self, zone_name: &str) -> Result<(String, Vec<CloudflareRecord>)> {
+        let zone_id = self.zone_id(zone_name).await?;
+        let mut records = Vec::new();
+        let mut page = 1;
+        loop {
+            let response: CloudflareResponse<Vec<CloudflareRecord>> = self
+                .get(&format!(
+                    "/zones/{}/dns_records?per_page=1000&page={}",
+                    zone_id, page
+                ))
+                .await?;
+            records.extend(response.result);
+            match response.result_info {
+                Some(info) if info.page < info.total_pages => page = info.page + 1,
+                _ => break,
+            }
+        }
+
+        Ok((zone_id, records))
+    }
+}
+
+impl RecordApi for CloudflareWrapper {
+    async fn add_records(
+        &self,
+        zone_name: &str,
+        records: &[(String, &str, String)],
+        ttl: u32,
+    ) -> Result<()> {
+        let (zone_id, existing_records) = self.records(zone_name).await?;
+        for (fqdn, record_type, value) in records {
+            let name = normalise_name(fqdn);
+            let same_records: Vec<&CloudflareRecord> = existing_records
+                .iter()
+                .filter(|r| {
+                    normalise_name(&r.name) == name
+                        && r.record_type == *record_type
+                        && same_record_value(&r.value(), value)
+                })
+                .collect();
+            if same_records.iter().any(|r| r.ttl == ttl) {
+                continue;
+            }
+
+            for record in same_records {
+                self.request(
+                    reqwest::Method::DELETE,
+                    &format!("/zones/{}/dns_records/{}", zone_id, record.id),
+                    None,
+                )
+                .await?;
+            }
+            let mut body = serde_json::json!({
+                "type": record_type,
+                "name": name,
+                "ttl": ttl,
+                "proxied": false,
+            });
+            match value.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [priority, weight, port, target] if *record_type == "SRV" => {
+                    body["data"] = serde_json::json!({
+                        "priority": priority.parse::<u16>().unwrap_or_default(),
+                        "weight": weight.parse::<u16>().unwrap_or_default(),
+                        "port": port.parse::<u16>().unwrap_or_default(),
+                        "target": target.trim_end_matches('.'),
+                    });
+                }
+                _ => body["content"] = serde_json::json!(value.trim_end_matches('.')),
+            }
+            self.request(
+                reqwest::Method::POST,
+                &format!("/zones/{}/dns_records", zone_id),
+                Some(body),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn remove_records(
+        &self,
+        fqdn: &str,
+        zone_name: &str,
+        matches: impl Fn(&str, &str) -> bool,
+        whole_name_once_empty: bool,
+    ) -> Result<()> {
+        let name = normalise_name(fqdn);
+        let (zone_id, records) = self.records(zone_name).await?;
+        let (to_remove, remaining): (Vec<CloudflareRecord>, Vec<CloudflareRecord>) = records
+            .into_iter()
+            .filter(|r| normalise_name(&r.name) == name)
+            .partition(|r| matches(&r.record_type, &r.value()));
+        let to_remove = if whole_name_once_empty && !remaining.iter().any(|r| r.record_type == "A")
+        {
+            to_remove.into_iter().chain(remaining).collect()
+        } else {
+            to_remove
+        };
+
+        for record in to_remove {
+            self.request(
+                reqwest::Method::DELETE,
+                &format!("/zones/{}/dns_records/{}", zone_id, record.id),
+                None,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DnsBackend for CloudflareWrapper {
+    #[tracing::instrument]
+    async fn zone_records(&self, zone_name: &str) -> Result<Vec<ZoneRecord>> {
+        let (_, records) = self.records(zone_name).await?;
+
+        Ok(records
+            .into_iter()
+            .filter_map(|r| {
+                Some(ZoneRecord {
+                    name: normalise_name(&r.name),
+                    record_type: r.record_type.parse().ok()?,
+                    data: r.value(),
+                    ttl: r.ttl,
+                })
+            })
+            .collect())
+    }
+
+    #[tracing::instrument]
+    async fn add_server(&self, server: &Server) -> Result<()> {
+        tracing::debug!("Creating a DNS record for a server through the Cloudflare API.");
+
+        api_add_server(self, server, self.record_comment.as_deref()).await
+    }
+
+    #[tracing::instrument]
+    async fn remove_server(&self, server: &Server) -> Result<()> {
+        tracing::debug!("Deleting a DNS record for a server through the Cloudflare API.");
+
+        api_remove_server(self, server).await
+    }
+
+    #[tracing::instrument]
+    async fn remove_name(&self, fqdn: &str, zone_name: &str) -> Result<()> {
+        self.remove_records(fqdn, zone_name, |_, _| true, false)
+            .await
+    }
+
+    async fn verify_propagation(
+        &self,
+        server: &Server,
+        resolvers: &[SocketAddr],
+        timeout: Duration,
+    ) {
+        verify_propagation(server, resolvers, timeout).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{testing, Service};
+
+    /// Answers like the Cloudflare API with the "example.com" zone, which has a record for "db".
+    fn api(request: &str) -> (u16, String) {
+        match request {
+            "GET /zones?name=example.com" => (
+                200,
+                json!({"result": [{"id": "z1", "name": "example.com"}]}).to_string(),
+            ),
+            "GET /zones/z1/dns_records?per_page=1000&page=1" => (
+                200,
+                json!({
+                    "result": [
+                        {"id": "r1", "type": "A", "name": "db.example.com", "content": "10.0.0.3", "ttl": 300},
+                    ],
+                    "result_info": {"page": 1, "total_pages": 1},
+                })
+                .to_string(),
+            ),
+            _ if request.starts_with("GET /zones?name=") => {
+                (200, json!({"result": []}).to_string())
+            }
+            "POST /zones/z1/dns_records" | "DELETE /zones/z1/dns_records/r1" => {
+                (200, json!({"result": {}}).to_string())
+            }
+            _ => (404, json!({"errors": [{"message": "not found"}]}).to_string()),
+        }
+    }
+
+    /// Serves `api`, keeping every request along with its body.
+    async fn api_server(
+        zone_id: Option<&str>,
+    ) -> (CloudflareWrapper, Arc<Mutex<Vec<(String, String)>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let (url, _) = testing::http_server({
+            let requests = requests.clone();
+            move |request, body| {
+                requests
+                    .lock()
+                    .unwrap()
+                    .push((request.to_string(), body.to_string()));
+                api(request)
+            }
+        })
+        .await;
+
+        (cloudflare(&url, zone_id), requests)
+    }
+
+    fn cloudflare(url: &str, zone_id: Option<&str>) -> CloudflareWrapper {
+        let mut cloudflare = CloudflareWrapper::new(
+            "test".to_string(),
+            "example.com",
+            zone_id.map(String::from),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        cloudflare.api_url = url.to_string();
+        cloudflare
+    }
+
+    fn server(hostname: &str, ip_address: &str) -> Server {
+        Server {
+            id: 1,
+            ip_address: ip_address.to_string(),
+            hostname: hostname.to_string(),
+            zone: "example.com".to_string(),
+            ttl: 300,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn the_zone_id_is_looked_up_once_unless_it_was_given() {
+        let (cloudflare, requests) = api_server(None).await;
+        let take_requests = || -> Vec<String> {
+            std::mem::take(&mut *requests.lock().unwrap())
+                .into_iter()
+                .map(|(request, _)| request)
+                .collect()
+        };
+
+        cloudflare.zone_records("example.com").await.unwrap();
+        cloudflare.zone_records("example.com.").await.unwrap();
+        assert_eq!(
+            take_requests(),
+            [
+                "GET /zones?name=example.com",
+                "GET /zones/z1/dns_records?per_page=1000&page=1",
+                "GET /zones/z1/dns_records?per_page=1000&page=1",
+            ]
+        );
+
+        let Err(Error::Dns(message)) = cloudflare.zone_records("example.org").await else {
+            panic!("a zone that's not in the account was found");
+        };
+        assert!(
+            message.contains("example.org isn't in the Cloudflare account"),
+            "{}",
+            message
+        );
+
+        let (cloudflare, requests) = api_server(Some("z1")).await;
+        cloudflare.zone_records("example.com").await.unwrap();
+        assert_eq!(
+            requests.lock().unwrap()[0].0,
+            "GET /zones/z1/dns_records?per_page=1000&page=1"
+        );
+    }
+
+    #[tokio::test]
+    async fn records_are_created_unproxied_with_srv_records_split_up() {
+        let (cloudflare, requests) = api_server(Some("z1")).await;
+
+        let mut web = server("web", "10.0.0.2");
+        web.services = vec![Service {
+            name: "_http._tcp.web".to_string(),
+            port: 8080,
+        }];
+        cloudflare.add_server(&web).await.unwrap();
+        let created: Vec<serde_json::Value> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(request, _)| request == "POST /zones/z1/dns_records")
+            .map(|(_, body)| serde_json::from_str(body).unwrap())
+            .collect();
+        assert_eq!(
+            created,
+            [
+                json!({"type": "A", "name": "web.example.com", "ttl": 300, "proxied": false, "content": "10.0.0.2"}),
+                json!({"type": "SRV", "name": "_http._tcp.web.example.com", "ttl": 300, "proxied": false, "data": {
+                    "priority": 0,
+                    "weight": 0,
+                    "port": 8080,
+                    "target": "web.example.com",
+                }}),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_records_of_a_server_are_deleted_by_their_id() {
+        let (cloudflare, requests) = api_server(Some("z1")).await;
+
+        cloudflare
+            .remove_server(&server("db", "10.0.0.3"))
+            .await
+            .unwrap();
+        let deleted: Vec<String> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(request, _)| request.clone())
+            .filter(|request| !request.starts_with("GET "))
+            .collect();
+        assert_eq!(deleted, ["DELETE /zones/z1/dns_records/r1"]);
+    }
+
+    #[tokio::test]
+    async fn throttled_requests_are_sent_again_after_the_retry_after() {
+        let throttled = std::sync::atomic::AtomicBool::new(true);
+        let (url, requests) = testing::http_server_with_headers(move |request, _| {
+            if throttled.swap(false, std::sync::atomic::Ordering::Relaxed) {
+                return (
+                    429,
+                    vec![("retry-after", "1".to_string())],
+                    "{}".to_string(),
+                );
+            }
+            let (status, body) = api(request);
+            (status, Vec::new(), body)
+        })
+        .await;
+
+        let started = tokio::time::Instant::now();
+        let records = cloudflare(&url, Some("z1"))
+            .zone_records("example.com")
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,532 @@
+//! The Hetzner DNS API, with --dns-provider=hetzner-dns.
+
+use std::{collections::HashMap, fmt::Debug, net::SocketAddr, time::Duration};
+
+use serde::Deserialize;
+
+use super::{
+    api_add_server, api_remove_server, same_record_value, send_api_request, verify_propagation,
+    DnsBackend, RateLimiter, RecordApi, ZoneRecord,
+};
+use crate::{normalise_name, Error, Result, Server};
+
+/// Base URL of the Hetzner DNS API, for --dns-provider=hetzner-dns.
+const HETZNER_DNS_API_URL: &str = "https://dns.hetzner.com/api/v1";
+
+#[derive(Deserialize)]
+struct HetznerDnsZones {
+    zones: Vec<HetznerDnsZone>,
+}
+
+#[derive(Deserialize)]
+struct HetznerDnsZone {
+    id: String,
+    name: String,
+}
+
+/// How many records we ask the Hetzner DNS API for at once.
+const RECORDS_PER_PAGE: u32 = 100;
+
+#[derive(Deserialize)]
+struct HetznerDnsRecords {
+    records: Vec<HetznerDnsRecord>,
+    // Left out by the API when there's nothing to page through.
+    #[serde(default)]
+    meta: Option<HetznerDnsMeta>,
+}
+
+#[derive(Deserialize)]
+struct HetznerDnsMeta {
+    pagination: HetznerDnsPagination,
+}
+
+#[derive(Deserialize)]
+struct HetznerDnsPagination {
+    last_page: u32,
+}
+
+/// A record as the Hetzner DNS API has it.
+#[derive(Clone, Debug, Deserialize)]
+struct HetznerDnsRecord {
+    id: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    // Relative to the zone, with "@" for the zone itself.
+    name: String,
+    value: String,
+    // Records without a TTL of their own get the TTL of the zone.
+    #[serde(default)]
+    ttl: Option<u32>,
+}
+
+/// Writes records through the Hetzner DNS API, with --dns-provider=hetzner-dns. Unlike dynamic updates, the API lets us remove single records, so names shared by several servers don't need any extra care.
+pub(crate) struct HetznerDnsWrapper {
+    client: reqwest::Client,
+    // `HETZNER_DNS_API_URL`, except in tests.
+    api_url: String,
+    api_token: String,
+    rate_limiter: Option<RateLimiter>,
+    record_comment: Option<String>,
+    // IDs of the zones we looked up, by their normalised name.
+    zone_ids: tokio::sync::Mutex<HashMap<String, String>>,
+}
+
+// Keeps the API token out of the logs.
+impl Debug for HetznerDnsWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HetznerDnsWrapper {{}}")
+    }
+}
+
+impl HetznerDnsWrapper {
+    pub(crate) fn new(
+        api_token: String,
+        operation_timeout: Option<Duration>,
+        rate_limit: Option<u32>,
+        record_comment: Option<String>,
+    ) -> Result<Self> {
+        let mut client = reqwest::Client::builder();
+        if let Some(operation_timeout) = operation_timeout {
+            client = client.timeout(operation_timeout);
+        }
+        let client = client.build().map_err(|e| {
+            Error::Config(format!(
+                "unable to set up a client for the Hetzner DNS API. {}",
+                e
+            ))
+        })?;
+
+        Ok(Self {
+            client,
+            api_url: HETZNER_DNS_API_URL.to_string(),
+            api_token,
+            rate_limiter: rate_limit.map(RateLimiter::new),
+            record_comment,
+            zone_ids: tokio::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<Vec<u8>> {
+        let url = format!("{}{}", self.api_url, path);
+        let body = body.map(|body| body.to_string());
+        let (status, body) =
+            send_api_request("Hetzner DNS API", self.rate_limiter.as_ref(), || {
+                let request = self
+                    .client
+                    .request(method.clone(), &url)
+                    .header("Auth-API-Token", &self.api_token);
+                match &body {
+                    Some(body) => request
+                        .header(reqwest::header::CONTENT_TYPE, "application/json")
+                        .body(body.clone()),
+                    None => request,
+                }
+            })
+            .await?;
+        if !status.is_success() {
+            return Err(Error::Dns(format!(
+                "the Hetzner DNS API answered with {}. {}",
+                status,
+                String::from_utf8_lossy(&body).trim()
+            )));
+        }
+
+        Ok(body)
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let body = self.request(reqwest::Method::GET, path, None).await?;
+        serde_json::from_slice(&body).map_err(|e| {
+            Error::Dns(format!(
+                "unable to parse the answer of the Hetzner DNS API. {}",
+                e
+            ))
+        })
+    }
+
+    async fn zone_id(&self, zone_name: &str) -> Result<String> {
+        let zone_name = normalise_name(zone_name);
+        if let Some(zone_id) = self.zone_ids.lock().await.get(&zone_name) {
+            return Ok(zone_id.clone());
+        }
+
+        let zones: HetznerDnsZones = self.get(&format!("/zones?name={}", zone_name)).await?;
+        let zone_id = zones
+            .zones
+            .into_iter()
+            .find(|zone| normalise_name(&zone.name) == zone_name)
+            .map(|zone| zone.id)
+            .ok_or_else(|| {
+                Error::Dns(format!(
+                    "the zone {} isn't in the Hetzner DNS account of the API token.",
+                    zone_name
+                ))
+            })?;
+        self.zone_ids
+            .lock()
+            .await
+            .insert(zone_name, zone_id.clone());
+
+        Ok(zone_id)
+    }
+
+    async fn records(&self, zone_name: &str) -> Result<(String, Vec<HetznerDnsRecord>)> {
+        let zone_id = self.zone_id(zone_name).await?;
+        let mut records = Vec::new();
+        let mut page = 1;
+        loop {
+            let mut response: HetznerDnsRecords = self
+                .get(&format!(
+                    "/records?zone_id={}&page={}&per_page={}",
+                    zone_id, page, RECORDS_PER_PAGE
+                ))
+                .await?;
+            records.append(&mut response.records);
+            match response.meta {
+                Some(meta) if page < meta.pagination.last_page => page += 1,
+                _ => break,
+            }
+        }
+
+        Ok((zone_id, records))
+    }
+
+    /// The name of a record as the API has it, relative to its zone.
+    fn relative_name(fqdn: &str, zone_name: &str) -> String {
+        let fqdn = normalise_name(fqdn);
+        let zone_name = normalise_name(zone_name);
+        if fqdn == zone_name {
+            return "@".to_string();
+        }
+
+        fqdn.strip_suffix(&format!(".{}", zone_name))
+            .unwrap_or(&fqdn)
+            .to_string()
+    }
+}
+
+impl RecordApi for HetznerDnsWrapper {
+    async fn add_records(
+        &self,
+        zone_name: &str,
+        records: &[(String, &str, String)],
+        ttl: u32,
+    ) -> Result<()> {
+        let (zone_id, existing_records) = self.records(zone_name).await?;
+        for (fqdn, record_type, value) in records {
+            let name = Self::relative_name(fqdn, zone_name);
+            let same_records: Vec<&HetznerDnsRecord> = existing_records
+                .iter()
+                .filter(|r| {
+                    r.name == name
+                        && r.record_type == *record_type
+                        && same_record_value(&r.value, value)
+                })
+                .collect();
+            if same_records.iter().any(|r| r.ttl == Some(ttl)) {
+                continue;
+            }
+
+            for record in same_records {
+                self.request(
+                    reqwest::Method::DELETE,
+                    &format!("/records/{}", record.id),
+                    None,
+                )
+                .await?;
+            }
+            self.request(
+                reqwest::Method::POST,
+                "/records",
+                Some(serde_json::json!({
+                    "zone_id": zone_id,
+                    "type": record_type,
+                    "name": name,
+                    "value": value,
+                    "ttl": ttl,
+                })),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn remove_records(
+        &self,
+        fqdn: &str,
+        zone_name: &str,
+        matches: impl Fn(&str, &str) -> bool,
+        whole_name_once_empty: bool,
+    ) -> Result<()> {
+        let name = Self::relative_name(fqdn, zone_name);
+        let (_, records) = self.records(zone_name).await?;
+        let (to_remove, remaining): (Vec<HetznerDnsRecord>, Vec<HetznerDnsRecord>) = records
+            .into_iter()
+            .filter(|r| r.name == name)
+            .partition(|r| matches(&r.record_type, &r.value));
+        let to_remove = if whole_name_once_empty && !remaining.iter().any(|r| r.record_type == "A")
+        {
+            to_remove.into_iter().chain(remaining).collect()
+        } else {
+            to_remove
+        };
+
+        for record in to_remove {
+            self.request(
+                reqwest::Method::DELETE,
+                &format!("/records/{}", record.id),
+                None,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DnsBackend for HetznerDnsWrapper {
+    #[tracing::instrument]
+    async fn zone_records(&self, zone_name: &str) -> Result<Vec<ZoneRecord>> {
+        let (_, records) = self.records(zone_name).await?;
+
+        Ok(records
+            .into_iter()
+            .filter_map(|r| {
+                let fqdn = match r.name.as_str() {
+                    "@" => zone_name.to_string(),
+                    name => format!("{}.{}", name, zone_name),
+                };
+                // Names in values can be relative to the zone, but we compare them as fully-qualified names.
+                let data = match r.record_type.as_str() {
+                    "CNAME" if !r.value.ends_with('.') => {
+                        format!("{}.{}.", r.value, normalise_name(zone_name))
+                    }
+                    _ => r.value,
+                };
+
+                Some(ZoneRecord {
+                    name: normalise_name(&fqdn),
+                    record_type: r.record_type.parse().ok()?,
+                    data,
+                    ttl: r.ttl.unwrap_or_default(),
+                })
+            })
+            .collect())
+    }
+
+    #[tracing::instrument]
+    async fn add_server(&self, server: &Server) -> Result<()> {
+        tracing::debug!("Creating a DNS record for a server through the Hetzner DNS API.");
+
+        api_add_server(self, server, self.record_comment.as_deref()).await
+    }
+
+    #[tracing::instrument]
+    async fn remove_server(&self, server: &Server) -> Result<()> {
+        tracing::debug!("Deleting a DNS record for a server through the Hetzner DNS API.");
+
+        api_remove_server(self, server).await
+    }
+
+    #[tracing::instrument]
+    async fn remove_name(&self, fqdn: &str, zone_name: &str) -> Result<()> {
+        self.remove_records(fqdn, zone_name, |_, _| true, false)
+            .await
+    }
+
+    async fn verify_propagation(
+        &self,
+        server: &Server,
+        resolvers: &[SocketAddr],
+        timeout: Duration,
+    ) {
+        verify_propagation(server, resolvers, timeout).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing;
+
+    /// Answers like the Hetzner DNS API with the "example.com" zone, whose records come in two pages.
+    fn api(request: &str) -> (u16, String) {
+        let page = |records: serde_json::Value| {
+            json!({
+                "records": records,
+                "meta": {"pagination": {"page": 1, "per_page": 100, "last_page": 2, "total_entries": 4}},
+            })
+            .to_string()
+        };
+
+        match request {
+            "GET /zones?name=example.com" => (
+                200,
+                json!({"zones": [{"id": "z1", "name": "example.com"}]}).to_string(),
+            ),
+            "GET /records?zone_id=z1&page=1&per_page=100" => (
+                200,
+                page(json!([
+                    {"id": "r1", "type": "A", "name": "web", "value": "10.0.0.2", "ttl": 60},
+                    {"id": "r2", "type": "A", "name": "db", "value": "10.0.0.3", "ttl": 300},
+                ])),
+            ),
+            "GET /records?zone_id=z1&page=2&per_page=100" => (
+                200,
+                page(json!([
+                    {"id": "r3", "type": "A", "name": "app", "value": "10.0.0.4"},
+                    {"id": "r4", "type": "A", "name": "app", "value": "10.0.0.5", "ttl": 300},
+                ])),
+            ),
+            _ if !request.starts_with("GET ") => (200, "{}".to_string()),
+            _ => (404, json!({"error": {"message": "not found"}}).to_string()),
+        }
+    }
+
+    /// Serves `api`, keeping the requests that change records along with their bodies.
+    async fn api_server() -> (HetznerDnsWrapper, Arc<Mutex<Vec<(String, String)>>>) {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let (url, _) = testing::http_server({
+            let changes = changes.clone();
+            move |request, body| {
+                if !request.starts_with("GET ") {
+                    changes
+                        .lock()
+                        .unwrap()
+                        .push((request.to_string(), body.to_string()));
+                }
+                api(request)
+            }
+        })
+        .await;
+
+        (hetzner_dns(&url), changes)
+    }
+
+    fn hetzner_dns(url: &str) -> HetznerDnsWrapper {
+        let mut hetzner_dns = HetznerDnsWrapper::new("test".to_string(), None, None, None).unwrap();
+        hetzner_dns.api_url = url.to_string();
+        hetzner_dns
+    }
+
+    fn server(hostname: &str, ip_address: &str) -> Server {
+        Server {
+            id: 1,
+            ip_address: ip_address.to_string(),
+            hostname: hostname.to_string(),
+            zone: "example.com".to_string(),
+            ttl: 300,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn records_are_read_from_every_page() {
+        let (hetzner_dns, _) = api_server().await;
+
+        let records: Vec<(String, String, u32)> = hetzner_dns
+            .zone_records("example.com")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.name, r.data, r.ttl))
+            .collect();
+        assert_eq!(
+            records,
+            [
+                ("web.example.com".to_string(), "10.0.0.2".to_string(), 60),
+                ("db.example.com".to_string(), "10.0.0.3".to_string(), 300),
+                ("app.example.com".to_string(), "10.0.0.4".to_string(), 0),
+                ("app.example.com".to_string(), "10.0.0.5".to_string(), 300),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn records_are_only_replaced_when_their_ttl_changes() {
+        let (hetzner_dns, changes) = api_server().await;
+
+        hetzner_dns
+            .add_server(&server("db", "10.0.0.3"))
+            .await
+            .unwrap();
+        assert!(changes.lock().unwrap().is_empty());
+
+        hetzner_dns
+            .add_server(&server("web", "10.0.0.2"))
+            .await
+            .unwrap();
+        let changes = std::mem::take(&mut *changes.lock().unwrap());
+        assert_eq!(
+            changes[0],
+            ("DELETE /records/r1".to_string(), String::new())
+        );
+        assert_eq!(changes[1].0, "POST /records");
+        let body: serde_json::Value = serde_json::from_str(&changes[1].1).unwrap();
+        assert_eq!(
+            body,
+            json!({"zone_id": "z1", "type": "A", "name": "web", "value": "10.0.0.2", "ttl": 300})
+        );
+        assert_eq!(changes.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn only_the_records_of_the_server_go_from_a_shared_name() {
+        let (hetzner_dns, changes) = api_server().await;
+
+        let mut app = server("app", "10.0.0.5");
+        app.shared_name = true;
+        hetzner_dns.remove_server(&app).await.unwrap();
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [("DELETE /records/r4".to_string(), String::new())]
+        );
+
+        hetzner_dns
+            .remove_server(&server("db", "10.0.0.3"))
+            .await
+            .unwrap();
+        assert_eq!(
+            changes.lock().unwrap()[1],
+            ("DELETE /records/r2".to_string(), String::new())
+        );
+    }
+
+    #[tokio::test]
+    async fn throttled_requests_are_sent_again_after_the_retry_after() {
+        let throttled = std::sync::atomic::AtomicBool::new(true);
+        let (url, requests) = testing::http_server_with_headers(move |request, _| {
+            if throttled.swap(false, std::sync::atomic::Ordering::Relaxed) {
+                return (
+                    429,
+                    vec![("retry-after", "1".to_string())],
+                    "{}".to_string(),
+                );
+            }
+            let (status, body) = api(request);
+            (status, Vec::new(), body)
+        })
+        .await;
+
+        let started = tokio::time::Instant::now();
+        hetzner_dns(&url)
+            .add_server(&server("db", "10.0.0.3"))
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(
+            requests.lock().unwrap()[..2],
+            ["GET /zones?name=example.com", "GET /zones?name=example.com"]
+        );
+    }
+}
@@ -0,0 +1,695 @@
+//! Talking to the DNS servers the records are published on.
+
+mod cloudflare;
+mod hetzner;
+mod powerdns;
+mod rfc2136;
+
+pub(crate) use rfc2136::{
+    is_tls_address, ownership_record_content, read_tsig_key, MIN_TSIG_KEY_LENGTH, TLS_UNSUPPORTED,
+};
+
+use std::{net::SocketAddr, time::Duration};
+
+use hickory_client::{
+    client::{AsyncClient, ClientConnection, ClientHandle},
+    rr::{Name, RecordType},
+    tcp::TcpClientConnection,
+};
+
+use cloudflare::CloudflareWrapper;
+use hetzner::HetznerDnsWrapper;
+use powerdns::PowerDnsWrapper;
+use rfc2136::{DnsUpdaterWrapper, ReverseZone, TsigKey};
+
+use crate::{
+    metrics::PROPAGATION_SECONDS, normalise_name, plan::check_fqdn_length, Args, DnsProvider,
+    Error, Result, Server,
+};
+
+/// A record we found in a zone.
+#[derive(Clone, Debug)]
+pub(crate) struct ZoneRecord {
+    // Fully-qualified and normalised with `normalise_name`.
+    pub(crate) name: String,
+    pub(crate) record_type: RecordType,
+    // In the same form as in a zone file, e.g. "10.0.0.1" or "target.example.com.".
+    pub(crate) data: String,
+    pub(crate) ttl: u32,
+}
+
+/// What a sync needs from the DNS servers. `DnsUpdaterWrapper` sends dynamic updates, `HetznerDnsWrapper`, `PowerDnsWrapper` and `CloudflareWrapper` go through the APIs of their providers (sharing most of it through `RecordApi`), and tests can put something that records the calls in its place. A new provider only needs to implement this and get a variant in `DnsClient`.
+pub(crate) trait DnsBackend {
+    /// Every record in the zone, with fully-qualified names normalised with `normalise_name` and names in CNAME values fully-qualified with the trailing dot. Used to import and check the state, so records the provider can't describe can be left out.
+    async fn zone_records(&self, zone_name: &str) -> Result<Vec<ZoneRecord>>;
+    /// Publishes every record of the server with its TTL. It has to be safe to call again for a server that's already published, which is how TTL changes and retries get applied.
+    async fn add_server(&self, server: &Server) -> Result<()>;
+    /// Removes the records of the server. With `shared_name`, only the ones with its own values go, and the rest of the name once no server is left on it.
+    async fn remove_server(&self, server: &Server) -> Result<()>;
+    /// Removes everything on the name.
+    async fn remove_name(&self, fqdn: &str, zone_name: &str) -> Result<()>;
+    /// Waits for the records of the server to be visible on the resolvers, only logging what isn't.
+    async fn verify_propagation(
+        &self,
+        server: &Server,
+        resolvers: &[SocketAddr],
+        timeout: Duration,
+    );
+}
+
+/// A token bucket that refills at `rate` tokens per second, holding at most `rate` tokens.
+#[derive(Debug)]
+struct RateLimiter {
+    rate: f64,
+    // How many tokens are left, as of the instant next to it.
+    bucket: tokio::sync::Mutex<(f64, tokio::time::Instant)>,
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        let rate = f64::from(rate);
+
+        Self {
+            rate,
+            bucket: tokio::sync::Mutex::new((rate, tokio::time::Instant::now())),
+        }
+    }
+
+    /// Waits until there's a token, and takes it.
+    async fn acquire(&self) {
+        // Holding the lock while sleeping makes everyone else wait their turn behind us.
+        let mut bucket = self.bucket.lock().await;
+        let (tokens, last_refill) = &mut *bucket;
+
+        let now = tokio::time::Instant::now();
+        *tokens =
+            (*tokens + now.duration_since(*last_refill).as_secs_f64() * self.rate).min(self.rate);
+        *last_refill = now;
+
+        if *tokens < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - *tokens) / self.rate);
+            tracing::debug!(
+                ?wait,
+                "Waiting before sending an update, to stay within the rate limit."
+            );
+            tokio::time::sleep(wait).await;
+            *tokens = 1.0;
+            *last_refill = tokio::time::Instant::now();
+        }
+
+        *tokens -= 1.0;
+    }
+}
+
+/// How many times we send a request the DNS server or API turned away for being busy, before giving up on it.
+const THROTTLED_ATTEMPTS: u32 = 4;
+
+/// Longest we wait before sending a request again, whatever the API asks for in Retry-After.
+const MAX_THROTTLED_WAIT: Duration = Duration::from_secs(60);
+
+/// How long to wait after the attempt number `attempt` was turned away: as long as the API asked for with Retry-After, or else a second, doubling with every attempt.
+fn throttled_wait(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    retry_after
+        .unwrap_or_else(|| Duration::from_secs(1 << (attempt - 1)))
+        .min(MAX_THROTTLED_WAIT)
+}
+
+/// Sends a request to the API of a provider, and sends it again for as long as the API answers with 429 Too Many Requests. Returns the status and body of the answer we took.
+async fn send_api_request(
+    api_name: &str,
+    rate_limiter: Option<&RateLimiter>,
+    request: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<(reqwest::StatusCode, Vec<u8>)> {
+    let mut attempt = 1;
+    loop {
+        if let Some(rate_limiter) = rate_limiter {
+            rate_limiter.acquire().await;
+        }
+
+        let response = request()
+            .send()
+            .await
+            .map_err(|e| Error::Dns(format!("unable to reach the {}. {}", api_name, e)))?;
+        let status = response.status();
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS && attempt < THROTTLED_ATTEMPTS {
+            // Retry-After can also be a date, which we treat like it wasn't there.
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs);
+            let wait = throttled_wait(attempt, retry_after);
+            tracing::warn!(
+                attempt,
+                ?wait,
+                "The {} is throttling us, will try again.",
+                api_name
+            );
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+            continue;
+        }
+
+        let body = response.bytes().await.map_err(|e| {
+            Error::Dns(format!(
+                "unable to read the answer of the {}. {}",
+                api_name, e
+            ))
+        })?;
+
+        return Ok((status, body.to_vec()));
+    }
+}
+
+/// With --verify, polls each resolver until it answers with the record we published for the server, and logs how long it took to show up. Returns that time for each resolver, in order, with `None` where the record didn't show up before the timeout.
+async fn verify_propagation(
+    server: &Server,
+    resolvers: &[SocketAddr],
+    timeout: Duration,
+) -> Vec<Option<Duration>> {
+    let Ok(name) = Name::from_str_relaxed(server.fqdn()) else {
+        tracing::warn!("Can't verify the record, its name is invalid.");
+        return vec![None; resolvers.len()];
+    };
+    let record_type = match (&server.cname, server.address_record().0) {
+        (Some(_), _) => RecordType::CNAME,
+        (None, "AAAA") => RecordType::AAAA,
+        (None, _) => RecordType::A,
+    };
+
+    let mut propagation = Vec::with_capacity(resolvers.len());
+    for &resolver in resolvers {
+        let started = tokio::time::Instant::now();
+        let mut backoff = Duration::from_millis(100);
+
+        let took = loop {
+            match query(resolver, name.clone(), record_type).await {
+                Ok(answers) if answers.iter().any(|a| server.matches_record_data(a)) => {
+                    let took = started.elapsed();
+                    tracing::info!(
+                        %resolver,
+                        propagation_seconds = took.as_secs_f64(),
+                        "The record showed up."
+                    );
+                    PROPAGATION_SECONDS.observe(&resolver.to_string(), took.as_secs_f64());
+                    break Some(took);
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    tracing::debug!(%resolver, error = %e, "Unable to query for the record.")
+                }
+            }
+
+            if started.elapsed() + backoff > timeout {
+                tracing::warn!(
+                    %resolver,
+                    timeout_seconds = timeout.as_secs(),
+                    "The record didn't show up in time."
+                );
+                break None;
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_secs(2));
+        };
+        propagation.push(took);
+    }
+
+    propagation
+}
+
+/// What a backend writing records through an HTTP API has to do, so `api_add_server` and `api_remove_server` can work out the rest. Values are in the same form as in a zone file.
+trait RecordApi {
+    /// Adds the records to the zone. Just like with dynamic updates, a record that's already there isn't duplicated, and one that's there with another TTL gets the new one.
+    async fn add_records(
+        &self,
+        zone_name: &str,
+        records: &[(String, &str, String)],
+        ttl: u32,
+    ) -> Result<()>;
+
+    /// Removes the records on the name that `matches` picks by their type and value. With `whole_name_once_empty`, everything on the name goes once it has no A record left.
+    async fn remove_records(
+        &self,
+        fqdn: &str,
+        zone_name: &str,
+        matches: impl Fn(&str, &str) -> bool,
+        whole_name_once_empty: bool,
+    ) -> Result<()>;
+}
+
+/// Whether two record values are the same, regardless of the quotes around TXT values and the case and trailing dot of names.
+fn same_record_value(a: &str, b: &str) -> bool {
+    normalise_name(a.trim_matches('"')) == normalise_name(b.trim_matches('"'))
+}
+
+/// Publishes the same records for the server as `DnsUpdaterWrapper::add_server` does, except for the ones only dynamic updates have (ownership and PTR records).
+async fn api_add_server(
+    api: &impl RecordApi,
+    server: &Server,
+    record_comment: Option<&str>,
+) -> Result<()> {
+    check_fqdn_length(server)?;
+    let fqdn = server.fqdn();
+    let mut records = Vec::new();
+    match &server.cname {
+        Some(target) => records.push((fqdn.clone(), "CNAME", target.clone())),
+        None => {
+            let (address_type, address) = server.address_record();
+            records.push((fqdn.clone(), address_type, address.to_string()));
+            if let Some(wildcard_fqdn) = server.wildcard_fqdn() {
+                records.push((wildcard_fqdn, address_type, address.to_string()));
+            }
+            if let Some(comment) = record_comment {
+                records.push((fqdn.clone(), "TXT", format!("\"{}\"", comment)));
+            }
+            if let Some(metadata) = &server.metadata {
+                records.push((fqdn.clone(), "TXT", format!("\"{}\"", metadata)));
+            }
+            if let (Some(ipv6_address), false) = (&server.ipv6_address, server.no_a_record) {
+                records.push((fqdn.clone(), "AAAA", ipv6_address.clone()));
+            }
+            for group_fqdn in server.group_fqdns() {
+                records.push((group_fqdn, address_type, address.to_string()));
+            }
+            for service in &server.services {
+                records.push((
+                    server.service_fqdn(service),
+                    "SRV",
+                    format!("0 0 {} {}.", service.port, fqdn.trim_end_matches('.')),
+                ));
+            }
+        }
+    }
+    api.add_records(&server.zone, &records, server.ttl).await?;
+
+    if let (Some((public_zone, public_ip_address)), Some(public_fqdn)) =
+        (server.public_record(), server.public_fqdn())
+    {
+        api.add_records(
+            public_zone,
+            &[(public_fqdn, "A", public_ip_address.to_string())],
+            server.ttl,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Removes the records `api_add_server` published for the server.
+async fn api_remove_server(api: &impl RecordApi, server: &Server) -> Result<()> {
+    let is = |record_type: &str, value: &str, expected_type: &str, expected_value: &str| {
+        record_type == expected_type && same_record_value(value, expected_value)
+    };
+
+    // The records of a shared name are removed one by one, like with dynamic updates, so the other servers keep theirs.
+    let shared_name = server.shared_name;
+    let is_own_record = |record_type: &str, value: &str| {
+        !shared_name
+            || is(record_type, value, "A", &server.ip_address)
+            || server
+                .ipv6_address
+                .as_ref()
+                .is_some_and(|ipv6_address| is(record_type, value, "AAAA", ipv6_address))
+            || server
+                .metadata
+                .as_ref()
+                .is_some_and(|metadata| is(record_type, value, "TXT", metadata))
+    };
+    api.remove_records(&server.fqdn(), &server.zone, is_own_record, shared_name)
+        .await?;
+    if let Some(wildcard_fqdn) = server.wildcard_fqdn() {
+        api.remove_records(&wildcard_fqdn, &server.zone, is_own_record, shared_name)
+            .await?;
+    }
+    if let (Some((public_zone, public_ip_address)), Some(public_fqdn)) =
+        (server.public_record(), server.public_fqdn())
+    {
+        api.remove_records(
+            &public_fqdn,
+            public_zone,
+            |record_type, value| !shared_name || is(record_type, value, "A", public_ip_address),
+            shared_name,
+        )
+        .await?;
+    }
+
+    let (address_type, address) = server.address_record();
+    for group_fqdn in server.group_fqdns() {
+        api.remove_records(
+            &group_fqdn,
+            &server.zone,
+            |record_type, value| is(record_type, value, address_type, address),
+            false,
+        )
+        .await?;
+    }
+    for service in &server.services {
+        let srv_value = format!(
+            "0 0 {} {}.",
+            service.port,
+            server.fqdn().trim_end_matches('.')
+        );
+        api.remove_records(
+            &server.service_fqdn(service),
+            &server.zone,
+            |record_type, value| is(record_type, value, "SRV", &srv_value),
+            false,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// The DNS backend picked with --dns-provider.
+#[derive(Debug)]
+pub(crate) enum DnsClient {
+    Rfc2136(DnsUpdaterWrapper),
+    HetznerDns(HetznerDnsWrapper),
+    PowerDns(PowerDnsWrapper),
+    Cloudflare(CloudflareWrapper),
+}
+
+impl DnsBackend for DnsClient {
+    async fn zone_records(&self, zone_name: &str) -> Result<Vec<ZoneRecord>> {
+        match self {
+            Self::Rfc2136(backend) => backend.zone_records(zone_name).await,
+            Self::HetznerDns(backend) => backend.zone_records(zone_name).await,
+            Self::PowerDns(backend) => backend.zone_records(zone_name).await,
+            Self::Cloudflare(backend) => backend.zone_records(zone_name).await,
+        }
+    }
+
+    async fn add_server(&self, server: &Server) -> Result<()> {
+        match self {
+            Self::Rfc2136(backend) => backend.add_server(server).await,
+            Self::HetznerDns(backend) => backend.add_server(server).await,
+            Self::PowerDns(backend) => backend.add_server(server).await,
+            Self::Cloudflare(backend) => backend.add_server(server).await,
+        }
+    }
+
+    async fn remove_server(&self, server: &Server) -> Result<()> {
+        match self {
+            Self::Rfc2136(backend) => backend.remove_server(server).await,
+            Self::HetznerDns(backend) => backend.remove_server(server).await,
+            Self::PowerDns(backend) => backend.remove_server(server).await,
+            Self::Cloudflare(backend) => backend.remove_server(server).await,
+        }
+    }
+
+    async fn remove_name(&self, fqdn: &str, zone_name: &str) -> Result<()> {
+        match self {
+            Self::Rfc2136(backend) => backend.remove_name(fqdn, zone_name).await,
+            Self::HetznerDns(backend) => backend.remove_name(fqdn, zone_name).await,
+            Self::PowerDns(backend) => backend.remove_name(fqdn, zone_name).await,
+            Self::Cloudflare(backend) => backend.remove_name(fqdn, zone_name).await,
+        }
+    }
+
+    async fn verify_propagation(
+        &self,
+        server: &Server,
+        resolvers: &[SocketAddr],
+        timeout: Duration,
+    ) {
+        match self {
+            Self::Rfc2136(backend) => backend.verify_propagation(server, resolvers, timeout).await,
+            Self::HetznerDns(backend) => {
+                backend.verify_propagation(server, resolvers, timeout).await
+            }
+            Self::PowerDns(backend) => backend.verify_propagation(server, resolvers, timeout).await,
+            Self::Cloudflare(backend) => {
+                backend.verify_propagation(server, resolvers, timeout).await
+            }
+        }
+    }
+}
+
+pub(crate) async fn dns_updater(args: &Args) -> Result<DnsClient> {
+    if args.dns_provider == DnsProvider::HetznerDns {
+        let Some(api_token) = &args.hetzner_dns_api_token else {
+            return Err(Error::Config(
+                "--hetzner-dns-api-token is needed with --dns-provider=hetzner-dns.".to_string(),
+            ));
+        };
+        let dns_updater = HetznerDnsWrapper::new(
+            api_token.trim().to_string(),
+            args.dns_op_timeout.map(Duration::from_secs),
+            args.dns_update_rate_limit,
+            args.record_comment.clone(),
+        )?;
+        tracing::info!("Hetzner DNS API client initialised.");
+
+        return Ok(DnsClient::HetznerDns(dns_updater));
+    }
+
+    if args.dns_provider == DnsProvider::PowerDns {
+        let (Some(api_url), Some(api_key)) = (&args.powerdns_api_url, &args.powerdns_api_key)
+        else {
+            return Err(Error::Config(
+                "--powerdns-api-url and --powerdns-api-key are needed with --dns-provider=powerdns."
+                    .to_string(),
+            ));
+        };
+        let dns_updater = PowerDnsWrapper::new(
+            api_url,
+            &args.powerdns_server_id,
+            api_key.trim().to_string(),
+            args.dns_op_timeout.map(Duration::from_secs),
+            args.dns_update_rate_limit,
+            args.record_comment.clone(),
+        )?;
+        tracing::info!("PowerDNS API client initialised.");
+
+        return Ok(DnsClient::PowerDns(dns_updater));
+    }
+
+    if args.dns_provider == DnsProvider::Cloudflare {
+        let Some(api_token) = &args.cloudflare_api_token else {
+            return Err(Error::Config(
+                "--cloudflare-api-token is needed with --dns-provider=cloudflare.".to_string(),
+            ));
+        };
+        let dns_updater = CloudflareWrapper::new(
+            api_token.trim().to_string(),
+            &args.zone_name,
+            args.cloudflare_zone_id.clone(),
+            args.dns_op_timeout.map(Duration::from_secs),
+            args.dns_update_rate_limit,
+            args.record_comment.clone(),
+        )?;
+        tracing::info!("Cloudflare API client initialised.");
+
+        return Ok(DnsClient::Cloudflare(dns_updater));
+    }
+
+    let (Some(tsig_key_name), Some(tsig_key_path)) = (&args.tsig_key_name, &args.tsig_key_path)
+    else {
+        return Err(Error::Config(
+            "--tsig-key-name and --tsig-key-path are needed with --dns-provider=rfc2136."
+                .to_string(),
+        ));
+    };
+    let mut dns_updater = DnsUpdaterWrapper::new(
+        args.server_address.clone(),
+        TsigKey {
+            name: tsig_key_name.clone(),
+            path: tsig_key_path.clone(),
+            algorithm: args.tsig_algorithm,
+            fudge: args.tsig_fudge,
+        },
+        args.dns_op_timeout.map(Duration::from_secs),
+        args.dns_update_rate_limit,
+        args.record_comment.clone(),
+    )
+    .await?;
+    dns_updater.reverse_zone = ReverseZone::new(args).await?;
+    dns_updater.owner_id = args.owner_id.clone();
+    dns_updater.update_zone = args
+        .update_zone
+        .as_ref()
+        .map(|update_zone| (normalise_name(&args.zone_name), normalise_name(update_zone)));
+    tracing::info!("DNS Updater initialised.");
+
+    Ok(DnsClient::Rfc2136(dns_updater))
+}
+
+/// Asks a DNS server for the records of a name, and returns their data in the same form as in a zone file.
+async fn query(
+    resolver: SocketAddr,
+    name: Name,
+    record_type: RecordType,
+) -> std::result::Result<Vec<String>, hickory_client::error::ClientError> {
+    let connection = TcpClientConnection::new(resolver)?.new_stream(None);
+    let (mut client, background) = AsyncClient::connect(connection).await?;
+    tokio::spawn(background);
+
+    let response = client
+        .query(name, hickory_client::rr::DNSClass::IN, record_type)
+        .await?;
+
+    Ok(response
+        .answers()
+        .iter()
+        .filter(|r| r.record_type() == record_type)
+        .filter_map(|r| r.data().map(|d| d.to_string()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use hickory_client::{
+        op::{Message, MessageType},
+        rr::{rdata::A, RData, Record},
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// A resolver answering queries over TCP, with an A record of 10.0.0.2 for every name once `delay` has passed since it started, and with no answer before that.
+    async fn delayed_resolver(delay: Duration) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let started = tokio::time::Instant::now();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let length = stream.read_u16().await.unwrap();
+                    let mut buffer = vec![0; usize::from(length)];
+                    stream.read_exact(&mut buffer).await.unwrap();
+                    let request = Message::from_vec(&buffer).unwrap();
+
+                    let mut response = Message::new();
+                    response
+                        .set_id(request.id())
+                        .set_message_type(MessageType::Response)
+                        .add_queries(request.queries().to_vec());
+                    if started.elapsed() >= delay {
+                        let name = request.queries()[0].name().clone();
+                        response.add_answer(Record::from_rdata(
+                            name,
+                            60,
+                            RData::A(A(Ipv4Addr::new(10, 0, 0, 2))),
+                        ));
+                    }
+
+                    let response = response.to_vec().unwrap();
+                    stream
+                        .write_u16(u16::try_from(response.len()).unwrap())
+                        .await
+                        .unwrap();
+                    stream.write_all(&response).await.unwrap();
+                });
+            }
+        });
+
+        address
+    }
+
+    #[tokio::test]
+    async fn verify_propagation_waits_for_the_record_to_show_up() {
+        let delay = Duration::from_millis(300);
+        let resolvers = [
+            delayed_resolver(delay).await,
+            delayed_resolver(Duration::MAX).await,
+        ];
+        let server = Server {
+            id: 1,
+            ip_address: "10.0.0.2".to_string(),
+            hostname: "web".to_string(),
+            zone: "example.com".to_string(),
+            ..Default::default()
+        };
+
+        let propagation = verify_propagation(&server, &resolvers, Duration::from_secs(1)).await;
+
+        assert_eq!(propagation.len(), 2);
+        let took = propagation[0].expect("the record should have shown up");
+        assert!(took >= delay && took < Duration::from_secs(1), "{took:?}");
+        assert_eq!(propagation[1], None);
+
+        // Only the resolver that got the record has it in the histogram. Other tests observe into it too, but not for these resolvers.
+        let metrics = crate::metrics::render_metrics();
+        let count = format!(
+            "dns_sync_propagation_seconds_count{{resolver=\"{}\"}} 1\n",
+            resolvers[0]
+        );
+        assert!(metrics.contains(&count), "{}", metrics);
+        assert!(
+            !metrics.contains(&format!("resolver=\"{}\"", resolvers[1])),
+            "{}",
+            metrics
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_rate_limiter_spaces_out_updates_past_its_burst() {
+        let rate_limiter = RateLimiter::new(5);
+        let started = tokio::time::Instant::now();
+
+        let mut acquired_at = Vec::new();
+        for _ in 0..8 {
+            rate_limiter.acquire().await;
+            acquired_at.push(started.elapsed().as_millis());
+        }
+        assert_eq!(acquired_at, [0, 0, 0, 0, 0, 200, 400, 600]);
+
+        // A second's worth of quiet fills the bucket back up.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let started = tokio::time::Instant::now();
+        for _ in 0..5 {
+            rate_limiter.acquire().await;
+        }
+        assert_eq!(started.elapsed(), Duration::ZERO);
+    }
+
+    #[test]
+    fn throttled_requests_wait_for_what_the_api_asks_for_within_a_minute() {
+        assert_eq!(throttled_wait(1, None), Duration::from_secs(1));
+        assert_eq!(throttled_wait(3, None), Duration::from_secs(4));
+        assert_eq!(
+            throttled_wait(1, Some(Duration::from_secs(5))),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            throttled_wait(1, Some(Duration::from_secs(3600))),
+            MAX_THROTTLED_WAIT
+        );
+    }
+
+    #[tokio::test]
+    async fn api_requests_are_sent_again_after_the_retry_after_of_a_429() {
+        let throttled = std::sync::atomic::AtomicBool::new(true);
+        let (url, requests) = crate::testing::http_server_with_headers(move |_, _| {
+            if throttled.swap(false, std::sync::atomic::Ordering::Relaxed) {
+                (
+                    429,
+                    vec![("retry-after", "1".to_string())],
+                    "{}".to_string(),
+                )
+            } else {
+                (200, Vec::new(), r#"{"ok": true}"#.to_string())
+            }
+        })
+        .await;
+        let client = reqwest::Client::new();
+
+        let started = tokio::time::Instant::now();
+        let (status, body) =
+            send_api_request("test API", None, || client.get(format!("{}/zones", url)))
+                .await
+                .unwrap();
+        assert_eq!(status, reqwest::StatusCode::OK);
+        assert_eq!(body, br#"{"ok": true}"#);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+}
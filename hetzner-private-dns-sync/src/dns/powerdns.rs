@@ -0,0 +1,540 @@
+//! The PowerDNS HTTP API, with --dns-provider=powerdns.
+
+use std::{fmt::Debug, net::SocketAddr, time::Duration};
+
+use serde::Deserialize;
+
+use super::{
+    api_add_server, api_remove_server, same_record_value, send_api_request, verify_propagation,
+    DnsBackend, RateLimiter, RecordApi, ZoneRecord,
+};
+use crate::{normalise_name, Error, Result, Server};
+
+#[derive(Deserialize)]
+struct PowerDnsZone {
+    rrsets: Vec<PowerDnsRrset>,
+}
+
+/// The records of one type on a name, as the PowerDNS API has them. Names are fully-qualified, with the trailing dot.
+#[derive(Clone, Debug, Deserialize)]
+struct PowerDnsRrset {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    ttl: u32,
+    records: Vec<PowerDnsRecord>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct PowerDnsRecord {
+    content: String,
+    #[serde(default)]
+    disabled: bool,
+}
+
+/// Writes records through the HTTP API of PowerDNS Authoritative, with --dns-provider=powerdns. The API replaces whole RRsets, so adding or removing a record rewrites the RRset with the records it should end up with.
+pub(crate) struct PowerDnsWrapper {
+    client: reqwest::Client,
+    // Like "http://127.0.0.1:8081/api/v1/servers/localhost/zones".
+    zones_url: String,
+    api_key: String,
+    rate_limiter: Option<RateLimiter>,
+    record_comment: Option<String>,
+}
+
+// Keeps the API key out of the logs.
+impl Debug for PowerDnsWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PowerDnsWrapper {{}}")
+    }
+}
+
+impl PowerDnsWrapper {
+    pub(crate) fn new(
+        api_url: &reqwest::Url,
+        server_id: &str,
+        api_key: String,
+        operation_timeout: Option<Duration>,
+        rate_limit: Option<u32>,
+        record_comment: Option<String>,
+    ) -> Result<Self> {
+        let mut client = reqwest::Client::builder();
+        if let Some(operation_timeout) = operation_timeout {
+            client = client.timeout(operation_timeout);
+        }
+        let client = client.build().map_err(|e| {
+            Error::Config(format!(
+                "unable to set up a client for the PowerDNS API. {}",
+                e
+            ))
+        })?;
+
+        Ok(Self {
+            client,
+            zones_url: format!(
+                "{}/api/v1/servers/{}/zones",
+                api_url.as_str().trim_end_matches('/'),
+                server_id
+            ),
+            api_key,
+            rate_limiter: rate_limit.map(RateLimiter::new),
+            record_comment,
+        })
+    }
+
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        zone_name: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<Vec<u8>> {
+        // PowerDNS uses the fully-qualified name of a zone as its ID.
+        let url = format!("{}/{}.", self.zones_url, normalise_name(zone_name));
+        let body = body.map(|body| body.to_string());
+        let (status, body) = send_api_request("PowerDNS API", self.rate_limiter.as_ref(), || {
+            let request = self
+                .client
+                .request(method.clone(), &url)
+                .header("X-API-Key", &self.api_key);
+            match &body {
+                Some(body) => request
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body.clone()),
+                None => request,
+            }
+        })
+        .await?;
+        match status {
+            _ if status.is_success() => (),
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                return Err(Error::Config(format!(
+                    "the PowerDNS API didn't take the API key, it answered with {}. Check --powerdns-api-key.",
+                    status
+                )))
+            }
+            reqwest::StatusCode::NOT_FOUND => {
+                return Err(Error::Dns(format!(
+                    "the zone {} isn't on the PowerDNS server, or --powerdns-server-id is wrong.",
+                    zone_name
+                )))
+            }
+            _ => {
+                return Err(Error::Dns(format!(
+                    "the PowerDNS API answered with {} for the zone {}. {}",
+                    status,
+                    zone_name,
+                    String::from_utf8_lossy(&body).trim()
+                )))
+            }
+        }
+
+        Ok(body)
+    }
+
+    async fn rrsets(&self, zone_name: &str) -> Result<Vec<PowerDnsRrset>> {
+        let body = self.request(reqwest::Method::GET, zone_name, None).await?;
+        let zone: PowerDnsZone = serde_json::from_slice(&body).map_err(|e| {
+            Error::Dns(format!(
+                "unable to parse the answer of the PowerDNS API. {}",
+                e
+            ))
+        })?;
+
+        Ok(zone.rrsets)
+    }
+
+    /// Sends the RRsets to replace, with the records they should have. RRsets without records are deleted.
+    async fn patch_rrsets(
+        &self,
+        zone_name: &str,
+        rrsets: Vec<(String, String, u32, Vec<String>)>,
+    ) -> Result<()> {
+        if rrsets.is_empty() {
+            return Ok(());
+        }
+
+        let rrsets: Vec<serde_json::Value> = rrsets
+            .into_iter()
+            .map(|(name, record_type, ttl, contents)| {
+                if contents.is_empty() {
+                    serde_json::json!({
+                        "name": name,
+                        "type": record_type,
+                        "changetype": "DELETE",
+                    })
+                } else {
+                    serde_json::json!({
+                        "name": name,
+                        "type": record_type,
+                        "ttl": ttl,
+                        "changetype": "REPLACE",
+                        "records": contents
+                            .iter()
+                            .map(|content| serde_json::json!({ "content": content, "disabled": false }))
+                            .collect::<Vec<_>>(),
+                    })
+                }
+            })
+            .collect();
+        self.request(
+            reqwest::Method::PATCH,
+            zone_name,
+            Some(serde_json::json!({ "rrsets": rrsets })),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// A name the way PowerDNS has it, fully-qualified with the trailing dot.
+fn powerdns_name(fqdn: &str) -> String {
+    format!("{}.", normalise_name(fqdn))
+}
+
+impl RecordApi for PowerDnsWrapper {
+    async fn add_records(
+        &self,
+        zone_name: &str,
+        records: &[(String, &str, String)],
+        ttl: u32,
+    ) -> Result<()> {
+        let existing_rrsets = self.rrsets(zone_name).await?;
+        let mut rrsets: Vec<(String, String, u32, Vec<String>)> = Vec::new();
+        for (fqdn, record_type, value) in records {
+            let name = powerdns_name(fqdn);
+            let position = match rrsets
+                .iter()
+                .position(|(n, t, _, _)| *n == name && t == record_type)
+            {
+                Some(position) => position,
+                None => {
+                    let contents = existing_rrsets
+                        .iter()
+                        .find(|r| {
+                            normalise_name(&r.name) == normalise_name(&name)
+                                && r.record_type == *record_type
+                        })
+                        .map(|r| r.records.iter().map(|r| r.content.clone()).collect())
+                        .unwrap_or_default();
+                    rrsets.push((name, record_type.to_string(), ttl, contents));
+                    rrsets.len() - 1
+                }
+            };
+            let contents = &mut rrsets[position].3;
+            if !contents
+                .iter()
+                .any(|content| same_record_value(content, value))
+            {
+                contents.push(value.clone());
+            }
+        }
+
+        // RRsets that already have every record with the right TTL are left alone.
+        rrsets.retain(|(name, record_type, ttl, contents)| {
+            !existing_rrsets.iter().any(|r| {
+                normalise_name(&r.name) == normalise_name(name)
+                    && r.record_type == *record_type
+                    && r.ttl == *ttl
+                    && r.records.len() == contents.len()
+            })
+        });
+        self.patch_rrsets(zone_name, rrsets).await
+    }
+
+    async fn remove_records(
+        &self,
+        fqdn: &str,
+        zone_name: &str,
+        matches: impl Fn(&str, &str) -> bool,
+        whole_name_once_empty: bool,
+    ) -> Result<()> {
+        let name = powerdns_name(fqdn);
+        let rrsets: Vec<(String, String, u32, Vec<String>, usize)> = self
+            .rrsets(zone_name)
+            .await?
+            .into_iter()
+            .filter(|r| normalise_name(&r.name) == normalise_name(&name))
+            .map(|r| {
+                let before = r.records.len();
+                let kept = r
+                    .records
+                    .into_iter()
+                    .map(|record| record.content)
+                    .filter(|content| !matches(&r.record_type, content))
+                    .collect();
+                (name.clone(), r.record_type, r.ttl, kept, before)
+            })
+            .collect();
+        let remove_whole_name = whole_name_once_empty
+            && !rrsets
+                .iter()
+                .any(|(_, record_type, _, kept, _)| record_type == "A" && !kept.is_empty());
+
+        let changes = rrsets
+            .into_iter()
+            .filter(|(_, _, _, kept, before)| remove_whole_name || kept.len() < *before)
+            .map(|(name, record_type, ttl, kept, _)| {
+                (
+                    name,
+                    record_type,
+                    ttl,
+                    if remove_whole_name { Vec::new() } else { kept },
+                )
+            })
+            .collect();
+        self.patch_rrsets(zone_name, changes).await
+    }
+}
+
+impl DnsBackend for PowerDnsWrapper {
+    #[tracing::instrument]
+    async fn zone_records(&self, zone_name: &str) -> Result<Vec<ZoneRecord>> {
+        Ok(self
+            .rrsets(zone_name)
+            .await?
+            .into_iter()
+            .flat_map(|rrset| {
+                let record_type = rrset.record_type.parse().ok();
+                let name = normalise_name(&rrset.name);
+                rrset
+                    .records
+                    .into_iter()
+                    .filter(|record| !record.disabled)
+                    .filter_map(move |record| {
+                        Some(ZoneRecord {
+                            name: name.clone(),
+                            record_type: record_type?,
+                            data: record.content,
+                            ttl: rrset.ttl,
+                        })
+                    })
+            })
+            .collect())
+    }
+
+    #[tracing::instrument]
+    async fn add_server(&self, server: &Server) -> Result<()> {
+        tracing::debug!("Creating a DNS record for a server through the PowerDNS API.");
+
+        api_add_server(self, server, self.record_comment.as_deref()).await
+    }
+
+    #[tracing::instrument]
+    async fn remove_server(&self, server: &Server) -> Result<()> {
+        tracing::debug!("Deleting a DNS record for a server through the PowerDNS API.");
+
+        api_remove_server(self, server).await
+    }
+
+    #[tracing::instrument]
+    async fn remove_name(&self, fqdn: &str, zone_name: &str) -> Result<()> {
+        self.remove_records(fqdn, zone_name, |_, _| true, false)
+            .await
+    }
+
+    async fn verify_propagation(
+        &self,
+        server: &Server,
+        resolvers: &[SocketAddr],
+        timeout: Duration,
+    ) {
+        verify_propagation(server, resolvers, timeout).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing;
+
+    const ZONE: &str = "/api/v1/servers/localhost/zones/example.com.";
+
+    /// Answers like a PowerDNS server with the "example.com" zone, where "app" is shared by two servers.
+    fn api(request: &str) -> (u16, String) {
+        match request.split_once(' ') {
+            Some(("GET", ZONE)) => (
+                200,
+                json!({"rrsets": [
+                    {"name": "web.example.com.", "type": "A", "ttl": 300, "records": [
+                        {"content": "10.0.0.2", "disabled": false},
+                    ]},
+                    {"name": "app.example.com.", "type": "A", "ttl": 300, "records": [
+                        {"content": "10.0.0.4", "disabled": false},
+                        {"content": "10.0.0.5", "disabled": false},
+                    ]},
+                ]})
+                .to_string(),
+            ),
+            Some(("PATCH", ZONE)) => (204, String::new()),
+            _ => (404, json!({"error": "Not Found"}).to_string()),
+        }
+    }
+
+    /// Serves `respond`, keeping the bodies of the PATCH requests.
+    async fn api_server(
+        respond: impl Fn(&str) -> (u16, String) + Send + Sync + 'static,
+    ) -> (PowerDnsWrapper, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let patches = Arc::new(Mutex::new(Vec::new()));
+        let (url, _) = testing::http_server({
+            let patches = patches.clone();
+            move |request, body| {
+                if request.starts_with("PATCH ") {
+                    patches
+                        .lock()
+                        .unwrap()
+                        .push(serde_json::from_str(body).unwrap());
+                }
+                respond(request)
+            }
+        })
+        .await;
+
+        (powerdns(&url), patches)
+    }
+
+    fn powerdns(url: &str) -> PowerDnsWrapper {
+        PowerDnsWrapper::new(
+            &url.parse().unwrap(),
+            "localhost",
+            "test".to_string(),
+            None,
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    fn server(hostname: &str, ip_address: &str) -> Server {
+        Server {
+            id: 1,
+            ip_address: ip_address.to_string(),
+            hostname: hostname.to_string(),
+            zone: "example.com".to_string(),
+            ttl: 300,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn adding_a_record_replaces_its_rrset_with_the_record_in_it() {
+        let (powerdns, patches) = api_server(api).await;
+
+        let mut app = server("app", "10.0.0.6");
+        app.shared_name = true;
+        powerdns.add_server(&app).await.unwrap();
+        assert_eq!(
+            *patches.lock().unwrap(),
+            [json!({"rrsets": [{
+                "name": "app.example.com.",
+                "type": "A",
+                "ttl": 300,
+                "changetype": "REPLACE",
+                "records": [
+                    {"content": "10.0.0.4", "disabled": false},
+                    {"content": "10.0.0.5", "disabled": false},
+                    {"content": "10.0.0.6", "disabled": false},
+                ],
+            }]})]
+        );
+
+        // The records are all there already.
+        patches.lock().unwrap().clear();
+        powerdns
+            .add_server(&server("web", "10.0.0.2"))
+            .await
+            .unwrap();
+        assert!(patches.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn removing_the_last_record_deletes_the_rrset() {
+        let (powerdns, patches) = api_server(api).await;
+
+        let mut app = server("app", "10.0.0.5");
+        app.shared_name = true;
+        powerdns.remove_server(&app).await.unwrap();
+        powerdns
+            .remove_server(&server("web", "10.0.0.2"))
+            .await
+            .unwrap();
+        assert_eq!(
+            *patches.lock().unwrap(),
+            [
+                json!({"rrsets": [{
+                    "name": "app.example.com.",
+                    "type": "A",
+                    "ttl": 300,
+                    "changetype": "REPLACE",
+                    "records": [{"content": "10.0.0.4", "disabled": false}],
+                }]}),
+                json!({"rrsets": [{
+                    "name": "web.example.com.",
+                    "type": "A",
+                    "changetype": "DELETE",
+                }]}),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn error_statuses_are_told_apart() {
+        let (powerdns, _) = api_server(|_| (401, "Unauthorized".to_string())).await;
+        let Err(Error::Config(message)) = powerdns.zone_records("example.com").await else {
+            panic!("a rejected API key isn't a configuration error");
+        };
+        assert!(message.contains("--powerdns-api-key"), "{}", message);
+
+        let (powerdns, _) = api_server(api).await;
+        let Err(Error::Dns(message)) = powerdns.zone_records("example.org").await else {
+            panic!("a missing zone isn't a DNS error");
+        };
+        assert!(
+            message.contains("example.org isn't on the PowerDNS server"),
+            "{}",
+            message
+        );
+
+        let (powerdns, _) = api_server(|request| match request.split_once(' ') {
+            Some(("PATCH", _)) => (
+                422,
+                json!({"error": "RRset app.example.com. IN A: Conflicts with pre-existing RRset"})
+                    .to_string(),
+            ),
+            _ => api(request),
+        })
+        .await;
+        let Err(Error::Dns(message)) = powerdns.add_server(&server("app", "10.0.0.6")).await else {
+            panic!("a rejected change isn't a DNS error");
+        };
+        assert!(message.contains("422 Unprocessable Entity"), "{}", message);
+        assert!(
+            message.contains("Conflicts with pre-existing RRset"),
+            "{}",
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn throttled_requests_are_sent_again_after_the_retry_after() {
+        let throttled = std::sync::atomic::AtomicBool::new(true);
+        let (url, requests) = testing::http_server_with_headers(move |request, _| {
+            if throttled.swap(false, std::sync::atomic::Ordering::Relaxed) {
+                return (429, vec![("retry-after", "1".to_string())], String::new());
+            }
+            let (status, body) = api(request);
+            (status, Vec::new(), body)
+        })
+        .await;
+
+        let started = tokio::time::Instant::now();
+        let records = powerdns(&url).zone_records("example.com").await.unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,1683 @@
+//! Dynamic updates (RFC 2136) signed with TSIG, the default --dns-provider.
+
+use std::{
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use dns_update::{providers::rfc2136::DnsAddress, DnsRecord, DnsUpdater};
+use futures_util::StreamExt;
+use hickory_client::{
+    client::{AsyncClient, ClientConnection, ClientHandle, Signer},
+    proto::rr::dnssec::tsig::TSigner,
+    rr::{
+        rdata::{PTR, SRV, TXT},
+        DNSClass, Name, RData, Record, RecordType,
+    },
+    tcp::TcpClientConnection,
+};
+
+use super::{
+    query, throttled_wait, verify_propagation, DnsBackend, RateLimiter, ZoneRecord,
+    THROTTLED_ATTEMPTS,
+};
+use crate::{
+    normalise_name, parse_address, plan::check_fqdn_length, Args, Error, Result, Server,
+    TsigAlgorithm,
+};
+
+/// Response codes a DNS server can answer an update with. `dns-update` only gives us the human-readable description of the code, so we map it back to what it was.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResponseCode {
+    FormErr,
+    ServFail,
+    NXDomain,
+    NotImp,
+    Refused,
+    YXDomain,
+    YXRRSet,
+    NXRRSet,
+    NotAuth,
+    NotZone,
+    BadSig,
+    BadKey,
+    BadTime,
+}
+
+impl ResponseCode {
+    fn from_description(description: &str) -> Option<Self> {
+        match description {
+            "Form Error" => Some(Self::FormErr),
+            "Server Failure" => Some(Self::ServFail),
+            "Non-Existent Domain" => Some(Self::NXDomain),
+            "Not Implemented" => Some(Self::NotImp),
+            "Query Refused" => Some(Self::Refused),
+            "Name should not exist" => Some(Self::YXDomain),
+            "RR Set should not exist" => Some(Self::YXRRSet),
+            "RR Set does not exist" => Some(Self::NXRRSet),
+            "Not authorized" => Some(Self::NotAuth),
+            "Name not in zone" => Some(Self::NotZone),
+            "TSIG Failure" => Some(Self::BadSig),
+            "Key not recognized" => Some(Self::BadKey),
+            "Signature out of time window" => Some(Self::BadTime),
+            _ => None,
+        }
+    }
+
+    /// Formats a response error from `dns-update` with its RCODE, if we know it.
+    fn describe(description: &str) -> String {
+        match Self::from_description(description) {
+            Some(rcode) => format!("{} ({})", rcode, description),
+            None => description.to_string(),
+        }
+    }
+
+    /// Whether this is what we get when creating a record that already exists.
+    fn means_record_exists(self) -> bool {
+        matches!(self, Self::YXRRSet | Self::YXDomain)
+    }
+
+    /// Whether the DNS server didn't take the TSIG key we signed with, which is what we get after the key was rotated under us.
+    fn means_key_rejected(self) -> bool {
+        matches!(self, Self::BadKey | Self::BadSig)
+    }
+
+    /// Whether the DNS server may have turned the update away for being busy, e.g. behind a proxy throttling us, so it's worth sending again a little later.
+    fn means_busy(self) -> bool {
+        matches!(self, Self::ServFail | Self::Refused)
+    }
+}
+
+impl std::fmt::Display for ResponseCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mnemonic = match self {
+            Self::FormErr => "FORMERR",
+            Self::ServFail => "SERVFAIL",
+            Self::NXDomain => "NXDOMAIN",
+            Self::NotImp => "NOTIMP",
+            Self::Refused => "REFUSED",
+            Self::YXDomain => "YXDOMAIN",
+            Self::YXRRSet => "YXRRSET",
+            Self::NXRRSet => "NXRRSET",
+            Self::NotAuth => "NOTAUTH",
+            Self::NotZone => "NOTZONE",
+            Self::BadSig => "BADSIG",
+            Self::BadKey => "BADKEY",
+            Self::BadTime => "BADTIME",
+        };
+
+        write!(f, "{}", mnemonic)
+    }
+}
+
+/// One of the DNS servers we can send updates to.
+struct DnsEndpoint {
+    address: String,
+    // Replaced when the TSIG key is read again, see `reload_tsig_key`.
+    client: tokio::sync::RwLock<DnsUpdater>,
+    // `dns-update` can only change records, so anything that needs to read a zone goes straight to the DNS server through this.
+    socket_address: SocketAddr,
+}
+
+pub(crate) struct DnsUpdaterWrapper {
+    endpoints: Vec<DnsEndpoint>,
+    // Index of the endpoint that last worked. Once we had to move on from an endpoint, we stick with the new one for the rest of the run instead of waiting for the broken one to time out on every request.
+    active_endpoint: AtomicUsize,
+    operation_timeout: Option<Duration>,
+    rate_limiter: Option<RateLimiter>,
+    signer: tokio::sync::RwLock<Arc<Signer>>,
+    // Where the signer and the clients got their key from, to read it again when the DNS server stops taking it.
+    tsig_key: TsigKey,
+    record_comment: Option<String>,
+    pub(crate) reverse_zone: Option<ReverseZone>,
+    pub(crate) owner_id: Option<String>,
+    // With --update-zone, the normalised --zone-name and the zone its updates go to instead.
+    pub(crate) update_zone: Option<(String, String)>,
+}
+
+/// The TSIG key updates are signed with, and how.
+#[derive(Clone, Debug)]
+pub(crate) struct TsigKey {
+    pub(crate) name: String,
+    pub(crate) path: PathBuf,
+    pub(crate) algorithm: TsigAlgorithm,
+    // Only `hickory` takes it, `dns-update` always signs with a fudge of 60 seconds.
+    pub(crate) fudge: u16,
+}
+
+/// First label of the name the ownership TXT record of a name is on, with --owner-id.
+const OWNERSHIP_RECORD_LABEL: &str = "_owner";
+
+fn ownership_record_name(fqdn: &str) -> String {
+    format!("{}.{}", OWNERSHIP_RECORD_LABEL, fqdn)
+}
+
+pub(crate) fn ownership_record_content(owner_id: &str) -> String {
+    format!("hetzner-private-dns-sync owner={}", owner_id)
+}
+
+/// The --reverse-zone-name. `dns-update` can't send PTR records, so its updates go through `hickory` instead, signed with their own signer.
+pub(crate) struct ReverseZone {
+    origin: Name,
+    signer: Arc<Signer>,
+}
+
+// `Signer` doesn't impl Debug either.
+impl Debug for ReverseZone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ReverseZone {{ origin: {} }}", self.origin)
+    }
+}
+
+impl ReverseZone {
+    pub(crate) async fn new(args: &Args) -> Result<Option<Self>> {
+        let Some(reverse_zone_name) = &args.reverse_zone_name else {
+            return Ok(None);
+        };
+
+        let origin = Name::from_str_relaxed(reverse_zone_name).map_err(|e| {
+            Error::Config(format!(
+                "the reverse zone name '{}' is invalid. {}",
+                reverse_zone_name, e
+            ))
+        })?;
+        let (key_name, key_path) = match (
+            &args.reverse_tsig_key_name,
+            &args.reverse_tsig_key_path,
+            &args.tsig_key_name,
+            &args.tsig_key_path,
+        ) {
+            (Some(key_name), Some(key_path), _, _) | (None, None, Some(key_name), Some(key_path)) => {
+                (key_name, key_path)
+            }
+            _ => {
+                return Err(Error::Config(
+                    "the reverse zone needs a TSIG key, from --reverse-tsig-key-name and --reverse-tsig-key-path or from --tsig-key-name and --tsig-key-path.".to_string(),
+                ))
+            }
+        };
+
+        Ok(Some(Self {
+            origin,
+            signer: Arc::new(Signer::from(tsig_signer(
+                key_name,
+                read_tsig_key_with_retries(key_path).await?,
+                args.tsig_algorithm,
+                args.tsig_fudge,
+            )?)),
+        }))
+    }
+}
+
+fn tsig_signer(
+    key_name: &str,
+    tsig_key: Vec<u8>,
+    algorithm: TsigAlgorithm,
+    fudge: u16,
+) -> Result<TSigner> {
+    let signer_name = Name::from_ascii(key_name)
+        .map_err(|e| Error::Config(format!("the TSIG key name is invalid. {}", e)))?;
+    TSigner::new(tsig_key, algorithm.for_hickory(), signer_name, fudge)
+        .map_err(|e| Error::Config(format!("unable to create a TSIG signer. {}", e)))
+}
+
+// `DnsUpdater` doesn't impl Debug, so we need this.
+impl Debug for DnsUpdaterWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DnsUpdaterWrapper {{}}")
+    }
+}
+
+/// `dns-update` only sends updates over UDP or TCP, so we can't do anything useful with addresses asking for TLS except telling the user so.
+pub(crate) const TLS_UNSUPPORTED: &str = "the DNS client we use can only send updates over UDP or TCP. Put a TLS proxy (e.g. stunnel) in front of the DNS server and point a tcp:// address at it instead.";
+
+/// How many times we try to read the TSIG key before giving up. The wait between attempts starts at a second and doubles every time.
+const TSIG_KEY_READ_ATTEMPTS: u32 = 4;
+
+/// Reads the TSIG key once. Validating the configuration uses this, so a missing key is reported straight away.
+pub(crate) fn read_tsig_key(key_path: &std::path::Path) -> Result<Vec<u8>> {
+    std::fs::read(key_path).map_err(|e| {
+        Error::Config(format!(
+            "unable to read the TSIG key at {}. {}",
+            key_path.display(),
+            e
+        ))
+    })
+}
+
+/// Reads the TSIG key, retrying for a little while if we can't. Keys are often mounted from a secret store, and can go missing for a moment while they're being remounted.
+async fn read_tsig_key_with_retries(key_path: &std::path::Path) -> Result<Vec<u8>> {
+    let mut attempt = 1;
+    loop {
+        match read_tsig_key(key_path) {
+            Err(e) if attempt < TSIG_KEY_READ_ATTEMPTS => {
+                let wait = Duration::from_secs(1 << (attempt - 1));
+                tracing::warn!(error = %e, attempt, ?wait, "Unable to read the TSIG key, will try again.");
+                tokio::time::sleep(wait).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn rfc2136_client(
+    server_address: &str,
+    key_name: &str,
+    tsig_key: Vec<u8>,
+    algorithm: TsigAlgorithm,
+) -> Result<DnsUpdater> {
+    DnsUpdater::new_rfc2136_tsig(
+        server_address,
+        key_name,
+        tsig_key,
+        algorithm.for_dns_update(),
+    )
+    .map_err(|e| Error::Config(format!("unable to create a DNS updater client. {}", e)))
+}
+
+/// Shortest TSIG key we take as plausible. Keys made by `tsig-keygen` for HMAC-SHA256 are 32 bytes, and anything under 16 is more likely a typo than a key.
+pub(crate) const MIN_TSIG_KEY_LENGTH: usize = 16;
+
+pub(crate) fn is_tls_address(server_address: &str) -> bool {
+    server_address.starts_with("tls://")
+}
+
+/// Whether a `dns-update` error means we couldn't talk to the DNS server at all, as opposed to the server answering with an error.
+fn is_transport_error(error: &dns_update::Error) -> bool {
+    matches!(
+        error,
+        dns_update::Error::Client(_) | dns_update::Error::Protocol(_)
+    ) && !is_clock_skew_error(error)
+}
+
+/// Whether a `dns-update` error means the DNS server rejected the time in our TSIG signature, which happens when our clock and the server's clock are too far apart.
+fn is_clock_skew_error(error: &dns_update::Error) -> bool {
+    match error {
+        dns_update::Error::Response(description) => {
+            ResponseCode::from_description(description) == Some(ResponseCode::BadTime)
+        }
+        // The DNS server signs its BADTIME answers with its own time, which `hickory` then refuses because it's outside the window we signed our request with. Only the updates we send through `hickory` ourselves keep that reason, `dns-update` boils it down to "proto error".
+        error => is_clock_skew_message(&error.to_string()),
+    }
+}
+
+fn is_clock_skew_message(message: &str) -> bool {
+    message.contains("tsig validation error: outdated response")
+}
+
+/// The A or AAAA record data for an address.
+fn address_rdata(address: IpAddr) -> RData {
+    match address {
+        IpAddr::V4(address) => RData::A(hickory_client::rr::rdata::A(address)),
+        IpAddr::V6(address) => RData::AAAA(hickory_client::rr::rdata::AAAA(address)),
+    }
+}
+
+/// Turns the answer to an update we sent through `hickory` into an error if the DNS server didn't take it.
+fn check_update_response(
+    action: &str,
+    response: std::result::Result<
+        hickory_client::proto::xfer::DnsResponse,
+        hickory_client::error::ClientError,
+    >,
+) -> Result<()> {
+    let response = response
+        .map_err(|e| dns_error(action, dns_update::Error::Client(with_causes(e.kind()))))?;
+    if response.response_code() != hickory_client::op::ResponseCode::NoError {
+        return Err(Error::Dns(format!(
+            "failed to {}, the DNS server answered with {}.",
+            action,
+            ResponseCode::describe(response.response_code().to_str())
+        )));
+    }
+
+    Ok(())
+}
+
+/// The message of `error` followed by the ones of what caused it. `hickory` errors leave the cause out of their own message, like "proto error" for an answer whose signature we refused, and only their kind has it as its source.
+fn with_causes(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+
+    message
+}
+
+/// Turns a `dns-update` error into ours, pointing at clock skew when that's what the DNS server complained about.
+fn dns_error(action: &str, error: dns_update::Error) -> Error {
+    if is_clock_skew_error(&error) {
+        Error::Dns(format!("failed to {}, the DNS server rejected the time of our TSIG signature. The clock of this machine is probably too far off from the clock of the DNS server, check that both are synchronised (e.g. through NTP). {}", action, error))
+    } else {
+        Error::Dns(format!("failed to {}. {}", action, error))
+    }
+}
+
+impl DnsUpdaterWrapper {
+    #[tracing::instrument]
+    pub(crate) async fn new(
+        server_addresses: Vec<String>,
+        tsig_key: TsigKey,
+        operation_timeout: Option<Duration>,
+        rate_limit: Option<u32>,
+        record_comment: Option<String>,
+    ) -> Result<Self> {
+        let key = read_tsig_key_with_retries(&tsig_key.path).await?;
+        let signer = tsig_signer(
+            &tsig_key.name,
+            key.clone(),
+            tsig_key.algorithm,
+            tsig_key.fudge,
+        )?;
+
+        let mut endpoints = Vec::with_capacity(server_addresses.len());
+        for server_address in server_addresses {
+            if is_tls_address(&server_address) {
+                return Err(Error::Config(format!(
+                    "the DNS server address '{}' asks for TLS, but {}",
+                    server_address, TLS_UNSUPPORTED
+                )));
+            }
+
+            let socket_address = match DnsAddress::try_from(server_address.as_str()) {
+                Ok(DnsAddress::Tcp(addr)) | Ok(DnsAddress::Udp(addr)) => addr,
+                Err(_) => {
+                    return Err(Error::Config(format!(
+                        "the DNS server address '{}' is invalid.",
+                        server_address
+                    )))
+                }
+            };
+
+            let client = rfc2136_client(
+                &server_address,
+                &tsig_key.name,
+                key.clone(),
+                tsig_key.algorithm,
+            )?;
+
+            endpoints.push(DnsEndpoint {
+                address: server_address,
+                client: tokio::sync::RwLock::new(client),
+                socket_address,
+            });
+        }
+
+        if endpoints.is_empty() {
+            return Err(Error::Config(
+                "at least one DNS server address is needed.".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            endpoints,
+            active_endpoint: AtomicUsize::new(0),
+            operation_timeout,
+            rate_limiter: rate_limit.map(RateLimiter::new),
+            signer: tokio::sync::RwLock::new(Arc::new(Signer::from(signer))),
+            tsig_key,
+            record_comment,
+            reverse_zone: None,
+            owner_id: None,
+            update_zone: None,
+        })
+    }
+
+    /// The zone updates for records in `zone_name` have to be sent to, which is only different with --update-zone.
+    fn update_origin<'a>(&'a self, zone_name: &'a str) -> &'a str {
+        match &self.update_zone {
+            Some((zone, update_zone)) if *zone == normalise_name(zone_name) => update_zone,
+            _ => zone_name,
+        }
+    }
+
+    /// Whether the name has the ownership TXT record with our --owner-id. Always true without --owner-id, since then every name we touch is taken as ours.
+    async fn owns_name(&self, fqdn: &str) -> Result<bool> {
+        let Some(owner_id) = &self.owner_id else {
+            return Ok(true);
+        };
+
+        let name = Name::from_str_relaxed(ownership_record_name(fqdn)).map_err(|e| {
+            Error::Config(format!(
+                "the name {} can't have an ownership record. {}",
+                fqdn, e
+            ))
+        })?;
+        let endpoint = &self.endpoints[self.active_endpoint.load(Ordering::Relaxed)];
+        let what = format!("look up the ownership record of {}", fqdn);
+        let answers = self
+            .within_timeout(&what, async {
+                query(endpoint.socket_address, name, RecordType::TXT)
+                    .await
+                    .map_err(|e| Error::Dns(format!("unable to {}. {}", what, e)))
+            })
+            .await?;
+        let content = ownership_record_content(owner_id);
+
+        Ok(answers
+            .iter()
+            .any(|answer| answer.trim_matches('"') == content))
+    }
+
+    /// Whether the name has A records without being ours, so we mustn't add to them.
+    async fn has_foreign_records(&self, fqdn: &str) -> Result<bool> {
+        if self.owner_id.is_none() || self.owns_name(fqdn).await? {
+            return Ok(false);
+        }
+
+        let name = Name::from_str_relaxed(fqdn)
+            .map_err(|e| Error::Config(format!("the name {} is invalid. {}", fqdn, e)))?;
+        let answers = self.addresses(fqdn, name, RecordType::A).await?;
+
+        Ok(!answers.is_empty())
+    }
+
+    /// With --owner-id, adds the ownership TXT record to a name we just published on.
+    async fn mark_owned(&self, fqdn: &str, ttl: u32, zone_name: &str) -> Result<()> {
+        let Some(owner_id) = &self.owner_id else {
+            return Ok(());
+        };
+        let zone_name = self.update_origin(zone_name);
+
+        let ownership_name = &ownership_record_name(fqdn);
+        self.with_endpoint(|client| async move {
+            client
+                .update(
+                    ownership_name,
+                    DnsRecord::TXT {
+                        content: ownership_record_content(owner_id),
+                    },
+                    ttl,
+                    zone_name,
+                )
+                .await
+        })
+        .await
+        .map_err(|e| dns_error("create the ownership record", e))
+    }
+
+    /// Replaces whatever PTR record the server's address has in the --reverse-zone-name with one pointing at the server. Addresses outside the zone are left alone.
+    async fn add_reverse_record(&self, server: &Server) -> Result<()> {
+        let Some((reverse_zone, ptr_name)) = self.reverse_record_name(server) else {
+            return Ok(());
+        };
+
+        let target = Name::from_ascii(format!("{}.", server.fqdn().trim_end_matches('.')))
+            .map_err(|e| {
+                Error::Config(format!(
+                    "the name {} can't be used in a PTR record. {}",
+                    server.fqdn(),
+                    e
+                ))
+            })?;
+        let mut client = self.update_client(&reverse_zone.signer).await?;
+        check_update_response(
+            "delete the previous PTR record",
+            client
+                .delete_all(ptr_name.clone(), reverse_zone.origin.clone(), DNSClass::IN)
+                .await,
+        )?;
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+        check_update_response(
+            "create the PTR record",
+            client
+                .append(
+                    Record::from_rdata(ptr_name, server.ttl, RData::PTR(PTR(target))),
+                    reverse_zone.origin.clone(),
+                    false,
+                )
+                .await,
+        )
+    }
+
+    async fn remove_reverse_record(&self, server: &Server) -> Result<()> {
+        let Some((reverse_zone, ptr_name)) = self.reverse_record_name(server) else {
+            return Ok(());
+        };
+
+        let mut client = self.update_client(&reverse_zone.signer).await?;
+        check_update_response(
+            "delete the PTR record",
+            client
+                .delete_all(ptr_name, reverse_zone.origin.clone(), DNSClass::IN)
+                .await,
+        )
+    }
+
+    /// Removes the server's SRV records and the addresses it has on its groups, and only those, so the other servers providing the same services or in the same groups keep theirs. `dns-update` can only remove every record on a name, so this goes through `hickory`.
+    async fn remove_shared_records(&self, server: &Server) -> Result<()> {
+        if server.services.is_empty() && server.groups.is_empty() {
+            return Ok(());
+        }
+
+        let name = |name: &str| {
+            Name::from_str_relaxed(format!("{}.", name.trim_end_matches('.')))
+                .map_err(|e| Error::Config(format!("the name {} is invalid. {}", name, e)))
+        };
+        let zone_origin = name(self.update_origin(&server.zone))?;
+        let target = name(&server.fqdn())?;
+        let mut client = self
+            .update_client(&self.signer.read().await.clone())
+            .await?;
+        for service in &server.services {
+            check_update_response(
+                "delete an SRV record",
+                client
+                    .delete_by_rdata(
+                        Record::from_rdata(
+                            name(&server.service_fqdn(service))?,
+                            server.ttl,
+                            RData::SRV(SRV::new(0, 0, service.port, target.clone())),
+                        ),
+                        zone_origin.clone(),
+                    )
+                    .await,
+            )?;
+        }
+
+        let (_, address) = server.address_record();
+        for group_fqdn in server.group_fqdns() {
+            let address = parse_address(server.id, "IP address", address)?;
+            check_update_response(
+                "remove the address of a server from its group",
+                client
+                    .delete_by_rdata(
+                        Record::from_rdata(name(&group_fqdn)?, server.ttl, address_rdata(address)),
+                        zone_origin.clone(),
+                    )
+                    .await,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the server's addresses from the names it shares with other servers, and only those, so the others keep theirs. Once a name has no address left, whatever else we put on it goes too.
+    async fn remove_shared_name(&self, server: &Server) -> Result<()> {
+        if !self.owns_name(&server.fqdn()).await? {
+            tracing::warn!(
+                fqdn = server.fqdn(),
+                "The name isn't marked as ours with --owner-id, so we'll leave its records alone."
+            );
+            return Ok(());
+        }
+
+        let name = |name: &str| {
+            Name::from_str_relaxed(format!("{}.", name.trim_end_matches('.')))
+                .map_err(|e| Error::Config(format!("the name {} is invalid. {}", name, e)))
+        };
+        let address = |address: &str| parse_address::<IpAddr>(server.id, "IP address", address);
+        // The AAAA record next to the A record goes first, since the name is looked at for addresses left after each of the others. The last one of the name's addresses takes the rest of the name with it.
+        let mut records = Vec::new();
+        if let (Some(ipv6_address), false) = (&server.ipv6_address, server.no_a_record) {
+            records.push((
+                server.fqdn(),
+                server.zone.as_str(),
+                address(ipv6_address)?,
+                false,
+            ));
+        }
+        records.push((
+            server.fqdn(),
+            &server.zone,
+            address(server.address_record().1)?,
+            true,
+        ));
+        if let Some(wildcard_fqdn) = server.wildcard_fqdn() {
+            records.push((
+                wildcard_fqdn,
+                &server.zone,
+                address(server.address_record().1)?,
+                true,
+            ));
+        }
+        if let (Some((public_zone, public_ip_address)), Some(public_fqdn)) =
+            (server.public_record(), server.public_fqdn())
+        {
+            records.push((public_fqdn, public_zone, address(public_ip_address)?, true));
+        }
+
+        let mut client = self
+            .update_client(&self.signer.read().await.clone())
+            .await?;
+        if let Some(metadata) = &server.metadata {
+            check_update_response(
+                "delete the TXT record with the server's metadata",
+                client
+                    .delete_by_rdata(
+                        Record::from_rdata(
+                            name(&server.fqdn())?,
+                            server.ttl,
+                            RData::TXT(TXT::new(vec![metadata.clone()])),
+                        ),
+                        name(self.update_origin(&server.zone))?,
+                    )
+                    .await,
+            )?;
+        }
+        for (fqdn, zone_name, address, can_be_last) in records {
+            check_update_response(
+                "remove the address of a server from a name it shares with other servers",
+                client
+                    .delete_by_rdata(
+                        Record::from_rdata(name(&fqdn)?, server.ttl, address_rdata(address)),
+                        name(self.update_origin(zone_name))?,
+                    )
+                    .await,
+            )?;
+            if can_be_last && !self.has_addresses(&fqdn).await? {
+                self.remove_name(&fqdn, zone_name).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the name still has A or AAAA records.
+    async fn has_addresses(&self, fqdn: &str) -> Result<bool> {
+        let name = Name::from_str_relaxed(fqdn)
+            .map_err(|e| Error::Config(format!("the name {} is invalid. {}", fqdn, e)))?;
+
+        Ok(!self
+            .addresses(fqdn, name.clone(), RecordType::A)
+            .await?
+            .is_empty()
+            || !self
+                .addresses(fqdn, name, RecordType::AAAA)
+                .await?
+                .is_empty())
+    }
+
+    /// The records of a name with the given type of address, as the DNS server in use has them.
+    async fn addresses(
+        &self,
+        fqdn: &str,
+        name: Name,
+        record_type: RecordType,
+    ) -> Result<Vec<String>> {
+        let endpoint = &self.endpoints[self.active_endpoint.load(Ordering::Relaxed)];
+        let what = format!("look up the records of {}", fqdn);
+        self.within_timeout(&what, async {
+            query(endpoint.socket_address, name, record_type)
+                .await
+                .map_err(|e| Error::Dns(format!("unable to {}. {}", what, e)))
+        })
+        .await
+    }
+
+    /// Name of the PTR record for the server's address, if the server has an A record with an address in the --reverse-zone-name.
+    fn reverse_record_name(&self, server: &Server) -> Option<(&ReverseZone, Name)> {
+        let reverse_zone = self.reverse_zone.as_ref()?;
+        if server.cname.is_some() || server.no_a_record || server.no_ptr_record {
+            return None;
+        }
+
+        let ptr_name = Name::from(server.ip_address.parse::<std::net::Ipv4Addr>().ok()?);
+        if !reverse_zone.origin.zone_of(&ptr_name) {
+            tracing::debug!(
+                server_id = server.id,
+                "The server's address isn't in the reverse zone, so it won't get a PTR record."
+            );
+            return None;
+        }
+
+        Some((reverse_zone, ptr_name))
+    }
+
+    /// Connects to the DNS server in use with `hickory`, for the updates `dns-update` can't send.
+    async fn update_client(&self, signer: &Arc<Signer>) -> Result<AsyncClient> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+
+        let endpoint = &self.endpoints[self.active_endpoint.load(Ordering::Relaxed)];
+        let connection = TcpClientConnection::new(endpoint.socket_address)
+            .map_err(|e| Error::Dns(format!("unable to connect to the DNS server. {}", e)))?
+            .new_stream(Some(signer.clone()));
+        let (client, background) = self
+            .within_timeout("connect to the DNS server", async {
+                AsyncClient::connect(connection)
+                    .await
+                    .map_err(|e| Error::Dns(format!("unable to connect to the DNS server. {}", e)))
+            })
+            .await?;
+        tokio::spawn(background);
+
+        Ok(client)
+    }
+
+    /// Gives up on a request sent with `hickory` after --dns-op-timeout, like `with_endpoint` does for the ones sent with `dns-update`.
+    async fn within_timeout<T>(
+        &self,
+        what: &str,
+        request: impl std::future::Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let Some(operation_timeout) = self.operation_timeout else {
+            return request.await;
+        };
+
+        tokio::time::timeout(operation_timeout, request)
+            .await
+            .unwrap_or_else(|_| {
+                Err(Error::Dns(format!(
+                    "unable to {}, the DNS server didn't answer within {} second(s).",
+                    what,
+                    operation_timeout.as_secs()
+                )))
+            })
+    }
+
+    /// Reads the TSIG key again, and signs everything from now on with what we read.
+    async fn reload_tsig_key(&self) -> Result<()> {
+        let key = read_tsig_key_with_retries(&self.tsig_key.path).await?;
+        let signer = tsig_signer(
+            &self.tsig_key.name,
+            key.clone(),
+            self.tsig_key.algorithm,
+            self.tsig_key.fudge,
+        )?;
+
+        for endpoint in &self.endpoints {
+            *endpoint.client.write().await = rfc2136_client(
+                &endpoint.address,
+                &self.tsig_key.name,
+                key.clone(),
+                self.tsig_key.algorithm,
+            )?;
+        }
+        *self.signer.write().await = Arc::new(Signer::from(signer));
+
+        Ok(())
+    }
+
+    /// Runs an operation against the DNS server that last worked. If we can't reach it, moves on to the next servers in the order they were given, and remembers the one that answered.
+    async fn with_endpoint<F, Fut>(&self, operation: F) -> dns_update::Result<()>
+    where
+        F: Fn(DnsUpdater) -> Fut,
+        Fut: std::future::Future<Output = dns_update::Result<()>>,
+    {
+        let first = self.active_endpoint.load(Ordering::Relaxed);
+        let mut last_error = None;
+
+        let mut reloaded_tsig_key = false;
+
+        for offset in 0..self.endpoints.len() {
+            let index = (first + offset) % self.endpoints.len();
+            let endpoint = &self.endpoints[index];
+            let mut attempt = 1;
+
+            let result = loop {
+                if let Some(rate_limiter) = &self.rate_limiter {
+                    rate_limiter.acquire().await;
+                }
+
+                let client = endpoint.client.read().await.clone();
+                let result = match self.operation_timeout {
+                    Some(operation_timeout) => {
+                        match tokio::time::timeout(operation_timeout, operation(client)).await {
+                            Ok(result) => result,
+                            // The DNS server is there, just slow, so the next one wouldn't do any better.
+                            Err(_) => {
+                                return Err(dns_update::Error::Client(format!(
+                                    "the operation didn't finish within {} second(s)",
+                                    operation_timeout.as_secs()
+                                )))
+                            }
+                        }
+                    }
+                    None => operation(client).await,
+                };
+
+                let rcode = match &result {
+                    Err(dns_update::Error::Response(description)) => {
+                        ResponseCode::from_description(description)
+                    }
+                    _ => None,
+                };
+                match rcode {
+                    // The key may have been rotated since we read it, so we read it again once and retry with it.
+                    Some(rcode) if rcode.means_key_rejected() && !reloaded_tsig_key => {
+                        reloaded_tsig_key = true;
+                        match self.reload_tsig_key().await {
+                            Ok(()) => {
+                                tracing::warn!(%rcode, "The DNS server didn't take our TSIG key, so we read it again and will retry with it.");
+                                continue;
+                            }
+                            Err(e) => {
+                                tracing::warn!(%rcode, error = %e, "The DNS server didn't take our TSIG key, and we couldn't read it again.")
+                            }
+                        }
+                    }
+                    Some(rcode) if rcode.means_busy() && attempt < THROTTLED_ATTEMPTS => {
+                        let wait = throttled_wait(attempt, None);
+                        tracing::warn!(server_address = endpoint.address, %rcode, attempt, ?wait, "The DNS server turned the update away, will try again.");
+                        tokio::time::sleep(wait).await;
+                        attempt += 1;
+                        continue;
+                    }
+                    _ => (),
+                }
+
+                break result;
+            };
+
+            match result {
+                Err(e) if is_transport_error(&e) => {
+                    tracing::warn!(server_address = endpoint.address, error = %e, "Unable to reach a DNS server, trying the next one.");
+                    last_error = Some(e);
+                }
+                result => {
+                    if index != first {
+                        tracing::info!(
+                            server_address = endpoint.address,
+                            "Switching to a different DNS server for the rest of the run."
+                        );
+                        self.active_endpoint.store(index, Ordering::Relaxed);
+                    }
+                    return result;
+                }
+            }
+        }
+
+        // `new()` makes sure there's at least one endpoint, so we always went through the loop.
+        Err(last_error.expect("there is at least one DNS endpoint"))
+    }
+}
+
+impl DnsBackend for DnsUpdaterWrapper {
+    /// Gets every record in a zone through a zone transfer (AXFR). Transfers always go over TCP, regardless of the transport we use for updates.
+    #[tracing::instrument]
+    async fn zone_records(&self, zone_name: &str) -> Result<Vec<ZoneRecord>> {
+        tracing::debug!("Transferring the zone.");
+
+        let requested_zone = normalise_name(zone_name);
+        let zone_name = self.update_origin(zone_name);
+        let zone_origin = Name::from_str_relaxed(zone_name).map_err(|e| {
+            Error::Config(format!("the zone name '{}' is invalid. {}", zone_name, e))
+        })?;
+        let mut records = self
+            .within_timeout(&format!("transfer zone {}", zone_name), async {
+                let endpoint = &self.endpoints[self.active_endpoint.load(Ordering::Relaxed)];
+                let connection = TcpClientConnection::new(endpoint.socket_address)
+                    .map_err(|e| Error::Dns(format!("unable to connect to the DNS server. {}", e)))?
+                    .new_stream(Some(self.signer.read().await.clone()));
+                let (mut client, background) =
+                    AsyncClient::connect(connection).await.map_err(|e| {
+                        Error::Dns(format!("unable to connect to the DNS server. {}", e))
+                    })?;
+                tokio::spawn(background);
+
+                let mut transfer = client.zone_transfer(zone_origin, None);
+                let mut records = Vec::new();
+                while let Some(response) = transfer.next().await {
+                    let response = response.map_err(|e| {
+                        dns_error(
+                            &format!("transfer zone {}", zone_name),
+                            dns_update::Error::Client(e.to_string()),
+                        )
+                    })?;
+
+                    if response.response_code() != hickory_client::op::ResponseCode::NoError {
+                        return Err(Error::Dns(format!(
+                            "failed to transfer zone {}, the DNS server answered with {}.",
+                            zone_name,
+                            ResponseCode::describe(response.response_code().to_str())
+                        )));
+                    }
+
+                    records.extend(response.answers().iter().map(|r| ZoneRecord {
+                        name: normalise_name(&r.name().to_ascii()),
+                        record_type: r.record_type(),
+                        data: r.data().map(|d| d.to_string()).unwrap_or_default(),
+                        ttl: r.ttl(),
+                    }));
+                }
+
+                Ok(records)
+            })
+            .await?;
+
+        // With --update-zone, the transfer has the rest of the update zone too.
+        if normalise_name(zone_name) != requested_zone {
+            records.retain(|r| {
+                r.name == requested_zone || r.name.ends_with(&format!(".{}", requested_zone))
+            });
+        }
+
+        Ok(records)
+    }
+
+    #[tracing::instrument]
+    async fn add_server(&self, server: &Server) -> Result<()> {
+        tracing::debug!("Creating a DNS record for a server.");
+
+        let zone_name = self.update_origin(&server.zone);
+        let server_fqdn = &server.fqdn();
+        check_fqdn_length(server)?;
+
+        // Catch an invalid record here, so the closures below can't fail building it.
+        server.record()?;
+        let record = || {
+            server
+                .record()
+                .map_err(|e| dns_update::Error::Parse(e.to_string()))
+        };
+
+        match self
+            .with_endpoint(|client| async move {
+                client
+                    .create(server_fqdn, record()?, server.ttl, zone_name)
+                    .await
+            })
+            .await
+        {
+            Ok(v) => Ok(v),
+            Err(dns_update::Error::Response(resp_text)) => {
+                match ResponseCode::from_description(&resp_text) {
+                    Some(rcode) if rcode.means_record_exists() => {
+                        if !self.owns_name(server_fqdn).await? {
+                            return Err(Error::Dns(format!(
+                                "{} already has records, but they aren't marked as ours with --owner-id, so we won't add to them.",
+                                server_fqdn
+                            )));
+                        }
+                        tracing::info!(%rcode, server_fqdn, "The DNS server told us the record already exists, so we'll update it instead.");
+
+                        self.with_endpoint(|client| async move {
+                            client
+                                .update(server_fqdn, record()?, server.ttl, zone_name)
+                                .await
+                        })
+                        .await
+                        .map_err(|e| dns_error("update a DNS record", e))
+                    }
+                    Some(ResponseCode::BadTime) => Err(dns_error(
+                        "create a DNS record",
+                        dns_update::Error::Response(resp_text),
+                    )),
+                    _ => Err(Error::Dns(format!(
+                        "failed to create a DNS record for {}, the DNS server answered with {}.",
+                        server_fqdn,
+                        ResponseCode::describe(&resp_text)
+                    ))),
+                }
+            }
+            Err(e) => Err(dns_error("create a DNS record", e)),
+        }?;
+        self.mark_owned(server_fqdn, server.ttl, zone_name).await?;
+
+        // The wildcard name only ever has this server's record, unless several servers share the name, in which case it's shared as well.
+        if let Some(wildcard_fqdn) = server.wildcard_fqdn() {
+            let wildcard_fqdn = &wildcard_fqdn;
+            self.with_endpoint(|client| async move {
+                client
+                    .update(wildcard_fqdn, record()?, server.ttl, zone_name)
+                    .await
+            })
+            .await
+            .map_err(|e| dns_error("create the wildcard record", e))?;
+            self.mark_owned(wildcard_fqdn, server.ttl, zone_name)
+                .await?;
+        }
+
+        // Adding a TXT record that's already there doesn't duplicate it, so this is fine on names shared by several servers too.
+        if let (Some(comment), None) = (&self.record_comment, &server.cname) {
+            self.with_endpoint(|client| async move {
+                client
+                    .update(
+                        server_fqdn,
+                        DnsRecord::TXT {
+                            content: comment.clone(),
+                        },
+                        server.ttl,
+                        zone_name,
+                    )
+                    .await
+            })
+            .await
+            .map_err(|e| dns_error("create the TXT record with the record comment", e))?;
+        }
+
+        if let (Some(metadata), None) = (&server.metadata, &server.cname) {
+            self.with_endpoint(|client| async move {
+                client
+                    .update(
+                        server_fqdn,
+                        DnsRecord::TXT {
+                            content: metadata.clone(),
+                        },
+                        server.ttl,
+                        zone_name,
+                    )
+                    .await
+            })
+            .await
+            .map_err(|e| dns_error("create the TXT record with the server's metadata", e))?;
+        }
+
+        // Like the TXT record, an AAAA record that's already there isn't duplicated.
+        if server.ipv6_record()?.is_some() {
+            let ipv6_record = || {
+                server
+                    .ipv6_record()
+                    .map_err(|e| dns_update::Error::Parse(e.to_string()))?
+                    .ok_or_else(|| dns_update::Error::Parse("no AAAA record".to_string()))
+            };
+            self.with_endpoint(|client| async move {
+                client
+                    .update(server_fqdn, ipv6_record()?, server.ttl, zone_name)
+                    .await
+            })
+            .await
+            .map_err(|e| dns_error("create the AAAA record", e))?;
+        }
+
+        if let (Some((public_zone, public_ip_address)), Some(public_fqdn)) =
+            (server.public_record(), server.public_fqdn())
+        {
+            let public_ip_address_parsed =
+                parse_address(server.id, "public IP address", public_ip_address)?;
+            if self.has_foreign_records(&public_fqdn).await? {
+                return Err(Error::Dns(format!(
+                    "{} already has records, but they aren't marked as ours with --owner-id, so we won't add to them.",
+                    public_fqdn
+                )));
+            }
+            let public_fqdn = &public_fqdn;
+            self.with_endpoint(|client| async move {
+                client
+                    .update(
+                        public_fqdn,
+                        DnsRecord::A {
+                            content: public_ip_address_parsed,
+                        },
+                        server.ttl,
+                        public_zone,
+                    )
+                    .await
+            })
+            .await
+            .map_err(|e| dns_error("create the record in the public zone", e))?;
+            self.mark_owned(public_fqdn, server.ttl, public_zone)
+                .await?;
+        }
+
+        // The other servers in a group already have their addresses on its name, so ours is added next to them.
+        if !server.groups.is_empty() {
+            server.record()?;
+        }
+        for group_fqdn in server.group_fqdns() {
+            let group_fqdn = &group_fqdn;
+            self.with_endpoint(|client| async move {
+                client
+                    .update(group_fqdn, record()?, server.ttl, zone_name)
+                    .await
+            })
+            .await
+            .map_err(|e| dns_error("add the address of a server to its group", e))?;
+        }
+
+        // Several servers can provide the same service, so their SRV records are added next to each other.
+        for service in &server.services {
+            let service_fqdn = &server.service_fqdn(service);
+            self.with_endpoint(|client| async move {
+                client
+                    .update(
+                        service_fqdn,
+                        DnsRecord::SRV {
+                            content: format!("{}.", server_fqdn.trim_end_matches('.')),
+                            priority: 0,
+                            weight: 0,
+                            port: service.port,
+                        },
+                        server.ttl,
+                        zone_name,
+                    )
+                    .await
+            })
+            .await
+            .map_err(|e| dns_error("create an SRV record", e))?;
+        }
+
+        self.add_reverse_record(server).await
+    }
+
+    #[tracing::instrument]
+    async fn remove_server(&self, server: &Server) -> Result<()> {
+        tracing::debug!("Deleting a DNS record for a server.");
+
+        if server.shared_name {
+            self.remove_shared_name(server).await?;
+        } else {
+            self.remove_name(&server.fqdn(), &server.zone).await?;
+            if let Some(wildcard_fqdn) = server.wildcard_fqdn() {
+                self.remove_name(&wildcard_fqdn, &server.zone).await?;
+            }
+            if let (Some((public_zone, _)), Some(public_fqdn)) =
+                (server.public_record(), server.public_fqdn())
+            {
+                self.remove_name(&public_fqdn, public_zone).await?;
+            }
+        }
+
+        self.remove_shared_records(server).await?;
+        self.remove_reverse_record(server).await
+    }
+
+    /// With --verify, polls each resolver until it answers with the record we published for the server, and logs how long it took to show up.
+    #[tracing::instrument(skip(self))]
+    async fn verify_propagation(
+        &self,
+        server: &Server,
+        resolvers: &[SocketAddr],
+        timeout: Duration,
+    ) {
+        let default_resolvers: Vec<SocketAddr> =
+            self.endpoints.iter().map(|e| e.socket_address).collect();
+        let resolvers = if resolvers.is_empty() {
+            &default_resolvers
+        } else {
+            resolvers
+        };
+
+        verify_propagation(server, resolvers, timeout).await;
+    }
+
+    /// Removes every record with the given name.
+    #[tracing::instrument]
+    async fn remove_name(&self, fqdn: &str, zone_name: &str) -> Result<()> {
+        if !self.owns_name(fqdn).await? {
+            tracing::warn!(
+                fqdn,
+                "The name isn't marked as ours with --owner-id, so we'll leave its records alone."
+            );
+            return Ok(());
+        }
+        let zone_name = self.update_origin(zone_name);
+
+        self.with_endpoint(|client| async move { client.delete(fqdn, zone_name).await })
+            .await
+            .map_err(|e| dns_error("delete a DNS record", e))?;
+
+        if self.owner_id.is_some() {
+            let ownership_name = &ownership_record_name(fqdn);
+            self.with_endpoint(
+                |client| async move { client.delete(ownership_name, zone_name).await },
+            )
+            .await
+            .map_err(|e| dns_error("delete the ownership record", e))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use hickory_client::{
+        op::{Message, MessageType, ResponseCode as Rcode},
+        proto::rr::dnssec::rdata::{
+            tsig::{make_tsig_record, message_tbs, TSIG},
+            DNSSECRData,
+        },
+    };
+
+    use super::*;
+    use crate::{
+        dns::{dns_updater, DnsClient},
+        testing,
+    };
+
+    /// A DNS server answering the updates it gets, over UDP or TCP, with `response_codes`, one for each, signed with the empty TSIG key of `testing::args`.
+    async fn update_server(response_codes: Vec<Rcode>) -> std::net::SocketAddr {
+        update_server_off_by(0, response_codes).await.0
+    }
+
+    /// Like `update_server`, but with a clock that's `clock_offset` seconds away from ours. Also returns the updates it answered so far.
+    async fn update_server_off_by(
+        clock_offset: i64,
+        response_codes: Vec<Rcode>,
+    ) -> (std::net::SocketAddr, Arc<Mutex<Vec<Message>>>) {
+        let response_codes = Mutex::new(std::collections::VecDeque::from(response_codes));
+        dns_server(clock_offset, move |_| {
+            Some((response_codes.lock().unwrap().pop_front()?, Vec::new()))
+        })
+        .await
+    }
+
+    /// A DNS server answering each update it gets with what `answer` makes of it: the response code, and the TSIG key to sign the response with. Updates `answer` has nothing for go unanswered.
+    async fn dns_server(
+        clock_offset: i64,
+        answer: impl Fn(&[u8]) -> Option<(Rcode, Vec<u8>)> + Send + Sync + 'static,
+    ) -> (std::net::SocketAddr, Arc<Mutex<Vec<Message>>>) {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let listener = tokio::net::TcpListener::bind(address).await.unwrap();
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let answered = updates.clone();
+        let respond = Arc::new(move |request: &[u8]| {
+            let (response_code, tsig_key) = answer(request)?;
+            let signer =
+                tsig_signer("test", tsig_key, testing::args(&[]).tsig_algorithm, 60).unwrap();
+            let request = Message::from_vec(request).unwrap();
+            answered.lock().unwrap().push(request.clone());
+            let Some(RData::DNSSEC(DNSSECRData::TSIG(request_tsig))) =
+                request.signature()[0].data()
+            else {
+                panic!("the update isn't signed");
+            };
+
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(request.op_code())
+                .set_response_code(response_code)
+                .add_queries(request.queries().to_vec());
+            // Response codes past 15 only fit with EDNS.
+            if response_code.high() != 0 {
+                response.set_edns(hickory_client::op::Edns::new());
+            }
+            let pre_tsig = TSIG::new(
+                request_tsig.algorithm().clone(),
+                request_tsig.time().saturating_add_signed(clock_offset),
+                request_tsig.fudge(),
+                Vec::new(),
+                response.id(),
+                0,
+                Vec::new(),
+            );
+            let tbs = message_tbs(
+                Some(request_tsig.mac()),
+                &response,
+                &pre_tsig,
+                signer.signer_name(),
+            )
+            .unwrap();
+            let mac = signer.sign(&tbs).unwrap();
+            response.add_tsig(make_tsig_record(
+                signer.signer_name().clone(),
+                pre_tsig.set_mac(mac),
+            ));
+
+            Some(response.to_vec().unwrap())
+        });
+
+        let udp_respond = respond.clone();
+        tokio::spawn(async move {
+            let mut buffer = vec![0; 4096];
+            loop {
+                let (length, peer) = socket.recv_from(&mut buffer).await.unwrap();
+                if let Some(response) = udp_respond(&buffer[..length]) {
+                    socket.send_to(&response, peer).await.unwrap();
+                }
+            }
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let respond = respond.clone();
+                tokio::spawn(async move {
+                    while let Ok(length) = stream.read_u16().await {
+                        let mut request = vec![0; usize::from(length)];
+                        stream.read_exact(&mut request).await.unwrap();
+                        let Some(response) = respond(&request) else {
+                            continue;
+                        };
+                        stream
+                            .write_u16(u16::try_from(response.len()).unwrap())
+                            .await
+                            .unwrap();
+                        stream.write_all(&response).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        (address, updates)
+    }
+
+    async fn rfc2136_updater(args: &Args) -> DnsUpdaterWrapper {
+        let DnsClient::Rfc2136(dns_updater) = dns_updater(args).await.unwrap() else {
+            panic!("expected an RFC 2136 client");
+        };
+
+        dns_updater
+    }
+
+    fn web() -> Server {
+        Server {
+            id: 1,
+            ip_address: "10.0.0.2".to_string(),
+            hostname: "web".to_string(),
+            zone: "example.com".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn add_server_updates_records_that_exist_and_logs_the_rcode() {
+        let mut args = testing::args(&[]);
+        args.server_address = vec![format!(
+            "udp://{}",
+            update_server(vec![Rcode::YXRRSet, Rcode::NoError]).await
+        )];
+        let dns_updater = rfc2136_updater(&args).await;
+
+        let (logs, _guard) = testing::captured_logs();
+        dns_updater.add_server(&web()).await.unwrap();
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("already exists"))
+            .expect("the fallback to an update wasn't logged");
+        assert!(line.contains(" INFO "), "{}", line);
+        assert!(line.contains("rcode=YXRRSET"), "{}", line);
+        assert!(line.contains("web.example.com"), "{}", line);
+
+        // Any other error is a failure, with the RCODE in it.
+        args.server_address = vec![format!(
+            "udp://{}",
+            update_server(vec![Rcode::NotAuth]).await
+        )];
+        let dns_updater = rfc2136_updater(&args).await;
+        let Err(Error::Dns(error)) = dns_updater.add_server(&web()).await else {
+            panic!("the update should have failed");
+        };
+        assert!(error.contains("NOTAUTH (Not authorized)"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn updates_go_to_the_update_zone_with_names_in_the_zone_name() {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut args = testing::args(&["--update-zone", "example.com"]);
+        args.zone_name = "internal.example.com".to_string();
+        args.server_address = vec![format!("udp://{}", socket.local_addr().unwrap())];
+        let dns_updater = rfc2136_updater(&args).await;
+        let server = Server {
+            zone: "internal.example.com".to_string(),
+            ..web()
+        };
+
+        // Nothing answers, so the update is dropped as soon as it arrives.
+        let mut buffer = vec![0; 4096];
+        let length = tokio::select! {
+            received = socket.recv(&mut buffer) => received.unwrap(),
+            _ = dns_updater.add_server(&server) => panic!("the update wasn't sent"),
+        };
+        let update = Message::from_vec(&buffer[..length]).unwrap();
+
+        let zones: Vec<String> = update
+            .queries()
+            .iter()
+            .map(|zone| zone.name().to_ascii())
+            .collect();
+        assert_eq!(zones, ["example.com."]);
+        let names: Vec<String> = update
+            .name_servers()
+            .iter()
+            .map(|record| record.name().to_ascii())
+            .collect();
+        assert!(!names.is_empty());
+        assert!(names.iter().all(|name| name == "web.internal.example.com."));
+    }
+
+    #[tokio::test]
+    async fn updates_go_to_the_first_dns_server_that_can_be_reached_and_stay_there() {
+        let mut down = Vec::new();
+        for _ in 0..2 {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            down.push(format!("tcp://{}", listener.local_addr().unwrap()));
+        }
+        let up = update_server(vec![Rcode::NoError, Rcode::NoError]).await;
+        let mut args = testing::args(&[]);
+        args.server_address = vec![down[0].clone(), down[1].clone(), format!("udp://{}", up)];
+        let dns_updater = rfc2136_updater(&args).await;
+
+        dns_updater.add_server(&web()).await.unwrap();
+        assert_eq!(dns_updater.active_endpoint.load(Ordering::Relaxed), 2);
+
+        // The next update goes straight to the one that worked.
+        let (logs, _guard) = testing::captured_logs();
+        dns_updater.add_server(&web()).await.unwrap();
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        assert!(!logs.contains("Unable to reach a DNS server"), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn updates_refused_for_the_time_of_their_signature_point_at_clock_skew() {
+        let mut args = testing::args(&[]);
+        args.server_address = vec![format!(
+            "udp://{}",
+            update_server(vec![Rcode::BADTIME]).await
+        )];
+        let Err(Error::Dns(error)) = rfc2136_updater(&args).await.add_server(&web()).await else {
+            panic!("the update should have failed");
+        };
+        assert!(
+            error.starts_with("failed to create a DNS record, the DNS server rejected the time of our TSIG signature."),
+            "{}",
+            error
+        );
+
+        // The DNS server answering with its own time, which is too far off from ours for `hickory` to take the answer.
+        args.server_address = vec![format!(
+            "tcp://{}",
+            update_server_off_by(-3600, vec![Rcode::NoError]).await.0
+        )];
+        let shared = Server {
+            shared_name: true,
+            ..web()
+        };
+        let Err(Error::Dns(error)) = rfc2136_updater(&args).await.remove_server(&shared).await
+        else {
+            panic!("the update should have failed");
+        };
+        assert!(
+            error.contains("the DNS server rejected the time of our TSIG signature."),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn tls_server_addresses_are_rejected_as_unsupported() {
+        let mut args = testing::args(&[]);
+        args.server_address = vec![
+            "udp://127.0.0.1:53".to_string(),
+            "tcp://127.0.0.1:53".to_string(),
+            "tls://127.0.0.1:853".to_string(),
+        ];
+
+        let problems: Vec<String> = args
+            .validate()
+            .into_iter()
+            .filter(|p| p.starts_with("The DNS server address"))
+            .collect();
+        assert_eq!(problems.len(), 1);
+        assert!(
+            problems[0].starts_with("The DNS server address 'tls://127.0.0.1:853' asks for TLS"),
+            "{}",
+            problems[0]
+        );
+
+        let Err(Error::Config(message)) = dns_updater(&args).await else {
+            panic!("a client was made for a tls:// address");
+        };
+        assert!(message.contains(TLS_UNSUPPORTED), "{}", message);
+
+        args.server_address.pop();
+        assert!(dns_updater(&args).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn the_record_comment_is_published_as_a_txt_record_next_to_the_a_record() {
+        let (address, updates) = update_server_off_by(0, vec![Rcode::NoError; 2]).await;
+        let mut args = testing::args(&["--record-comment", "managed by hetzner-private-dns-sync"]);
+        args.server_address = vec![format!("udp://{}", address)];
+        let dns_updater = rfc2136_updater(&args).await;
+
+        dns_updater.add_server(&web()).await.unwrap();
+        let records: Vec<(String, String)> = updates
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|update| update.name_servers().to_vec())
+            .filter(|record| record.record_type() != RecordType::ANY)
+            .map(|record| {
+                (
+                    record.name().to_ascii(),
+                    record.data().map(ToString::to_string).unwrap_or_default(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            records,
+            [
+                ("web.example.com.".to_string(), "10.0.0.2".to_string()),
+                (
+                    "web.example.com.".to_string(),
+                    "managed by hetzner-private-dns-sync".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_tsig_key_that_shows_up_a_moment_later_is_read() {
+        let key_directory = testing::temp_dir("transient-tsig-key");
+        let key_path = key_directory.join("key");
+
+        // Validating the configuration doesn't wait for the key.
+        assert!(read_tsig_key(&key_path).is_err());
+
+        // Like a secret being remounted, the key is back before the first retry.
+        let writer = tokio::spawn({
+            let key_path = key_path.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                std::fs::write(key_path, b"0123456789abcdef").unwrap();
+            }
+        });
+        assert_eq!(
+            read_tsig_key_with_retries(&key_path).await.unwrap(),
+            b"0123456789abcdef"
+        );
+
+        writer.await.unwrap();
+        std::fs::remove_dir_all(key_directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_tsig_key_is_read_again_when_the_dns_server_stops_taking_it() {
+        let key_directory = testing::temp_dir("rotated-tsig-key");
+        let key_path = key_directory.join("key");
+        let old_key = b"0123456789abcdef".to_vec();
+        let new_key = b"fedcba9876543210".to_vec();
+        std::fs::write(&key_path, &old_key).unwrap();
+
+        // Like a DNS server in the middle of a key rotation, it knows both keys but only takes the one in `accepted`.
+        let accepted = Arc::new(Mutex::new(old_key.clone()));
+        let (address, updates) = dns_server(0, {
+            let accepted = accepted.clone();
+            let keys = [old_key.clone(), new_key.clone()];
+            move |request| {
+                let algorithm = testing::args(&[]).tsig_algorithm;
+                let key = keys.iter().find(|key| {
+                    tsig_signer("test", key.to_vec(), algorithm, 60)
+                        .unwrap()
+                        .verify_message_byte(None, request, true)
+                        .is_ok()
+                })?;
+                let response_code = if *key == *accepted.lock().unwrap() {
+                    Rcode::NoError
+                } else {
+                    Rcode::BADKEY
+                };
+                Some((response_code, key.clone()))
+            }
+        })
+        .await;
+        let mut args = testing::args(&[]);
+        args.server_address = vec![format!("udp://{}", address)];
+        args.tsig_key_path = Some(key_path.clone());
+        let dns_updater = rfc2136_updater(&args).await;
+
+        dns_updater.add_server(&web()).await.unwrap();
+        std::fs::write(&key_path, &new_key).unwrap();
+        *accepted.lock().unwrap() = new_key;
+        let db = Server {
+            id: 2,
+            ip_address: "10.0.0.3".to_string(),
+            hostname: "db".to_string(),
+            ..web()
+        };
+        dns_updater.add_server(&db).await.unwrap();
+
+        // The update for db was sent again, signed with the key read after the BADKEY.
+        assert_eq!(updates.lock().unwrap().len(), 3);
+        std::fs::remove_dir_all(key_directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn updates_turned_away_with_servfail_are_sent_again() {
+        let mut args = testing::args(&[]);
+        let (address, updates) =
+            update_server_off_by(0, vec![Rcode::ServFail, Rcode::NoError]).await;
+        args.server_address = vec![format!("udp://{}", address)];
+        let dns_updater = rfc2136_updater(&args).await;
+
+        let started = tokio::time::Instant::now();
+        dns_updater.add_server(&web()).await.unwrap();
+        assert_eq!(updates.lock().unwrap().len(), 2);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn updates_sent_through_hickory_are_signed_with_the_tsig_fudge() {
+        let (address, updates) = dns_server(0, |_| Some((Rcode::NoError, Vec::new()))).await;
+        let mut args = testing::args(&["--reverse-zone-name", "0.10.in-addr.arpa"]);
+        args.server_address = vec![format!("udp://{}", address)];
+        args.tsig_fudge = 300;
+        let dns_updater = rfc2136_updater(&args).await;
+
+        dns_updater.add_server(&web()).await.unwrap();
+        let fudges: Vec<(String, u16)> = updates
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|update| {
+                let Some(RData::DNSSEC(DNSSECRData::TSIG(tsig))) = update.signature()[0].data()
+                else {
+                    panic!("the update isn't signed");
+                };
+                (update.queries()[0].name().to_ascii(), tsig.fudge())
+            })
+            .collect();
+        // `dns-update` always signs with 60 seconds.
+        assert_eq!(
+            fudges,
+            [
+                ("example.com.".to_string(), 60),
+                ("0.10.in-addr.arpa.".to_string(), 300),
+                ("0.10.in-addr.arpa.".to_string(), 300),
+            ]
+        );
+    }
+}
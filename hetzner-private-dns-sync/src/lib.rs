@@ -0,0 +1,2114 @@
+//! Keeps DNS records in sync with the servers attached to a Hetzner Cloud private network. The `hetzner-private-dns-sync` binary is a thin CLI around this library.
+
+mod cloud;
+mod dns;
+mod metrics;
+mod plan;
+mod reconcile;
+mod state;
+#[cfg(test)]
+mod testing;
+
+use std::{collections::HashSet, ffi::OsString, net::SocketAddr, path::PathBuf};
+
+use clap::{parser::ValueSource, ArgAction, CommandFactory, Parser, Subcommand, ValueEnum};
+use dns_update::{providers::rfc2136::DnsAddress, DnsRecord};
+use hcloud::apis::ResponseContent;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cloud::{fill_template, read_hcloud_api_token, RECORD_NAME_TEMPLATE_VARIABLES},
+    dns::{
+        is_tls_address, ownership_record_content, read_tsig_key, MIN_TSIG_KEY_LENGTH,
+        TLS_UNSUPPORTED,
+    },
+    state::check_state_directory,
+};
+
+pub use cloud::{network_info, NetworkInfo, RouteInfo, ServerNetworkInfo, SubnetInfo};
+pub use metrics::{render_metrics, write_metrics_file};
+pub use plan::{check, plan, Drift, Plan, RecordDrift, ServerUpdate, StaleName};
+pub use reconcile::{adopt, cleanup, purge, ServerFailure, SyncReport, Syncer};
+pub use state::{
+    dump_state, export_state, load_state, state_save_failed, state_status, StateStatus,
+};
+
+/// Largest --tsig-fudge we take. RFC 8945 recommends 300 seconds, and anything much past that leaves signed updates open to replay for longer than any clock should drift.
+const MAX_TSIG_FUDGE: u16 = 3600;
+
+/// Errors that can happen during a sync, grouped by the part of the system they come from.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Talking to the Hetzner API failed, or it returned something we can't work with.
+    #[error("{0}")]
+    HCloud(String),
+
+    /// The Hetzner API rejected the API token.
+    #[error("{0}")]
+    HCloudAuth(String),
+
+    /// Talking to the DNS server failed, or it rejected an update.
+    #[error("{0}")]
+    Dns(String),
+
+    /// Reading or writing the state file failed.
+    #[error("{0}")]
+    State(String),
+
+    /// The arguments we got (or the way they relate to the current state) don't let us continue.
+    #[error("{0}")]
+    Config(String),
+
+    /// A server doesn't have an address we can publish. Addresses come from the Hetzner API, but also from --transform-command and the state, which can be edited by hand.
+    #[error("{0}")]
+    Address(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// The code the CLI exits with when it stops because of this error, so whatever runs it can react to each kind of failure differently. Code 1 is left for failures that aren't any of these, and code 2 for the changes and drift found by the diff and check subcommands.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Config(_) => 3,
+            Error::HCloudAuth(_) => 4,
+            Error::HCloud(_) => 5,
+            Error::Dns(_) => 6,
+            Error::State(_) => 7,
+            Error::Address(_) => 11,
+        }
+    }
+}
+
+/// Turns an error from the Hetzner API into ours, telling a rejected token apart from everything else.
+fn hcloud_error<T>(context: String, error: hcloud::apis::Error<T>) -> Error {
+    match &error {
+        hcloud::apis::Error::ResponseError(ResponseContent { status, .. })
+            if matches!(status.as_u16(), 401 | 403) =>
+        {
+            Error::HCloudAuth(format!(
+                "{} The Hetzner API rejected the API token, check that it's valid and belongs to the project with the private network. {}",
+                context, error
+            ))
+        }
+        _ => Error::HCloud(format!("{} {}", context, error)),
+    }
+}
+
+/// Key of the --config file that holds the jobs, instead of a flag.
+const CONFIG_JOBS_KEY: &str = "jobs";
+
+/// Reads the --config file at `path` into its keys and values.
+fn read_config_file(path: &std::path::Path) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        Error::Config(format!(
+            "Unable to read the config file {}. {}",
+            path.display(),
+            e
+        ))
+    })?;
+    let is_yaml = path
+        .extension()
+        .is_some_and(|extension| extension == "yaml" || extension == "yml");
+    if is_yaml {
+        serde_yaml::from_str(&contents).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(&contents).map_err(|e| e.to_string())
+    }
+    .map_err(|e| {
+        Error::Config(format!(
+            "Unable to parse the config file {}. {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// Turns every value in `settings` (read from the --config file at `path`) that `matches` didn't get from the command line or the environment into the flags that would set it.
+fn config_file_args(
+    path: &std::path::Path,
+    settings: serde_json::Map<String, serde_json::Value>,
+    matches: &clap::ArgMatches,
+) -> Result<Vec<OsString>> {
+    let command = Args::command();
+    let mut args = Vec::new();
+    for (key, value) in settings {
+        let flag = key.replace('_', "-");
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(flag.as_str()))
+            .filter(|_| !matches!(flag.as_str(), "config" | "help" | "version"))
+        else {
+            return Err(Error::Config(format!(
+                "The config file {} has '{}', which isn't a flag we know.",
+                path.display(),
+                key
+            )));
+        };
+
+        // Whatever the command line sets wins, and that includes flags that conflict with this one (e.g. -q over a `verbose` in the file).
+        let set_outside_file = |arg: &clap::Arg| {
+            matches!(
+                matches.value_source(arg.get_id().as_str()),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+        let conflicts = |a: &clap::Arg, b: &clap::Arg| {
+            command
+                .get_arg_conflicts_with(a)
+                .iter()
+                .any(|conflict| conflict.get_id() == b.get_id())
+        };
+        if set_outside_file(arg)
+            || command
+                .get_arguments()
+                .filter(|other| set_outside_file(other))
+                .any(|other| conflicts(arg, other) || conflicts(other, arg))
+        {
+            continue;
+        }
+
+        let invalid_value = || {
+            Error::Config(format!(
+                "The config file {} has an invalid value for '{}': {}",
+                path.display(),
+                key,
+                value
+            ))
+        };
+        match arg.get_action() {
+            ArgAction::SetTrue => match value {
+                serde_json::Value::Bool(true) => args.push(format!("--{}", flag).into()),
+                serde_json::Value::Bool(false) => (),
+                _ => return Err(invalid_value()),
+            },
+            ArgAction::Count => {
+                let count = value.as_u64().ok_or_else(invalid_value)?;
+                args.extend((0..count).map(|_| format!("--{}", flag).into()));
+            }
+            _ => {
+                let values = match &value {
+                    serde_json::Value::Array(values) => values.iter().collect(),
+                    value => vec![value],
+                };
+                for value in values {
+                    let value = match value {
+                        serde_json::Value::String(value) => value.clone(),
+                        serde_json::Value::Number(value) => value.to_string(),
+                        serde_json::Value::Bool(value) => value.to_string(),
+                        _ => return Err(invalid_value()),
+                    };
+                    args.push(format!("--{}={}", flag, value).into());
+                }
+            }
+        }
+    }
+
+    Ok(args)
+}
+
+/// Where the Hetzner metadata service tells a server its own ID.
+const METADATA_INSTANCE_ID_URL: &str = "http://169.254.169.254/hetzner/v1/metadata/instance-id";
+
+/// Server label that makes us publish a CNAME pointing at the label's value instead of an A record.
+const CNAME_LABEL: &str = "dns-cname";
+
+/// Server label with extra names for a server, which get a CNAME pointing at the server's name. Hetzner doesn't allow commas in label values, so the names can be separated by dots too (e.g. "db.primary-db"). Each name is a single label in the server's zone.
+const ALIAS_LABEL: &str = "dns-alias";
+
+/// Server label with services the server provides, which get an SRV record pointing at the server's name. Each service is written as "_service._proto:port" (e.g. "_postgres._tcp:5432"), or as "service.proto.port" (e.g. "postgres.tcp.5432") since Hetzner label values can only have letters, digits, "-", "_" and ".". Services are separated by commas, or in the second form just follow each other (e.g. "postgres.tcp.5432.http.tcp.80").
+const SRV_LABEL: &str = "dns-srv";
+
+/// Server label with groups the server is in. Every group gets an A record on its name with the address of each server in it, for round-robin between them. Like with the alias label, names are single labels in the server's zone, separated by dots or commas.
+const GROUP_LABEL: &str = "dns-group";
+
+/// Server label that gives a server a wildcard record when set to "true", or keeps it from getting one with --wildcard-records when set to "false".
+const WILDCARD_LABEL: &str = "dns-wildcard";
+
+/// Server label with the TTL (in seconds) of the server's records, in place of --record-ttl. Servers sharing a name or a group should have the same TTL, since DNS servers give all the records on a name a single TTL.
+const TTL_LABEL: &str = "dns-ttl";
+
+/// Server label with the only types of records the server's name gets, in place of --record-types. Hetzner doesn't allow commas in label values, so the types can be separated by dots too (e.g. "aaaa.ptr").
+const RECORD_TYPES_LABEL: &str = "dns-record-types";
+
+/// Server label that stops us from publishing a record for a server when set to "false", without having to detach it from the network.
+const DNS_ENABLED_LABEL: &str = "dns-enabled";
+
+/// Server label that makes us leave a server's records completely alone when set to "ignore", so they can be managed by hand.
+const DNS_SYNC_LABEL: &str = "dns-sync";
+
+/// What `diff` and `check` exit with when anything disagrees. Errors exit with other codes, so drift gets a code of its own.
+pub const DRIFT_EXIT_CODE: u8 = 2;
+
+/// Listed at the end of `--help`, so whatever runs us knows how to tell failures apart. Kept in line with `Error::exit_code()`.
+const EXIT_CODES_HELP: &str = "Exit codes:
+  0  Success
+  1  Any other failure
+  2  The diff subcommand found something to change, or the check subcommand found drift
+  3  Problem with the configuration
+  4  The Hetzner API rejected the API token
+  5  Other failure talking to the Hetzner API
+  6  Failure talking to the DNS servers
+  7  Problem reading or writing the state
+  8  Interrupted by a signal before finishing, so a sync may be partially done
+  9  Didn't finish before --run-timeout, so a sync may be partially done
+  10 A sync finished, but the records of some servers couldn't be changed before --dns-op-timeout
+  11 A server has an address that isn't a valid IP address";
+
+/// Shown by `--help` and in the man page, since how the state works isn't obvious from the flags alone.
+const LONG_ABOUT: &str = "Keeps DNS records in sync with the servers attached to a Hetzner Cloud private network, through RFC 2136 updates.
+
+The state directory holds state.json, which records the private network and the record we published for every server, grouped by zone. It's only readable by its owner (mode 0600). A sync compares the servers in the network with the state: servers that aren't in the state get a record, servers whose record changed get it updated, and servers that are gone get their record removed. A sync never touches records that aren't in the state, so records created by hand are left alone. If the state is lost, the adopt subcommand takes over the existing records again, and cleanup removes orphaned ones. dump-state and load-state back the state up and restore it. With --no-state there's no state directory, and the zones are read instead.
+
+Every flag (except -v and -q) can also be set through an environment variable, which is listed next to it in --help. The variables are HETZNER_PRIVATE_DNS_SYNC_ followed by the flag's name in upper case with \"_\" instead of \"-\" (e.g. HETZNER_PRIVATE_DNS_SYNC_ZONE_NAME), except for HCLOUD_API_TOKEN and STATE_DIRECTORY. Flags that can be repeated take a comma-separated list, and flags without a value take \"true\" or \"false\". Flags on the command line win over the environment, which wins over --config.";
+
+/// Another Hetzner project to take servers from, from --hcloud-project.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HCloudProject {
+    pub name: String,
+    /// File with the project's API token.
+    pub api_token_file: PathBuf,
+}
+
+impl std::str::FromStr for HCloudProject {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, api_token_file)) if !name.is_empty() && !api_token_file.is_empty() => {
+                Ok(Self {
+                    name: name.to_string(),
+                    api_token_file: api_token_file.into(),
+                })
+            }
+            _ => Err("must be in the format \"NAME=PATH\"".to_string()),
+        }
+    }
+}
+
+/// A record from --static-record, which we publish like the record of a server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StaticRecord {
+    /// Name of the record, relative to --zone-name.
+    pub name: String,
+    pub data: StaticRecordData,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StaticRecordData {
+    A(std::net::Ipv4Addr),
+    /// Target of the CNAME, relative to --zone-name unless it ends with a dot.
+    Cname(String),
+}
+
+impl std::str::FromStr for StaticRecord {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || "must be in the format \"NAME A IP\" or \"NAME CNAME TARGET\"".to_string();
+        let [name, record_type, data] = s.split_whitespace().collect::<Vec<_>>()[..] else {
+            return Err(invalid());
+        };
+
+        let data = match record_type.to_ascii_uppercase().as_str() {
+            "A" => StaticRecordData::A(
+                data.parse()
+                    .map_err(|e| format!("has an invalid IPv4 address '{}'. {}", data, e))?,
+            ),
+            "CNAME" => StaticRecordData::Cname(data.to_string()),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self {
+            name: name.trim_end_matches('.').to_string(),
+            data,
+        })
+    }
+}
+
+impl StaticRecord {
+    /// The ID the record is kept under in the state. It's negative so it never clashes with the ID of a server, and only depends on the name, so changing the data of the record updates it.
+    fn id(&self) -> i64 {
+        record_id(&self.name.to_ascii_lowercase())
+    }
+
+    /// The server entry we publish the record as.
+    fn server(&self, args: &Args) -> Server {
+        let (ip_address, cname) = match &self.data {
+            StaticRecordData::A(ip_address) => (ip_address.to_string(), None),
+            StaticRecordData::Cname(target) if target.ends_with('.') => {
+                (String::new(), Some(target.clone()))
+            }
+            StaticRecordData::Cname(target) => (
+                String::new(),
+                Some(format!(
+                    "{}.{}.",
+                    target,
+                    args.zone_name.trim_end_matches('.')
+                )),
+            ),
+        };
+
+        Server {
+            id: self.id(),
+            ip_address,
+            ipv6_address: None,
+            public_ip_address: None,
+            public_zone: None,
+            services: Vec::new(),
+            groups: Vec::new(),
+            metadata: None,
+            wildcard: false,
+            shared_name: false,
+            no_a_record: false,
+            no_ptr_record: false,
+            hostname: self.name.clone(),
+            zone: args.zone_name.clone(),
+            cname,
+            ttl: args.record_ttl,
+            project: None,
+            // Not in any network.
+            network_id: None,
+        }
+    }
+}
+
+/// A negative ID for a record that doesn't belong to a single server, made from a key that stays the same for as long as the record does.
+fn record_id(key: &str) -> i64 {
+    let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest.as_ref()[..8]);
+    -((u64::from_be_bytes(bytes) >> 1) as i64) - 1
+}
+
+/// One set of arguments to run with, from the jobs in the --config file.
+#[derive(Debug, Clone)]
+pub struct Job {
+    /// Name of the job in the --config file, or nothing if the file doesn't have jobs.
+    pub name: Option<String>,
+    pub args: Args,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(version, about, long_about = LONG_ABOUT, after_help = EXIT_CODES_HELP)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to a TOML file (or a YAML one, if it ends in ".yaml" or ".yml") with values for any of the other flags, so they don't all have to be passed on the command line. Keys are the flag names without the leading "--" (e.g. `zone-name = "internal.example.com"`, or `zone_name`), flags that can be repeated take a list, and flags without a value take `true` or `false`. Flags passed on the command line or through their environment variable win over the file.
+    ///
+    /// The file can also have a "jobs" table, with a table of flags for every job (e.g. `[jobs.net-a]`), to sync several networks or zones in one run. Each job runs with the flags at the top of the file, overridden by its own. Jobs run one after the other in the order of their names (or all at once with --parallel-jobs), and a job failing doesn't stop the others. Unless a job sets its own --state-directory, it keeps its state in a subdirectory of the state directory named after it, so job names can only have letters, digits, "-" and "_". Files like --heartbeat-file and --report-file are shared unless each job sets its own.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// How records get to the zones. "rfc2136" sends dynamic updates to the DNS servers at --server-address, signed with the TSIG key. "hetzner-dns" writes them through the API of Hetzner DNS with --hetzner-dns-api-token, for zones hosted there. "powerdns" writes them through the HTTP API of PowerDNS Authoritative at --powerdns-api-url, with --powerdns-api-key. "cloudflare" writes them through the Cloudflare API with --cloudflare-api-token, for zones hosted there. With an API, zones are read through it instead of zone transfers, --owner-id and --reverse-zone-name don't work, and --verify needs --verify-resolver.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_DNS_PROVIDER",
+        value_enum,
+        default_value_t
+    )]
+    pub dns_provider: DnsProvider,
+
+    /// Hetzner DNS API token, needed with --dns-provider=hetzner-dns. It's a different token than the HCloud one, created in the Hetzner DNS console.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_HETZNER_DNS_API_TOKEN")]
+    pub hetzner_dns_api_token: Option<String>,
+
+    /// URL of the PowerDNS Authoritative API (its "webserver-address" and "webserver-port"), like "http://127.0.0.1:8081". Needed with --dns-provider=powerdns.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_POWERDNS_API_URL")]
+    pub powerdns_api_url: Option<reqwest::Url>,
+
+    /// Key of the PowerDNS API (its "api-key" setting). Needed with --dns-provider=powerdns.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_POWERDNS_API_KEY")]
+    pub powerdns_api_key: Option<String>,
+
+    /// ID of the server in the PowerDNS API, which is always "localhost" for PowerDNS Authoritative.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_POWERDNS_SERVER_ID",
+        default_value = "localhost"
+    )]
+    pub powerdns_server_id: String,
+
+    /// Cloudflare API token, needed with --dns-provider=cloudflare. It needs the "DNS:Edit" permission on the zones, and "Zone:Read" to look up the ones without an ID.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_CLOUDFLARE_API_TOKEN")]
+    pub cloudflare_api_token: Option<String>,
+
+    /// ID of the Cloudflare zone of --zone-name, shown on the overview page of the zone. Without it, the zone is looked up by its name. Other zones, like --public-zone-name, are always looked up by name.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_CLOUDFLARE_ZONE_ID")]
+    pub cloudflare_zone_id: Option<String>,
+
+    /// Path to the raw TSIG key. Needed with --dns-provider=rfc2136.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_TSIG_KEY_PATH")]
+    pub tsig_key_path: Option<PathBuf>,
+
+    /// Name of the TSIG key. Needed with --dns-provider=rfc2136.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_TSIG_KEY_NAME")]
+    pub tsig_key_name: Option<String>,
+
+    /// Algorithm of the TSIG key. Keys using hmac-sha1 or hmac-sha224 aren't supported, since the DNS libraries we use can't sign with them, so those need a new key (e.g. `tsig-keygen -a hmac-sha256`). Zones served by DNS servers with different keys can each get their own job in --config, with their own --tsig-key-path, --tsig-key-name and --tsig-algorithm.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_TSIG_ALGORITHM",
+        value_enum,
+        default_value_t
+    )]
+    pub tsig_algorithm: TsigAlgorithm,
+
+    /// Number of seconds the DNS server may find the time in our TSIG signatures to be off from its own clock (the "fudge" of RFC 8945), between 1 and 3600. Raising it lets updates through when the clocks drift apart, at the cost of a longer window for replaying them. Only requests we send through `hickory` (zone transfers, PTR records and removals from shared names) use it, since `dns-update`, which sends the other updates, always uses 60 seconds.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_TSIG_FUDGE",
+        default_value_t = 60
+    )]
+    pub tsig_fudge: u16,
+
+    /// Address of the DNS server in the format "tcp|udp://ip:port". Updates can't be sent over TLS, so "tls://" addresses are rejected. Can be repeated or given as a comma-separated list, in which case the servers are tried in order whenever one can't be reached, and the first one that answers is used for the rest of the run. Needed with --dns-provider=rfc2136.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_SERVER_ADDRESS",
+        value_delimiter = ','
+    )]
+    pub server_address: Vec<String>,
+
+    /// Hetzner HCloud API token.
+    #[arg(long, env = "HCLOUD_API_TOKEN")]
+    pub hcloud_api_token: String,
+
+    /// Another Hetzner project to take servers from, in the format "NAME=PATH", where PATH is a file with the project's API token. Can be passed multiple times. Every project needs a private network named --private-network-name, and the servers of all of them get records as if they were in a single network. The state records the NAME of the project each server came from (the project of --hcloud-api-token doesn't have a name). Only the network of --hcloud-api-token is remembered between runs and shown by network-info, the others are looked up by name every time.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_HCLOUD_PROJECT",
+        value_delimiter = ','
+    )]
+    pub hcloud_project: Vec<HCloudProject>,
+
+    /// Name of the private network in the Hetzner account.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_PRIVATE_NETWORK_NAME")]
+    pub private_network_name: String,
+
+    /// Directory to keep state in. Created (along with its parents) if it doesn't exist. Not needed with --no-state.
+    #[arg(long, env = "STATE_DIRECTORY", required_unless_present = "no_state")]
+    pub state_directory: Option<PathBuf>,
+
+    /// DNS zone name.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_ZONE_NAME")]
+    pub zone_name: String,
+
+    /// Zone the dynamic updates for --zone-name are sent to, when it isn't a zone of its own on the DNS servers (like "example.com" for records in "internal.example.com", or the zone a BIND view serves them from). Names stay under --zone-name, which has to be in this zone, and the zone is transferred instead of --zone-name when reading records. Other zones, like --public-zone-name and the ones from --zone-per-label, aren't affected. Only works with --dns-provider=rfc2136.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_UPDATE_ZONE")]
+    pub update_zone: Option<String>,
+
+    /// Also publish each server's public IPv4 address in this zone, on the same name it gets in its private zone (so "db.<zone-name>" also gets a record on "db.<public-zone-name>"). Servers without a public IPv4 address and servers with a CNAME only get their private record. The zone must be served by the DNS servers at --server-address and accept the same TSIG key. With --no-state, the public records are worked out from the zone too.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_PUBLIC_ZONE_NAME")]
+    pub public_zone_name: Option<String>,
+
+    /// Also publish a wildcard record on "*.<name>" for every server, with the same data as the server's record, for servers that serve many names (like ingresses and reverse proxies). A server's dns-wildcard label overrides this: "true" gives it a wildcard record even without the flag, "false" none even with it.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_WILDCARD_RECORDS")]
+    pub wildcard_records: bool,
+
+    /// Reverse zone (like "0.10.in-addr.arpa") to keep a PTR record in for each A record we publish in the private zones, pointing back at the server's name. Only addresses in the zone get one, and it's removed along with the A record. The zone must be served by the DNS servers at --server-address. Its updates are signed with --reverse-tsig-key-name and --reverse-tsig-key-path if they're given, and with the key of the other zones otherwise.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_REVERSE_ZONE_NAME")]
+    pub reverse_zone_name: Option<String>,
+
+    /// Name of the TSIG key for the --reverse-zone-name, if it doesn't take the same key as the other zones. Uses the same --tsig-algorithm.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_REVERSE_TSIG_KEY_NAME",
+        requires_all = ["reverse_zone_name", "reverse_tsig_key_path"]
+    )]
+    pub reverse_tsig_key_name: Option<String>,
+
+    /// Path to the raw TSIG key for the --reverse-zone-name.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_REVERSE_TSIG_KEY_PATH",
+        requires_all = ["reverse_zone_name", "reverse_tsig_key_name"]
+    )]
+    pub reverse_tsig_key_path: Option<PathBuf>,
+
+    /// TTL (in seconds) of the records we publish. Changing it updates the records published before with the previous TTL. A server's dns-ttl label (e.g. "dns-ttl=60") overrides it for that server's records.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_RECORD_TTL", default_value_t = DEFAULT_TTL)]
+    pub record_ttl: u32,
+
+    /// Name of a server label used to pick the DNS zone for each server. A server with the label "<label>=<value>" gets its record in the zone "<value>.<zone-name>" instead of "<zone-name>". Servers without the label are published in "<zone-name>". All zones must be served by the DNS servers at --server-address and accept the same TSIG key.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_ZONE_PER_LABEL")]
+    pub zone_per_label: Option<String>,
+
+    /// Subdomain to put the records of this network's servers in, so a server gets "<hostname>.<subdomain>.<zone>" instead of "<hostname>.<zone>", in the same zone. Meant for jobs in --config that publish different networks in the same zone, so servers with the same name in different networks get different records. Can have several labels (e.g. "net-a.eu"). CNAME targets from the dns-cname label are taken relative to the subdomain too.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_NETWORK_SUBDOMAIN")]
+    pub network_subdomain: Option<String>,
+
+    /// Template for the name of each server's record, relative to its zone, instead of the server's name. It can have "{hostname}" (the server's name), "{id}", "{datacenter}" (like "fsn1-dc14"), "{location}" (like "fsn1") and "{label.<key>}" (the value of the server's label with that key, which it must have), e.g. "{hostname}.{location}" or "{hostname}-int". It can end with ".{zone}" for readability, which is left out. The name still goes under --network-subdomain, if there's one.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_RECORD_NAME_TEMPLATE")]
+    pub record_name_template: Option<String>,
+
+    /// Text to put in front of the name of every server before it's used in its record (and in --record-name-template), so several environments can share a zone: with "prod-", the server "web1" gets "prod-web1.<zone>". Alias and group names from labels get it too.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_RECORD_PREFIX",
+        allow_hyphen_values = true
+    )]
+    pub record_prefix: Option<String>,
+
+    /// Like --record-prefix, but put after the name: with "-hel", the server "web1" gets "web1-hel.<zone>".
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_RECORD_SUFFIX",
+        allow_hyphen_values = true
+    )]
+    pub record_suffix: Option<String>,
+
+    /// Only publish records for servers whose private IP is inside this subnet, in CIDR notation (e.g. "10.0.1.0/24"). Can be passed multiple times, in which case the IP has to be inside any of the subnets. Servers whose IP moves out of the subnets get their records removed. If not passed, servers are published regardless of their IP.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_PUBLISH_SUBNET",
+        value_delimiter = ','
+    )]
+    pub publish_subnet: Vec<IpNet>,
+
+    /// If the private network name changes between invocations, this software will remove all DNS entries it previously created to clean up its state, and then start with a new state for the new network name. This flag indicates an acknowledgement of this behaviour. If not passed (or false), the software will exit with an error instead of cleaning things up.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_ALLOW_PRIVATE_NETWORK_CHANGE")]
+    pub allow_private_network_change: bool,
+
+    /// Maximum number of records a single sync can add, update or remove. If a sync would change more than this (counting the records removed because the private network name changed), it stops with an error instead of making the changes, which protects the zones from a bad answer from the Hetzner API or a token for the wrong project. If not passed, there's no limit.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_MAX_CHANGES")]
+    pub max_changes: Option<usize>,
+
+    /// Percentage of the records we synced that a single sync can remove before it needs --allow-mass-delete. A sync that would remove more than this (and more than one record) stops with an error instead, since a transient empty answer from the Hetzner API would otherwise remove everything. Removals because the private network name changed are acknowledged by --allow-private-network-change instead.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_MASS_DELETE_THRESHOLD",
+        default_value_t = 50
+    )]
+    pub mass_delete_threshold: u8,
+
+    /// Allow a sync to remove more records than --mass-delete-threshold allows.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_ALLOW_MASS_DELETE")]
+    pub allow_mass_delete: bool,
+
+    /// Prefix shared by the names of all records this software manages, relative to their zone (e.g. "web-" for "web-1.example.com"). Required by the cleanup subcommand, which only ever removes records with this prefix.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_CLEANUP_PREFIX")]
+    pub cleanup_prefix: Option<String>,
+
+    /// Only remove records of servers that left the private network (or stopped being published), without adding or updating any other records. The state is still kept up to date with what got removed.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_PRUNE_ONLY",
+        conflicts_with = "add_only"
+    )]
+    pub prune_only: bool,
+
+    /// Only add records for new servers and update records of changed servers, without removing records of servers that left the private network. Those stay in the state, so they'll be removed by a later run without this flag.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_ADD_ONLY")]
+    pub add_only: bool,
+
+    /// Name we must never create, update or remove a record on, even if a sync, cleanup or purge would. Takes the full name (e.g. "ns1.internal.example.com"), where "*" matches any number of characters and "?" matches a single one (e.g. "*.infra.internal.example.com"). Can be passed multiple times. Changes on protected names are left out with a warning, so a server that would get a protected name keeps coming up in every run until its name changes.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_PROTECT_NAME",
+        value_delimiter = ','
+    )]
+    pub protect_name: Vec<String>,
+
+    /// Don't manage the record of the server this software is running on. Its ID is taken from the Hetzner metadata service, unless --self-server-id is passed. If its record was managed before, it's dropped from the state and left as it is.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_EXCLUDE_SELF")]
+    pub exclude_self: bool,
+
+    /// ID of the server this software is running on, for when the Hetzner metadata service isn't available. Only used with --exclude-self.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_SELF_SERVER_ID",
+        requires = "exclude_self"
+    )]
+    pub self_server_id: Option<i64>,
+
+    /// When to write the state to disk while syncing. Writing after each change is the safest, but can dominate the time a sync takes on slow storage. Regardless of the mode, the state is always written before exiting, including when interrupted by SIGINT or SIGTERM.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_STATE_SAVE_MODE",
+        value_enum,
+        default_value_t
+    )]
+    pub state_save_mode: StateSaveMode,
+
+    /// How many changes to records to batch before writing the state, when --state-save-mode is "batch".
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_STATE_SAVE_BATCH_SIZE",
+        default_value_t = 10
+    )]
+    pub state_save_batch_size: usize,
+
+    /// What to do with the records we published when the private network has no servers at all. An empty network is more often a mistake (or a network being drained on purpose) than a reason to remove everything, so "keep" leaves the records and the state as they are, and only logs a warning.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_ON_EMPTY",
+        value_enum,
+        default_value_t
+    )]
+    pub on_empty: OnEmpty,
+
+    /// How much we print, and in which form. "human" logs everything of interest as we go. "json" only logs warnings and errors, and a sync prints a single JSON document to stdout when it's done, for automation. The document has the "status" ("ok" or "failed"), the "duration_seconds" of the sync, and either the number of records "added", "updated", "removed" and "forgotten" along with the full "report" of what changed, or the "error" that stopped the sync. "quiet" only logs errors, for cron. Logs always go to stderr.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_OUTPUT_FORMAT",
+        value_enum,
+        default_value_t
+    )]
+    pub output_format: OutputFormat,
+
+    /// Log more. Each -v goes one level down from the level --output-format logs at (so "-v" shows debug logs and "-vv" trace logs with the "human" format). If the RUST_LOG environment variable is set, its directives (e.g. "warn,hetzner_private_dns_sync=debug") pick what's logged instead, so logs can be filtered per module.
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Log less. Each -q goes one level up from the level --output-format logs at, until nothing is logged.
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub quiet: u8,
+
+    /// What to do with a server whose name ends up empty (or only whitespace), which would otherwise get a bogus record on the zone name itself. "skip" leaves the server without a record and logs a warning, "error" stops the sync.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_ON_EMPTY_HOSTNAME",
+        value_enum,
+        default_value_t
+    )]
+    pub on_empty_hostname: OnEmptyHostname,
+
+    /// What to do with a server whose name isn't valid in DNS (e.g. it has spaces or other characters DNS names can't have, or a part longer than 63 characters). "keep" sends the name to the DNS server as it is. "sanitize" lowercases it, replaces every character other than letters, digits and "-" (underscores included) with "-", and cuts parts that are too long, logging a warning when it has to cut. "skip" leaves the server without a record and logs a warning, "error" stops the sync.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_ON_INVALID_HOSTNAME",
+        value_enum,
+        default_value_t
+    )]
+    pub on_invalid_hostname: OnInvalidHostname,
+
+    /// Leave servers whose name has non-ASCII characters (e.g. "büro") without a record, logging a warning, instead of publishing them under the IDNA encoding of their name ("xn--bro-hoa"). Useful when the clients resolving these names don't handle IDNA, and would never look them up anyway.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_SKIP_NON_ASCII_HOSTNAMES")]
+    pub skip_non_ascii_hostnames: bool,
+
+    /// What to do when several servers in the same zone end up with the same name. "round-robin" publishes an A record for each of them on the name, and removes only a server's own records when it goes away, so the others keep theirs. "suffix" adds "-<id>" to the first label of the name of each of them (so two servers named "web" get "web-1234" and "web-5678"). "error" stops the sync. --max-records-per-name limits how many servers can share a name with "round-robin".
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_ON_DUPLICATE_HOSTNAME",
+        value_enum,
+        default_value_t
+    )]
+    pub on_duplicate_hostname: OnDuplicateHostname,
+
+    /// Where to get an IPv6 address to publish an AAAA record for each server from, next to its A record. "none" only publishes A records. "public" publishes the first address of the server's public IPv6 network (the "::1" Hetzner configures on the server by default), for servers that have one. Servers with a CNAME don't get an AAAA record.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_IPV6_SOURCE",
+        value_enum,
+        default_value_t
+    )]
+    pub ipv6_source: Ipv6Source,
+
+    /// Only publish these types of records on the names of servers: "a" for the private IP, "aaaa" for the address from --ipv6-source, and "ptr" for the PTR record in the --reverse-zone-name. Can be passed multiple times or as a comma-separated list. Without "a", the name (and the wildcard and group names) get the AAAA record in its place, servers without an IPv6 address get no records at all, and there's no PTR record either, since that's for the private IP. A server's dns-record-types label overrides this. Records of types taken out of the list are removed on the next sync. Servers with a CNAME aren't affected. If not passed, servers get every type of record they have an address for.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_RECORD_TYPES",
+        value_delimiter = ','
+    )]
+    pub record_types: Vec<ServerRecordType>,
+
+    /// Also publish the alias IPs servers have in the private network, each in an A record of its own. "none" only publishes the main IP of each server. "same-name" puts them on the server's name, next to its main IP. "numbered" puts them on names of their own, "<name>-alias1", "<name>-alias2" and so on, in the order of the addresses. Servers with a CNAME don't get these records.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_ALIAS_IP_RECORDS",
+        value_enum,
+        default_value_t
+    )]
+    pub alias_ip_records: AliasIpRecords,
+
+    /// What to do when more than one network in the Hetzner account has the --private-network-name. "error" stops with an error listing the IDs of the networks that matched, "first" goes on with the first one the Hetzner API returned.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_ON_AMBIGUOUS_NETWORK",
+        value_enum,
+        default_value_t
+    )]
+    pub on_ambiguous_network: OnAmbiguousNetwork,
+
+    /// Path of a file to write the time of the last successful sync to, as seconds since the Unix epoch. The file is rewritten after every successful sync, so monitoring can alert when its modification time gets too old. Nothing is written when a sync fails.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_HEARTBEAT_FILE")]
+    pub heartbeat_file: Option<PathBuf>,
+
+    /// URL of a dead man's switch monitor (like healthchecks.io) to ping after each sync. A successful sync sends a POST to the URL with a summary of what changed, and a failed one sends a POST to the URL with "/fail" appended and the error. Failing to ping only gets a warning.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_PING_URL")]
+    pub ping_url: Option<reqwest::Url>,
+
+    /// Print the records a sync would add, update and remove (with their names, TTLs and addresses) instead of syncing. Nothing is changed in DNS or in the state. This is the same as the diff subcommand, except that it exits successfully whether or not there's anything to change.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_DRY_RUN")]
+    pub dry_run: bool,
+
+    /// Before syncing, print what the sync would change and ask for confirmation on the terminal. The sync stops if the answer isn't "y", or if stdin isn't a terminal. Nothing is asked if there's nothing to change. The sync looks at the private network again after the answer, so a server that changed in the meantime is synced as it is then.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_CONFIRM")]
+    pub confirm: bool,
+
+    /// Answer "yes" to --confirm without asking, so scripts can keep passing --confirm and still run unattended.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_ASSUME_YES")]
+    pub assume_yes: bool,
+
+    /// Run the jobs in the --config file at the same time instead of one after the other. Their logs get mixed, but every line says which job it's from.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_PARALLEL_JOBS",
+        conflicts_with = "confirm"
+    )]
+    pub parallel_jobs: bool,
+
+    /// Only run the job with this name from the --config file. Can be passed multiple times. If not passed, every job runs.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_JOB",
+        value_delimiter = ',',
+        requires = "config"
+    )]
+    pub job: Vec<String>,
+
+    /// Path of a file to write a JSON report of each sync to, for auditing. The report has the inputs of the sync (network, zones and filters), what we planned to change and what we changed, when the sync started and how long it took, and the SHA-256 of the state it left behind. A failed sync writes the error instead of what it changed. The file is replaced on every run.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_REPORT_FILE")]
+    pub report_file: Option<PathBuf>,
+
+    /// Path of a file to write metrics of each sync to, in the Prometheus text format (e.g. for the textfile collector of node_exporter). For now, that's the dns_sync_propagation_seconds histogram, with how long records took to show up on each resolver with --verify. The file is replaced on every run.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_METRICS_FILE")]
+    pub metrics_file: Option<PathBuf>,
+
+    /// Number of seconds a server must stay detached from the private network before its record is removed. Until then, its record is left alone, so detaching a server for a short while (e.g. for maintenance) doesn't remove and re-create its record. A server that gets attached again within the period is treated as if it was never detached. If not passed, records of detached servers are removed right away.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_DETACH_GRACE_PERIOD")]
+    pub detach_grace_period: Option<u64>,
+
+    /// Maximum number of servers that can get a record on the same name, which happens when servers in the same zone have the same name. If more servers than this would share a name, the sync stops with an error instead of piling up records on it. If not passed, there's no limit.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_MAX_RECORDS_PER_NAME")]
+    pub max_records_per_name: Option<usize>,
+
+    /// Don't keep any state, and use the records in the zones as the source of truth instead. Every run transfers the zones, treats the records on the names of published servers as theirs, and removes records with the --cleanup-prefix that don't belong to any server in the private network (just like the cleanup subcommand). Only the zones of the servers currently in the network and the --zone-name zone are looked at. Needs a non-empty --cleanup-prefix, and the DNS server must allow zone transfers.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_NO_STATE",
+        conflicts_with = "detach_grace_period"
+    )]
+    pub no_state: bool,
+
+    /// Number of seconds the changes to a single server's records can take before we give up on them. A sync doesn't stop because of it: the server is reported as failed, keeps what the state had for it, and gets tried again on the next sync, while the other servers are synced as usual. The run then exits with code 10. Zone transfers and lookups get the same timeout, and fail the run if they time out. A DNS server that's just slow doesn't make us try the next --server-address, only one we can't reach does. If not passed, operations can take as long as the DNS client allows.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_DNS_OP_TIMEOUT")]
+    pub dns_op_timeout: Option<u64>,
+
+    /// Number of seconds the whole run can take before we give up on it, so a Hetzner API or DNS server that hangs doesn't block whatever runs us (e.g. a systemd timer) forever. Just like with a signal, the state is saved with every change done until then, and the next run picks up from there. With jobs in --config, the timeout is for all of them together. If not passed, a run can take as long as it needs.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_RUN_TIMEOUT")]
+    pub run_timeout: Option<u64>,
+
+    /// Maximum number of update messages sent to the DNS servers per second. Short bursts of up to this many messages are allowed, after which messages are spaced out evenly. If not passed, messages are sent as fast as the DNS servers answer.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_DNS_UPDATE_RATE_LIMIT")]
+    pub dns_update_rate_limit: Option<u32>,
+
+    /// Shell command that gets to change the record of each server before it's published. It's run once per server with the server as JSON on its stdin (with the fields "id", "ip_address", "hostname", "zone", "ttl", with --ipv6-source "ipv6_address", with --public-zone-name "public_ip_address" and "public_zone", for servers with a dns-srv label "services", for CNAMEs "cname", and for servers of an --hcloud-project "project"), and must print the server to publish as JSON in the same form on its stdout, or an array of them to publish several records for it. The "id" must stay the same, and the "project" can't be changed. If the command exits with an error or prints something we can't parse, the server is skipped with a warning, and its records stay as they are until the next sync.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_TRANSFORM_COMMAND")]
+    pub transform_command: Option<String>,
+
+    /// After adding or updating a record, poll DNS servers until they answer with it, and log how long that took. Useful to find out how quickly records make it to secondaries. A record that doesn't show up before --verify-timeout only gets a warning.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_VERIFY")]
+    pub verify: bool,
+
+    /// Address of a DNS server to poll with --verify, in the format "ip:port". Can be passed multiple times. If not passed, the servers at --server-address are polled.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_VERIFY_RESOLVER",
+        value_delimiter = ',',
+        requires = "verify"
+    )]
+    pub verify_resolver: Vec<SocketAddr>,
+
+    /// Number of seconds to wait for a record to show up with --verify before giving up on it.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_VERIFY_TIMEOUT",
+        default_value_t = 30
+    )]
+    pub verify_timeout: u64,
+
+    /// Text to publish in a TXT record next to each A record we create, so whoever looks at the zone can tell the record is managed by this software (e.g. "managed by hetzner-private-dns-sync"). RFC 2136 has no way to attach a comment to a record itself. CNAMEs can't share their name with other records, so they don't get the TXT record. The TXT record is removed together with the A record. Can be at most 255 characters.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_RECORD_COMMENT")]
+    pub record_comment: Option<String>,
+
+    /// Fields of each server to publish in a TXT record on its name, so other tools can find out about servers through DNS without asking the Hetzner API. Can be "hostname" (the name of the server in Hetzner), "id", "datacenter", "location" or "label.KEY" for the value of the server's KEY label (e.g. "label.role"). The TXT record holds "FIELD=VALUE" for each of them that the server has, separated by spaces (e.g. "id=1234 role=db"), where the FIELD of a label is its KEY. CNAMEs can't share their name with other records, so they don't get the TXT record. It can be at most 255 characters.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_METADATA_FIELD",
+        value_delimiter = ','
+    )]
+    pub metadata_field: Vec<String>,
+
+    /// Marks every name we publish records on as ours with a TXT record on "_owner.<name>", holding "hetzner-private-dns-sync owner=<ID>", and only removes records from names marked with the same ID. Records people or other tools created are then left alone, even on a name we'd otherwise remove, and we refuse to add records to a name that has someone else's records. Names we published on before the flag was set aren't marked, so they're left alone too: add the TXT record to them by hand to hand them over. Installations sharing a zone must each have their own ID.
+    #[arg(long, env = "HETZNER_PRIVATE_DNS_SYNC_OWNER_ID")]
+    pub owner_id: Option<String>,
+
+    /// Extra record to publish and keep in sync alongside the records of the servers, in the format "NAME A IP" or "NAME CNAME TARGET" (e.g. "vpn A 10.0.0.2"). NAME is relative to --zone-name, and so is TARGET unless it ends with a dot. Can be passed multiple times (or as a list in --config), but only once per NAME. The records get --record-ttl, and they're kept in the state like servers are, under negative IDs made from their NAME. A record that isn't passed anymore gets removed, just like the record of a server that's gone.
+    #[arg(
+        long,
+        env = "HETZNER_PRIVATE_DNS_SYNC_STATIC_RECORD",
+        value_delimiter = ','
+    )]
+    pub static_record: Vec<StaticRecord>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Checks the given arguments for problems and reports all of them, without contacting the Hetzner API or the DNS server. Besides what every run checks, this also makes sure the state directory can be written to, without touching the state itself. Exits successfully if no problems were found, so it can gate deployments.
+    ValidateConfig,
+
+    /// Removes every record in the zone whose name starts with --cleanup-prefix and which doesn't belong to a server currently in the private network. Records are found through a zone transfer (AXFR), so the DNS server must allow transfers with the TSIG key. Useful to get rid of orphaned records after the state was lost.
+    Cleanup,
+
+    /// Prints the records a sync would add, update and remove, without changing anything in DNS or in the state. Exits successfully if there's nothing to change, and with code 2 if there is.
+    #[command(visible_alias = "plan")]
+    Diff,
+
+    /// Compares the servers in the private network, the state and the records actually in the zones, and prints whatever disagrees, without changing anything. Exits with code 2 if anything disagrees, so drift can be told apart from errors (which exit with code 1). The zones are read through a zone transfer (AXFR), so the DNS server must allow transfers with the TSIG key.
+    Check,
+
+    /// Removes the records of every server in the state and empties the state, for decommissioning an environment. Doesn't look at the private network at all, so servers still in it get their records back on the next sync.
+    #[command(visible_alias = "clean")]
+    Purge,
+
+    /// Prints what's in the state in a readable form: the private network, when the last successful sync happened, and the record of each server we synced. Doesn't change anything.
+    Status,
+
+    /// Prints the records of the servers in the state, without changing anything.
+    Export {
+        /// Form to print the records in. "zonefile" prints them as zone file resource records with absolute names, grouped by zone, which can seed a secondary server. "json" prints an array with an object per server, and "csv" prints a row per server with a header. Both have the fields "id", "hostname", "ip_address", "ipv6_address" (empty without an AAAA record), "public_ip_address" and "public_fqdn" (empty without a record in the --public-zone-name), "fqdn", "zone", "ttl" and "cname" (empty for A records), so other tools can use them without knowing how the state is laid out.
+        #[arg(long, value_enum, default_value_t)]
+        format: ExportFormat,
+    },
+
+    /// Takes ownership of records that already exist for the servers in the private network, without recreating them. The zones are read through a zone transfer (AXFR), so the DNS server must allow transfers with the TSIG key. Every A or CNAME record on the name a server would get is added to the state as that server's record, so the next sync only changes the records that don't match. Nothing is changed in DNS.
+    Adopt,
+
+    /// Prints a completion script for the given shell, e.g. `hetzner-private-dns-sync completions bash > /etc/bash_completion.d/hetzner-private-dns-sync`. Doesn't need any of the other arguments.
+    Completions {
+        /// Shell to print the completion script for.
+        shell: clap_complete::Shell,
+    },
+
+    /// Prints a man page for this software in roff, for packaging. Doesn't need any of the other arguments.
+    #[command(hide = true)]
+    Man,
+
+    /// Prints the state as JSON, in the same form as it's kept in the state directory. Doesn't change anything.
+    DumpState,
+
+    /// Replaces the state with one printed by dump-state, after checking it's valid. Doesn't change anything in DNS, so the next sync picks up from the loaded state.
+    LoadState {
+        /// Path of the file with the state to load.
+        path: PathBuf,
+    },
+
+    /// Prints the private network's IP range, subnets and routes, and the IP each server in it has, along with the subnet the IP belongs to and whether it's inside --publish-subnet. Doesn't change anything in DNS or in the state.
+    NetworkInfo,
+}
+
+impl Args {
+    /// Parses the command line like `Args::parse()`, filling in whatever it doesn't set from --config, and returns the jobs to run. Without jobs in the file, that's a single unnamed job. Just like `Args::parse()`, this exits with a usage message if the arguments (including the ones from the file) don't parse.
+    pub fn parse_with_config() -> Result<Vec<Job>> {
+        let command_line: Vec<OsString> = std::env::args_os().collect();
+        let single_job = |command_line| {
+            Ok(vec![Job {
+                name: None,
+                args: Args::parse_from(command_line),
+            }])
+        };
+
+        // A first pass only to find --config and what the command line already sets. Anything wrong with the command line gets reported when parsing for real.
+        let Ok(matches) = Args::command()
+            .ignore_errors(true)
+            .try_get_matches_from(&command_line)
+        else {
+            return single_job(command_line);
+        };
+        let Some(config_path) = matches.get_one::<PathBuf>("config") else {
+            return single_job(command_line);
+        };
+
+        let mut settings = read_config_file(config_path)?;
+        let with_config = |settings| -> Result<Args> {
+            let mut full_command_line = command_line[..1].to_vec();
+            full_command_line.extend(config_file_args(config_path, settings, &matches)?);
+            full_command_line.extend_from_slice(&command_line[1..]);
+            Ok(Args::parse_from(full_command_line))
+        };
+
+        let Some(jobs) = settings.remove(CONFIG_JOBS_KEY) else {
+            return Ok(vec![Job {
+                name: None,
+                args: with_config(settings)?,
+            }]);
+        };
+        let serde_json::Value::Object(jobs) = jobs else {
+            return Err(Error::Config(format!(
+                "The \"{}\" in the config file {} must be a table with the flags of each job.",
+                CONFIG_JOBS_KEY,
+                config_path.display()
+            )));
+        };
+
+        let selected_jobs: Vec<&String> = matches
+            .get_many::<String>("job")
+            .map(|names| names.collect())
+            .unwrap_or_default();
+        if let Some(name) = selected_jobs
+            .iter()
+            .find(|name| !jobs.contains_key(name.as_str()))
+        {
+            return Err(Error::Config(format!(
+                "There's no job named '{}' in the config file {}.",
+                name,
+                config_path.display()
+            )));
+        }
+
+        let mut result = Vec::new();
+        for (name, job_settings) in jobs {
+            if !selected_jobs.is_empty() && !selected_jobs.contains(&&name) {
+                continue;
+            }
+
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(Error::Config(format!(
+                    "The job '{}' in the config file {} can only have letters, digits, \"-\" and \"_\" in its name.",
+                    name,
+                    config_path.display()
+                )));
+            }
+            let serde_json::Value::Object(job_settings) = job_settings else {
+                return Err(Error::Config(format!(
+                    "The job '{}' in the config file {} must be a table of flags.",
+                    name,
+                    config_path.display()
+                )));
+            };
+
+            // The job only keeps its state where it says if that's where the state directory ended up coming from.
+            let sets_state_directory = job_settings
+                .keys()
+                .any(|key| key.replace('_', "-") == "state-directory")
+                && !matches!(
+                    matches.value_source("state_directory"),
+                    Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+                );
+
+            let mut merged_settings = settings.clone();
+            merged_settings.extend(job_settings);
+            let mut args = with_config(merged_settings)?;
+            if !sets_state_directory {
+                args.state_directory = args.state_directory.map(|d| d.join(&name));
+            }
+
+            result.push(Job {
+                name: Some(name),
+                args,
+            });
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the problems the validate-config subcommand looks for on top of `validate()`: a TSIG key that's probably not the right one, and a state directory that can't be written to. Syncs don't check these, since a key that looks wrong may still work.
+    pub fn deployment_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for key_path in self.tsig_key_path.iter().chain(&self.reverse_tsig_key_path) {
+            match read_tsig_key(key_path) {
+                Ok(key) if key.starts_with(b"key ") || key.windows(7).any(|w| w == b"secret ") => {
+                    problems.push(format!(
+                        "The TSIG key at {} looks like a BIND key file. It must have the raw key, which is the \"secret\" in the file decoded from base64.",
+                        key_path.display()
+                    ))
+                }
+                Ok(key) if !key.is_empty() && key.len() < MIN_TSIG_KEY_LENGTH => {
+                    problems.push(format!(
+                        "The TSIG key at {} is only {} bytes long, but it should have at least {}.",
+                        key_path.display(),
+                        key.len(),
+                        MIN_TSIG_KEY_LENGTH
+                    ))
+                }
+                // `validate()` already reports these.
+                Ok(_) | Err(_) => (),
+            }
+        }
+
+        problems.extend(check_state_directory(self));
+        problems
+    }
+
+    /// A name from the server or its labels, with --record-prefix and --record-suffix around it.
+    fn record_label(&self, name: &str) -> String {
+        format!(
+            "{}{}{}",
+            self.record_prefix.as_deref().unwrap_or_default(),
+            name,
+            self.record_suffix.as_deref().unwrap_or_default()
+        )
+    }
+
+    /// Whether --protect-name keeps us away from the records of `fqdn`.
+    pub fn is_protected_name(&self, fqdn: &str) -> bool {
+        let fqdn = normalise_name(fqdn);
+        self.protect_name
+            .iter()
+            .any(|pattern| matches_glob(&normalise_name(pattern), &fqdn))
+    }
+
+    /// Returns every problem found with the arguments. This doesn't do any network calls and doesn't touch the state.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for server_address in &self.server_address {
+            if is_tls_address(server_address) {
+                problems.push(format!(
+                    "The DNS server address '{}' asks for TLS, but {}",
+                    server_address, TLS_UNSUPPORTED
+                ));
+            } else if DnsAddress::try_from(server_address.as_str()).is_err() {
+                problems.push(format!(
+                    "The DNS server address '{}' isn't in the format \"tcp|udp://ip:port\".",
+                    server_address
+                ));
+            }
+        }
+
+        if let Some(tsig_key_path) = &self.tsig_key_path {
+            match read_tsig_key(tsig_key_path) {
+                Ok(key) if key.is_empty() => problems.push(format!(
+                    "The TSIG key at {} is empty.",
+                    tsig_key_path.display()
+                )),
+                Ok(_) => (),
+                Err(e) => problems.push(e.to_string()),
+            }
+        }
+
+        if self
+            .tsig_key_name
+            .as_ref()
+            .is_some_and(|key_name| key_name.trim().is_empty())
+        {
+            problems.push("The TSIG key name is empty.".to_string());
+        }
+
+        match self.dns_provider {
+            DnsProvider::Rfc2136 => {
+                for (flag, missing) in [
+                    ("--server-address", self.server_address.is_empty()),
+                    ("--tsig-key-path", self.tsig_key_path.is_none()),
+                    ("--tsig-key-name", self.tsig_key_name.is_none()),
+                ] {
+                    if missing {
+                        problems.push(format!(
+                            "{} is needed to send updates with --dns-provider=rfc2136.",
+                            flag
+                        ));
+                    }
+                }
+            }
+            DnsProvider::HetznerDns | DnsProvider::PowerDns | DnsProvider::Cloudflare => {
+                let provider = self
+                    .dns_provider
+                    .to_possible_value()
+                    .map(|value| value.get_name().to_string())
+                    .unwrap_or_default();
+                let credentials: &[(&str, bool)] = match self.dns_provider {
+                    DnsProvider::HetznerDns => &[(
+                        "--hetzner-dns-api-token",
+                        self.hetzner_dns_api_token
+                            .as_ref()
+                            .map_or(true, |api_token| api_token.trim().is_empty()),
+                    )],
+                    DnsProvider::Cloudflare => &[(
+                        "--cloudflare-api-token",
+                        self.cloudflare_api_token
+                            .as_ref()
+                            .map_or(true, |api_token| api_token.trim().is_empty()),
+                    )],
+                    _ => &[
+                        ("--powerdns-api-url", self.powerdns_api_url.is_none()),
+                        (
+                            "--powerdns-api-key",
+                            self.powerdns_api_key
+                                .as_ref()
+                                .map_or(true, |api_key| api_key.trim().is_empty()),
+                        ),
+                    ],
+                };
+                for (flag, missing) in credentials {
+                    if *missing {
+                        problems.push(format!(
+                            "{} is needed with --dns-provider={}.",
+                            flag, provider
+                        ));
+                    }
+                }
+                for (flag, set) in [
+                    ("--owner-id", self.owner_id.is_some()),
+                    ("--reverse-zone-name", self.reverse_zone_name.is_some()),
+                    ("--update-zone", self.update_zone.is_some()),
+                ] {
+                    if set {
+                        problems.push(format!("{} only works with --dns-provider=rfc2136.", flag));
+                    }
+                }
+                if self.verify && self.verify_resolver.is_empty() {
+                    problems.push(
+                        format!("--verify needs --verify-resolver with --dns-provider={}, since there's no --server-address to ask.", provider),
+                    );
+                }
+            }
+        }
+
+        if self.hcloud_api_token.trim().is_empty() {
+            problems.push("The Hetzner HCloud API token is empty.".to_string());
+        }
+
+        for (flag, affix) in [
+            ("record prefix", &self.record_prefix),
+            ("record suffix", &self.record_suffix),
+        ] {
+            if !affix
+                .iter()
+                .flat_map(|affix| affix.chars())
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                problems.push(format!(
+                    "The {} '{}' can only have letters, digits, \"-\" and \"_\".",
+                    flag,
+                    affix.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+
+        if let Some(template) = &self.record_name_template {
+            if let Err(e) = fill_template(template, |variable| {
+                (RECORD_NAME_TEMPLATE_VARIABLES.contains(&variable)
+                    || variable.starts_with("label."))
+                .then(String::new)
+            }) {
+                problems.push(format!("The record name template is invalid. {}", e));
+            }
+        }
+
+        if let Some(network_subdomain) = &self.network_subdomain {
+            if !is_valid_dns_name(network_subdomain) {
+                problems.push(format!(
+                    "The network subdomain '{}' isn't a valid DNS name. It needs labels of up to {} letters, digits, \"-\" and \"_\", separated by dots.",
+                    network_subdomain, MAX_LABEL_LENGTH
+                ));
+            }
+        }
+
+        let mut static_record_names = HashSet::new();
+        for static_record in &self.static_record {
+            if !is_valid_dns_name(&static_record.name) {
+                problems.push(format!(
+                    "The name of the static record '{}' isn't a valid DNS name.",
+                    static_record.name
+                ));
+            }
+            if !static_record_names.insert(static_record.name.to_ascii_lowercase()) {
+                problems.push(format!(
+                    "There's more than one static record named '{}'.",
+                    static_record.name
+                ));
+            }
+        }
+
+        let mut project_names = HashSet::new();
+        for project in &self.hcloud_project {
+            if !project_names.insert(&project.name) {
+                problems.push(format!(
+                    "The Hetzner project '{}' is passed more than once.",
+                    project.name
+                ));
+            }
+            match read_hcloud_api_token(&project.api_token_file) {
+                Ok(api_token) if api_token.is_empty() => problems.push(format!(
+                    "The API token of the Hetzner project '{}' at {} is empty.",
+                    project.name,
+                    project.api_token_file.display()
+                )),
+                Ok(_) => (),
+                Err(e) => problems.push(e.to_string()),
+            }
+        }
+
+        if self.private_network_name.trim().is_empty() {
+            problems.push("The private network name is empty.".to_string());
+        }
+
+        if self.zone_name.trim_end_matches('.').is_empty() {
+            problems.push("The zone name is empty.".to_string());
+        } else if !is_valid_dns_name(self.zone_name.trim_end_matches('.')) {
+            problems.push(format!(
+                "The zone name '{}' isn't a valid DNS name. It needs labels of up to {} letters, digits, \"-\" and \"_\", separated by dots.",
+                self.zone_name, MAX_LABEL_LENGTH
+            ));
+        }
+
+        if let Some(public_zone_name) = &self.public_zone_name {
+            if !is_valid_dns_name(public_zone_name.trim_end_matches('.')) {
+                problems.push(format!(
+                    "The public zone name '{}' isn't a valid DNS name. It needs labels of up to {} letters, digits, \"-\" and \"_\", separated by dots.",
+                    public_zone_name, MAX_LABEL_LENGTH
+                ));
+            } else if normalise_name(public_zone_name) == normalise_name(&self.zone_name) {
+                problems.push(
+                    "The public zone name is the same as the zone name, so servers would get their private and public records on the same name.".to_string(),
+                );
+            }
+        }
+
+        if let Some(reverse_zone_name) = &self.reverse_zone_name {
+            let reverse_zone_name = normalise_name(reverse_zone_name);
+            if !is_valid_dns_name(&reverse_zone_name)
+                || !(reverse_zone_name == "in-addr.arpa"
+                    || reverse_zone_name.ends_with(".in-addr.arpa"))
+            {
+                problems.push(format!(
+                    "The reverse zone name '{}' isn't a zone under in-addr.arpa.",
+                    reverse_zone_name
+                ));
+            }
+        }
+
+        if let Some(reverse_tsig_key_path) = &self.reverse_tsig_key_path {
+            if !reverse_tsig_key_path.is_file() {
+                problems.push(format!(
+                    "The reverse zone's TSIG key at {} doesn't exist or isn't a file.",
+                    reverse_tsig_key_path.display()
+                ));
+            }
+        }
+
+        if self
+            .zone_per_label
+            .as_ref()
+            .is_some_and(|label| label.trim().is_empty())
+        {
+            problems.push("The label used to pick zones per server is empty.".to_string());
+        }
+
+        if let Some(update_zone) = &self.update_zone {
+            let zone_name = normalise_name(&self.zone_name);
+            let update_zone = normalise_name(update_zone);
+            if !is_valid_dns_name(&update_zone) {
+                problems.push(format!(
+                    "The update zone '{}' isn't a valid DNS name.",
+                    update_zone
+                ));
+            } else if zone_name != update_zone && !zone_name.ends_with(&format!(".{}", update_zone))
+            {
+                problems.push(format!(
+                    "The zone name {} isn't in the update zone {}, so its records can't be updated there.",
+                    zone_name, update_zone
+                ));
+            }
+        }
+
+        for subnet in &self.publish_subnet {
+            if subnet.trunc() != *subnet {
+                problems.push(format!(
+                    "The subnet to publish {} has host bits set. Did you mean {}?",
+                    subnet,
+                    subnet.trunc()
+                ));
+            }
+        }
+
+        if (self.no_state || matches!(self.command, Some(Command::Cleanup)))
+            && self
+                .cleanup_prefix
+                .as_deref()
+                .unwrap_or_default()
+                .is_empty()
+        {
+            problems.push(
+                "The cleanup subcommand and --no-state need a non-empty --cleanup-prefix, so they know which records are managed."
+                    .to_string(),
+            );
+        }
+
+        if self.state_save_batch_size == 0 {
+            problems.push("The state save batch size must be at least 1.".to_string());
+        }
+
+        if !(1..=MAX_TSIG_FUDGE).contains(&self.tsig_fudge) {
+            problems.push(format!(
+                "The TSIG fudge must be between 1 and {} seconds.",
+                MAX_TSIG_FUDGE
+            ));
+        }
+
+        if self.verify && self.verify_timeout == 0 {
+            problems.push("The verify timeout must be at least 1 second.".to_string());
+        }
+
+        if self.dns_update_rate_limit == Some(0) {
+            problems.push("The DNS update rate limit must be at least 1 per second.".to_string());
+        }
+
+        if self.dns_op_timeout == Some(0) {
+            problems.push("The DNS operation timeout must be at least 1 second.".to_string());
+        }
+
+        if self.run_timeout == Some(0) {
+            problems.push("The run timeout must be at least 1 second.".to_string());
+        }
+
+        if self
+            .record_comment
+            .as_ref()
+            .is_some_and(|comment| comment.len() > 255)
+        {
+            problems.push("The record comment can be at most 255 characters.".to_string());
+        }
+
+        for metadata_field in &self.metadata_field {
+            let is_known = RECORD_NAME_TEMPLATE_VARIABLES.contains(&metadata_field.as_str())
+                || metadata_field
+                    .strip_prefix("label.")
+                    .is_some_and(|key| !key.is_empty());
+            if !is_known {
+                problems.push(format!(
+                    "The metadata field '{}' isn't one of {} or \"label.KEY\".",
+                    metadata_field,
+                    RECORD_NAME_TEMPLATE_VARIABLES
+                        .map(|variable| format!("\"{}\"", variable))
+                        .join(", ")
+                ));
+            }
+        }
+
+        if let Some(owner_id) = &self.owner_id {
+            if owner_id.trim().is_empty() {
+                problems.push("The owner ID is empty.".to_string());
+            } else if ownership_record_content(owner_id).len() > 255 {
+                problems.push(format!(
+                    "The owner ID can be at most {} characters.",
+                    255 - ownership_record_content("").len()
+                ));
+            }
+        }
+
+        if self.mass_delete_threshold > 100 {
+            problems.push(
+                "The mass delete threshold is a percentage, so it can be at most 100.".to_string(),
+            );
+        }
+
+        if self.max_records_per_name == Some(0) {
+            problems.push("The maximum number of records per name must be at least 1.".to_string());
+        }
+
+        if let Some(heartbeat_file) = &self.heartbeat_file {
+            match heartbeat_file.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => problems
+                    .push(format!(
+                        "The directory of the heartbeat file {} doesn't exist.",
+                        heartbeat_file.display()
+                    )),
+                _ => (),
+            }
+        }
+
+        if let Some(report_file) = &self.report_file {
+            match report_file.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => problems
+                    .push(format!(
+                        "The directory of the report file {} doesn't exist.",
+                        report_file.display()
+                    )),
+                _ => (),
+            }
+        }
+
+        if let Some(metrics_file) = &self.metrics_file {
+            match metrics_file.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => problems
+                    .push(format!(
+                        "The directory of the metrics file {} doesn't exist.",
+                        metrics_file.display()
+                    )),
+                _ => (),
+            }
+        }
+
+        match &self.state_directory {
+            _ if self.no_state => (),
+            // A missing directory gets created when the state is opened.
+            Some(state_directory) if state_directory.exists() && !state_directory.is_dir() => {
+                problems.push(format!(
+                    "The state directory {} isn't a directory.",
+                    state_directory.display()
+                ))
+            }
+            Some(_) => (),
+            None => problems
+                .push("A state directory is needed unless --no-state is passed.".to_string()),
+        }
+
+        problems
+    }
+}
+
+/// When the state gets written to disk while a sync is running.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum StateSaveMode {
+    /// After every change to a record.
+    #[default]
+    Each,
+    /// After every --state-save-batch-size changes to records.
+    Batch,
+    /// Only once the sync is done (or is interrupted).
+    End,
+}
+
+/// What happens to published records when the private network has no servers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnEmpty {
+    /// Leave the records and the state alone.
+    Keep,
+    /// Remove the records, like with any other server leaving the network.
+    #[default]
+    Remove,
+}
+
+/// What happens to a server whose name would give it an empty label.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnEmptyHostname {
+    /// Don't publish a record for the server.
+    #[default]
+    Skip,
+    /// Stop the sync with an error.
+    Error,
+}
+
+/// What happens to servers that end up with the same name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnDuplicateHostname {
+    /// Publish the records of all of them on the name.
+    #[default]
+    RoundRobin,
+    /// Give each of them its own name, with its ID at the end of the first label.
+    Suffix,
+    /// Stop the sync with an error.
+    Error,
+}
+
+/// What happens to a server whose name isn't a valid DNS name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnInvalidHostname {
+    /// Publish the record with the name as it is.
+    #[default]
+    Keep,
+    /// Turn the name into a valid one.
+    Sanitize,
+    /// Don't publish a record for the server.
+    Skip,
+    /// Stop the sync with an error.
+    Error,
+}
+
+/// Where the address of the AAAA record of a server comes from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Ipv6Source {
+    /// Don't publish AAAA records.
+    #[default]
+    None,
+    /// The first address of the server's public IPv6 network.
+    Public,
+}
+
+/// A type of record a server can be limited to, with --record-types or its dns-record-types label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ServerRecordType {
+    A,
+    Aaaa,
+    Ptr,
+}
+
+/// Where the alias IPs of a server get their A records.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum AliasIpRecords {
+    /// Don't publish alias IPs.
+    #[default]
+    None,
+    /// On the server's name, next to its main IP.
+    SameName,
+    /// On a name of their own for each of them.
+    Numbered,
+}
+
+/// How the CLI reports what it's doing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Log everything of interest in a human-readable form.
+    #[default]
+    Human,
+    /// Only log warnings and errors, and print a JSON summary at the end of a sync.
+    Json,
+    /// Only log errors.
+    Quiet,
+}
+
+/// Forms `export` can print records in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// Zone file resource records.
+    #[default]
+    Zonefile,
+    /// A JSON array of servers.
+    Json,
+    /// CSV with a header row.
+    Csv,
+}
+
+/// Where the records get written to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum DnsProvider {
+    /// Dynamic updates (RFC 2136) to the DNS servers at --server-address.
+    #[default]
+    Rfc2136,
+    /// The Hetzner DNS API.
+    HetznerDns,
+    /// The HTTP API of PowerDNS Authoritative.
+    #[value(name = "powerdns")]
+    PowerDns,
+    /// The Cloudflare API.
+    Cloudflare,
+}
+
+/// HMAC algorithms a TSIG key can use. These are the ones both the DNS updater and the client we use for zone transfers and queries support.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TsigAlgorithm {
+    #[default]
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    fn for_dns_update(self) -> dns_update::TsigAlgorithm {
+        match self {
+            TsigAlgorithm::HmacSha256 => dns_update::TsigAlgorithm::HmacSha256,
+            TsigAlgorithm::HmacSha384 => dns_update::TsigAlgorithm::HmacSha384,
+            TsigAlgorithm::HmacSha512 => dns_update::TsigAlgorithm::HmacSha512,
+        }
+    }
+
+    fn for_hickory(self) -> hickory_client::rr::rdata::tsig::TsigAlgorithm {
+        match self {
+            TsigAlgorithm::HmacSha256 => hickory_client::rr::rdata::tsig::TsigAlgorithm::HmacSha256,
+            TsigAlgorithm::HmacSha384 => hickory_client::rr::rdata::tsig::TsigAlgorithm::HmacSha384,
+            TsigAlgorithm::HmacSha512 => hickory_client::rr::rdata::tsig::TsigAlgorithm::HmacSha512,
+        }
+    }
+}
+
+/// What happens when more than one network has the name we're looking for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnAmbiguousNetwork {
+    /// Stop with an error.
+    #[default]
+    Error,
+    /// Use the first network the Hetzner API returned.
+    First,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Server {
+    pub id: i64,
+    pub ip_address: String,
+    /// Address of the AAAA record published next to the A record, with --ipv6-source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6_address: Option<String>,
+    /// Address of the A record published in the --public-zone-name, which is kept in `public_zone`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_ip_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_zone: Option<String>,
+    /// SRV records pointing at the server, from its dns-srv label.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<Service>,
+    /// Names of the groups from the server's dns-group label, relative to its zone. Each of them has an A record with the server's address on it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// Content of the TXT record with the --metadata-field of the server, on its name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+    /// Whether the server also gets a wildcard record on "*.<name>".
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub wildcard: bool,
+    /// Whether other servers have their records on the same name, with --on-duplicate-hostname=round-robin. The server's records are then removed one by one, so the others keep theirs.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shared_name: bool,
+    /// Whether "a" was left out of the server's record types, so its names get the AAAA record instead of the A record.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_a_record: bool,
+    /// Whether "ptr" was left out of the server's record types.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_ptr_record: bool,
+    pub hostname: String,
+    #[serde(default)]
+    pub zone: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cname: Option<String>,
+    // States written before the TTL was configurable don't record it, but their records all got the default.
+    #[serde(default = "default_ttl")]
+    pub ttl: u32,
+    /// Name of the --hcloud-project the server is in, or nothing for the project of --hcloud-api-token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    /// ID of the private network the server's address is in, which is the network of its project. Records we synced before the state kept track of it don't have one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_id: Option<i64>,
+}
+
+const DEFAULT_TTL: u32 = 600;
+
+fn default_ttl() -> u32 {
+    DEFAULT_TTL
+}
+
+impl Server {
+    pub fn fqdn(&self) -> String {
+        format!("{}.{}", self.hostname, self.zone)
+    }
+
+    /// Whether `current` still has the records we synced for this server. It doesn't if it moved to another network, but a record we synced without knowing its network could be from either.
+    pub(crate) fn same_records_as(&self, current: &Server) -> bool {
+        match self.network_id {
+            Some(_) => self == current,
+            None => {
+                *self
+                    == Server {
+                        network_id: None,
+                        ..current.clone()
+                    }
+            }
+        }
+    }
+
+    /// Name of the record in the --public-zone-name, if the server gets one.
+    pub fn public_fqdn(&self) -> Option<String> {
+        self.public_record()
+            .map(|(zone, _)| format!("{}.{}", self.hostname, zone))
+    }
+
+    fn public_record(&self) -> Option<(&str, &str)> {
+        match (&self.public_zone, &self.public_ip_address, &self.cname) {
+            (Some(zone), Some(ip_address), None) => Some((zone, ip_address)),
+            _ => None,
+        }
+    }
+
+    /// Type and data of the address record on the server's names: the A record, or the AAAA record without "a" in its record types.
+    fn address_record(&self) -> (&'static str, &str) {
+        match (&self.ipv6_address, self.no_a_record) {
+            (Some(ipv6_address), true) => ("AAAA", ipv6_address),
+            _ => ("A", &self.ip_address),
+        }
+    }
+
+    /// Describes the record we publish for this server, in the same way it'd show up in a zone file.
+    pub fn record_description(&self) -> String {
+        let description = match (&self.cname, &self.ipv6_address) {
+            (Some(target), _) => format!("{} CNAME {}", self.ttl, target),
+            (None, Some(ipv6_address)) if self.no_a_record => {
+                format!("{} AAAA {}", self.ttl, ipv6_address)
+            }
+            (None, Some(ipv6_address)) => format!(
+                "{} A {} + {} AAAA {}",
+                self.ttl, self.ip_address, self.ttl, ipv6_address
+            ),
+            (None, None) => format!("{} A {}", self.ttl, self.ip_address),
+        };
+
+        let description = match self.wildcard_fqdn() {
+            Some(wildcard_fqdn) => format!("{}, and the same on {}", description, wildcard_fqdn),
+            None => description,
+        };
+        let mut description = match (self.public_fqdn(), &self.public_ip_address) {
+            (Some(public_fqdn), Some(public_ip_address)) => format!(
+                "{}, and {} A {} on {}",
+                description, self.ttl, public_ip_address, public_fqdn
+            ),
+            _ => description,
+        };
+        let (address_type, address) = self.address_record();
+        for group_fqdn in self.group_fqdns() {
+            description.push_str(&format!(
+                ", and {} {} {} on {}",
+                self.ttl, address_type, address, group_fqdn
+            ));
+        }
+        if let Some(metadata) = &self.metadata {
+            description.push_str(&format!(", and {} TXT \"{}\"", self.ttl, metadata));
+        }
+        for service in &self.services {
+            description.push_str(&format!(
+                ", and {} SRV 0 0 {} {}. on {}",
+                self.ttl,
+                service.port,
+                self.fqdn().trim_end_matches('.'),
+                self.service_fqdn(service)
+            ));
+        }
+
+        description
+    }
+
+    /// Name of the wildcard record, if the server gets one.
+    pub fn wildcard_fqdn(&self) -> Option<String> {
+        self.wildcard.then(|| format!("*.{}", self.fqdn()))
+    }
+
+    fn group_fqdns(&self) -> impl Iterator<Item = String> + '_ {
+        self.groups
+            .iter()
+            .map(|group| format!("{}.{}", group, self.zone))
+    }
+
+    fn service_fqdn(&self, service: &Service) -> String {
+        format!("{}.{}", service.name, self.zone)
+    }
+
+    /// Whether the data of a record, in the same form as in a zone file, is what we publish for this server.
+    fn matches_record_data(&self, data: &str) -> bool {
+        match &self.cname {
+            Some(target) => normalise_name(data) == normalise_name(target),
+            None => data == self.address_record().1,
+        }
+    }
+
+    /// Builds the record we publish for this server. `DnsRecord` isn't `Clone`, so this is called every time we need one.
+    fn record(&self) -> Result<DnsRecord> {
+        if let Some(target) = &self.cname {
+            return Ok(DnsRecord::CNAME {
+                content: target.clone(),
+            });
+        }
+
+        if self.no_a_record {
+            return self.aaaa_record()?.ok_or_else(|| {
+                Error::Address(format!(
+                    "server with id {} only gets an AAAA record, but it doesn't have an IPv6 address.",
+                    self.id
+                ))
+            });
+        }
+
+        let server_ip_parsed = parse_address(self.id, "IP address", &self.ip_address)?;
+
+        Ok(DnsRecord::A {
+            content: server_ip_parsed,
+        })
+    }
+
+    /// Builds the AAAA record we publish for this server next to its A record, if it gets one. Without the A record, the AAAA record is what `record()` builds.
+    fn ipv6_record(&self) -> Result<Option<DnsRecord>> {
+        match self.no_a_record {
+            true => Ok(None),
+            false => self.aaaa_record(),
+        }
+    }
+
+    /// Builds an AAAA record with the server's IPv6 address, if it has one.
+    fn aaaa_record(&self) -> Result<Option<DnsRecord>> {
+        let Some(ipv6_address) = self.ipv6_address.as_ref().filter(|_| self.cname.is_none()) else {
+            return Ok(None);
+        };
+
+        let ipv6_address_parsed = parse_address(self.id, "IPv6 address", ipv6_address)?;
+
+        Ok(Some(DnsRecord::AAAA {
+            content: ipv6_address_parsed,
+        }))
+    }
+}
+
+/// Parses one of the addresses of a server, which is called `kind` in the error.
+pub(crate) fn parse_address<T>(server_id: i64, kind: &str, address: &str) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    address.parse().map_err(|e| {
+        Error::Address(format!(
+            "server with id {} has an invalid {} '{}'. {}",
+            server_id, kind, address, e
+        ))
+    })
+}
+
+/// A service from the dns-srv label of a server. It gets an SRV record on "<name>.<zone>", with the server's name as the target.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Service {
+    /// Like "_postgres._tcp".
+    pub name: String,
+    pub port: u16,
+}
+
+impl Service {
+    fn parse_label(server_id: i64, label_value: &str) -> Result<Vec<Self>> {
+        let invalid = || {
+            Error::HCloud(format!(
+                "Server with id {} has '{}' in its {} label, but services must be written as \"_service._proto:port\" or \"service.proto.port\"!",
+                server_id, label_value, SRV_LABEL
+            ))
+        };
+        let service = |service: &str, proto: &str, port: &str| {
+            let service = service.strip_prefix('_').unwrap_or(service);
+            let proto = proto.strip_prefix('_').unwrap_or(proto);
+            match port.parse::<u16>() {
+                Ok(port) if port > 0 && is_valid_dns_name(service) && is_valid_dns_name(proto) => {
+                    Ok(Self {
+                        name: format!("_{}._{}", service, proto).to_ascii_lowercase(),
+                        port,
+                    })
+                }
+                _ => Err(invalid()),
+            }
+        };
+
+        let mut services = Vec::new();
+        for entry in label_value.split(',') {
+            if let Some((name, port)) = entry.split_once(':') {
+                let (service_name, proto) = name.split_once('.').ok_or_else(invalid)?;
+                services.push(service(service_name, proto, port)?);
+                continue;
+            }
+
+            let parts: Vec<&str> = entry.split('.').collect();
+            if parts.len() % 3 != 0 {
+                return Err(invalid());
+            }
+            for chunk in parts.chunks(3) {
+                services.push(service(chunk[0], chunk[1], chunk[2])?);
+            }
+        }
+
+        Ok(services)
+    }
+}
+
+/// Whether `name` matches `pattern`, where "*" in the pattern matches any number of characters and "?" matches a single one.
+fn matches_glob(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Where to go back to if what follows the last "*" doesn't match: the position after the "*" in the pattern, and the position in the name it started matching at.
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    backtrack = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Lowercases a name and strips its trailing dot, so names can be compared with each other.
+fn normalise_name(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// Whether `name` is made of labels with only letters, digits, "-" (but not at their start or end) and "_". Names that need IDNA must be passed already encoded.
+fn is_valid_dns_name(name: &str) -> bool {
+    name.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= MAX_LABEL_LENGTH
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+/// The longest name DNS can carry is 255 octets on the wire, which is 253 characters in text form once the length prefixes and the root label are accounted for. Each label can have at most 63 octets.
+const MAX_FQDN_LENGTH: usize = 253;
+
+const MAX_LABEL_LENGTH: usize = 63;
+
+fn unix_time_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_error_exits_with_the_code_in_the_help() {
+        for (error, exit_code, help) in [
+            (
+                Error::Config(String::new()),
+                3,
+                "Problem with the configuration",
+            ),
+            (
+                Error::HCloudAuth(String::new()),
+                4,
+                "The Hetzner API rejected the API token",
+            ),
+            (
+                Error::HCloud(String::new()),
+                5,
+                "Other failure talking to the Hetzner API",
+            ),
+            (
+                Error::Dns(String::new()),
+                6,
+                "Failure talking to the DNS servers",
+            ),
+            (
+                Error::State(String::new()),
+                7,
+                "Problem reading or writing the state",
+            ),
+            (
+                Error::Address(String::new()),
+                11,
+                "A server has an address that isn't a valid IP address",
+            ),
+        ] {
+            assert_eq!(error.exit_code(), exit_code, "{:?}", error);
+            assert!(
+                EXIT_CODES_HELP
+                    .lines()
+                    .any(|line| line == format!("  {:<2} {}", exit_code, help)),
+                "{:?} isn't in the help with code {}",
+                error,
+                exit_code
+            );
+        }
+    }
+
+    #[test]
+    fn failures_come_back_as_the_error_for_what_failed() {
+        let mut args = testing::args(&[]);
+        args.state_directory = None;
+        assert!(matches!(
+            state::open_state(&args, false),
+            Err(Error::Config(_))
+        ));
+
+        let state_directory = testing::temp_dir("error-kinds");
+        std::fs::write(state_directory.join("state.json"), "not json").unwrap();
+        args.state_directory = Some(state_directory.clone());
+        assert!(matches!(
+            state::open_state(&args, true),
+            Err(Error::State(_))
+        ));
+        std::fs::remove_dir_all(state_directory).unwrap();
+
+        let mut server = testing::hcloud_server(1, "web", "10.0.0.2");
+        server
+            .labels
+            .insert(TTL_LABEL.to_string(), "soon".to_string());
+        assert!(matches!(
+            cloud::server_from_hcloud(server, "10.0.0.2".to_string(), &args),
+            Err(Error::HCloud(_))
+        ));
+    }
+
+    #[test]
+    fn validate_reports_every_problem_at_once() {
+        let mut args = testing::args(&[
+            "--record-prefix",
+            "web.",
+            "--record-name-template",
+            "{hostname}-{nope}",
+        ]);
+        args.server_address = vec!["ftp://127.0.0.1:53".to_string()];
+        args.zone_name = "bad name".to_string();
+
+        assert_eq!(
+            args.validate(),
+            [
+                "The DNS server address 'ftp://127.0.0.1:53' isn't in the format \"tcp|udp://ip:port\".",
+                "The TSIG key at /dev/null is empty.",
+                "The record prefix 'web.' can only have letters, digits, \"-\" and \"_\".",
+                "The record name template is invalid. there's no \"{nope}\" to fill in.",
+                "The zone name 'bad name' isn't a valid DNS name. It needs labels of up to 63 letters, digits, \"-\" and \"_\", separated by dots.",
+            ]
+        );
+    }
+
+    #[test]
+    fn the_tsig_fudge_has_to_be_between_a_second_and_an_hour() {
+        let mut args = testing::args(&["--tsig-fudge", "3600"]);
+        assert!(!args
+            .validate()
+            .iter()
+            .any(|problem| problem.contains("fudge")));
+
+        for fudge in [0, 3601] {
+            args.tsig_fudge = fudge;
+            assert!(
+                args.validate()
+                    .contains(&"The TSIG fudge must be between 1 and 3600 seconds.".to_string()),
+                "{}",
+                fudge
+            );
+        }
+    }
+
+    #[test]
+    fn servers_with_an_invalid_address_exit_with_their_own_code() {
+        // Like an address a --transform-command made up.
+        let server = Server {
+            id: 1,
+            ip_address: "10.0.0.300".to_string(),
+            hostname: "web".to_string(),
+            zone: "example.com".to_string(),
+            ..Default::default()
+        };
+        let Err(error @ Error::Address(_)) = server.record() else {
+            panic!("the address should have been rejected");
+        };
+        assert_eq!(error.exit_code(), 11);
+        assert!(error.to_string().contains("'10.0.0.300'"), "{}", error);
+
+        assert!(matches!(
+            cloud::first_address_of(1, "2001:db8::/nope"),
+            Err(Error::Address(_))
+        ));
+    }
+}
@@ -1,398 +1,932 @@
-use std::{
-    collections::HashSet,
-    fmt::Debug,
-    io::Seek,
-    ops::{Deref, DerefMut},
-    path::PathBuf,
-};
+use std::{io::IsTerminal, path::PathBuf, process::ExitCode};
 
-use anyhow::anyhow;
-use clap::Parser;
-use dns_update::DnsUpdater;
-use hcloud::{
-    apis::{
-        configuration::Configuration,
-        networks_api::{self, ListNetworksParams},
-        servers_api::{self, GetServerParams},
-    },
-    models::Network,
+use clap::{CommandFactory, Parser};
+use futures_util::FutureExt;
+use hetzner_private_dns_sync::{
+    Args, Command, Error, Job, OutputFormat, Plan, SyncReport, Syncer, DRIFT_EXIT_CODE,
+};
+use tracing::Instrument;
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
 };
-use serde::{Deserialize, Serialize};
-
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    /// Path to the raw TSIG key.
-    #[arg(long)]
-    tsig_key_path: PathBuf,
-
-    /// Name of the TSIG key.
-    #[arg(long)]
-    tsig_key_name: String,
-
-    /// Address of the DNS server in the format "tcp|udp://ip:port".
-    #[arg(long)]
-    server_address: String,
-
-    /// Hetzner HCloud API token.
-    #[arg(long, env = "HCLOUD_API_TOKEN")]
-    hcloud_api_token: String,
-
-    /// Name of the private network in the Hetzner account.
-    #[arg(long)]
-    private_network_name: String,
-
-    /// Directory to keep state in.
-    #[arg(long, env = "STATE_DIRECTORY")]
-    state_directory: PathBuf,
-
-    /// DNS zone name.
-    #[arg(long)]
-    zone_name: String,
-
-    /// If the private network name changes between invocations, this software will remove all DNS entries it previously created to clean up its state, and then start with a new state for the new network name. This flag indicates an acknowledgement of this behaviour. If not passed (or false), the software will exit with an error instead of cleaning things up.
-    #[arg(long)]
-    allow_private_network_change: bool,
+
+/// What we exit with when a signal stopped us in the middle of something, which can leave a sync partially done.
+const INTERRUPTED_EXIT_CODE: u8 = 8;
+
+/// A signal asked us to stop before we finished.
+#[derive(Debug, thiserror::Error)]
+#[error("Received {0}, stopped before finishing.")]
+struct Interrupted(&'static str);
+
+/// What we exit with when --run-timeout ran out in the middle of something. Just like with a signal, a sync may be partially done.
+const TIMED_OUT_EXIT_CODE: u8 = 9;
+
+/// What we exit with when a sync finished, but gave up on the records of some servers after --dns-op-timeout.
+const SERVERS_FAILED_EXIT_CODE: u8 = 10;
+
+/// --run-timeout ran out before we finished.
+#[derive(Debug, thiserror::Error)]
+#[error("Didn't finish within {0} seconds, stopped before finishing.")]
+struct TimedOut(u64);
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let result = run().await.and_then(|exit_code| {
+        // The state is written one last time when it's dropped, which has no way to return the error.
+        if exit_code == ExitCode::SUCCESS && hetzner_private_dns_sync::state_save_failed() {
+            Err(Error::State(
+                "the state couldn't be written at the end of the run, the changes since it was last written are lost.".to_string(),
+            )
+            .into())
+        } else {
+            Ok(exit_code)
+        }
+    });
+
+    match result {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+
+            if e.downcast_ref::<Interrupted>().is_some() {
+                ExitCode::from(INTERRUPTED_EXIT_CODE)
+            } else if e.downcast_ref::<TimedOut>().is_some() {
+                ExitCode::from(TIMED_OUT_EXIT_CODE)
+            } else if let Some(e) = e.downcast_ref::<Error>() {
+                ExitCode::from(e.exit_code())
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
-struct StateWrapper {
-    file: std::fs::File,
-    data: State,
+/// Just the subcommands that print documentation, so it can be printed without the arguments a sync needs (e.g. while packaging).
+#[derive(Parser)]
+#[command(name = env!("CARGO_BIN_NAME"))]
+enum DocumentationArgs {
+    Completions { shell: clap_complete::Shell },
+    Man,
 }
 
-impl StateWrapper {
-    fn from_directory(dir: PathBuf) -> anyhow::Result<Self> {
-        let state_path = dir.join("state.json");
-        let state_file = std::fs::File::options()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(state_path)?;
-
-        let state_data = if state_file.metadata()?.len() == 0 {
-            State::default()
-        } else {
-            serde_json::from_reader(&state_file)?
-        };
+fn print_completions(shell: clap_complete::Shell) {
+    clap_complete::generate(
+        shell,
+        &mut Args::command(),
+        env!("CARGO_BIN_NAME"),
+        &mut std::io::stdout(),
+    );
+}
+
+fn print_man_page() -> anyhow::Result<()> {
+    clap_mangen::Man::new(Args::command()).render(&mut std::io::stdout())?;
+    Ok(())
+}
+
+async fn run() -> anyhow::Result<ExitCode> {
+    match DocumentationArgs::try_parse() {
+        Ok(DocumentationArgs::Completions { shell }) => {
+            print_completions(shell);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Ok(DocumentationArgs::Man) => {
+            print_man_page()?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Err(_) => (),
+    }
+
+    let jobs = Args::parse_with_config()?;
+    if jobs.is_empty() {
+        return Err(
+            Error::Config("The config file doesn't have any jobs to run.".to_string()).into(),
+        );
+    }
+
+    // Logs go to stderr, so they never get mixed with what subcommands print to stdout. What gets logged can't differ between jobs, so the first job picks it.
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(log_filter(
+            &jobs[0].args,
+            std::env::var("RUST_LOG").ok().as_deref(),
+        )?)
+        .init();
+    tracing::info!("hetzner-private-dns-sync has initialising logging.");
+
+    let mut problems = Vec::new();
+    for job in &jobs {
+        for problem in job.args.validate() {
+            tracing::error!(
+                job = job.name.as_deref(),
+                problem,
+                "Found a problem with the configuration."
+            );
+            problems.push(problem);
+        }
+    }
 
-        Ok(Self {
-            file: state_file,
-            data: state_data,
-        })
+    if !problems.is_empty() {
+        return Err(Error::Config(format!(
+            "Found {} problem(s) with the configuration!",
+            problems.len()
+        ))
+        .into());
     }
 
-    fn save(&mut self) -> anyhow::Result<()> {
-        self.file.seek(std::io::SeekFrom::Start(0))?;
-        serde_json::to_writer(&self.file, &self.data)?;
+    let run_timeout = jobs[0].args.run_timeout;
+    let command = run_jobs(jobs);
 
-        Ok(())
+    // Whatever is running gets dropped if we get a signal or run out of time, which writes the state with everything done until then.
+    tokio::select! {
+        result = command => result,
+        signal = shutdown_signal() => Err(Interrupted(signal?).into()),
+        _ = deadline(run_timeout) => Err(TimedOut(run_timeout.unwrap_or_default()).into()),
     }
 }
 
-impl Drop for StateWrapper {
-    fn drop(&mut self) {
-        self.save().unwrap();
+/// Runs the subcommand of a single job.
+async fn run_command(args: Args) -> anyhow::Result<ExitCode> {
+    match args.command {
+        Some(Command::ValidateConfig) => {
+            let problems = args.deployment_problems();
+            for problem in &problems {
+                tracing::error!(problem, "Found a problem with the configuration.");
+            }
+            if !problems.is_empty() {
+                return Err(Error::Config(format!(
+                    "Found {} problem(s) with the configuration!",
+                    problems.len()
+                ))
+                .into());
+            }
+            tracing::info!("The configuration is valid.");
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Cleanup) => cleanup(args).await,
+        Some(Command::Diff) => diff(args).await,
+        Some(Command::Check) => check(args).await,
+        Some(Command::NetworkInfo) => network_info(args).await,
+        Some(Command::Status) => status(args),
+        Some(Command::Completions { shell }) => {
+            print_completions(shell);
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Man) => {
+            print_man_page()?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Export { format }) => {
+            print!("{}", hetzner_private_dns_sync::export_state(&args, format)?);
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Adopt) => {
+            let servers_adopted = hetzner_private_dns_sync::adopt(&args).await?;
+            tracing::info!(
+                servers_adopted = ?servers_adopted.iter().map(|s| s.id).collect::<Vec<_>>(),
+                "Adopted the existing records of servers into the state."
+            );
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Purge) => {
+            let servers_removed = hetzner_private_dns_sync::purge(&args).await?;
+            tracing::info!(
+                servers_removed = servers_removed.len(),
+                "Removed every record in the state, and emptied it."
+            );
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::DumpState) => {
+            println!("{}", hetzner_private_dns_sync::dump_state(&args)?);
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::LoadState { ref path }) => {
+            hetzner_private_dns_sync::load_state(&args, path)?;
+            tracing::info!("Done!");
+            Ok(ExitCode::SUCCESS)
+        }
+        None if args.dry_run => dry_run(args).await,
+        None => sync(args).await,
     }
 }
 
-impl Deref for StateWrapper {
-    type Target = State;
+/// Runs every job, one after the other or all at once with --parallel-jobs. A job failing doesn't stop the others, and the run ends up with the result of the first job that didn't succeed.
+async fn run_jobs(jobs: Vec<Job>) -> anyhow::Result<ExitCode> {
+    let parallel_jobs = jobs.first().is_some_and(|job| job.args.parallel_jobs);
+    let runs = jobs.into_iter().map(|job| {
+        let span = match &job.name {
+            Some(name) => tracing::info_span!("job", name),
+            None => tracing::Span::none(),
+        };
+        run_command(job.args)
+            .instrument(span)
+            .map(move |result| (job.name, result))
+    });
+
+    let results = if parallel_jobs {
+        futures_util::future::join_all(runs).await
+    } else {
+        let mut results = Vec::new();
+        for run in runs {
+            results.push(run.await);
+        }
+        results
+    };
+
+    let mut first_failure = None;
+    for (name, result) in results {
+        let failure = match (result, name) {
+            (Ok(exit_code), _) if exit_code == ExitCode::SUCCESS => continue,
+            (Ok(exit_code), _) => Ok(exit_code),
+            (Err(e), Some(name)) => Err(e.context(format!("The job '{}' failed.", name))),
+            (Err(e), None) => Err(e),
+        };
 
-    fn deref(&self) -> &Self::Target {
-        &self.data
+        match (&first_failure, failure) {
+            (None, failure) => first_failure = Some(failure),
+            (Some(_), Err(e)) => tracing::error!(error = ?e, "Another job failed too."),
+            (Some(_), Ok(_)) => (),
+        }
     }
+
+    first_failure.unwrap_or(Ok(ExitCode::SUCCESS))
 }
 
-impl DerefMut for StateWrapper {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.data
+/// Waits until --run-timeout runs out, or forever if it wasn't passed.
+async fn deadline(run_timeout: Option<u64>) {
+    match run_timeout {
+        Some(run_timeout) => tokio::time::sleep(std::time::Duration::from_secs(run_timeout)).await,
+        None => std::future::pending().await,
     }
 }
 
-#[derive(Debug, Default, Deserialize, Serialize)]
-struct State {
-    private_network_name: String,
-    servers_synced: Vec<Server>,
+/// Picks what gets logged. `rust_log` (the RUST_LOG we got) wins if it's set, otherwise --output-format picks a level that -v and -q move from.
+fn log_filter(args: &Args, rust_log: Option<&str>) -> anyhow::Result<Targets> {
+    if let Some(directives) = rust_log {
+        if !directives.trim().is_empty() {
+            return directives.parse().map_err(|e| {
+                Error::Config(format!("Unable to parse RUST_LOG '{}'. {}", directives, e)).into()
+            });
+        }
+    }
+
+    let levels = [
+        LevelFilter::OFF,
+        LevelFilter::ERROR,
+        LevelFilter::WARN,
+        LevelFilter::INFO,
+        LevelFilter::DEBUG,
+        LevelFilter::TRACE,
+    ];
+    let base_level = match args.output_format {
+        OutputFormat::Human => 3,
+        OutputFormat::Json => 2,
+        OutputFormat::Quiet => 1,
+    };
+    let level = (base_level + args.verbose as usize)
+        .saturating_sub(args.quiet as usize)
+        .min(levels.len() - 1);
+
+    Ok(Targets::new().with_default(levels[level]))
+}
+
+/// Waits until we're asked to stop, and returns the name of the signal we got.
+async fn shutdown_signal() -> anyhow::Result<&'static str> {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT").map_err(Into::into),
+        _ = sigterm.recv() => Ok("SIGTERM"),
+    }
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
-struct Server {
-    id: i64,
-    ip_address: String,
-    hostname: String,
+/// Removes records matching `--cleanup-prefix` that don't belong to any server currently in the private network.
+async fn cleanup(args: Args) -> anyhow::Result<ExitCode> {
+    hetzner_private_dns_sync::cleanup(&args).await?;
+    tracing::info!("Done!");
+    Ok(ExitCode::SUCCESS)
 }
 
-struct DnsUpdaterWrapper {
-    client: DnsUpdater,
-    zone_name: String,
+/// Prints what a sync would change, and nothing if it wouldn't, without changing anything. Exits with `DRIFT_EXIT_CODE` if there's anything to change.
+async fn diff(args: Args) -> anyhow::Result<ExitCode> {
+    let plan = hetzner_private_dns_sync::plan(&args).await?;
+
+    print!("{}", plan.listing(std::io::stdout().is_terminal()));
+    Ok(ExitCode::from(plan.exit_code()))
 }
 
-// `DnsUpdater` doesn't impl Debug, so we need this.
-impl Debug for DnsUpdaterWrapper {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "DnsUpdaterWrapper {{}}")
+/// Prints everything that disagrees between the private network, the state and the zones, without changing anything.
+async fn check(args: Args) -> anyhow::Result<ExitCode> {
+    let drift = hetzner_private_dns_sync::check(&args).await?;
+
+    print!("{}", drift.plan.listing(std::io::stdout().is_terminal()));
+    for record in &drift.records {
+        println!(
+            "! {} has {:?} in the zone, but the state says it should have {:?}",
+            record.fqdn, record.found, record.expected
+        );
+    }
+
+    if drift.is_empty() {
+        println!("Everything is in sync.");
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::from(DRIFT_EXIT_CODE))
     }
 }
 
-impl DnsUpdaterWrapper {
-    #[tracing::instrument]
-    fn new(
-        server_address: String,
-        key_name: String,
-        key_path: PathBuf,
-        zone_name: String,
-    ) -> anyhow::Result<Self> {
-        let tsig_key = std::fs::read(key_path)?;
-
-        let client = DnsUpdater::new_rfc2136_tsig(
-            server_address,
-            key_name,
-            tsig_key,
-            dns_update::TsigAlgorithm::HmacSha256,
-        )
-        .map_err(|e| anyhow!("unable to create a DNS updater client. {}", e))?;
+/// Prints what a sync would change, without changing anything.
+async fn dry_run(args: Args) -> anyhow::Result<ExitCode> {
+    print_plan(&hetzner_private_dns_sync::plan(&args).await?);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Prints what's in the state.
+fn status(args: Args) -> anyhow::Result<ExitCode> {
+    let status = hetzner_private_dns_sync::state_status(&args)?;
 
-        Ok(Self { client, zone_name })
+    match status.private_network_id {
+        Some(id) => println!("Network {} (id {})", status.private_network_name, id),
+        None => println!("Network {}", status.private_network_name),
     }
 
-    #[tracing::instrument]
-    async fn add_server(&self, server: &Server) -> anyhow::Result<()> {
-        tracing::debug!("Creating a DNS record for a server.");
+    match status.last_synced_at {
+        Some(last_synced_at) => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            println!(
+                "  Last successful sync: {} ({} seconds ago)",
+                last_synced_at,
+                now.saturating_sub(last_synced_at)
+            );
+        }
+        None => println!("  Last successful sync: unknown"),
+    }
 
-        let server_fqdn = format!("{}.{}", server.hostname, self.zone_name);
-        let server_ip_parsed = server.ip_address.parse()?;
+    println!("  Synced servers ({}):", status.servers_synced.len());
+    for server in &status.servers_synced {
+        let detached_since = status
+            .servers_pending_removal
+            .get(&server.id)
+            .map(|since| format!(", detached since {}", since))
+            .unwrap_or_default();
+        let project = server
+            .project
+            .as_ref()
+            .map(|project| format!(" in project {}", project))
+            .unwrap_or_default();
+        println!(
+            "    {} {} (server {}{}{})",
+            server.fqdn(),
+            server.record_description(),
+            server.id,
+            project,
+            detached_since
+        );
+    }
 
-        match self
-            .client
-            .create(
-                &server_fqdn,
-                dns_update::DnsRecord::A {
-                    content: server_ip_parsed,
-                },
-                600,
-                &self.zone_name,
-            )
-            .await
-        {
-            Ok(v) => Ok(v),
-            Err(dns_update::Error::Response(resp_text)) => {
-                tracing::warn!(resp_text, "Received a response error when trying to create a DNS record. We'll assume we got that because the record already exists, and will update it instead.");
-
-                self.client
-                    .update(
-                        &server_fqdn,
-                        dns_update::DnsRecord::A {
-                            content: server_ip_parsed,
-                        },
-                        600,
-                        &self.zone_name,
-                    )
-                    .await
-                    .map_err(|e| anyhow!("failed to update a DNS record. {}", e))
-            }
-            Err(e) => Err(anyhow!("failed to create a DNS record. {}", e)),
-        }?;
+    Ok(ExitCode::SUCCESS)
+}
 
-        Ok(())
+/// Prints the private network and where each server in it stands.
+async fn network_info(args: Args) -> anyhow::Result<ExitCode> {
+    let network = hetzner_private_dns_sync::network_info(&args).await?;
+
+    println!("Network {} (id {})", network.name, network.id);
+    println!("  IP range: {}", network.ip_range);
+
+    println!("  Subnets:");
+    for subnet in &network.subnets {
+        println!(
+            "    {} (gateway {}, zone {})",
+            subnet.ip_range.as_deref().unwrap_or("(no IP range)"),
+            subnet.gateway,
+            subnet.network_zone
+        );
     }
 
-    #[tracing::instrument]
-    async fn remove_server(&self, server: &Server) -> anyhow::Result<()> {
-        tracing::debug!("Deleting a DNS record for a server.");
+    println!("  Routes:");
+    for route in &network.routes {
+        println!("    {} via {}", route.destination, route.gateway);
+    }
 
-        self.client
-            .delete(
-                format!("{}.{}", server.hostname, self.zone_name),
-                &self.zone_name,
-            )
-            .await
-            .map_err(|e| anyhow!("failed to delete a DNS record. {}", e))?;
+    println!("  Servers:");
+    for server in &network.servers {
+        let Some(ip_address) = &server.ip_address else {
+            println!(
+                "    {} (id {}): no IP in the network yet",
+                server.name, server.id
+            );
+            continue;
+        };
 
-        Ok(())
+        println!(
+            "    {} (id {}): {} in subnet {}, {}",
+            server.name,
+            server.id,
+            ip_address,
+            server.subnet.as_deref().unwrap_or("(none)"),
+            if server.in_published_subnets {
+                "inside --publish-subnet"
+            } else {
+                "outside --publish-subnet"
+            }
+        );
     }
-}
 
-#[derive(Debug)]
-struct HCloudWrapper {
-    configuration: Configuration,
-    network_name: String,
+    Ok(ExitCode::SUCCESS)
+}
 
-    // Quick cache to avoid getting the network multiple times.
-    network_info: Option<Network>,
+/// Prints what a sync would change, or that there's nothing to change.
+fn print_plan(plan: &Plan) {
+    print!("{}", plan.listing(std::io::stdout().is_terminal()));
+    if plan.is_empty() {
+        println!("Everything is in sync.");
+    }
 }
 
-impl HCloudWrapper {
-    fn new(api_token: String, network_name: String) -> Self {
-        let mut configuration = Configuration::new();
-        configuration.bearer_access_token = Some(api_token);
+async fn sync(args: Args) -> anyhow::Result<ExitCode> {
+    let started_at = std::time::SystemTime::now();
+    let report_args = args.report_file.is_some().then(|| args.clone());
+    let metrics_file = args.metrics_file.clone();
+    let heartbeat_file = args.heartbeat_file.clone();
+    let ping_url = args.ping_url.clone();
+    let output_format = args.output_format;
+
+    if args.confirm && !args.assume_yes && !confirm_plan(&args).await? {
+        tracing::info!("The changes weren't confirmed, so we won't sync.");
+        return Ok(ExitCode::FAILURE);
+    }
 
-        Self {
-            configuration,
-            network_name,
+    let result = match Syncer::new(args).await {
+        Ok(mut syncer) => syncer.reconcile().await,
+        Err(e) => Err(e),
+    };
 
-            network_info: None,
-        }
+    if let Some(ping_url) = &ping_url {
+        ping_result(ping_url, &result).await;
+    }
+    if let Some(report_args) = &report_args {
+        write_report(report_args, &result, started_at);
+    }
+    if let Some(metrics_file) = &metrics_file {
+        hetzner_private_dns_sync::write_metrics_file(metrics_file);
     }
 
-    #[tracing::instrument(skip_all)]
-    async fn retrieve_network(&mut self) -> anyhow::Result<()> {
-        if self.network_info.is_some() {
-            return Ok(());
-        }
-
-        tracing::debug!("Networking info wasn't retrieved yet. Will do that now.");
+    if output_format == OutputFormat::Json {
+        let duration_seconds = started_at
+            .elapsed()
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        println!("{}", json_summary(&result, duration_seconds));
+    }
+    let report = result?;
 
-        let networks = networks_api::list_networks(
-            &self.configuration,
-            ListNetworksParams {
-                name: Some(self.network_name.clone()),
-                ..Default::default()
-            },
-        )
-        .await?;
+    if let Some(heartbeat_file) = &heartbeat_file {
+        write_heartbeat(heartbeat_file);
+    }
+    tracing::info!(summary = report.summary(), "Done!");
+    if report.servers_failed.is_empty() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::from(SERVERS_FAILED_EXIT_CODE))
+    }
+}
 
-        if networks.networks.is_empty() {
-            return Err(anyhow!(
-                "Private network with name '{}' not found on the Hetzner account!",
-                self.network_name
-            ));
-        }
+/// The document a sync prints with `--output-format json`.
+fn json_summary(
+    result: &hetzner_private_dns_sync::Result<SyncReport>,
+    duration_seconds: f64,
+) -> serde_json::Value {
+    match result {
+        Ok(report) => serde_json::json!({
+            "status": "ok",
+            "duration_seconds": duration_seconds,
+            "added": report.servers_added.len(),
+            "updated": report.servers_updated.len(),
+            "removed": report.servers_removed.len() + report.names_removed.len(),
+            "forgotten": report.servers_forgotten.len(),
+            "failed": report.servers_failed.len(),
+            "report": report,
+        }),
+        Err(e) => serde_json::json!({
+            "status": "failed",
+            "duration_seconds": duration_seconds,
+            "error": e.to_string(),
+        }),
+    }
+}
 
-        if networks.networks.len() > 1 {
-            tracing::warn!("More than one network retrieved from the Hetzner API! Will proceed with the first one.");
+/// Pings --ping-url with the summary of a sync that succeeded, and its "/fail" variant with the error of one that didn't.
+async fn ping_result(
+    ping_url: &reqwest::Url,
+    result: &hetzner_private_dns_sync::Result<SyncReport>,
+) {
+    match result {
+        Ok(report) => ping(ping_url.clone(), report.summary()).await,
+        Err(e) => {
+            let mut fail_url = ping_url.clone();
+            if let Ok(mut segments) = fail_url.path_segments_mut() {
+                segments.pop_if_empty().push("fail");
+            }
+            ping(fail_url, e.to_string()).await
         }
-
-        self.network_info = Some(networks.networks.first().unwrap().clone());
-        Ok(())
     }
+}
 
-    #[tracing::instrument(skip_all)]
-    async fn server_ids(&mut self) -> anyhow::Result<Vec<i64>> {
-        self.retrieve_network().await?;
-        Ok(self.network_info.as_ref().unwrap().servers.clone())
+/// Tells the monitor at --ping-url how the sync went. Like with the heartbeat file, a monitor we can't reach isn't a reason to fail the run.
+#[tracing::instrument(skip(body))]
+async fn ping(url: reqwest::Url, body: String) {
+    if let Err(e) = reqwest::Client::new()
+        .post(url)
+        .timeout(std::time::Duration::from_secs(10))
+        .body(body)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+    {
+        tracing::warn!(error = %e, "Unable to ping the monitor.");
     }
+}
 
-    #[tracing::instrument(skip_all)]
-    async fn hydrate_server_list(&mut self, server_ids: Vec<i64>) -> anyhow::Result<Vec<Server>> {
-        self.retrieve_network().await?;
+/// Writes the --report-file for a sync that started at `started_at` and just finished with `result`. Like the heartbeat, it's written next to its final path and renamed over it.
+#[tracing::instrument(skip_all)]
+fn write_report(
+    args: &Args,
+    result: &hetzner_private_dns_sync::Result<SyncReport>,
+    started_at: std::time::SystemTime,
+) {
+    let Some(report_file) = &args.report_file else {
+        return;
+    };
+
+    // Without a state, there's nothing to hash.
+    let state_sha256 = if args.no_state {
+        None
+    } else {
+        match hetzner_private_dns_sync::dump_state(args) {
+            Ok(state) => Some(
+                ring::digest::digest(&ring::digest::SHA256, state.as_bytes())
+                    .as_ref()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>(),
+            ),
+            Err(e) => {
+                tracing::warn!(error = %e, "Unable to read the state to hash it for the report.");
+                None
+            }
+        }
+    };
+
+    let (status, error, report) = match result {
+        Ok(report) => ("ok", None, Some(report)),
+        Err(e) => ("failed", Some(e.to_string()), None),
+    };
+    let report = serde_json::json!({
+        "inputs": {
+            "private_network_name": args.private_network_name,
+            "zone_name": args.zone_name,
+            "zone_per_label": args.zone_per_label,
+            "publish_subnet": args.publish_subnet.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "cleanup_prefix": args.cleanup_prefix,
+            "exclude_self": args.exclude_self,
+            "prune_only": args.prune_only,
+            "add_only": args.add_only,
+            "no_state": args.no_state,
+        },
+        "timings": {
+            "started_at": started_at
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            "duration_seconds": started_at.elapsed().map(|d| d.as_secs_f64()).unwrap_or_default(),
+        },
+        "status": status,
+        "error": error,
+        "report": report,
+        "state_sha256": state_sha256,
+    });
+
+    let mut temporary_file = report_file.clone().into_os_string();
+    temporary_file.push(".tmp");
+
+    // Same as with the heartbeat, the sync is done by now, so we only warn if we can't write the report.
+    if let Err(e) = std::fs::write(&temporary_file, format!("{:#}\n", report))
+        .and_then(|_| std::fs::rename(&temporary_file, report_file))
+    {
+        tracing::warn!(error = %e, "Unable to write the report file.");
+    }
+}
 
-        let network_id = self.network_info.as_ref().unwrap().id;
-        let mut hydrated_servers = Vec::with_capacity(server_ids.len());
+/// Prints what a sync would change and asks whether to go on with it. Fails if there's no terminal to ask on.
+async fn confirm_plan(args: &Args) -> anyhow::Result<bool> {
+    let plan = hetzner_private_dns_sync::plan(args).await?;
+    if plan.is_empty() {
+        return Ok(true);
+    }
 
-        for server_id in server_ids {
-            let server_info =
-                servers_api::get_server(&self.configuration, GetServerParams { id: server_id })
-                    .await?;
+    if !std::io::stdin().is_terminal() {
+        return Err(Error::Config(
+            "--confirm needs a terminal to ask on. Pass --assume-yes to sync without asking."
+                .to_string(),
+        )
+        .into());
+    }
 
-            if let Some(server_info) = server_info.server {
-                let current_server = Server {
-                    id: server_id,
-                    ip_address: server_info.private_net.iter().find(|n| n.network.is_some_and(|nid| nid == network_id)).and_then(|n| n.ip.clone()).ok_or_else(|| anyhow!("Server with id {} doesn't have a network with id {} attached to it!", server_id, network_id))?,
-                    hostname: server_info.name,
-                };
+    print_plan(&plan);
+    print!("Apply these changes? [y/N] ");
+    std::io::Write::flush(&mut std::io::stdout())?;
 
-                hydrated_servers.push(current_server);
-            } else {
-                return Err(anyhow!(
-                    "Couldn't get information for server with id {}!",
-                    server_id
-                ));
-            }
-        }
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
 
-        Ok(hydrated_servers)
+/// Records that a sync succeeded. The file is written next to its final path and then renamed over it, so whoever is watching it never reads a partial timestamp.
+#[tracing::instrument]
+fn write_heartbeat(heartbeat_file: &PathBuf) {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    let mut temporary_file = heartbeat_file.clone().into_os_string();
+    temporary_file.push(".tmp");
+
+    // The sync itself already succeeded, so failing to write the heartbeat isn't a reason to fail the run. Monitoring will notice the stale file anyway.
+    if let Err(e) = std::fs::write(&temporary_file, format!("{}\n", timestamp))
+        .and_then(|_| std::fs::rename(&temporary_file, heartbeat_file))
+    {
+        tracing::warn!(error = %e, "Unable to write the heartbeat file.");
     }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-    tracing::info!("hetzner-private-dns-sync has initialising logging.");
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    use hetzner_private_dns_sync::{Server, ServerUpdate};
+
+    use super::*;
+
+    /// The arguments of a sync of the "test" network into "example.com", plus `extra`.
+    fn args(extra: &[&str]) -> Args {
+        Args::parse_from(
+            [
+                "hetzner-private-dns-sync",
+                "--tsig-key-name",
+                "test",
+                "--tsig-key-path",
+                "/dev/null",
+                "--server-address",
+                "udp://127.0.0.1:53",
+                "--hcloud-api-token",
+                "test",
+                "--private-network-name",
+                "test",
+                "--zone-name",
+                "example.com",
+                "--state-directory",
+                "/nonexistent",
+            ]
+            .iter()
+            .chain(extra),
+        )
+    }
 
-    let args = Args::parse();
-
-    let dns_updater = DnsUpdaterWrapper::new(
-        args.server_address,
-        args.tsig_key_name,
-        args.tsig_key_path,
-        args.zone_name,
-    )?;
-    tracing::info!("DNS Updater initialised.");
-    let mut hcloud = HCloudWrapper::new(args.hcloud_api_token, args.private_network_name.clone());
-    let mut current_state = StateWrapper::from_directory(args.state_directory)?;
-    tracing::info!("Current state retrieved.");
-
-    if current_state.private_network_name != args.private_network_name {
-        if !current_state.servers_synced.is_empty() {
-            if !args.allow_private_network_change {
-                return Err(anyhow!("The private network name has changed, but the --allow-private-network-change flag was false! We'll exit with an error instead. If you expect the private network name to change and acknolwedge the behaviour of this software when that happens, pass the --allow-private-network-change flag to continue."));
-            }
+    fn heartbeat(heartbeat_file: &PathBuf) -> u64 {
+        std::fs::read_to_string(heartbeat_file)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_heartbeat_only_advances_after_a_sync_that_succeeded() {
+        let dir = std::env::temp_dir().join(format!(
+            "hetzner-private-dns-sync-{}-heartbeat",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let heartbeat_file = dir.join("heartbeat");
+        std::fs::write(&heartbeat_file, "0\n").unwrap();
+
+        // The state directory can't be made inside a file, so the sync fails before it gets anywhere.
+        let mut args = args(&["--heartbeat-file", heartbeat_file.to_str().unwrap()]);
+        args.state_directory = Some(heartbeat_file.join("state"));
+        let error = sync(args).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::State(_))));
+        assert_eq!(heartbeat(&heartbeat_file), 0);
+
+        write_heartbeat(&heartbeat_file);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!((now - 1..=now).contains(&heartbeat(&heartbeat_file)));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 
-            tracing::warn!("The private network name has changed and we got the flag acknowledging we'll clean up the state. Will do that now.");
-            for server_info in current_state.servers_synced.clone() {
-                tracing::debug!(?server_info, "Removing record for server.");
-                dns_updater.remove_server(&server_info).await?;
-                current_state
-                    .servers_synced
-                    .retain(|s| s.id != server_info.id);
-                current_state.save()?;
+    /// A monitor that answers every ping with `status`. Returns its URL and the pings it got so far, as the path and the body.
+    async fn monitor(status: u16) -> (reqwest::Url, Arc<Mutex<Vec<(String, String)>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/check", listener.local_addr().unwrap());
+        let pings = Arc::new(Mutex::new(Vec::new()));
+
+        let seen = pings.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = tokio::io::BufReader::new(stream);
+                let mut request_line = String::new();
+                stream.read_line(&mut request_line).await.unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).await.unwrap();
+                    let Some((name, value)) = header.trim_end().split_once(':') else {
+                        break;
+                    };
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                stream.read_exact(&mut body).await.unwrap();
+
+                let path = request_line.split_whitespace().nth(1).unwrap().to_string();
+                seen.lock()
+                    .unwrap()
+                    .push((path, String::from_utf8(body).unwrap()));
+                let response = format!(
+                    "HTTP/1.1 {} -\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                stream
+                    .get_mut()
+                    .write_all(response.as_bytes())
+                    .await
+                    .unwrap();
             }
+        });
 
-            // We removed all the previous servers, so we can switch the private network name now.
-            current_state.private_network_name = args.private_network_name;
-            current_state.save()?;
-        } else {
-            // We're in a new state, so we'll populate the network name.
-            current_state.private_network_name = args.private_network_name;
-            current_state.save()?;
-        }
+        (url.parse().unwrap(), pings)
     }
 
-    let server_ids_from_state: HashSet<i64> =
-        current_state.servers_synced.iter().map(|s| s.id).collect();
-    let current_servers: HashSet<i64> = hcloud.server_ids().await?.into_iter().collect();
-    let servers_to_add: Vec<i64> = current_servers
-        .difference(&server_ids_from_state)
-        .cloned()
-        .collect();
-    let servers_to_remove: Vec<i64> = server_ids_from_state
-        .difference(&current_servers)
-        .cloned()
-        .collect();
-
-    tracing::info!(
-        ?servers_to_add,
-        ?servers_to_remove,
-        "Finished determining which servers got added and removed, will start updating things."
-    );
+    #[tokio::test]
+    async fn the_monitor_is_pinged_with_how_the_sync_went() {
+        let (url, pings) = monitor(200).await;
 
-    let servers_to_add = hcloud.hydrate_server_list(servers_to_add).await?;
+        ping_result(&url, &Ok(SyncReport::default())).await;
+        ping_result(
+            &url,
+            &Err(Error::Dns("the DNS server refused the update.".to_string())),
+        )
+        .await;
+        let pings = std::mem::take(&mut *pings.lock().unwrap());
+        assert_eq!(pings.len(), 2);
+        assert_eq!(pings[0].0, "/check");
+        assert_eq!(pings[0].1, SyncReport::default().summary());
+        assert_eq!(pings[1].0, "/check/fail");
+        assert!(pings[1].1.contains("refused the update"), "{}", pings[1].1);
+
+        // Monitors that fail or can't be reached don't fail the sync.
+        let (url, pings) = monitor(500).await;
+        ping_result(&url, &Ok(SyncReport::default())).await;
+        assert_eq!(pings.lock().unwrap().len(), 1);
+        ping_result(
+            &"http://127.0.0.1:1/check".parse().unwrap(),
+            &Ok(SyncReport::default()),
+        )
+        .await;
+    }
 
-    if !servers_to_add.is_empty() {
-        for server_info in servers_to_add {
-            tracing::debug!(?server_info, "Adding record for server.");
-            dns_updater.add_server(&server_info).await?;
-            current_state.servers_synced.push(server_info);
-            current_state.save()?;
+    #[test]
+    fn the_output_format_picks_the_log_level_that_v_and_q_move_from() {
+        let level = |extra: &[&str]| log_filter(&args(extra), None).unwrap().default_level();
+
+        for (extra, expected) in [
+            (&[][..], LevelFilter::INFO),
+            (&["--output-format", "json"], LevelFilter::WARN),
+            (&["--output-format", "quiet"], LevelFilter::ERROR),
+            (&["-v"], LevelFilter::DEBUG),
+            (&["-vvvv"], LevelFilter::TRACE),
+            (&["--output-format", "json", "-q"], LevelFilter::ERROR),
+            (&["--output-format", "quiet", "-qq"], LevelFilter::OFF),
+        ] {
+            assert_eq!(level(extra), Some(expected), "with {:?}", extra);
         }
+
+        // RUST_LOG wins over everything else, unless it's empty.
+        let with_rust_log = |rust_log| {
+            log_filter(&args(&["-qq"]), Some(rust_log))
+                .unwrap()
+                .default_level()
+        };
+        assert_eq!(with_rust_log("debug"), Some(LevelFilter::DEBUG));
+        assert_eq!(with_rust_log(" "), Some(LevelFilter::ERROR));
+        assert!(log_filter(&args(&[]), Some("=nope=")).is_err());
     }
 
-    if !servers_to_remove.is_empty() {
-        for server_id in servers_to_remove {
-            let server_info = current_state
-                .servers_synced
-                .iter()
-                .find(|s| s.id == server_id)
-                .unwrap();
-            tracing::debug!(?server_info, "Removing record for server.");
-            dns_updater.remove_server(&server_info).await?;
-            let server_id = server_info.id;
-            current_state.servers_synced.retain(|s| s.id != server_id);
-            current_state.save()?;
+    #[test]
+    fn the_json_summary_has_the_counts_or_the_error() {
+        let summary = json_summary(&Ok(SyncReport::default()), 1.5);
+        assert_eq!(summary["status"], "ok");
+        assert_eq!(summary["duration_seconds"], 1.5);
+        for count in ["added", "updated", "removed", "forgotten", "failed"] {
+            assert_eq!(summary[count], 0, "{}", count);
         }
+        assert!(summary["report"].is_object());
+        assert!(summary.get("error").is_none());
+
+        let summary = json_summary(&Err(Error::Dns("the update was refused.".to_string())), 0.5);
+        assert_eq!(summary["status"], "failed");
+        assert!(summary["error"]
+            .as_str()
+            .unwrap()
+            .contains("the update was refused."));
+        assert!(summary.get("report").is_none());
     }
 
-    tracing::info!("Done!");
-    Ok(())
+    #[test]
+    fn the_report_file_describes_the_whole_run() {
+        let dir = std::env::temp_dir().join(format!(
+            "hetzner-private-dns-sync-{}-report",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("state.json"),
+            r#"{"version": 2, "private_network_name": "test", "scopes": []}"#,
+        )
+        .unwrap();
+        let report_file = dir.join("report.json");
+        let mut args = args(&["--report-file", report_file.to_str().unwrap()]);
+        args.state_directory = Some(dir.clone());
+
+        let server = |id, hostname: &str, ip_address: &str| Server {
+            id,
+            ip_address: ip_address.to_string(),
+            hostname: hostname.to_string(),
+            zone: "example.com".to_string(),
+            ..Default::default()
+        };
+        let report = SyncReport {
+            servers_added: vec![server(1, "web", "10.0.0.2")],
+            servers_updated: vec![ServerUpdate {
+                previous: server(2, "db", "10.0.0.3"),
+                current: server(2, "db", "10.0.0.8"),
+            }],
+            servers_removed: vec![server(3, "cache", "10.0.0.4")],
+            ..Default::default()
+        };
+        write_report(&args, &Ok(report), std::time::SystemTime::now());
+
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&report_file).unwrap()).unwrap();
+        assert_eq!(written["status"], "ok");
+        assert!(written["error"].is_null());
+        assert_eq!(written["inputs"]["private_network_name"], "test");
+        assert_eq!(written["inputs"]["zone_name"], "example.com");
+        assert!(written["timings"]["started_at"].as_u64().unwrap() > 0);
+        assert!(written["timings"]["duration_seconds"].is_number());
+        assert!(written["report"]["plan"].is_object());
+        assert_eq!(written["report"]["servers_added"][0]["hostname"], "web");
+        assert_eq!(
+            written["report"]["servers_updated"][0]["current"]["ip_address"],
+            "10.0.0.8"
+        );
+        assert_eq!(written["report"]["servers_removed"][0]["hostname"], "cache");
+        let state = hetzner_private_dns_sync::dump_state(&args).unwrap();
+        let state_sha256: String = ring::digest::digest(&ring::digest::SHA256, state.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(written["state_sha256"], state_sha256);
+
+        write_report(
+            &args,
+            &Err(Error::Dns("the update was refused.".to_string())),
+            std::time::SystemTime::now(),
+        );
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&report_file).unwrap()).unwrap();
+        assert_eq!(written["status"], "failed");
+        assert!(written["error"]
+            .as_str()
+            .unwrap()
+            .contains("the update was refused."));
+        assert!(written["report"].is_null());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,151 @@
+//! Metrics of the run, which the --metrics-file has in the Prometheus text format, for the textfile collector of node_exporter.
+
+use std::{collections::BTreeMap, fmt::Write, path::Path, sync::Mutex};
+
+/// How long records took to resolve on each --verify-resolver after we published them, with --verify.
+pub(crate) static PROPAGATION_SECONDS: Histogram = Histogram::new(
+    "dns_sync_propagation_seconds",
+    "Seconds between publishing a record and it resolving on a --verify-resolver.",
+    &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0],
+);
+
+/// A Prometheus histogram with a "resolver" label.
+pub(crate) struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    // Upper bounds of the buckets, in increasing order. The "+Inf" bucket is implied.
+    buckets: &'static [f64],
+    observations: Mutex<BTreeMap<String, Observations>>,
+}
+
+/// What a histogram saw for one resolver.
+struct Observations {
+    // How many observations fell in each bucket, not cumulatively, with the last one for "+Inf".
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    const fn new(name: &'static str, help: &'static str, buckets: &'static [f64]) -> Self {
+        Self {
+            name,
+            help,
+            buckets,
+            observations: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub(crate) fn observe(&self, resolver: &str, value: f64) {
+        // A panic somewhere else while holding the lock doesn't make the observations any less valid.
+        let mut observations = self.observations.lock().unwrap_or_else(|e| e.into_inner());
+        let observations =
+            observations
+                .entry(resolver.to_string())
+                .or_insert_with(|| Observations {
+                    counts: vec![0; self.buckets.len() + 1],
+                    sum: 0.0,
+                    count: 0,
+                });
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.buckets.len());
+        observations.counts[bucket] += 1;
+        observations.sum += value;
+        observations.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        let observations = self.observations.lock().unwrap_or_else(|e| e.into_inner());
+        if observations.is_empty() {
+            return;
+        }
+
+        // Writing to a String can't fail.
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+        for (resolver, observations) in observations.iter() {
+            let bounds = self
+                .buckets
+                .iter()
+                .map(ToString::to_string)
+                .chain(["+Inf".to_string()]);
+            let mut cumulative = 0;
+            for (bound, bucket_count) in bounds.zip(&observations.counts) {
+                cumulative += bucket_count;
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{resolver=\"{}\",le=\"{}\"}} {}",
+                    self.name, resolver, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_sum{{resolver=\"{}\"}} {}",
+                self.name, resolver, observations.sum
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{{resolver=\"{}\"}} {}",
+                self.name, resolver, observations.count
+            );
+        }
+    }
+}
+
+/// Every metric with observations so far, in the Prometheus text format.
+pub fn render_metrics() -> String {
+    let mut out = String::new();
+    PROPAGATION_SECONDS.render(&mut out);
+    out
+}
+
+/// Writes the --metrics-file. Like the report, it's written next to its final path and renamed over it, so the collector never reads half of it.
+pub fn write_metrics_file(metrics_file: &Path) {
+    let mut temporary_file = metrics_file.to_path_buf().into_os_string();
+    temporary_file.push(".tmp");
+
+    // The sync is done by now, so we only warn if we can't write the metrics.
+    if let Err(e) = std::fs::write(&temporary_file, render_metrics())
+        .and_then(|_| std::fs::rename(&temporary_file, metrics_file))
+    {
+        tracing::warn!(error = %e, "Unable to write the metrics file.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_are_rendered_with_cumulative_buckets_per_resolver() {
+        let histogram = Histogram::new("test_seconds", "Test.", &[1.0, 5.0]);
+        let mut out = String::new();
+        histogram.render(&mut out);
+        assert_eq!(out, "");
+
+        histogram.observe("10.0.0.53:53", 0.5);
+        histogram.observe("10.0.0.53:53", 3.0);
+        histogram.observe("10.0.0.53:53", 7.5);
+        histogram.observe("10.0.0.54:53", 1.0);
+        histogram.render(&mut out);
+        assert_eq!(
+            out,
+            r#"# HELP test_seconds Test.
+# TYPE test_seconds histogram
+test_seconds_bucket{resolver="10.0.0.53:53",le="1"} 1
+test_seconds_bucket{resolver="10.0.0.53:53",le="5"} 2
+test_seconds_bucket{resolver="10.0.0.53:53",le="+Inf"} 3
+test_seconds_sum{resolver="10.0.0.53:53"} 11
+test_seconds_count{resolver="10.0.0.53:53"} 3
+test_seconds_bucket{resolver="10.0.0.54:53",le="1"} 1
+test_seconds_bucket{resolver="10.0.0.54:53",le="5"} 1
+test_seconds_bucket{resolver="10.0.0.54:53",le="+Inf"} 1
+test_seconds_sum{resolver="10.0.0.54:53"} 1
+test_seconds_count{resolver="10.0.0.54:53"} 1
+"#
+        );
+    }
+}
@@ -0,0 +1,1443 @@
+//! Working out which records the servers should have, and what has to change to get there.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::IpAddr,
+};
+
+use hickory_client::rr::RecordType;
+use ipnet::IpNet;
+use serde::Serialize;
+
+use crate::{
+    cloud::{
+        alias_ip_servers, alias_server, first_address_of, names_in_label, record_types_of,
+        self_server_id, server_from_hcloud, with_first_label_suffix, CloudBackend, HCloudProjects,
+    },
+    dns::{dns_updater, DnsBackend},
+    is_valid_dns_name, normalise_name, record_id,
+    state::{open_state, state_from_zones, zones_of},
+    unix_time_now, Args, Error, Ipv6Source, OnDuplicateHostname, OnEmpty, OnEmptyHostname,
+    OnInvalidHostname, Result, Server, ServerRecordType, ALIAS_LABEL, DNS_ENABLED_LABEL,
+    DNS_SYNC_LABEL, DRIFT_EXIT_CODE, MAX_FQDN_LENGTH, MAX_LABEL_LENGTH, WILDCARD_LABEL,
+};
+
+/// DNS servers reject names that are too long, sometimes without saying why, so we check the name a server would get before sending anything.
+pub(crate) fn check_fqdn_length(server: &Server) -> Result<()> {
+    let fqdn = server.fqdn();
+    let fqdn = fqdn.trim_end_matches('.');
+
+    if fqdn.len() > MAX_FQDN_LENGTH {
+        return Err(Error::Config(format!(
+            "Server with id {} would get a record on {}, which is {} characters long, but DNS names can have at most {}!",
+            server.id,
+            fqdn,
+            fqdn.len(),
+            MAX_FQDN_LENGTH
+        )));
+    }
+
+    if let Some(label) = fqdn.split('.').find(|l| l.len() > MAX_LABEL_LENGTH) {
+        return Err(Error::Config(format!(
+            "Server with id {} would get a record on {}, but its label '{}' is {} characters long, and DNS labels can have at most {}!",
+            server.id,
+            fqdn,
+            label,
+            label.len(),
+            MAX_LABEL_LENGTH
+        )));
+    }
+
+    Ok(())
+}
+
+fn servers_by_fqdn(servers: &[Server]) -> HashMap<String, Vec<&Server>> {
+    let mut servers_by_fqdn: HashMap<String, Vec<&Server>> = HashMap::new();
+    for server in servers {
+        servers_by_fqdn
+            .entry(server.fqdn().to_lowercase())
+            .or_default()
+            .push(server);
+    }
+
+    servers_by_fqdn
+}
+
+/// Servers with the same name in the same zone all get an A record on the same name. We refuse to continue if more of them than allowed would end up there.
+fn check_records_per_name(servers: &[Server], max_records_per_name: Option<usize>) -> Result<()> {
+    let Some(max_records_per_name) = max_records_per_name else {
+        return Ok(());
+    };
+
+    for (fqdn, servers) in servers_by_fqdn(servers) {
+        if servers.len() > max_records_per_name {
+            return Err(Error::Config(format!(
+                "Servers with ids {:?} would all get records on {}, but at most {} record(s) are allowed on a name!",
+                servers.iter().map(|s| s.id).collect::<Vec<_>>(),
+                fqdn,
+                max_records_per_name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies --on-duplicate-hostname to the servers that ended up on the same name as another server. Names with a CNAME are left to `check_cname_conflicts`.
+fn resolve_duplicate_hostnames(
+    servers: &mut [Server],
+    on_duplicate_hostname: OnDuplicateHostname,
+) -> Result<()> {
+    let duplicate_server_ids: Vec<(String, Vec<i64>)> = servers_by_fqdn(servers)
+        .into_iter()
+        .filter(|(_, servers)| servers.len() > 1 && servers.iter().all(|s| s.cname.is_none()))
+        .map(|(fqdn, servers)| (fqdn, servers.iter().map(|s| s.id).collect()))
+        .collect();
+
+    for (fqdn, server_ids) in duplicate_server_ids {
+        if on_duplicate_hostname == OnDuplicateHostname::Error {
+            return Err(Error::Config(format!(
+                "Servers with ids {:?} would all get records on {}! Rename them, or pick another --on-duplicate-hostname.",
+                server_ids, fqdn
+            )));
+        }
+
+        for server in servers.iter_mut().filter(|s| server_ids.contains(&s.id)) {
+            match on_duplicate_hostname {
+                OnDuplicateHostname::RoundRobin => server.shared_name = true,
+                OnDuplicateHostname::Suffix => {
+                    server.hostname =
+                        with_first_label_suffix(&server.hostname, &format!("-{}", server.id));
+                    tracing::info!(
+                        server_id = server.id,
+                        fqdn,
+                        hostname = server.hostname,
+                        "Server shares its name with other servers, so it gets its ID at the end of it."
+                    );
+                }
+                OnDuplicateHostname::Error => unreachable!(),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A name with a CNAME record can't have any other records, so we refuse to continue if a server wants a CNAME on a name another server also gets a record on.
+fn check_cname_conflicts(servers: &[Server]) -> Result<()> {
+    for (fqdn, servers) in servers_by_fqdn(servers) {
+        if servers.len() > 1 && servers.iter().any(|s| s.cname.is_some()) {
+            return Err(Error::Config(format!(
+                "Servers with ids {:?} would all get records on {}, but at least one of them wants a CNAME record there, and a CNAME can't coexist with other records!",
+                servers.iter().map(|s| s.id).collect::<Vec<_>>(),
+                fqdn
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn is_in_published_subnets(server: &Server, subnets: &[IpNet]) -> Result<bool> {
+    if subnets.is_empty() {
+        return Ok(true);
+    }
+
+    let server_ip: IpAddr = server.ip_address.parse().map_err(|e| {
+        Error::HCloud(format!(
+            "server with id {} has an invalid IP address '{}'. {}",
+            server.id, server.ip_address, e
+        ))
+    })?;
+
+    Ok(subnets.iter().any(|subnet| subnet.contains(&server_ip)))
+}
+
+/// An update to the record of a server we already synced.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerUpdate {
+    pub previous: Server,
+    pub current: Server,
+}
+
+/// What has to change in DNS so it matches the servers currently in the private network.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Plan {
+    pub servers_to_add: Vec<Server>,
+    pub servers_to_update: Vec<ServerUpdate>,
+    pub servers_to_remove: Vec<Server>,
+    /// Servers we synced before but shouldn't manage anymore. They're dropped from the state, but their records are left alone.
+    pub servers_to_forget: Vec<Server>,
+    /// Names found in the zones that no server owns anymore. Only used with --no-state, where there's no state to tell us which servers the records belonged to.
+    pub names_to_remove: Vec<StaleName>,
+}
+
+/// A managed name in a zone that doesn't belong to any server.
+#[derive(Clone, Debug, Serialize)]
+pub struct StaleName {
+    pub fqdn: String,
+    pub zone: String,
+}
+
+impl Plan {
+    pub(crate) fn new(servers_synced: &[Server], published: PublishedServers) -> Self {
+        let PublishedServers {
+            servers: current_servers,
+            attached_server_ids: _,
+            pending_server_ids,
+            ignored_servers,
+        } = published;
+
+        let current_server_ids: HashSet<i64> = current_servers.iter().map(|s| s.id).collect();
+        // The records --transform-command added for a pending server are left alone along with it. There can't be more of them than there are servers in the state.
+        let pending_server_ids: HashSet<i64> = pending_server_ids
+            .iter()
+            .flat_map(|&id| {
+                (0..=servers_synced.len()).map(move |position| transform_record_id(id, position))
+            })
+            .collect();
+        let ignored_server_ids: HashSet<i64> = ignored_servers.iter().map(|s| s.id).collect();
+        let (servers_to_forget, mut servers_to_remove): (Vec<Server>, Vec<Server>) = servers_synced
+            .iter()
+            .filter(|s| !current_server_ids.contains(&s.id) && !pending_server_ids.contains(&s.id))
+            .cloned()
+            .partition(|s| ignored_server_ids.contains(&s.id));
+
+        // Servers we already synced and which didn't change since then are left alone, so we don't touch the zone needlessly.
+        let mut servers_to_add = Vec::new();
+        let mut servers_to_update = Vec::new();
+        for server in current_servers {
+            match servers_synced.iter().find(|s| s.id == server.id) {
+                Some(previous) if previous.same_records_as(&server) => (),
+                Some(previous) => servers_to_update.push(ServerUpdate {
+                    previous: previous.clone(),
+                    current: server,
+                }),
+                None => servers_to_add.push(server),
+            }
+        }
+
+        // A server that got destroyed and recreated with the same name comes back with a new ID. Removing the old record deletes everything under the name, so doing that around adding the new one could leave no record at all. We move the name over to the new server instead.
+        servers_to_add.retain(|server| {
+            let Some(position) = servers_to_remove
+                .iter()
+                .position(|s| s.zone == server.zone && s.fqdn() == server.fqdn())
+            else {
+                return true;
+            };
+
+            let previous = servers_to_remove.remove(position);
+            tracing::info!(
+                previous_server_id = previous.id,
+                server_id = server.id,
+                fqdn = server.fqdn(),
+                "A new server took the name of one that's gone, will move the record over to it."
+            );
+            servers_to_update.push(ServerUpdate {
+                previous,
+                current: server.clone(),
+            });
+            false
+        });
+
+        Self {
+            servers_to_add,
+            servers_to_update,
+            servers_to_remove,
+            servers_to_forget,
+            names_to_remove: Vec::new(),
+        }
+    }
+
+    /// Holds back the removal of servers that were detached from the network until they've been detached for `grace_period` seconds, keeping track of since when each of them is gone in `pending_removal`. Servers that aren't detached anymore are dropped from `pending_removal`. Servers that are attached but got filtered out for other reasons are still removed right away.
+    pub(crate) fn hold_detached_servers(
+        &mut self,
+        pending_removal: &mut BTreeMap<i64, u64>,
+        attached_server_ids: &HashSet<i64>,
+        grace_period: u64,
+        now: u64,
+    ) {
+        let mut still_pending_removal = BTreeMap::new();
+        let mut servers_to_remove = Vec::with_capacity(self.servers_to_remove.len());
+        for server in self.servers_to_remove.drain(..) {
+            if attached_server_ids.contains(&server.id) {
+                servers_to_remove.push(server);
+                continue;
+            }
+
+            let detached_since = pending_removal.get(&server.id).copied().unwrap_or(now);
+            still_pending_removal.insert(server.id, detached_since);
+            if now.saturating_sub(detached_since) >= grace_period {
+                servers_to_remove.push(server);
+            } else {
+                tracing::info!(
+                    server_id = server.id,
+                    detached_since,
+                    "Server is detached from the private network, but will keep its record until the grace period is over."
+                );
+            }
+        }
+
+        self.servers_to_remove = servers_to_remove;
+        *pending_removal = still_pending_removal;
+    }
+
+    /// With `OnEmpty::Keep`, leaves every record alone if there's no server in the network at all.
+    pub(crate) fn apply_on_empty_policy(
+        &mut self,
+        on_empty: OnEmpty,
+        attached_server_ids: &HashSet<i64>,
+    ) {
+        if on_empty != OnEmpty::Keep
+            || !attached_server_ids.is_empty()
+            || (self.servers_to_remove.is_empty() && self.names_to_remove.is_empty())
+        {
+            return;
+        }
+
+        tracing::warn!(
+            servers_to_remove = ?self.servers_to_remove.iter().map(|s| s.id).collect::<Vec<_>>(),
+            names_to_remove = ?self.names_to_remove.iter().map(|n| &n.fqdn).collect::<Vec<_>>(),
+            "The private network has no servers, but we were told to keep the records when that happens. Won't remove anything."
+        );
+        self.servers_to_remove.clear();
+        self.names_to_remove.clear();
+    }
+
+    /// Drops the changes --prune-only, --add-only and --protect-name tell us not to make.
+    pub(crate) fn apply_change_filters(&mut self, args: &Args) {
+        if args.prune_only {
+            tracing::info!("Got the --prune-only flag, so we'll only remove records.");
+            self.servers_to_add.clear();
+            self.servers_to_update.clear();
+        }
+
+        if args.add_only {
+            tracing::info!("Got the --add-only flag, so we won't remove any records.");
+            self.servers_to_remove.clear();
+            self.names_to_remove.clear();
+        }
+
+        let protected = |fqdn: &str| {
+            let protected = args.is_protected_name(fqdn);
+            if protected {
+                tracing::warn!(
+                    fqdn,
+                    "The name is protected by --protect-name, so we'll leave its records alone."
+                );
+            }
+            protected
+        };
+        self.servers_to_add.retain(|s| !protected(&s.fqdn()));
+        self.servers_to_update
+            .retain(|u| !protected(&u.previous.fqdn()) && !protected(&u.current.fqdn()));
+        self.servers_to_remove.retain(|s| !protected(&s.fqdn()));
+        self.names_to_remove.retain(|n| !protected(&n.fqdn));
+    }
+
+    /// How many records this changes in DNS. Forgetting a server doesn't change its record, so it doesn't count.
+    pub fn change_count(&self) -> usize {
+        self.servers_to_add.len()
+            + self.servers_to_update.len()
+            + self.servers_to_remove.len()
+            + self.names_to_remove.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers_to_add.is_empty()
+            && self.servers_to_update.is_empty()
+            && self.servers_to_remove.is_empty()
+            && self.servers_to_forget.is_empty()
+            && self.names_to_remove.is_empty()
+    }
+
+    /// What a sync would change, a line for each record like in a diff, and nothing if there's nothing to change. `colored` paints the lines for a terminal.
+    pub fn listing(&self, colored: bool) -> String {
+        let mut listing = String::new();
+        let mut line = |color: &str, line: String| {
+            if colored {
+                listing.push_str(&format!("\x1b[{}m{}\x1b[0m\n", color, line));
+            } else {
+                listing.push_str(&line);
+                listing.push('\n');
+            }
+        };
+
+        for server in &self.servers_to_add {
+            line(
+                "32",
+                format!(
+                    "+ {} {} (server {})",
+                    server.fqdn(),
+                    server.record_description(),
+                    server.id
+                ),
+            );
+        }
+
+        for ServerUpdate { previous, current } in &self.servers_to_update {
+            line(
+                "33",
+                format!(
+                    "~ {} {} -> {} {} (server {})",
+                    previous.fqdn(),
+                    previous.record_description(),
+                    current.fqdn(),
+                    current.record_description(),
+                    current.id
+                ),
+            );
+        }
+
+        for server in &self.servers_to_remove {
+            line(
+                "31",
+                format!(
+                    "- {} {} (server {})",
+                    server.fqdn(),
+                    server.record_description(),
+                    server.id
+                ),
+            );
+        }
+
+        for server in &self.servers_to_forget {
+            line(
+                "36",
+                format!(
+                    "= {} {} (server {}, won't be managed anymore)",
+                    server.fqdn(),
+                    server.record_description(),
+                    server.id
+                ),
+            );
+        }
+
+        for stale_name in &self.names_to_remove {
+            line(
+                "31",
+                format!("- {} (no server owns it anymore)", stale_name.fqdn),
+            );
+        }
+
+        listing
+    }
+
+    /// What the diff subcommand exits with for this plan.
+    pub fn exit_code(&self) -> u8 {
+        match self.is_empty() {
+            true => 0,
+            false => DRIFT_EXIT_CODE,
+        }
+    }
+}
+
+/// Refuses to go on with a sync that would change more records than `--max-changes` allows.
+pub(crate) fn check_max_changes(changes: usize, max_changes: Option<usize>) -> Result<()> {
+    match max_changes {
+        Some(max_changes) if changes > max_changes => Err(Error::Config(format!(
+            "the sync would change {} records, but --max-changes only allows {}! Check that the Hetzner API and the state look right, and raise --max-changes if the changes are expected.",
+            changes, max_changes
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Refuses to go on with a sync that would remove more than `threshold` percent of the `synced` records.
+pub(crate) fn check_mass_delete(plan: &Plan, synced: usize, threshold: u8) -> Result<()> {
+    let removals = plan.servers_to_remove.len() + plan.names_to_remove.len();
+    if removals > 1 && removals * 100 > synced * threshold as usize {
+        return Err(Error::Config(format!(
+            "the sync would remove {} of the {} records we synced, which is more than --mass-delete-threshold allows ({}%)! Check that the private network looks right, and pass --allow-mass-delete if the removals are expected.",
+            removals, synced, threshold
+        )));
+    }
+
+    Ok(())
+}
+
+/// The servers currently in the private network that we want to have records for.
+pub(crate) struct PublishedServers {
+    pub(crate) servers: Vec<Server>,
+    // Every server attached to the network, regardless of whether we want a record for it.
+    pub(crate) attached_server_ids: HashSet<i64>,
+    // Servers attached to the network that don't have an IP in it yet. Whatever records they have are left alone until they get one.
+    pub(crate) pending_server_ids: HashSet<i64>,
+    // Servers in the network that we were told not to manage. Whatever records they have are left alone.
+    pub(crate) ignored_servers: Vec<Server>,
+}
+
+/// Gets the servers currently in the private network, and turns them into the servers we want to have records for.
+pub(crate) async fn published_servers(
+    hcloud: &mut impl CloudBackend,
+    args: &Args,
+    self_server_id: Option<i64>,
+) -> Result<PublishedServers> {
+    let current_server_ids = hcloud.server_ids().await?;
+    let mut attached_server_ids: HashSet<i64> = current_server_ids.iter().copied().collect();
+    let mut current_servers = Vec::with_capacity(current_server_ids.len());
+    let mut pending_server_ids = HashSet::new();
+    let mut ignored_servers = Vec::new();
+    let mut aliases_by_server_id = HashMap::new();
+    let mut alias_ips_by_server_id = HashMap::new();
+    for hcloud_server in hcloud.hydrate_server_list(current_server_ids).await? {
+        let aliases = names_in_label(&hcloud_server, ALIAS_LABEL)?;
+        let alias_ips = hcloud_server.alias_ips.clone();
+        // Unlike a disabled server, an ignored server keeps whatever records it has. If we synced it before, we just stop managing it.
+        if hcloud_server
+            .labels
+            .get(DNS_SYNC_LABEL)
+            .is_some_and(|v| v == "ignore")
+        {
+            tracing::debug!(
+                server_id = hcloud_server.id,
+                "Server is labelled to be ignored, so we'll leave its records alone."
+            );
+            let ip_address = hcloud_server.ip_address.clone().unwrap_or_default();
+            let server = server_from_hcloud(hcloud_server, ip_address, args)?;
+            for alias in &aliases {
+                ignored_servers.push(alias_server(&server, alias, args));
+            }
+            ignored_servers.extend(alias_ip_servers(&mut server.clone(), &alias_ips, args));
+            ignored_servers.push(server);
+            continue;
+        }
+
+        // A disabled server is treated like any other server we don't want a record for, so its record gets removed, and added back once the label is gone or set to something else.
+        if hcloud_server
+            .labels
+            .get(DNS_ENABLED_LABEL)
+            .is_some_and(|v| v == "false")
+        {
+            tracing::debug!(
+                server_id = hcloud_server.id,
+                "Server has DNS publishing disabled through its label, so it won't have a record."
+            );
+            continue;
+        }
+
+        if args.skip_non_ascii_hostnames && !hcloud_server.name.is_ascii() {
+            tracing::warn!(
+                server_id = hcloud_server.id,
+                name = hcloud_server.name,
+                "Server's name has non-ASCII characters, so it won't have a record."
+            );
+            continue;
+        }
+
+        match hcloud_server.ip_address.clone() {
+            Some(ip_address) => {
+                let ipv6_address = match args.ipv6_source {
+                    Ipv6Source::None => None,
+                    Ipv6Source::Public => hcloud_server
+                        .public_ipv6_network
+                        .as_deref()
+                        .map(|network| first_address_of(hcloud_server.id, network))
+                        .transpose()?,
+                };
+                let public_ip_address = hcloud_server.public_ip_address.clone();
+                let record_types = record_types_of(&hcloud_server, args)?;
+                let wildcard = match hcloud_server.labels.get(WILDCARD_LABEL).map(String::as_str) {
+                    Some("true") => true,
+                    Some("false") => false,
+                    _ => args.wildcard_records,
+                };
+                let mut server = server_from_hcloud(hcloud_server, ip_address, args)?;
+                aliases_by_server_id.insert(server.id, aliases);
+                alias_ips_by_server_id.insert(server.id, alias_ips);
+                server.wildcard = wildcard;
+                if server.cname.is_none() {
+                    server.ipv6_address =
+                        ipv6_address.filter(|_| record_types.contains(&ServerRecordType::Aaaa));
+                    server.no_a_record = !record_types.contains(&ServerRecordType::A);
+                    server.no_ptr_record = !record_types.contains(&ServerRecordType::Ptr);
+                    if let (Some(public_zone_name), Some(_)) =
+                        (&args.public_zone_name, &public_ip_address)
+                    {
+                        server.public_ip_address = public_ip_address;
+                        server.public_zone = Some(public_zone_name.clone());
+                    }
+                }
+                if server.no_a_record && server.ipv6_address.is_none() {
+                    tracing::warn!(
+                        server_id = server.id,
+                        "Server can only get an AAAA record but doesn't have an IPv6 address, so it won't have a record."
+                    );
+                    continue;
+                }
+                current_servers.push(server);
+            }
+            None => {
+                tracing::warn!(server_id = hcloud_server.id, "Server is attached to the private network but doesn't have an IP in it yet, probably because it's still being provisioned. Will skip it and try again next time.");
+                pending_server_ids.insert(hcloud_server.id);
+                // Without an IP we can't tell which zone the server ends up in, but it's where it was the last time, so we keep every alias it could have.
+                let server = server_from_hcloud(hcloud_server, String::new(), args)?;
+                pending_server_ids.extend(
+                    aliases
+                        .iter()
+                        .map(|alias| alias_server(&server, alias, args).id),
+                );
+                pending_server_ids.extend(
+                    alias_ip_servers(&mut server.clone(), &alias_ips, args)
+                        .iter()
+                        .map(|s| s.id),
+                );
+            }
+        }
+    }
+
+    let mut published_servers = Vec::with_capacity(current_servers.len());
+    for server_info in current_servers {
+        if self_server_id == Some(server_info.id) {
+            tracing::debug!(
+                ?server_info,
+                "Server is the one we're running on, and we were told to exclude it."
+            );
+            ignored_servers.push(server_info);
+        } else if is_in_published_subnets(&server_info, &args.publish_subnet)? {
+            let mut records = match &args.transform_command {
+                Some(transform_command) => {
+                    match transform_server(transform_command, server_info.clone()).await {
+                        Ok(records) => records,
+                        Err(e) => {
+                            // Like with a server that doesn't have an IP yet, whatever records it has are left alone, and the next sync tries again.
+                            tracing::warn!(server_id = server_info.id, error = %e, "Unable to transform the server, so its records stay as they are.");
+                            pending_server_ids.insert(server_info.id);
+                            pending_server_ids.extend(
+                                aliases_by_server_id
+                                    .get(&server_info.id)
+                                    .into_iter()
+                                    .flatten()
+                                    .map(|alias| alias_server(&server_info, alias, args).id),
+                            );
+                            pending_server_ids.extend(
+                                alias_ip_servers(
+                                    &mut server_info.clone(),
+                                    alias_ips_by_server_id
+                                        .get(&server_info.id)
+                                        .map_or(&[][..], Vec::as_slice),
+                                    args,
+                                )
+                                .iter()
+                                .map(|s| s.id),
+                            );
+                            continue;
+                        }
+                    }
+                }
+                None => vec![server_info],
+            };
+            let server_info = records.remove(0);
+
+            if !server_info.hostname.trim().is_empty() && !is_valid_dns_name(&server_info.hostname)
+            {
+                match args.on_invalid_hostname {
+                    OnInvalidHostname::Error => {
+                        return Err(Error::HCloud(format!(
+                            "Server with id {} would get a record on '{}', which isn't a valid DNS name!",
+                            server_info.id, server_info.hostname
+                        )))
+                    }
+                    OnInvalidHostname::Skip => {
+                        tracing::warn!(
+                            server_id = server_info.id,
+                            hostname = server_info.hostname,
+                            "Server's name isn't valid in DNS, so it won't have a record."
+                        );
+                        continue;
+                    }
+                    OnInvalidHostname::Keep | OnInvalidHostname::Sanitize => (),
+                }
+            }
+
+            if !server_info.hostname.trim().is_empty() {
+                let alias_servers: Vec<Server> = aliases_by_server_id
+                    .get(&server_info.id)
+                    .into_iter()
+                    .flatten()
+                    .map(|alias| alias_server(&server_info, alias, args))
+                    .collect();
+                attached_server_ids.extend(alias_servers.iter().map(|s| s.id));
+                attached_server_ids.extend(records.iter().map(|s| s.id));
+                published_servers.push(server_info);
+                published_servers.extend(alias_servers);
+                published_servers.extend(records);
+            } else if args.on_empty_hostname == OnEmptyHostname::Error {
+                return Err(Error::HCloud(format!(
+                    "Server with id {} has an empty name, so it can't get a record!",
+                    server_info.id
+                )));
+            } else {
+                tracing::warn!(
+                    server_id = server_info.id,
+                    "Server has an empty name, so it won't have a record."
+                );
+            }
+        } else {
+            tracing::debug!(
+                ?server_info,
+                "Server's IP isn't in any of the subnets to publish, so it won't have a record."
+            );
+        }
+    }
+
+    resolve_duplicate_hostnames(&mut published_servers, args.on_duplicate_hostname)?;
+
+    // Alias IPs go on the names the servers ended up with, so they're added after the duplicates are sorted out.
+    let mut alias_ip_servers_published = Vec::new();
+    for server in &mut published_servers {
+        if let Some(alias_ips) = alias_ips_by_server_id.get(&server.id) {
+            alias_ip_servers_published.extend(alias_ip_servers(server, alias_ips, args));
+        }
+    }
+    attached_server_ids.extend(alias_ip_servers_published.iter().map(|s| s.id));
+    published_servers.extend(alias_ip_servers_published);
+
+    // Static records don't go through any of the filters, they're published as they were given.
+    for static_record in &args.static_record {
+        let server = static_record.server(args);
+        attached_server_ids.insert(server.id);
+        published_servers.push(server);
+    }
+
+    check_cname_conflicts(&published_servers)?;
+    check_records_per_name(&published_servers, args.max_records_per_name)?;
+    Ok(PublishedServers {
+        servers: published_servers,
+        attached_server_ids,
+        pending_server_ids,
+        ignored_servers,
+    })
+}
+
+/// ID of the record in the given position of what `--transform-command` printed for a server. The first one is the server's own, and the others are kept in the state like servers of their own.
+fn transform_record_id(server_id: i64, position: usize) -> i64 {
+    match position {
+        0 => server_id,
+        position => record_id(&format!("transform {} {}", server_id, position)),
+    }
+}
+
+/// Runs the server through `--transform-command`, and returns the servers it printed back. There's at least one, and the first one is the server itself.
+#[tracing::instrument(skip(transform_command))]
+async fn transform_server(transform_command: &str, server: Server) -> Result<Vec<Server>> {
+    let input = serde_json::to_vec(&server).map_err(|e| {
+        Error::Config(format!(
+            "unable to serialise server with id {}. {}",
+            server.id, e
+        ))
+    })?;
+
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(transform_command)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::Config(format!("unable to run the transform command. {}", e)))?;
+
+    // Dropping stdin once it's written closes it, so the command knows there's nothing else coming.
+    let mut stdin = child.stdin.take().unwrap();
+    tokio::io::AsyncWriteExt::write_all(&mut stdin, &input)
+        .await
+        .map_err(|e| {
+            Error::Config(format!(
+                "unable to give server with id {} to the transform command. {}",
+                server.id, e
+            ))
+        })?;
+    drop(stdin);
+
+    let output = child.wait_with_output().await.map_err(|e| {
+        Error::Config(format!(
+            "the transform command failed for server with id {}. {}",
+            server.id, e
+        ))
+    })?;
+    if !output.status.success() {
+        return Err(Error::Config(format!(
+            "the transform command failed for server with id {}, it exited with {}.",
+            server.id, output.status
+        )));
+    }
+
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Transformed {
+        One(Box<Server>),
+        Many(Vec<Server>),
+    }
+    let mut transformed = match serde_json::from_slice(&output.stdout).map_err(|e| {
+        Error::Config(format!(
+            "the transform command printed something we can't parse for server with id {}. {}",
+            server.id, e
+        ))
+    })? {
+        Transformed::One(transformed) => vec![*transformed],
+        Transformed::Many(transformed) if transformed.is_empty() => {
+            return Err(Error::Config(format!(
+                "the transform command printed no records for server with id {}.",
+                server.id
+            )))
+        }
+        Transformed::Many(transformed) => transformed,
+    };
+
+    for (position, record) in transformed.iter_mut().enumerate() {
+        if record.id != server.id {
+            return Err(Error::Config(format!(
+                "the transform command changed the id of server with id {} to {}.",
+                server.id, record.id
+            )));
+        }
+        record.id = transform_record_id(server.id, position);
+        // The project is where the server is, not something we publish.
+        record.project = server.project.clone();
+        // Catch a bad IP now, instead of when we're halfway through updating the zone.
+        record.record()?;
+        // The first record goes through the same checks as any server's, but the others only exist because of the command.
+        if position > 0 && !is_valid_dns_name(&record.hostname) {
+            return Err(Error::Config(format!(
+                "the transform command printed a record on '{}' for server with id {}, which isn't a valid DNS name.",
+                record.hostname, server.id
+            )));
+        }
+    }
+
+    tracing::debug!(?transformed, "Transformed the server.");
+    Ok(transformed)
+}
+
+/// Works out what a sync would change, without changing anything.
+pub async fn plan(args: &Args) -> Result<Plan> {
+    plan_with(args, |cached_network_id| {
+        HCloudProjects::new(args, cached_network_id)
+    })
+    .await
+}
+
+/// `plan`, with the cloud `new_cloud` makes from the network ID remembered in the state. Tests put a fake one in.
+pub(crate) async fn plan_with<C: CloudBackend>(
+    args: &Args,
+    new_cloud: impl FnOnce(Option<i64>) -> Result<C>,
+) -> Result<Plan> {
+    let mut current_state = open_state(args, true)?;
+
+    let network_changed = current_state.private_network_name != args.private_network_name;
+    let mut hcloud = new_cloud(if network_changed {
+        None
+    } else {
+        current_state.private_network_id
+    })?;
+    let published = published_servers(&mut hcloud, args, self_server_id(args).await?).await?;
+    let attached_server_ids = published.attached_server_ids.clone();
+
+    if args.no_state {
+        let dns_updater = dns_updater(args).await?;
+        let (servers_synced, names_to_remove) =
+            state_from_zones(&dns_updater, args, &published).await?;
+        let mut plan = Plan::new(&servers_synced, published);
+        plan.names_to_remove = names_to_remove;
+        plan.apply_on_empty_policy(args.on_empty, &attached_server_ids);
+        plan.apply_change_filters(args);
+        return Ok(plan);
+    }
+
+    // If the network changed, a sync would first remove everything it synced before and then start over. That happens regardless of --add-only.
+    if network_changed {
+        let mut plan = Plan::new(&[], published);
+        plan.servers_to_remove = current_state.servers_synced.clone();
+        if args.prune_only {
+            plan.servers_to_add.clear();
+        }
+        return Ok(plan);
+    }
+
+    let mut plan = Plan::new(&current_state.servers_synced, published);
+    plan.apply_on_empty_policy(args.on_empty, &attached_server_ids);
+    if let Some(grace_period) = args.detach_grace_period {
+        // The state is read-only, so this only changes our copy of it.
+        plan.hold_detached_servers(
+            &mut current_state.servers_pending_removal,
+            &attached_server_ids,
+            grace_period,
+            unix_time_now(),
+        );
+    }
+    plan.apply_change_filters(args);
+
+    Ok(plan)
+}
+
+/// A name whose records in its zone aren't the ones the state says we published there.
+#[derive(Clone, Debug)]
+pub struct RecordDrift {
+    pub fqdn: String,
+    /// Data of the records we expect, in the same form as in a zone file.
+    pub expected: Vec<String>,
+    /// Data of the A and CNAME records actually in the zone.
+    pub found: Vec<String>,
+}
+
+/// Everything that disagrees between the private network, the state and the zones.
+#[derive(Debug, Default)]
+pub struct Drift {
+    /// What a sync would change to match the private network.
+    pub plan: Plan,
+    /// Names whose records were changed (or removed) behind our back. Not checked with --no-state, where the zones are the state.
+    pub records: Vec<RecordDrift>,
+}
+
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.plan.is_empty() && self.records.is_empty()
+    }
+}
+
+/// Finds everything that disagrees between the private network, the state and the zones, without changing anything.
+pub async fn check(args: &Args) -> Result<Drift> {
+    let plan = plan(args).await?;
+    if args.no_state {
+        return Ok(Drift {
+            plan,
+            records: Vec::new(),
+        });
+    }
+
+    let current_state = open_state(args, true)?;
+    let dns_updater = dns_updater(args).await?;
+
+    let mut expected_by_name: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+    for server in &current_state.servers_synced {
+        expected_by_name
+            .entry((normalise_name(&server.zone), normalise_name(&server.fqdn())))
+            .or_default()
+            .extend(match &server.cname {
+                Some(target) => vec![normalise_name(target)],
+                None => (!server.no_a_record)
+                    .then(|| server.ip_address.clone())
+                    .into_iter()
+                    .chain(server.ipv6_address.clone())
+                    .collect(),
+            });
+    }
+
+    let mut records = Vec::new();
+    for zone in zones_of(current_state.servers_synced.iter(), &args.zone_name) {
+        let zone = normalise_name(&zone);
+        let mut found_by_name: HashMap<String, Vec<String>> = HashMap::new();
+        for record in dns_updater.zone_records(&zone).await? {
+            let data = match record.record_type {
+                RecordType::A | RecordType::AAAA => record.data,
+                RecordType::CNAME => normalise_name(&record.data),
+                _ => continue,
+            };
+            found_by_name.entry(record.name).or_default().push(data);
+        }
+
+        for ((_, fqdn), expected) in expected_by_name.iter().filter(|((z, _), _)| *z == zone) {
+            let mut expected = expected.clone();
+            expected.sort();
+            expected.dedup();
+            let mut found = found_by_name.remove(fqdn).unwrap_or_default();
+            found.sort();
+            found.dedup();
+
+            if expected != found {
+                records.push(RecordDrift {
+                    fqdn: fqdn.clone(),
+                    expected,
+                    found,
+                });
+            }
+        }
+    }
+
+    Ok(Drift { plan, records })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{self, hcloud_server, FakeCloud},
+        RECORD_TYPES_LABEL,
+    };
+
+    fn web() -> Server {
+        Server {
+            id: 1,
+            ip_address: "10.0.0.2".to_string(),
+            hostname: "web".to_string(),
+            zone: "example.com".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn transform_server_fails_when_the_command_does() {
+        assert!(matches!(
+            transform_server("cat > /dev/null; exit 3", web()).await,
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            transform_server("cat > /dev/null; echo nope", web()).await,
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            transform_server("cat > /dev/null; echo '[]'", web()).await,
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn transform_server_accepts_several_records() {
+        let records = transform_server(
+            r#"read -r server; printf '[%s,%s]' "$server" "$(printf '%s' "$server" | sed 's/"hostname":"web"/"hostname":"web-admin"/')""#,
+            web(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], web());
+        assert_eq!(records[1].id, transform_record_id(1, 1));
+        assert_ne!(records[1].id, 1);
+        assert_eq!(records[1].hostname, "web-admin");
+    }
+
+    #[tokio::test]
+    async fn published_servers_only_get_the_record_types_they_are_limited_to() {
+        let mut ipv6_only = hcloud_server(1, "web", "10.0.0.2");
+        ipv6_only.public_ipv6_network = Some("2001:db8::/64".to_string());
+        ipv6_only
+            .labels
+            .insert(RECORD_TYPES_LABEL.to_string(), "aaaa.ptr".to_string());
+        let mut no_ipv6 = hcloud_server(2, "db", "10.0.0.3");
+        no_ipv6
+            .labels
+            .insert(RECORD_TYPES_LABEL.to_string(), "aaaa".to_string());
+        let mut ipv4_only = hcloud_server(3, "cache", "10.0.0.4");
+        ipv4_only.public_ipv6_network = Some("2001:db8:1::/64".to_string());
+        let mut hcloud = FakeCloud {
+            servers: vec![ipv6_only, no_ipv6, ipv4_only],
+        };
+        let args = testing::args(&["--ipv6-source", "public", "--record-types", "a,ptr"]);
+
+        let published = published_servers(&mut hcloud, &args, None).await.unwrap();
+        let descriptions: Vec<(i64, String, bool)> = published
+            .servers
+            .iter()
+            .map(|s| (s.id, s.record_description(), s.no_ptr_record))
+            .collect();
+        assert_eq!(
+            descriptions,
+            [
+                (1, "600 AAAA 2001:db8::1".to_string(), false),
+                (3, "600 A 10.0.0.4".to_string(), false),
+            ]
+        );
+
+        hcloud.servers[0]
+            .labels
+            .insert(RECORD_TYPES_LABEL.to_string(), "aaaa.mx".to_string());
+        assert!(matches!(
+            published_servers(&mut hcloud, &args, None).await,
+            Err(Error::HCloud(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn published_servers_keep_the_records_a_failed_transform_had() {
+        let mut hcloud = FakeCloud {
+            servers: vec![hcloud_server(1, "web", "10.0.0.2")],
+        };
+        let args = testing::args(&[
+            "--transform-command",
+            r#"read -r server; printf '[%s,%s]' "$server" "$(printf '%s' "$server" | sed 's/"hostname":"web"/"hostname":"web-admin"/')""#,
+        ]);
+        let published = published_servers(&mut hcloud, &args, None).await.unwrap();
+        assert!(published
+            .attached_server_ids
+            .contains(&transform_record_id(1, 1)));
+        let servers_synced = published.servers.clone();
+        assert_eq!(servers_synced.len(), 2);
+
+        // The command failing now leaves both records where they are.
+        let args = testing::args(&["--transform-command", "exit 1"]);
+        let published = published_servers(&mut hcloud, &args, None).await.unwrap();
+        assert!(published.servers.is_empty());
+        let plan = Plan::new(&servers_synced, published);
+        assert!(plan.is_empty());
+    }
+
+    #[tokio::test]
+    async fn alias_ip_records_stay_put_when_the_api_reorders_the_alias_ips() {
+        let mut web = hcloud_server(1, "web", "10.0.0.2");
+        web.alias_ips = vec!["10.0.0.10".to_string(), "10.0.0.9".to_string()];
+        let mut hcloud = FakeCloud { servers: vec![web] };
+
+        for alias_ip_records in ["same-name", "numbered"] {
+            let args = testing::args(&["--alias-ip-records", alias_ip_records]);
+            let published = published_servers(&mut hcloud, &args, None).await.unwrap();
+            let servers_synced = published.servers.clone();
+            assert_eq!(servers_synced.len(), 3);
+            if alias_ip_records == "numbered" {
+                let alias1 = servers_synced
+                    .iter()
+                    .find(|s| s.hostname == "web-alias1")
+                    .unwrap();
+                assert_eq!(alias1.ip_address, "10.0.0.9");
+            }
+
+            hcloud.servers[0].alias_ips.reverse();
+            let published = published_servers(&mut hcloud, &args, None).await.unwrap();
+            let plan = Plan::new(&servers_synced, published);
+            assert!(plan.is_empty(), "{alias_ip_records}");
+        }
+    }
+
+    #[tokio::test]
+    async fn servers_without_an_ip_yet_keep_their_records_until_they_get_one() {
+        let mut hcloud = FakeCloud {
+            servers: vec![hcloud_server(1, "web", "10.0.0.2")],
+        };
+        let args = testing::args(&[]);
+        let servers_synced = published_servers(&mut hcloud, &args, None)
+            .await
+            .unwrap()
+            .servers;
+
+        hcloud.servers[0].ip_address = None;
+        let published = published_servers(&mut hcloud, &args, None).await.unwrap();
+        assert!(published.servers.is_empty());
+        assert!(published.pending_server_ids.contains(&1));
+        assert!(Plan::new(&servers_synced, published).is_empty());
+    }
+
+    #[test]
+    fn detached_servers_are_removed_once_the_grace_period_is_over() {
+        let db = Server {
+            id: 2,
+            hostname: "db".to_string(),
+            ..web()
+        };
+        let removing = |servers: &[Server]| Plan {
+            servers_to_remove: servers.to_vec(),
+            ..Default::default()
+        };
+        let removed =
+            |plan: Plan| -> Vec<i64> { plan.servers_to_remove.iter().map(|s| s.id).collect() };
+        let mut pending_removal = BTreeMap::new();
+        // db is still attached, it just stopped being published.
+        let attached_server_ids = HashSet::from([2]);
+
+        let mut plan = removing(&[web(), db.clone()]);
+        plan.hold_detached_servers(&mut pending_removal, &attached_server_ids, 60, 1000);
+        assert_eq!(removed(plan), [2]);
+        assert_eq!(pending_removal, BTreeMap::from([(1, 1000)]));
+
+        let mut plan = removing(&[web()]);
+        plan.hold_detached_servers(&mut pending_removal, &attached_server_ids, 60, 1059);
+        assert!(removed(plan).is_empty());
+
+        let mut plan = removing(&[web()]);
+        plan.hold_detached_servers(&mut pending_removal, &attached_server_ids, 60, 1060);
+        assert_eq!(removed(plan), [1]);
+
+        // Coming back within the period starts it over the next time the server is detached.
+        let mut pending_removal = BTreeMap::from([(1, 1000)]);
+        let mut plan = removing(&[]);
+        plan.hold_detached_servers(&mut pending_removal, &HashSet::from([1]), 60, 1030);
+        assert!(pending_removal.is_empty());
+        let mut plan = removing(&[web()]);
+        plan.hold_detached_servers(&mut pending_removal, &HashSet::new(), 60, 1070);
+        assert!(removed(plan).is_empty());
+        assert_eq!(pending_removal, BTreeMap::from([(1, 1070)]));
+    }
+
+    #[test]
+    fn names_longer_than_dns_allows_are_rejected() {
+        // Three full labels and the dots between them take up 192 characters already.
+        let server_with_fqdn_length = |length: usize| Server {
+            hostname: "a".repeat(63),
+            zone: format!(
+                "{}.{}.{}",
+                "b".repeat(63),
+                "c".repeat(63),
+                "d".repeat(length - 192)
+            ),
+            ..web()
+        };
+
+        assert_eq!(server_with_fqdn_length(253).fqdn().len(), 253);
+        assert!(check_fqdn_length(&server_with_fqdn_length(253)).is_ok());
+        // A trailing dot doesn't count towards the limit.
+        let mut server = server_with_fqdn_length(253);
+        server.zone.push('.');
+        assert!(check_fqdn_length(&server).is_ok());
+
+        for length in [254, 255] {
+            let Err(Error::Config(message)) = check_fqdn_length(&server_with_fqdn_length(length))
+            else {
+                panic!("a name {} characters long was accepted", length);
+            };
+            assert!(message.contains("id 1"), "{}", message);
+            assert!(message.contains(&format!("{} characters long", length)));
+        }
+
+        let server = Server {
+            hostname: "a".repeat(64),
+            ..web()
+        };
+        assert!(matches!(check_fqdn_length(&server), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn names_with_more_records_than_allowed_are_rejected() {
+        let servers = [
+            web(),
+            Server {
+                id: 2,
+                ip_address: "10.0.0.3".to_string(),
+                hostname: "WEB".to_string(),
+                ..web()
+            },
+        ];
+
+        assert!(check_records_per_name(&servers, None).is_ok());
+        assert!(check_records_per_name(&servers, Some(2)).is_ok());
+        let Err(Error::Config(message)) = check_records_per_name(&servers, Some(1)) else {
+            panic!("two records on web.example.com were allowed");
+        };
+        assert!(message.contains("web.example.com"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn servers_with_an_empty_name_are_skipped_or_rejected() {
+        let mut hcloud = FakeCloud {
+            servers: vec![
+                hcloud_server(1, "web", "10.0.0.2"),
+                hcloud_server(2, "", "10.0.0.3"),
+            ],
+        };
+
+        let published = published_servers(&mut hcloud, &testing::args(&[]), None)
+            .await
+            .unwrap();
+        let ids: Vec<i64> = published.servers.iter().map(|s| s.id).collect();
+        assert_eq!(ids, [1]);
+
+        let args = testing::args(&["--on-empty-hostname", "error"]);
+        let Err(Error::HCloud(message)) = published_servers(&mut hcloud, &args, None).await else {
+            panic!("a server with an empty name was published");
+        };
+        assert!(message.contains("id 2"), "{}", message);
+
+        // Names that only become empty once they're sanitised count too.
+        hcloud.servers[1] = hcloud_server(2, "---", "10.0.0.3");
+        let args = testing::args(&["--on-invalid-hostname", "sanitize"]);
+        let published = published_servers(&mut hcloud, &args, None).await.unwrap();
+        let ids: Vec<i64> = published.servers.iter().map(|s| s.id).collect();
+        assert_eq!(ids, [1]);
+    }
+
+    #[test]
+    fn a_server_recreated_with_the_same_name_takes_over_its_record() {
+        let db = Server {
+            id: 2,
+            ip_address: "10.0.0.3".to_string(),
+            hostname: "db".to_string(),
+            ..web()
+        };
+        let recreated_web = Server {
+            id: 3,
+            ip_address: "10.0.0.5".to_string(),
+            ..web()
+        };
+        // Only the same name in the same zone is the same record.
+        let recreated_db = Server {
+            id: 4,
+            zone: "acme.example.com".to_string(),
+            ..db.clone()
+        };
+        let published = PublishedServers {
+            servers: vec![recreated_web, recreated_db],
+            attached_server_ids: HashSet::from([3, 4]),
+            pending_server_ids: HashSet::new(),
+            ignored_servers: Vec::new(),
+        };
+
+        let plan = Plan::new(&[web(), db], published);
+        let updates: Vec<(i64, i64)> = plan
+            .servers_to_update
+            .iter()
+            .map(|u| (u.previous.id, u.current.id))
+            .collect();
+        assert_eq!(updates, [(1, 3)]);
+        assert_eq!(
+            plan.servers_to_remove
+                .iter()
+                .map(|s| s.id)
+                .collect::<Vec<_>>(),
+            [2]
+        );
+        assert_eq!(
+            plan.servers_to_add.iter().map(|s| s.id).collect::<Vec<_>>(),
+            [4]
+        );
+    }
+
+    #[tokio::test]
+    async fn servers_on_the_same_name_follow_on_duplicate_hostname() {
+        let mut hcloud = FakeCloud {
+            servers: vec![
+                hcloud_server(1, "web", "10.0.0.2"),
+                hcloud_server(2, "web", "10.0.0.3"),
+                hcloud_server(3, "db", "10.0.0.4"),
+            ],
+        };
+
+        for (on_duplicate_hostname, expected) in [
+            (
+                "round-robin",
+                [
+                    ("web.example.com", true),
+                    ("web.example.com", true),
+                    ("db.example.com", false),
+                ],
+            ),
+            (
+                "suffix",
+                [
+                    ("web-1.example.com", false),
+                    ("web-2.example.com", false),
+                    ("db.example.com", false),
+                ],
+            ),
+        ] {
+            let args = testing::args(&["--on-duplicate-hostname", on_duplicate_hostname]);
+            let published = published_servers(&mut hcloud, &args, None).await.unwrap();
+            let names: Vec<(String, bool)> = published
+                .servers
+                .iter()
+                .map(|s| (s.fqdn(), s.shared_name))
+                .collect();
+            assert_eq!(
+                names,
+                expected.map(|(fqdn, shared_name)| (fqdn.to_string(), shared_name)),
+                "with {}",
+                on_duplicate_hostname
+            );
+        }
+
+        let args = testing::args(&["--on-duplicate-hostname", "error"]);
+        let Err(Error::Config(message)) = published_servers(&mut hcloud, &args, None).await else {
+            panic!("two servers were allowed on web.example.com");
+        };
+        assert!(message.contains("[1, 2]"), "{}", message);
+
+        // A CNAME can't share its name, whatever the policy.
+        let servers = [
+            web(),
+            Server {
+                id: 2,
+                cname: Some("lb.example.com.".to_string()),
+                ..web()
+            },
+        ];
+        assert!(matches!(
+            check_cname_conflicts(&servers),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn servers_that_move_to_another_network_have_their_records_synced_again() {
+        let in_network = |server: Server, network_id| Server {
+            network_id,
+            ..server
+        };
+        let db = Server {
+            id: 2,
+            ip_address: "10.1.0.3".to_string(),
+            hostname: "db".to_string(),
+            project: Some("other".to_string()),
+            ..web()
+        };
+        let api = Server {
+            id: 3,
+            ip_address: "10.0.0.4".to_string(),
+            hostname: "api".to_string(),
+            ..web()
+        };
+        let published = PublishedServers {
+            servers: vec![
+                in_network(web(), Some(1)),
+                in_network(db.clone(), Some(2)),
+                in_network(api.clone(), Some(2)),
+            ],
+            attached_server_ids: HashSet::from([1, 2, 3]),
+            pending_server_ids: HashSet::new(),
+            ignored_servers: Vec::new(),
+        };
+
+        // The record of db was synced before we knew which network it was from, so it can stay.
+        let plan = Plan::new(
+            &[
+                in_network(web(), Some(1)),
+                in_network(db, None),
+                in_network(api, Some(1)),
+            ],
+            published,
+        );
+        let updates: Vec<(i64, Option<i64>, Option<i64>)> = plan
+            .servers_to_update
+            .iter()
+            .map(|u| (u.current.id, u.previous.network_id, u.current.network_id))
+            .collect();
+        assert_eq!(updates, [(3, Some(1), Some(2))]);
+        assert!(plan.servers_to_add.is_empty());
+        assert!(plan.servers_to_remove.is_empty());
+    }
+
+    #[tokio::test]
+    async fn diff_lists_nothing_in_sync_and_every_change_after_drift() {
+        let state_directory = testing::temp_dir("diff");
+        let mut args = testing::args(&[]);
+        args.state_directory = Some(state_directory.clone());
+        let fake_cloud = |ip_address: &str| {
+            let servers = vec![testing::hcloud_server(1, "web", ip_address)];
+            move |_| Ok(FakeCloud { servers })
+        };
+
+        // The state of a sync of the network as it is now.
+        let mut current_state = open_state(&args, false).unwrap();
+        current_state.private_network_name = "test".to_string();
+        current_state.private_network_id = Some(1);
+        current_state.servers_synced = plan_with(&args, fake_cloud("10.0.0.2"))
+            .await
+            .unwrap()
+            .servers_to_add;
+        drop(current_state);
+        let state_file = || std::fs::read(state_directory.join("state.json")).unwrap();
+        let state_before = state_file();
+
+        let plan = plan_with(&args, fake_cloud("10.0.0.2")).await.unwrap();
+        assert_eq!(plan.listing(false), "");
+        assert_eq!(plan.exit_code(), 0);
+
+        let plan = plan_with(&args, fake_cloud("10.0.0.5")).await.unwrap();
+        assert_eq!(
+            plan.listing(false),
+            "~ web.example.com 600 A 10.0.0.2 -> web.example.com 600 A 10.0.0.5 (server 1)\n"
+        );
+        assert_eq!(plan.exit_code(), DRIFT_EXIT_CODE);
+        assert!(plan.listing(true).starts_with("\x1b[33m~ web.example.com"));
+
+        // Neither changed the state.
+        assert_eq!(state_file(), state_before);
+
+        std::fs::remove_dir_all(state_directory).unwrap();
+    }
+}
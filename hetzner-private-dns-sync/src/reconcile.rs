@@ -0,0 +1,1396 @@
+//! Applying a plan to the DNS servers, and the other commands that change records.
+
+use std::{collections::HashSet, future::Future, time::Duration};
+
+use hickory_client::rr::RecordType;
+use serde::Serialize;
+
+use crate::{
+    cloud::{self_server_id, CloudBackend, HCloudProjects},
+    dns::{dns_updater, DnsBackend, DnsClient},
+    normalise_name,
+    plan::{
+        check_mass_delete, check_max_changes, published_servers, Plan, PublishedServers,
+        ServerUpdate, StaleName,
+    },
+    state::{has_cleanup_prefix, open_state, state_from_zones, zones_of, State, StateWrapper},
+    unix_time_now, Args, Error, Result, Server,
+};
+
+/// Removes records matching `--cleanup-prefix` that don't belong to any server currently in the private network. This finds records through a zone transfer, so it works even if the state doesn't know about them.
+pub async fn cleanup(args: &Args) -> Result<()> {
+    let cleanup_prefix = args.cleanup_prefix.clone().unwrap();
+    let dns_updater = dns_updater(args).await?;
+    let mut current_state = open_state(args, false)?;
+
+    // We don't go through the network change process here, so we only trust the cached ID if it belongs to the network we were given.
+    let cached_network_id = if current_state.private_network_name == args.private_network_name {
+        current_state.private_network_id
+    } else {
+        None
+    };
+    let mut hcloud = HCloudProjects::new(args, cached_network_id)?;
+    let published = published_servers(&mut hcloud, args, self_server_id(args).await?).await?;
+    remove_stale_names(
+        &dns_updater,
+        args,
+        &cleanup_prefix,
+        published,
+        &mut current_state,
+    )
+    .await?;
+
+    current_state.save()
+}
+
+/// Removes the names with `cleanup_prefix` in the zones that none of the published servers (nor the ones we couldn't work out this time) have records on, and forgets the servers the state had on them.
+async fn remove_stale_names(
+    dns_updater: &impl DnsBackend,
+    args: &Args,
+    cleanup_prefix: &str,
+    published: PublishedServers,
+    current_state: &mut StateWrapper,
+) -> Result<()> {
+    let PublishedServers {
+        servers: current_servers,
+        pending_server_ids,
+        ignored_servers,
+        ..
+    } = published;
+    let live_fqdns: HashSet<String> = current_servers
+        .iter()
+        .chain(ignored_servers.iter())
+        .chain(
+            current_state
+                .servers_synced
+                .iter()
+                .filter(|s| pending_server_ids.contains(&s.id)),
+        )
+        .flat_map(|s| std::iter::once(s.fqdn()).chain(s.group_fqdns()))
+        .map(|fqdn| normalise_name(&fqdn))
+        .collect();
+
+    let zones = zones_of(
+        current_servers
+            .iter()
+            .chain(current_state.servers_synced.iter()),
+        &args.zone_name,
+    );
+
+    for zone in zones {
+        let mut stale_names: Vec<String> = dns_updater
+            .zone_records(&zone)
+            .await?
+            .into_iter()
+            .filter(|r| {
+                matches!(
+                    r.record_type,
+                    RecordType::A | RecordType::AAAA | RecordType::CNAME
+                )
+            })
+            .map(|r| r.name)
+            .filter(|name| !live_fqdns.contains(name))
+            .filter(|name| has_cleanup_prefix(name, &zone, cleanup_prefix))
+            .filter(|name| !args.is_protected_name(name))
+            .collect();
+        stale_names.sort();
+        stale_names.dedup();
+
+        tracing::info!(zone, ?stale_names, "Found stale records in the zone.");
+
+        for name in stale_names {
+            dns_updater.remove_name(&name, &zone).await?;
+            current_state
+                .servers_synced
+                .retain(|s| normalise_name(&s.fqdn()) != name);
+            current_state.checkpoint()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the records already in the zones for the servers in the private network to the state. Returns the servers whose records were adopted.
+pub async fn adopt(args: &Args) -> Result<Vec<Server>> {
+    if args.no_state {
+        return Err(Error::Config(
+            "there's no state to adopt records into with --no-state.".to_string(),
+        ));
+    }
+
+    let dns_updater = dns_updater(args).await?;
+    let mut current_state = open_state(args, false)?;
+    if !current_state.private_network_name.is_empty()
+        && current_state.private_network_name != args.private_network_name
+    {
+        return Err(Error::Config(format!(
+            "the state belongs to the private network '{}', so records of another network can't be adopted into it. Run a sync with --allow-private-network-change first.",
+            current_state.private_network_name
+        )));
+    }
+
+    let mut hcloud = HCloudProjects::new(args, current_state.private_network_id)?;
+    let network_id = hcloud.network_id().await?;
+    let published = published_servers(&mut hcloud, args, self_server_id(args).await?).await?;
+    let (servers_adopted, _) = state_from_zones(&dns_updater, args, &published).await?;
+
+    // What's in the zones wins over what the state says, since that's what the next sync will compare against.
+    current_state.private_network_name = args.private_network_name.clone();
+    current_state.private_network_id = Some(network_id);
+    for server in &servers_adopted {
+        tracing::debug!(server_info = ?server, "Adopting record for server.");
+        current_state.servers_synced.retain(|s| s.id != server.id);
+        current_state.servers_synced.push(server.clone());
+    }
+    current_state.save()?;
+
+    Ok(servers_adopted)
+}
+
+/// Removes the records of every server in the state and empties it. Returns the servers whose records were removed.
+pub async fn purge(args: &Args) -> Result<Vec<Server>> {
+    if args.no_state {
+        return Err(Error::Config(
+            "there's no state to purge with --no-state.".to_string(),
+        ));
+    }
+
+    let dns_updater = dns_updater(args).await?;
+    let mut current_state = open_state(args, false)?;
+
+    let mut servers_removed = Vec::with_capacity(current_state.servers_synced.len());
+    for server_info in current_state.servers_synced.clone() {
+        if args.is_protected_name(&server_info.fqdn()) {
+            tracing::warn!(
+                fqdn = server_info.fqdn(),
+                "The name is protected by --protect-name, so we'll leave its records alone."
+            );
+            continue;
+        }
+
+        tracing::debug!(?server_info, "Removing record for server.");
+        dns_updater.remove_server(&server_info).await?;
+        current_state
+            .servers_synced
+            .retain(|s| s.id != server_info.id);
+        current_state.checkpoint()?;
+        servers_removed.push(server_info);
+    }
+
+    *current_state = State::default();
+    current_state.save()?;
+    Ok(servers_removed)
+}
+
+/// What a sync changed.
+#[derive(Debug, Default, Serialize)]
+pub struct SyncReport {
+    /// What we set out to change, after --prune-only and --add-only were applied.
+    pub plan: Plan,
+    pub servers_added: Vec<Server>,
+    pub servers_updated: Vec<ServerUpdate>,
+    pub servers_removed: Vec<Server>,
+    /// Servers dropped from the state without touching their records.
+    pub servers_forgotten: Vec<Server>,
+    /// Names removed because no server owns them anymore. Only used with --no-state.
+    pub names_removed: Vec<StaleName>,
+    /// Servers whose records we gave up changing. The state keeps what it had for them, so the next sync tries again.
+    pub servers_failed: Vec<ServerFailure>,
+}
+
+/// A server a sync couldn't change the records of, and why.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerFailure {
+    pub server: Server,
+    pub error: String,
+}
+
+impl SyncReport {
+    /// A one-line summary of what the sync changed.
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "Added {}, updated {}, removed {} and forgot {} server(s), removed {} stale name(s).",
+            self.servers_added.len(),
+            self.servers_updated.len(),
+            self.servers_removed.len(),
+            self.servers_forgotten.len(),
+            self.names_removed.len()
+        );
+        if !self.servers_failed.is_empty() {
+            summary.push_str(&format!(
+                " Failed to change {} server(s).",
+                self.servers_failed.len()
+            ));
+        }
+
+        summary
+    }
+}
+
+/// Makes DNS match the servers in the private network, keeping track of what it did in the state.
+#[derive(Debug)]
+pub struct Syncer {
+    reconciler: Reconciler<DnsClient, HCloudProjects>,
+}
+
+/// Does the work of a `Syncer`, against whatever DNS servers and Hetzner API it's given.
+#[derive(Debug)]
+struct Reconciler<D, C> {
+    args: Args,
+    dns_updater: D,
+    hcloud: C,
+    // Holding the state keeps it open for as long as the syncer lives. Dropping the syncer (e.g. in the middle of a reconciliation) writes whatever was done until then.
+    state: StateWrapper,
+}
+
+impl Syncer {
+    pub async fn new(args: Args) -> Result<Self> {
+        let dns_updater = dns_updater(&args).await?;
+        let state = open_state(&args, false)?;
+
+        // If the network changed, `reconcile()` resets the cached ID before using it.
+        let hcloud = HCloudProjects::new(
+            &args,
+            if state.private_network_name == args.private_network_name {
+                state.private_network_id
+            } else {
+                None
+            },
+        )?;
+
+        Ok(Self {
+            reconciler: Reconciler {
+                args,
+                dns_updater,
+                hcloud,
+                state,
+            },
+        })
+    }
+
+    /// Runs a single sync. Can be called repeatedly, and every call looks at the private network again.
+    pub async fn reconcile(&mut self) -> Result<SyncReport> {
+        self.reconciler.reconcile().await
+    }
+}
+
+impl<D: DnsBackend, C: CloudBackend> Reconciler<D, C> {
+    async fn verify_propagation(&self, server: &Server) {
+        if self.args.verify {
+            self.dns_updater
+                .verify_propagation(
+                    server,
+                    &self.args.verify_resolver,
+                    Duration::from_secs(self.args.verify_timeout),
+                )
+                .await;
+        }
+    }
+
+    /// Runs a change to the records of a server, giving up on it after --dns-op-timeout. A change that times out is recorded in the report rather than failing the sync, so a server the DNS servers hang on doesn't hold up the others. Returns whether the change went through.
+    async fn change_records(
+        &self,
+        report: &mut SyncReport,
+        server: &Server,
+        change: impl Future<Output = Result<()>>,
+    ) -> Result<bool> {
+        let Some(dns_op_timeout) = self.args.dns_op_timeout else {
+            change.await?;
+            return Ok(true);
+        };
+
+        match tokio::time::timeout(Duration::from_secs(dns_op_timeout), change).await {
+            Ok(result) => result.map(|_| true),
+            Err(_) => {
+                tracing::warn!(
+                    server_id = server.id,
+                    fqdn = server.fqdn(),
+                    timeout_seconds = dns_op_timeout,
+                    "Gave up changing the records of the server, the next sync will try again."
+                );
+                report.servers_failed.push(ServerFailure {
+                    server: server.clone(),
+                    error: format!(
+                        "the change didn't finish within {} second(s).",
+                        dns_op_timeout
+                    ),
+                });
+                Ok(false)
+            }
+        }
+    }
+
+    async fn reconcile(&mut self) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+
+        if self.state.private_network_name != self.args.private_network_name {
+            if !self.state.servers_synced.is_empty() {
+                if !self.args.allow_private_network_change {
+                    return Err(Error::Config("The private network name has changed, but the --allow-private-network-change flag was false! We'll exit with an error instead. If you expect the private network name to change and acknolwedge the behaviour of this software when that happens, pass the --allow-private-network-change flag to continue.".to_string()));
+                }
+
+                check_max_changes(self.state.servers_synced.len(), self.args.max_changes)?;
+
+                tracing::warn!("The private network name has changed and we got the flag acknowledging we'll clean up the state. Will do that now.");
+                for server_info in self.state.servers_synced.clone() {
+                    tracing::debug!(?server_info, "Removing record for server.");
+                    if !self
+                        .change_records(
+                            &mut report,
+                            &server_info,
+                            self.dns_updater.remove_server(&server_info),
+                        )
+                        .await?
+                    {
+                        return Err(Error::Dns(format!(
+                            "the records of {} couldn't be removed in time, so we can't move to the new private network yet.",
+                            server_info.fqdn()
+                        )));
+                    }
+                    self.state.servers_synced.retain(|s| s.id != server_info.id);
+                    self.state.checkpoint()?;
+                    report.servers_removed.push(server_info);
+                }
+
+                // We removed all the previous servers, so we can switch the private network name now.
+                self.state.private_network_name = self.args.private_network_name.clone();
+                self.state.private_network_id = None;
+                self.state.servers_pending_removal.clear();
+                self.state.checkpoint()?;
+            } else {
+                // We're in a new state, so we'll populate the network name.
+                self.state.private_network_name = self.args.private_network_name.clone();
+                self.state.private_network_id = None;
+                self.state.checkpoint()?;
+            }
+        }
+
+        // Servers come and go between reconciliations, so we always look at the network again.
+        self.hcloud.forget_network();
+        let network_id = self.hcloud.network_id().await?;
+        if self.state.private_network_id != Some(network_id) {
+            self.state.private_network_id = Some(network_id);
+            self.state.checkpoint()?;
+        }
+
+        let published = published_servers(
+            &mut self.hcloud,
+            &self.args,
+            self_server_id(&self.args).await?,
+        )
+        .await?;
+        let attached_server_ids = published.attached_server_ids.clone();
+
+        // Without a state, the zones tell us what we synced before.
+        let mut names_to_remove = Vec::new();
+        if self.args.no_state {
+            let (servers_synced, stale_names) =
+                state_from_zones(&self.dns_updater, &self.args, &published).await?;
+            self.state.servers_synced = servers_synced;
+            names_to_remove = stale_names;
+        }
+
+        let mut plan = Plan::new(&self.state.servers_synced, published);
+        plan.names_to_remove = names_to_remove;
+        plan.apply_on_empty_policy(self.args.on_empty, &attached_server_ids);
+
+        if let Some(grace_period) = self.args.detach_grace_period {
+            let previous_pending_removal = self.state.servers_pending_removal.clone();
+            plan.hold_detached_servers(
+                &mut self.state.servers_pending_removal,
+                &attached_server_ids,
+                grace_period,
+                unix_time_now(),
+            );
+            if self.state.servers_pending_removal != previous_pending_removal {
+                self.state.checkpoint()?;
+            }
+        }
+
+        tracing::info!(
+            servers_to_add = ?plan.servers_to_add.iter().map(|s| s.id).collect::<Vec<_>>(),
+            servers_to_update = ?plan.servers_to_update.iter().map(|u| u.current.id).collect::<Vec<_>>(),
+            servers_to_remove = ?plan.servers_to_remove.iter().map(|s| s.id).collect::<Vec<_>>(),
+            names_to_remove = ?plan.names_to_remove.iter().map(|n| &n.fqdn).collect::<Vec<_>>(),
+            "Finished determining which servers got added, updated and removed, will start updating things."
+        );
+
+        plan.apply_change_filters(&self.args);
+        check_max_changes(
+            report.servers_removed.len() + plan.change_count(),
+            self.args.max_changes,
+        )?;
+        if !self.args.allow_mass_delete {
+            check_mass_delete(
+                &plan,
+                self.state.servers_synced.len(),
+                self.args.mass_delete_threshold,
+            )?;
+        }
+        report.plan = plan.clone();
+
+        for ServerUpdate { previous, current } in plan.servers_to_update {
+            tracing::debug!(?previous, ?current, "Updating record for server.");
+
+            // Updating a record in place would add the new address alongside the old one, so we remove the old record first.
+            if !self
+                .change_records(
+                    &mut report,
+                    &previous,
+                    self.dns_updater.remove_server(&previous),
+                )
+                .await?
+            {
+                continue;
+            }
+            self.state.servers_synced.retain(|s| s.id != previous.id);
+            self.state.servers_pending_removal.remove(&previous.id);
+            self.state.checkpoint()?;
+
+            if !self
+                .change_records(&mut report, &current, self.dns_updater.add_server(&current))
+                .await?
+            {
+                // The old records are gone already, but keeping them in the state has the next sync do the whole update again.
+                self.state.servers_synced.push(previous);
+                self.state.checkpoint()?;
+                continue;
+            }
+            self.state.servers_synced.push(current.clone());
+            self.state.checkpoint()?;
+            self.verify_propagation(&current).await;
+            report
+                .servers_updated
+                .push(ServerUpdate { previous, current });
+        }
+
+        for server_info in plan.servers_to_remove {
+            tracing::debug!(?server_info, "Removing record for server.");
+            if !self
+                .change_records(
+                    &mut report,
+                    &server_info,
+                    self.dns_updater.remove_server(&server_info),
+                )
+                .await?
+            {
+                continue;
+            }
+            self.state.servers_synced.retain(|s| s.id != server_info.id);
+            self.state.servers_pending_removal.remove(&server_info.id);
+            self.state.checkpoint()?;
+            report.servers_removed.push(server_info);
+        }
+
+        // Removals delete everything under a name, so adds go after them in case a new server reuses a name that's going away.
+        for server_info in plan.servers_to_add {
+            tracing::debug!(?server_info, "Adding record for server.");
+            if !self
+                .change_records(
+                    &mut report,
+                    &server_info,
+                    self.dns_updater.add_server(&server_info),
+                )
+                .await?
+            {
+                continue;
+            }
+            self.state.servers_synced.push(server_info.clone());
+            self.state.checkpoint()?;
+            self.verify_propagation(&server_info).await;
+            report.servers_added.push(server_info);
+        }
+
+        for stale_name in plan.names_to_remove {
+            tracing::debug!(?stale_name, "Removing records no server owns anymore.");
+            self.dns_updater
+                .remove_name(&stale_name.fqdn, &stale_name.zone)
+                .await?;
+            report.names_removed.push(stale_name);
+        }
+
+        for server_info in plan.servers_to_forget {
+            tracing::debug!(
+                ?server_info,
+                "Won't manage the record for server anymore, leaving it as it is."
+            );
+            self.state.servers_synced.retain(|s| s.id != server_info.id);
+            self.state.servers_pending_removal.remove(&server_info.id);
+            self.state.checkpoint()?;
+            report.servers_forgotten.push(server_info);
+        }
+
+        self.state.last_synced_at = Some(unix_time_now());
+        self.state.save()?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cloud::HCloudServer,
+        dns::ZoneRecord,
+        testing::{self, hcloud_server, FakeCloud, FakeDns},
+        OnEmpty, DNS_ENABLED_LABEL, DNS_SYNC_LABEL, RECORD_TYPES_LABEL, TTL_LABEL,
+    };
+
+    fn reconciler(args: Args, servers: Vec<HCloudServer>) -> Reconciler<FakeDns, FakeCloud> {
+        Reconciler {
+            args,
+            dns_updater: FakeDns::default(),
+            hcloud: FakeCloud { servers },
+            state: StateWrapper::in_memory(),
+        }
+    }
+
+    fn synced(reconciler: &Reconciler<FakeDns, FakeCloud>) -> Vec<(i64, String, String)> {
+        let mut synced: Vec<_> = reconciler
+            .state
+            .servers_synced
+            .iter()
+            .map(|s| (s.id, s.fqdn(), s.ip_address.clone()))
+            .collect();
+        synced.sort();
+
+        synced
+    }
+
+    #[tokio::test]
+    async fn reconcile_adds_updates_and_removes_records() {
+        let mut reconciler = reconciler(
+            testing::args(&[]),
+            vec![
+                hcloud_server(1, "web", "10.0.0.2"),
+                hcloud_server(2, "db", "10.0.0.3"),
+            ],
+        );
+
+        let report = reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "add web.example.com 600 A 10.0.0.2",
+                "add db.example.com 600 A 10.0.0.3",
+            ]
+        );
+        assert_eq!(report.servers_added.len(), 2);
+        assert_eq!(reconciler.state.private_network_name, "test");
+        assert_eq!(reconciler.state.private_network_id, Some(1));
+
+        // web moves to another address, db goes away and cache shows up.
+        reconciler.hcloud.servers = vec![
+            hcloud_server(1, "web", "10.0.0.9"),
+            hcloud_server(3, "cache", "10.0.0.4"),
+        ];
+        let report = reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "remove web.example.com 600 A 10.0.0.2",
+                "add web.example.com 600 A 10.0.0.9",
+                "remove db.example.com 600 A 10.0.0.3",
+                "add cache.example.com 600 A 10.0.0.4",
+            ]
+        );
+        assert_eq!(report.servers_updated.len(), 1);
+        assert_eq!(report.servers_updated[0].previous.ip_address, "10.0.0.2");
+        assert_eq!(report.servers_removed.len(), 1);
+        assert_eq!(report.servers_added.len(), 1);
+        assert_eq!(
+            synced(&reconciler),
+            [
+                (1, "web.example.com".to_string(), "10.0.0.9".to_string()),
+                (3, "cache.example.com".to_string(), "10.0.0.4".to_string()),
+            ]
+        );
+
+        let report = reconciler.reconcile().await.unwrap();
+        assert!(reconciler.dns_updater.take_calls().is_empty());
+        assert_eq!(
+            report.summary(),
+            "Added 0, updated 0, removed 0 and forgot 0 server(s), removed 0 stale name(s)."
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_moves_the_name_of_a_recreated_server_over() {
+        let mut reconciler = reconciler(
+            testing::args(&[]),
+            vec![hcloud_server(1, "web", "10.0.0.2")],
+        );
+        reconciler.reconcile().await.unwrap();
+        reconciler.dns_updater.take_calls();
+
+        reconciler.hcloud.servers = vec![hcloud_server(2, "web", "10.0.0.5")];
+        let report = reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "remove web.example.com 600 A 10.0.0.2",
+                "add web.example.com 600 A 10.0.0.5",
+            ]
+        );
+        assert_eq!(report.servers_updated.len(), 1);
+        assert!(report.servers_removed.is_empty());
+        assert_eq!(
+            synced(&reconciler),
+            [(2, "web.example.com".to_string(), "10.0.0.5".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_only_moves_to_another_network_when_allowed() {
+        let mut reconciler = reconciler(
+            testing::args(&[]),
+            vec![hcloud_server(1, "web", "10.0.0.2")],
+        );
+        reconciler.reconcile().await.unwrap();
+        reconciler.dns_updater.take_calls();
+
+        reconciler.args.private_network_name = "other".to_string();
+        assert!(matches!(
+            reconciler.reconcile().await,
+            Err(Error::Config(_))
+        ));
+        assert!(reconciler.dns_updater.take_calls().is_empty());
+
+        reconciler.args.allow_private_network_change = true;
+        let report = reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "remove web.example.com 600 A 10.0.0.2",
+                "add web.example.com 600 A 10.0.0.2",
+            ]
+        );
+        assert_eq!(report.servers_removed.len(), 1);
+        assert_eq!(report.servers_added.len(), 1);
+        assert_eq!(reconciler.state.private_network_name, "other");
+    }
+
+    #[tokio::test]
+    async fn reconcile_removes_the_records_of_types_taken_out_of_the_list() {
+        let mut web = hcloud_server(1, "web", "10.0.0.2");
+        web.public_ipv6_network = Some("2001:db8::/64".to_string());
+        let mut reconciler = reconciler(
+            testing::args(&["--ipv6-source", "public"]),
+            vec![web.clone()],
+        );
+        reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            ["add web.example.com 600 A 10.0.0.2 + 600 AAAA 2001:db8::1"]
+        );
+
+        web.labels
+            .insert(RECORD_TYPES_LABEL.to_string(), "aaaa".to_string());
+        reconciler.hcloud.servers = vec![web.clone()];
+        let report = reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "remove web.example.com 600 A 10.0.0.2 + 600 AAAA 2001:db8::1",
+                "add web.example.com 600 AAAA 2001:db8::1",
+            ]
+        );
+        assert_eq!(report.servers_updated.len(), 1);
+
+        // The state remembers the server only has the AAAA record, so nothing changes until the list does.
+        reconciler.reconcile().await.unwrap();
+        assert!(reconciler.dns_updater.take_calls().is_empty());
+
+        web.labels
+            .insert(RECORD_TYPES_LABEL.to_string(), "a".to_string());
+        reconciler.hcloud.servers = vec![web];
+        reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "remove web.example.com 600 AAAA 2001:db8::1",
+                "add web.example.com 600 A 10.0.0.2",
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconcile_goes_on_past_a_server_whose_changes_hang() {
+        let mut reconciler = reconciler(
+            testing::args(&["--dns-op-timeout", "5"]),
+            vec![
+                hcloud_server(1, "web", "10.0.0.2"),
+                hcloud_server(2, "db", "10.0.0.3"),
+            ],
+        );
+        reconciler.reconcile().await.unwrap();
+        reconciler.dns_updater.take_calls();
+
+        // db's records can't be changed, web's can.
+        reconciler
+            .dns_updater
+            .hanging
+            .insert("db.example.com".to_string());
+        reconciler.hcloud.servers = vec![
+            hcloud_server(1, "web", "10.0.0.9"),
+            hcloud_server(2, "db", "10.0.0.8"),
+            hcloud_server(3, "cache", "10.0.0.4"),
+        ];
+        let report = reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "remove web.example.com 600 A 10.0.0.2",
+                "add web.example.com 600 A 10.0.0.9",
+                "add cache.example.com 600 A 10.0.0.4",
+            ]
+        );
+        assert_eq!(report.servers_failed.len(), 1);
+        assert_eq!(report.servers_failed[0].server.id, 2);
+        assert_eq!(report.servers_updated.len(), 1);
+        assert_eq!(report.servers_added.len(), 1);
+        assert_eq!(
+            synced(&reconciler),
+            [
+                (1, "web.example.com".to_string(), "10.0.0.9".to_string()),
+                (2, "db.example.com".to_string(), "10.0.0.3".to_string()),
+                (3, "cache.example.com".to_string(), "10.0.0.4".to_string()),
+            ]
+        );
+
+        // Once the DNS server answers again, the next sync finishes the update.
+        reconciler.dns_updater.hanging.clear();
+        let report = reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "remove db.example.com 600 A 10.0.0.3",
+                "add db.example.com 600 A 10.0.0.8",
+            ]
+        );
+        assert!(report.servers_failed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn syncs_pick_up_where_the_last_one_left_off() {
+        let state_directory = testing::temp_dir("syncs-pick-up");
+        let mut args = testing::args(&[]);
+        args.state_directory = Some(state_directory.clone());
+        let sync = |servers| Reconciler {
+            args: args.clone(),
+            dns_updater: FakeDns::default(),
+            hcloud: FakeCloud { servers },
+            state: open_state(&args, false).unwrap(),
+        };
+
+        let mut reconciler = sync(vec![
+            hcloud_server(1, "web", "10.0.0.2"),
+            hcloud_server(2, "db", "10.0.0.3"),
+        ]);
+        reconciler.reconcile().await.unwrap();
+        assert_eq!(reconciler.dns_updater.take_calls().len(), 2);
+        drop(reconciler);
+        assert!(state_directory.join("state.json").exists());
+
+        // A new run with the same servers has nothing to do, since the state says it's all synced.
+        let mut reconciler = sync(vec![
+            hcloud_server(1, "web", "10.0.0.2"),
+            hcloud_server(2, "db", "10.0.0.3"),
+        ]);
+        assert_eq!(reconciler.state.private_network_id, Some(1));
+        let report = reconciler.reconcile().await.unwrap();
+        assert!(reconciler.dns_updater.take_calls().is_empty());
+        assert!(report.servers_added.is_empty());
+        drop(reconciler);
+
+        let mut reconciler = sync(vec![hcloud_server(1, "web", "10.0.0.2")]);
+        reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            ["remove db.example.com 600 A 10.0.0.3"]
+        );
+        assert_eq!(
+            synced(&reconciler),
+            [(1, "web.example.com".to_string(), "10.0.0.2".to_string())]
+        );
+
+        drop(reconciler);
+        std::fs::remove_dir_all(state_directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconcile_puts_servers_in_the_zone_of_their_label() {
+        let tenant = |id, name, ip, tenant: &str| {
+            let mut server = hcloud_server(id, name, ip);
+            server
+                .labels
+                .insert("tenant".to_string(), tenant.to_string());
+            server
+        };
+        let mut reconciler = reconciler(
+            testing::args(&["--zone-per-label", "tenant"]),
+            vec![
+                tenant(1, "web", "10.0.0.2", "acme"),
+                tenant(2, "db", "10.0.0.3", "globex"),
+                hcloud_server(3, "cache", "10.0.0.4"),
+            ],
+        );
+
+        reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "add web.acme.example.com 600 A 10.0.0.2",
+                "add db.globex.example.com 600 A 10.0.0.3",
+                "add cache.example.com 600 A 10.0.0.4",
+            ]
+        );
+        let zones: Vec<(i64, String)> = reconciler
+            .state
+            .servers_synced
+            .iter()
+            .map(|s| (s.id, s.zone.clone()))
+            .collect();
+        assert_eq!(
+            zones,
+            [
+                (1, "acme.example.com".to_string()),
+                (2, "globex.example.com".to_string()),
+                (3, "example.com".to_string()),
+            ]
+        );
+
+        // db moving to another tenant leaves the zone it was in.
+        reconciler.hcloud.servers[1] = tenant(2, "db", "10.0.0.3", "acme");
+        reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "remove db.globex.example.com 600 A 10.0.0.3",
+                "add db.acme.example.com 600 A 10.0.0.3",
+            ]
+        );
+
+        reconciler.hcloud.servers[1] = tenant(2, "db", "10.0.0.3", "");
+        assert!(matches!(
+            reconciler.reconcile().await,
+            Err(Error::HCloud(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reconcile_only_touches_the_servers_that_changed() {
+        let mut reconciler = reconciler(
+            testing::args(&[]),
+            vec![
+                hcloud_server(1, "web", "10.0.0.2"),
+                hcloud_server(2, "db", "10.0.0.3"),
+            ],
+        );
+        reconciler.reconcile().await.unwrap();
+        reconciler.dns_updater.take_calls();
+
+        let report = reconciler.reconcile().await.unwrap();
+        assert!(reconciler.dns_updater.take_calls().is_empty());
+        assert!(report.servers_added.is_empty() && report.servers_updated.is_empty());
+
+        // db is published again with its new TTL, and web is left alone.
+        reconciler.hcloud.servers[1]
+            .labels
+            .insert("dns-ttl".to_string(), "60".to_string());
+        let report = reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "remove db.example.com 600 A 10.0.0.3",
+                "add db.example.com 60 A 10.0.0.3",
+            ]
+        );
+        assert_eq!(report.servers_updated.len(), 1);
+        assert_eq!(report.servers_updated[0].current.id, 2);
+
+        reconciler.reconcile().await.unwrap();
+        assert!(reconciler.dns_updater.take_calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reconcile_only_publishes_servers_in_the_published_subnets() {
+        let mut reconciler = reconciler(
+            testing::args(&[
+                "--publish-subnet",
+                "10.0.1.0/24",
+                "--publish-subnet",
+                "10.0.2.0/24",
+            ]),
+            vec![
+                hcloud_server(1, "web", "10.0.1.2"),
+                hcloud_server(2, "scratch", "10.0.99.2"),
+                hcloud_server(3, "db", "10.0.2.3"),
+            ],
+        );
+
+        reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "add web.example.com 600 A 10.0.1.2",
+                "add db.example.com 600 A 10.0.2.3",
+            ]
+        );
+
+        // web moving out of the published subnets loses its record.
+        reconciler.hcloud.servers[0] = hcloud_server(1, "web", "10.0.99.3");
+        let report = reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            ["remove web.example.com 600 A 10.0.1.2"]
+        );
+        assert_eq!(report.servers_removed.len(), 1);
+        assert_eq!(
+            synced(&reconciler),
+            [(3, "db.example.com".to_string(), "10.0.2.3".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_publishes_a_cname_for_servers_with_the_label() {
+        let mut alias = hcloud_server(2, "alias", "10.0.0.3");
+        alias
+            .labels
+            .insert("dns-cname".to_string(), "web".to_string());
+        let mut reconciler = reconciler(
+            testing::args(&[]),
+            vec![hcloud_server(1, "web", "10.0.0.2"), alias.clone()],
+        );
+
+        reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "add web.example.com 600 A 10.0.0.2",
+                "add alias.example.com 600 CNAME web.example.com.",
+            ]
+        );
+
+        // Nor is it removed once db leaves the network, since we don't know about it anymore.
+        reconciler.hcloud.servers.pop();
+        reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            ["remove alias.example.com 600 CNAME web.example.com."]
+        );
+
+        // A CNAME can't share its name with the A record of another server.
+        alias.name = "web".to_string();
+        reconciler.hcloud.servers.push(alias);
+        assert!(matches!(
+            reconciler.reconcile().await,
+            Err(Error::Config(_))
+        ));
+        assert!(reconciler.dns_updater.take_calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cleanup_removes_the_names_with_the_prefix_no_server_has() {
+        let record = |name: &str, record_type, data: &str| ZoneRecord {
+            name: name.to_string(),
+            record_type,
+            data: data.to_string(),
+            ttl: 600,
+        };
+        let mut dns_updater = FakeDns::default();
+        dns_updater.zones.insert(
+            "example.com".to_string(),
+            vec![
+                record("web-1.example.com", RecordType::A, "10.0.0.2"),
+                record("web-2.example.com", RecordType::A, "10.0.0.3"),
+                record("web-2.example.com", RecordType::AAAA, "2001:db8::1"),
+                record("web-3.example.com", RecordType::TXT, "\"notes\""),
+                record("web-4.example.com", RecordType::CNAME, "web-1.example.com."),
+                record("web-9.example.com", RecordType::A, "10.0.0.9"),
+                record("db.example.com", RecordType::A, "10.0.0.4"),
+            ],
+        );
+        let args = testing::args(&["--cleanup-prefix", "web-", "--protect-name", "web-9.*"]);
+        let mut state = StateWrapper::in_memory();
+        state.servers_synced.push(Server {
+            id: 2,
+            ip_address: "10.0.0.3".to_string(),
+            hostname: "web-2".to_string(),
+            zone: "example.com".to_string(),
+            ..Default::default()
+        });
+        let published = published_servers(
+            &mut FakeCloud {
+                servers: vec![hcloud_server(1, "web-1", "10.0.0.2")],
+            },
+            &args,
+            None,
+        )
+        .await
+        .unwrap();
+
+        remove_stale_names(&dns_updater, &args, "web-", published, &mut state)
+            .await
+            .unwrap();
+        assert_eq!(
+            dns_updater.take_calls(),
+            [
+                "remove-name web-2.example.com",
+                "remove-name web-4.example.com",
+            ]
+        );
+        assert!(state.servers_synced.is_empty());
+    }
+
+    #[tokio::test]
+    async fn prune_only_and_add_only_keep_to_their_changes() {
+        for prune_only in [true, false] {
+            let mut reconciler = reconciler(
+                testing::args(&[]),
+                vec![
+                    hcloud_server(1, "web", "10.0.0.2"),
+                    hcloud_server(2, "db", "10.0.0.3"),
+                ],
+            );
+            reconciler.reconcile().await.unwrap();
+            reconciler.dns_updater.take_calls();
+
+            // web moves to another address, db goes away and cache shows up.
+            reconciler.hcloud.servers = vec![
+                hcloud_server(1, "web", "10.0.0.9"),
+                hcloud_server(3, "cache", "10.0.0.4"),
+            ];
+            reconciler.args.prune_only = prune_only;
+            reconciler.args.add_only = !prune_only;
+            reconciler.reconcile().await.unwrap();
+
+            if prune_only {
+                assert_eq!(
+                    reconciler.dns_updater.take_calls(),
+                    ["remove db.example.com 600 A 10.0.0.3"]
+                );
+                assert_eq!(
+                    synced(&reconciler),
+                    [(1, "web.example.com".to_string(), "10.0.0.2".to_string())]
+                );
+            } else {
+                assert_eq!(
+                    reconciler.dns_updater.take_calls(),
+                    [
+                        "remove web.example.com 600 A 10.0.0.2",
+                        "add web.example.com 600 A 10.0.0.9",
+                        "add cache.example.com 600 A 10.0.0.4",
+                    ]
+                );
+                // db stays in the state, for a later run to remove.
+                assert_eq!(
+                    synced(&reconciler),
+                    [
+                        (1, "web.example.com".to_string(), "10.0.0.9".to_string()),
+                        (2, "db.example.com".to_string(), "10.0.0.3".to_string()),
+                        (3, "cache.example.com".to_string(), "10.0.0.4".to_string()),
+                    ]
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn exclude_self_leaves_the_records_of_our_own_server_alone() {
+        let mut reconciler = reconciler(
+            testing::args(&[]),
+            vec![
+                hcloud_server(1, "web", "10.0.0.2"),
+                hcloud_server(2, "db", "10.0.0.3"),
+            ],
+        );
+        reconciler.reconcile().await.unwrap();
+        reconciler.dns_updater.take_calls();
+
+        // We run on db, which now moves to another address, and its records are managed elsewhere.
+        reconciler.args.exclude_self = true;
+        reconciler.args.self_server_id = Some(2);
+        reconciler.hcloud.servers[1] = hcloud_server(2, "db", "10.0.0.8");
+        reconciler.reconcile().await.unwrap();
+        assert!(reconciler.dns_updater.take_calls().is_empty());
+
+        // Nor does cleanup take our own name for a stale one.
+        let mut dns_updater = FakeDns::default();
+        dns_updater.zones.insert(
+            "example.com".to_string(),
+            vec![ZoneRecord {
+                name: "db.example.com".to_string(),
+                record_type: RecordType::A,
+                data: "10.0.0.8".to_string(),
+                ttl: 600,
+            }],
+        );
+        let published = published_servers(&mut reconciler.hcloud, &reconciler.args, Some(2))
+            .await
+            .unwrap();
+        assert!(published.servers.iter().all(|s| s.id != 2));
+        remove_stale_names(
+            &dns_updater,
+            &reconciler.args,
+            "",
+            published,
+            &mut StateWrapper::in_memory(),
+        )
+        .await
+        .unwrap();
+        assert!(dns_updater.take_calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn servers_with_dns_disabled_lose_their_record_until_reenabled() {
+        let mut reconciler = reconciler(
+            testing::args(&[]),
+            vec![
+                hcloud_server(1, "web", "10.0.0.2"),
+                hcloud_server(2, "db", "10.0.0.3"),
+            ],
+        );
+        reconciler.reconcile().await.unwrap();
+        reconciler.dns_updater.take_calls();
+
+        reconciler.hcloud.servers[1]
+            .labels
+            .insert(DNS_ENABLED_LABEL.to_string(), "false".to_string());
+        reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            ["remove db.example.com 600 A 10.0.0.3"]
+        );
+        assert_eq!(
+            synced(&reconciler),
+            [(1, "web.example.com".to_string(), "10.0.0.2".to_string())]
+        );
+
+        // It stays disabled without being removed over and over.
+        reconciler.reconcile().await.unwrap();
+        assert!(reconciler.dns_updater.take_calls().is_empty());
+
+        reconciler.hcloud.servers[1].labels.clear();
+        reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            ["add db.example.com 600 A 10.0.0.3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn without_a_state_the_zone_says_what_was_synced() {
+        let a_record = |name: &str, data: &str| ZoneRecord {
+            name: name.to_string(),
+            record_type: RecordType::A,
+            data: data.to_string(),
+            ttl: 600,
+        };
+        let mut args = testing::args(&["--cleanup-prefix", "web-"]);
+        args.no_state = true;
+        args.state_directory = None;
+        let servers = vec![
+            hcloud_server(1, "web", "10.0.0.2"),
+            hcloud_server(2, "db", "10.0.0.3"),
+        ];
+
+        let mut first_run = reconciler(args.clone(), servers.clone());
+        first_run.dns_updater.zones.insert(
+            "example.com".to_string(),
+            vec![
+                a_record("web.example.com", "10.0.0.2"),
+                a_record("db.example.com", "10.0.0.1"),
+                a_record("web-old.example.com", "10.0.0.7"),
+                a_record("mail.example.com", "10.0.0.5"),
+            ],
+        );
+        first_run.reconcile().await.unwrap();
+        assert_eq!(
+            first_run.dns_updater.take_calls(),
+            [
+                "remove db.example.com 600 A 10.0.0.1",
+                "add db.example.com 600 A 10.0.0.3",
+                "remove-name web-old.example.com",
+            ]
+        );
+
+        // The next run starts from nothing but the zone again, which has everything now.
+        let mut next_run = reconciler(args, servers);
+        next_run.dns_updater.zones.insert(
+            "example.com".to_string(),
+            vec![
+                a_record("web.example.com", "10.0.0.2"),
+                a_record("db.example.com", "10.0.0.3"),
+                a_record("mail.example.com", "10.0.0.5"),
+            ],
+        );
+        next_run.reconcile().await.unwrap();
+        assert!(next_run.dns_updater.take_calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reconcile_updates_the_records_whose_ttl_changed() {
+        let mut reconciler = reconciler(
+            testing::args(&[]),
+            vec![
+                hcloud_server(1, "web", "10.0.0.2"),
+                hcloud_server(2, "db", "10.0.0.3"),
+            ],
+        );
+        reconciler.reconcile().await.unwrap();
+        reconciler.dns_updater.take_calls();
+
+        reconciler.args.record_ttl = 60;
+        let report = reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "remove web.example.com 600 A 10.0.0.2",
+                "add web.example.com 60 A 10.0.0.2",
+                "remove db.example.com 600 A 10.0.0.3",
+                "add db.example.com 60 A 10.0.0.3",
+            ]
+        );
+        assert_eq!(report.servers_updated.len(), 2);
+
+        // The label of a server wins over --record-ttl.
+        reconciler.hcloud.servers[1]
+            .labels
+            .insert(TTL_LABEL.to_string(), "60".to_string());
+        reconciler.args.record_ttl = 300;
+        reconciler.reconcile().await.unwrap();
+        assert_eq!(
+            reconciler.dns_updater.take_calls(),
+            [
+                "remove web.example.com 60 A 10.0.0.2",
+                "add web.example.com 300 A 10.0.0.2",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn on_empty_decides_what_happens_when_the_network_has_no_servers() {
+        for on_empty in [OnEmpty::Keep, OnEmpty::Remove] {
+            let mut reconciler = reconciler(
+                testing::args(&[]),
+                vec![
+                    hcloud_server(1, "web", "10.0.0.2"),
+                    hcloud_server(2, "db", "10.0.0.3"),
+                ],
+            );
+            reconciler.args.on_empty = on_empty;
+            reconciler.args.allow_mass_delete = true;
+            reconciler.reconcile().await.unwrap();
+            reconciler.dns_updater.take_calls();
+
+            reconciler.hcloud.servers.clear();
+            reconciler.reconcile().await.unwrap();
+            if on_empty == OnEmpty::Keep {
+                assert!(reconciler.dns_updater.take_calls().is_empty());
+                assert_eq!(synced(&reconciler).len(), 2);
+            } else {
+                assert_eq!(
+                    reconciler.dns_updater.take_calls(),
+                    [
+                        "remove web.example.com 600 A 10.0.0.2",
+                        "remove db.example.com 600 A 10.0.0.3",
+                    ]
+                );
+                assert!(synced(&reconciler).is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cleanup_looks_in_every_zone_servers_are_or_were_in() {
+        let a_record = |name: &str, data: &str| ZoneRecord {
+            name: name.to_string(),
+            record_type: RecordType::A,
+            data: data.to_string(),
+            ttl: 600,
+        };
+        let mut dns_updater = FakeDns::default();
+        for (zone, records) in [
+            (
+                "acme.example.com",
+                vec![
+                    a_record("web-1.acme.example.com", "10.0.0.2"),
+                    a_record("web-2.acme.example.com", "10.0.0.3"),
+                ],
+            ),
+            (
+                "globex.example.com",
+                vec![a_record("web-2.globex.example.com", "10.0.0.3")],
+            ),
+            (
+                "example.com",
+                vec![a_record("web-1.example.com", "10.0.0.2")],
+            ),
+            (
+                "initech.example.com",
+                vec![a_record("web-3.initech.example.com", "10.0.0.4")],
+            ),
+        ] {
+            dns_updater.zones.insert(zone.to_string(), records);
+        }
+        let args = testing::args(&["--cleanup-prefix", "web-", "--zone-per-label", "tenant"]);
+        // The state only knows where web-2 was, a zone no server is in anymore.
+        let mut state = StateWrapper::in_memory();
+        state.servers_synced.push(Server {
+            id: 2,
+            ip_address: "10.0.0.3".to_string(),
+            hostname: "web-2".to_string(),
+            zone: "globex.example.com".to_string(),
+            ..Default::default()
+        });
+        let mut web = hcloud_server(1, "web-1", "10.0.0.2");
+        web.labels.insert("tenant".to_string(), "acme".to_string());
+        let published = published_servers(&mut FakeCloud { servers: vec![web] }, &args, None)
+            .await
+            .unwrap();
+
+        remove_stale_names(&dns_updater, &args, "web-", published, &mut state)
+            .await
+            .unwrap();
+        // Zones neither a server nor the state has records in are left alone.
+        assert_eq!(
+            dns_updater.take_calls(),
+            [
+                "remove-name web-2.acme.example.com",
+                "remove-name web-1.example.com",
+                "remove-name web-2.globex.example.com",
+            ]
+        );
+        assert!(state.servers_synced.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ignored_servers_are_left_alone_and_forgotten() {
+        let mut reconciler = reconciler(
+            testing::args(&[]),
+            vec![
+                hcloud_server(1, "web", "10.0.0.2"),
+                hcloud_server(2, "db", "10.0.0.3"),
+            ],
+        );
+        reconciler.reconcile().await.unwrap();
+        reconciler.dns_updater.take_calls();
+
+        // db's record is managed by hand from now on, whatever happens to db.
+        let mut db = hcloud_server(2, "db", "10.0.0.8");
+        db.labels
+            .insert(DNS_SYNC_LABEL.to_string(), "ignore".to_string());
+        reconciler.hcloud.servers[1] = db;
+        reconciler.reconcile().await.unwrap();
+        assert!(reconciler.dns_updater.take_calls().is_empty());
+        assert_eq!(
+            synced(&reconciler),
+            [(1, "web.example.com".to_string(), "10.0.0.2".to_string())]
+        );
+
+        reconciler.hcloud.servers.pop();
+        reconciler.reconcile().await.unwrap();
+        assert!(reconciler.dns_updater.take_calls().is_empty());
+    }
+}
@@ -0,0 +1,1246 @@
+//! The state we keep between syncs: which records we published, and for which servers.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    ops::{Deref, DerefMut},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt},
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use hickory_client::rr::RecordType;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    default_ttl,
+    dns::{DnsBackend, ZoneRecord},
+    normalise_name,
+    plan::{PublishedServers, StaleName},
+    Args, Error, ExportFormat, Result, Server, Service, StateSaveMode,
+};
+
+#[derive(Debug)]
+pub(crate) struct StateWrapper {
+    // Path of the state file. `None` if the state was opened read-only, in which case it's never written back.
+    path: Option<PathBuf>,
+    data: State,
+    save_mode: StateSaveMode,
+    batch_size: usize,
+    // How many changes happened since the last time the state was written.
+    unsaved_changes: usize,
+    #[cfg(test)]
+    writes: usize,
+}
+
+/// Set when the state couldn't be written as it was dropped, where there's nobody to return the error to.
+static SAVE_ON_DROP_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the state couldn't be written at the end of a run, when it was dropped. The error was logged already, this is only so the run can still end with the exit code of a state error.
+pub fn state_save_failed() -> bool {
+    SAVE_ON_DROP_FAILED.load(Ordering::Relaxed)
+}
+
+impl StateWrapper {
+    pub(crate) fn from_directory(dir: PathBuf) -> Result<Self> {
+        if !dir.exists() {
+            tracing::info!(state_directory = %dir.display(), "The state directory doesn't exist, will create it.");
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(&dir)
+                .map_err(|e| {
+                    Error::State(format!(
+                        "unable to create the state directory at {}. {}",
+                        dir.display(),
+                        e
+                    ))
+                })?;
+        }
+
+        let state_path = dir.join("state.json");
+        let state_file = std::fs::File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .read(true)
+            // The state has internal IPs and hostnames in it, so only we get to read it.
+            .mode(0o600)
+            .open(&state_path)
+            .map_err(|e| {
+                Error::State(format!(
+                    "unable to open the state file at {}. {}",
+                    state_path.display(),
+                    e
+                ))
+            })?;
+
+        let state_metadata = state_file
+            .metadata()
+            .map_err(|e| Error::State(format!("unable to read the state file metadata. {}", e)))?;
+
+        // Files created before we cared about this (or by someone else) could still be readable by others.
+        if state_metadata.permissions().mode() & 0o077 != 0 {
+            tracing::info!(state_path = %state_path.display(), "The state file can be accessed by other users, will restrict it to its owner.");
+            state_file
+                .set_permissions(std::fs::Permissions::from_mode(0o600))
+                .map_err(|e| {
+                    Error::State(format!(
+                        "unable to restrict the permissions of the state file. {}",
+                        e
+                    ))
+                })?;
+        }
+
+        let state_len = state_metadata.len();
+        let state_data = if state_len == 0 {
+            State::default()
+        } else {
+            serde_json::from_reader(&state_file)
+                .map_err(|e| Error::State(format!("unable to parse the state file. {}", e)))?
+        };
+
+        Ok(Self {
+            path: Some(state_path),
+            data: state_data,
+            save_mode: StateSaveMode::default(),
+            batch_size: 1,
+            unsaved_changes: 0,
+            #[cfg(test)]
+            writes: 0,
+        })
+    }
+
+    /// Starts with an empty state that's never written anywhere.
+    pub(crate) fn in_memory() -> Self {
+        Self {
+            path: None,
+            data: State::default(),
+            save_mode: StateSaveMode::default(),
+            batch_size: 1,
+            unsaved_changes: 0,
+            #[cfg(test)]
+            writes: 0,
+        }
+    }
+
+    /// Reads the state without ever writing it back. If there's no state file yet, this doesn't create one.
+    fn read_only_from_directory(dir: PathBuf) -> Result<Self> {
+        let state_path = dir.join("state.json");
+        let state_data = match std::fs::read(&state_path) {
+            Ok(contents) if contents.is_empty() => State::default(),
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| Error::State(format!("unable to parse the state file. {}", e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::default(),
+            Err(e) => {
+                return Err(Error::State(format!(
+                    "unable to read the state file at {}. {}",
+                    state_path.display(),
+                    e
+                )))
+            }
+        };
+
+        Ok(Self {
+            path: None,
+            data: state_data,
+            save_mode: StateSaveMode::default(),
+            batch_size: 1,
+            unsaved_changes: 0,
+            #[cfg(test)]
+            writes: 0,
+        })
+    }
+
+    fn set_save_mode(&mut self, save_mode: StateSaveMode, batch_size: usize) {
+        self.save_mode = save_mode;
+        self.batch_size = batch_size.max(1);
+    }
+
+    /// Marks that the state changed, and writes it if the save mode says so. The state is always written when it's dropped, so changes are never lost on a clean exit.
+    pub(crate) fn checkpoint(&mut self) -> Result<()> {
+        self.unsaved_changes += 1;
+
+        match self.save_mode {
+            StateSaveMode::Each => self.save(),
+            StateSaveMode::Batch if self.unsaved_changes >= self.batch_size => self.save(),
+            StateSaveMode::Batch | StateSaveMode::End => Ok(()),
+        }
+    }
+
+    /// Writes the state next to the state file and renames it over it, so the state file is never left half-written if we're killed or the disk fills up.
+    pub(crate) fn save(&mut self) -> Result<()> {
+        let Some(state_path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut temporary_path = state_path.clone().into_os_string();
+        temporary_path.push(".tmp");
+        let mut temporary_file = std::fs::File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .mode(0o600)
+            .open(&temporary_path)
+            .map_err(|e| Error::State(format!("unable to create the state file. {}", e)))?;
+        serde_json::to_writer(&temporary_file, &self.data)
+            .map_err(|e| Error::State(format!("unable to write the state file. {}", e)))?;
+        temporary_file
+            .flush()
+            .and_then(|_| temporary_file.sync_all())
+            .and_then(|_| std::fs::rename(&temporary_path, state_path))
+            .map_err(|e| Error::State(format!("unable to write the state file. {}", e)))?;
+        self.unsaved_changes = 0;
+        #[cfg(test)]
+        {
+            self.writes += 1;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for StateWrapper {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            tracing::error!(error = %e, "Unable to write the state, the changes since it was last written are lost.");
+            SAVE_ON_DROP_FAILED.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Deref for StateWrapper {
+    type Target = State;
+
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl DerefMut for StateWrapper {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.data
+    }
+}
+
+/// Quotes a CSV field if it needs to be.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(try_from = "StateFile", into = "StateFile")]
+pub(crate) struct State {
+    pub(crate) private_network_name: String,
+    pub(crate) private_network_id: Option<i64>,
+    pub(crate) servers_synced: Vec<Server>,
+    // Servers we synced that got detached from the network, and since when (in seconds since the Unix epoch) we've seen them gone. Only used with --detach-grace-period.
+    pub(crate) servers_pending_removal: BTreeMap<i64, u64>,
+    // When the last sync finished successfully, in seconds since the Unix epoch.
+    pub(crate) last_synced_at: Option<u64>,
+}
+
+/// Version of the layout of `state.json` we write.
+const STATE_VERSION: u32 = 3;
+
+/// How the state is laid out in `state.json`. Records are grouped by the zone and network they were published in, so it's clear where each of them lives without having to work it out from the arguments.
+#[derive(Deserialize, Serialize)]
+struct StateFile {
+    // States written before the layout was versioned don't have this, and are version 1.
+    #[serde(default = "first_state_version")]
+    version: u32,
+    private_network_name: String,
+    #[serde(default)]
+    private_network_id: Option<i64>,
+    // Version 1 kept every server in a single list.
+    #[serde(default, skip_serializing)]
+    servers_synced: Vec<Server>,
+    #[serde(default)]
+    scopes: Vec<StateScope>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    servers_pending_removal: BTreeMap<i64, u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_synced_at: Option<u64>,
+    // Of everything else we wrote, to notice when the file was changed by something else. States written before we had this don't have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+}
+
+fn first_state_version() -> u32 {
+    1
+}
+
+impl StateFile {
+    /// A SHA-256 of the file as we write it, without the checksum itself.
+    fn checksum(&self) -> String {
+        let mut file = serde_json::to_value(self).unwrap_or_default();
+        if let Some(file) = file.as_object_mut() {
+            file.remove("checksum");
+        }
+        let digest = ring::digest::digest(
+            &ring::digest::SHA256,
+            &serde_json::to_vec(&file).unwrap_or_default(),
+        );
+
+        digest
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Records we published in one zone, for the servers of one network.
+#[derive(Deserialize, Serialize)]
+struct StateScope {
+    zone: String,
+    // Version 2 put every record under the network of --hcloud-api-token, even those of servers from another --hcloud-project.
+    network_id: Option<i64>,
+    records: Vec<SyncedRecord>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SyncedRecord {
+    server_id: i64,
+    hostname: String,
+    // Only written to make the state easier to read, we always work them out from the other fields.
+    #[serde(default, skip_deserializing)]
+    fqdn: String,
+    #[serde(default, skip_deserializing)]
+    record_type: String,
+    ip_address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ipv6_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    public_ip_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    public_zone: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    services: Vec<Service>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    groups: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    wildcard: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    shared_name: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    no_a_record: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    no_ptr_record: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cname: Option<String>,
+    #[serde(default = "default_ttl")]
+    ttl: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    project: Option<String>,
+}
+
+impl TryFrom<StateFile> for State {
+    type Error = String;
+
+    fn try_from(file: StateFile) -> std::result::Result<Self, Self::Error> {
+        if file.version > STATE_VERSION {
+            return Err(format!(
+                "the state file has version {}, but we only know up to version {}. It was probably written by a newer version of this software",
+                file.version, STATE_VERSION
+            ));
+        }
+
+        let version = file.version;
+        let scoped_servers = file.scopes.into_iter().flat_map(move |scope| {
+            let zone = scope.zone;
+            let network_id = scope.network_id;
+            scope.records.into_iter().map(move |record| Server {
+                network_id: network_id.filter(|_| version > 2 || record.project.is_none()),
+                id: record.server_id,
+                ip_address: record.ip_address,
+                ipv6_address: record.ipv6_address,
+                public_ip_address: record.public_ip_address,
+                public_zone: record.public_zone,
+                services: record.services,
+                groups: record.groups,
+                metadata: record.metadata,
+                wildcard: record.wildcard,
+                shared_name: record.shared_name,
+                no_a_record: record.no_a_record,
+                no_ptr_record: record.no_ptr_record,
+                hostname: record.hostname,
+                zone: zone.clone(),
+                cname: record.cname,
+                ttl: record.ttl,
+                project: record.project,
+            })
+        });
+
+        // Version 1 didn't scope records by network, but only the servers of --hcloud-api-token were in the one it kept.
+        let private_network_id = file.private_network_id;
+        let servers_synced: Vec<Server> = file
+            .servers_synced
+            .into_iter()
+            .map(|server| Server {
+                network_id: server
+                    .network_id
+                    .or(private_network_id.filter(|_| server.project.is_none())),
+                ..server
+            })
+            .chain(scoped_servers)
+            .collect();
+        let state = Self {
+            private_network_name: file.private_network_name,
+            private_network_id: file.private_network_id,
+            servers_synced,
+            servers_pending_removal: file.servers_pending_removal,
+            last_synced_at: file.last_synced_at,
+        };
+
+        // Writing the state back gives the same file we wrote, unless something else changed it. Only advisory, since hand-editing the state is sometimes the right thing to do.
+        if file
+            .checksum
+            .is_some_and(|checksum| Some(checksum) != StateFile::from(state.clone()).checksum)
+        {
+            tracing::info!(
+                last_synced_at = ?state.last_synced_at,
+                "The state file changed since it was last written by us, it was probably edited by hand."
+            );
+        }
+
+        Ok(state)
+    }
+}
+
+impl From<State> for StateFile {
+    fn from(state: State) -> Self {
+        // Sorting makes the file the same for the same state, which makes dumps easy to compare.
+        let mut servers = state.servers_synced;
+        servers.sort_by(|a, b| a.zone.cmp(&b.zone).then(a.id.cmp(&b.id)));
+
+        let mut scopes: Vec<StateScope> = Vec::new();
+        for server in servers {
+            let record = SyncedRecord {
+                server_id: server.id,
+                fqdn: server.fqdn(),
+                record_type: match &server.cname {
+                    Some(_) => "CNAME",
+                    None => server.address_record().0,
+                }
+                .to_string(),
+                hostname: server.hostname,
+                ip_address: server.ip_address,
+                ipv6_address: server.ipv6_address,
+                public_ip_address: server.public_ip_address,
+                public_zone: server.public_zone,
+                services: server.services,
+                groups: server.groups,
+                metadata: server.metadata,
+                wildcard: server.wildcard,
+                shared_name: server.shared_name,
+                no_a_record: server.no_a_record,
+                no_ptr_record: server.no_ptr_record,
+                cname: server.cname,
+                ttl: server.ttl,
+                project: server.project,
+            };
+
+            match scopes
+                .iter_mut()
+                .find(|s| s.zone == server.zone && s.network_id == server.network_id)
+            {
+                Some(scope) => scope.records.push(record),
+                None => scopes.push(StateScope {
+                    zone: server.zone,
+                    network_id: server.network_id,
+                    records: vec![record],
+                }),
+            }
+        }
+
+        let mut file = Self {
+            version: STATE_VERSION,
+            private_network_name: state.private_network_name,
+            private_network_id: state.private_network_id,
+            servers_synced: Vec::new(),
+            scopes,
+            servers_pending_removal: state.servers_pending_removal,
+            last_synced_at: state.last_synced_at,
+            checksum: None,
+        };
+        file.checksum = Some(file.checksum());
+
+        file
+    }
+}
+
+/// Makes sure a sync would be able to write the state, without touching it, and returns the problem if it wouldn't. There's nothing to check with --no-state.
+pub(crate) fn check_state_directory(args: &Args) -> Option<String> {
+    let state_directory = args.state_directory.as_ref().filter(|_| !args.no_state)?;
+
+    // The state directory is created along with its parents, so it's the closest one that exists that has to be writable.
+    let existing_directory = state_directory
+        .ancestors()
+        .find(|directory| directory.exists())
+        .unwrap_or(std::path::Path::new("."));
+    if !existing_directory.is_dir() {
+        return Some(format!(
+            "{} isn't a directory, so the state directory {} can't be used.",
+            existing_directory.display(),
+            state_directory.display()
+        ));
+    }
+
+    let probe_file = existing_directory.join(format!(
+        ".hetzner-private-dns-sync-check-{}",
+        std::process::id()
+    ));
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe_file)
+    {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe_file);
+            None
+        }
+        Err(e) => Some(format!(
+            "Unable to write to {}, so the state can't be saved in {}. {}",
+            existing_directory.display(),
+            state_directory.display(),
+            e
+        )),
+    }
+}
+
+pub(crate) fn open_state(args: &Args, read_only: bool) -> Result<StateWrapper> {
+    let mut current_state = match &args.state_directory {
+        _ if args.no_state => StateWrapper::in_memory(),
+        Some(state_directory) if read_only => {
+            StateWrapper::read_only_from_directory(state_directory.clone())?
+        }
+        Some(state_directory) => StateWrapper::from_directory(state_directory.clone())?,
+        None => {
+            return Err(Error::Config(
+                "a state directory is needed unless --no-state is passed.".to_string(),
+            ))
+        }
+    };
+    current_state.set_save_mode(args.state_save_mode, args.state_save_batch_size);
+    tracing::info!("Current state retrieved.");
+
+    // States written before zones could be picked per server don't record a zone, which means their records are in the zone given by `--zone-name`.
+    for server in current_state
+        .servers_synced
+        .iter_mut()
+        .filter(|s| s.zone.is_empty())
+    {
+        server.zone = args.zone_name.clone();
+    }
+
+    Ok(current_state)
+}
+
+/// Every zone the given servers have records in, plus the default zone.
+pub(crate) fn zones_of<'a>(
+    servers: impl Iterator<Item = &'a Server>,
+    zone_name: &str,
+) -> Vec<String> {
+    let mut zones: Vec<String> = servers
+        .map(|s| s.zone.clone())
+        .chain(std::iter::once(zone_name.to_string()))
+        .collect();
+    zones.sort_by_key(|z| normalise_name(z));
+    zones.dedup_by_key(|z| normalise_name(z));
+
+    zones
+}
+
+/// Whether a normalised name in a zone starts with the cleanup prefix, once the zone is stripped from it.
+pub(crate) fn has_cleanup_prefix(name: &str, zone: &str, cleanup_prefix: &str) -> bool {
+    name.strip_suffix(&normalise_name(zone))
+        .and_then(|relative_name| relative_name.strip_suffix('.'))
+        .is_some_and(|relative_name| relative_name.starts_with(cleanup_prefix))
+}
+
+/// With --no-state, works out what the state would be from the records in the zones. The records on the name of a published server count as synced for it, with whatever data they have, so a server whose records don't match what we'd publish gets its records replaced. Returns those servers, and the names with the cleanup prefix that don't belong to any server in the network.
+pub(crate) async fn state_from_zones(
+    dns_updater: &impl DnsBackend,
+    args: &Args,
+    published: &PublishedServers,
+) -> Result<(Vec<Server>, Vec<StaleName>)> {
+    let cleanup_prefix = args.cleanup_prefix.as_deref().unwrap_or_default();
+
+    let mut records_by_name: HashMap<String, (String, Vec<ZoneRecord>)> = HashMap::new();
+    for zone in zones_of(published.servers.iter(), &args.zone_name) {
+        for record in dns_updater.zone_records(&zone).await? {
+            if matches!(
+                record.record_type,
+                RecordType::A | RecordType::AAAA | RecordType::CNAME
+            ) {
+                records_by_name
+                    .entry(record.name.clone())
+                    .or_insert_with(|| (zone.clone(), Vec::new()))
+                    .1
+                    .push(record);
+            }
+        }
+    }
+
+    let mut public_records_by_name: HashMap<String, Vec<String>> = HashMap::new();
+    if let Some(public_zone_name) = &args.public_zone_name {
+        for record in dns_updater.zone_records(public_zone_name).await? {
+            if record.record_type == RecordType::A {
+                public_records_by_name
+                    .entry(record.name)
+                    .or_default()
+                    .push(record.data);
+            }
+        }
+    }
+
+    let mut servers_synced = Vec::new();
+    for server in &published.servers {
+        let Some((_, records)) = records_by_name.remove(&normalise_name(&server.fqdn())) else {
+            continue;
+        };
+        let public_ip_address = args.public_zone_name.as_ref().and_then(|public_zone_name| {
+            public_records_by_name
+                .get(&normalise_name(&format!(
+                    "{}.{}",
+                    server.hostname, public_zone_name
+                )))
+                .map(|data| data.join(","))
+        });
+        let public_zone = public_ip_address
+            .as_ref()
+            .and(args.public_zone_name.clone());
+
+        let ttl = records[0].ttl;
+        let (ipv6_records, other_records): (Vec<&ZoneRecord>, Vec<&ZoneRecord>) = records
+            .iter()
+            .partition(|r| r.record_type == RecordType::AAAA);
+        let joined_data = |records: &[&ZoneRecord]| {
+            records
+                .iter()
+                .map(|r| r.data.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let synced_server = match (other_records.as_slice(), ipv6_records.is_empty()) {
+            ([record], true) if record.record_type == RecordType::CNAME => Server {
+                cname: Some(record.data.clone()),
+                ipv6_address: None,
+                public_ip_address,
+                public_zone,
+                ttl,
+                ..server.clone()
+            },
+            _ => Server {
+                ip_address: joined_data(&other_records),
+                ipv6_address: Some(joined_data(&ipv6_records)).filter(|data| !data.is_empty()),
+                no_a_record: other_records.is_empty(),
+                public_ip_address,
+                public_zone,
+                cname: None,
+                ttl,
+                ..server.clone()
+            },
+        };
+
+        // Without "a" in its record types, an A record left on the name is one to remove.
+        let ip_address = match server.no_a_record {
+            true => "",
+            false => server.ip_address.as_str(),
+        };
+        // The zone can give CNAME targets back in a different case than we'd write them, which isn't worth rewriting a record over.
+        let matches_published = match (&synced_server.cname, &server.cname) {
+            (Some(synced_target), Some(target)) => {
+                normalise_name(synced_target) == normalise_name(target)
+                    && synced_server.public_ip_address.is_none()
+            }
+            // A shared name has the addresses of all the servers on it, so we only look for this server's.
+            (None, None) if server.shared_name => {
+                let has_address = |addresses: &Option<String>, address: &Option<String>| {
+                    address.as_ref().map_or(true, |address| {
+                        addresses
+                            .as_deref()
+                            .is_some_and(|addresses| addresses.split(',').any(|a| a == address))
+                    })
+                };
+                (server.no_a_record || synced_server.ip_address.split(',').any(|a| a == ip_address))
+                    && has_address(&synced_server.ipv6_address, &server.ipv6_address)
+                    && has_address(&synced_server.public_ip_address, &server.public_ip_address)
+            }
+            (None, None) => {
+                synced_server.ip_address == ip_address
+                    && synced_server.ipv6_address == server.ipv6_address
+                    && synced_server.public_ip_address == server.public_ip_address
+            }
+            _ => false,
+        } && records.iter().all(|r| r.ttl == server.ttl);
+        servers_synced.push(if matches_published {
+            server.clone()
+        } else {
+            synced_server
+        });
+    }
+
+    // Servers we were told to leave alone keep their records, and the names of groups are shared by their servers.
+    for server in &published.ignored_servers {
+        records_by_name.remove(&normalise_name(&server.fqdn()));
+    }
+    for group_fqdn in published
+        .servers
+        .iter()
+        .chain(&published.ignored_servers)
+        .flat_map(Server::group_fqdns)
+    {
+        records_by_name.remove(&normalise_name(&group_fqdn));
+    }
+
+    let mut stale_names: Vec<StaleName> = records_by_name
+        .into_iter()
+        .filter(|(name, (zone, _))| has_cleanup_prefix(name, zone, cleanup_prefix))
+        .map(|(fqdn, (zone, _))| StaleName { fqdn, zone })
+        .collect();
+    stale_names.sort_by(|a, b| a.fqdn.cmp(&b.fqdn));
+
+    Ok((servers_synced, stale_names))
+}
+
+/// Returns the current state as JSON, in the same form as in the state file.
+pub fn dump_state(args: &Args) -> Result<String> {
+    let current_state = open_state(args, true)?;
+
+    serde_json::to_string_pretty(&*current_state)
+        .map_err(|e| Error::State(format!("unable to serialise the state. {}", e)))
+}
+
+/// What the state says about the last syncs.
+#[derive(Debug)]
+pub struct StateStatus {
+    pub private_network_name: String,
+    pub private_network_id: Option<i64>,
+    /// When the last sync finished successfully, in seconds since the Unix epoch. Not known for states last written by versions that didn't record it.
+    pub last_synced_at: Option<u64>,
+    pub servers_synced: Vec<Server>,
+    /// Servers detached from the network that still keep their record, and since when they're detached.
+    pub servers_pending_removal: BTreeMap<i64, u64>,
+}
+
+/// Reads the state, without changing it.
+pub fn state_status(args: &Args) -> Result<StateStatus> {
+    if args.no_state {
+        return Err(Error::Config(
+            "there's no state to look at with --no-state.".to_string(),
+        ));
+    }
+
+    let current_state = open_state(args, true)?;
+    let mut servers_synced = current_state.servers_synced.clone();
+    servers_synced.sort_by_key(|s| s.fqdn());
+
+    Ok(StateStatus {
+        private_network_name: current_state.private_network_name.clone(),
+        private_network_id: current_state.private_network_id,
+        last_synced_at: current_state.last_synced_at,
+        servers_synced,
+        servers_pending_removal: current_state.servers_pending_removal.clone(),
+    })
+}
+
+/// Prints the records of the servers in the state in the given form.
+pub fn export_state(args: &Args, format: ExportFormat) -> Result<String> {
+    let mut servers = state_status(args)?.servers_synced;
+    servers.sort_by(|a, b| a.zone.cmp(&b.zone).then(a.fqdn().cmp(&b.fqdn())));
+
+    let mut output = String::new();
+    match format {
+        ExportFormat::Zonefile => {
+            let mut current_zone = None;
+            for server in &servers {
+                if current_zone != Some(&server.zone) {
+                    output.push_str(&format!("; zone {}\n", server.zone));
+                    current_zone = Some(&server.zone);
+                }
+                let fqdn = server.fqdn();
+                let fqdn = fqdn.trim_end_matches('.');
+                match (&server.cname, &server.ipv6_address) {
+                    (Some(target), _) => {
+                        output.push_str(&format!("{}. {} CNAME {}\n", fqdn, server.ttl, target))
+                    }
+                    (None, ipv6_address) => {
+                        if !server.no_a_record {
+                            output.push_str(&format!(
+                                "{}. {} A {}\n",
+                                fqdn, server.ttl, server.ip_address
+                            ));
+                        }
+                        if let Some(ipv6_address) = ipv6_address {
+                            output.push_str(&format!(
+                                "{}. {} AAAA {}\n",
+                                fqdn, server.ttl, ipv6_address
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        ExportFormat::Json => {
+            let servers: Vec<serde_json::Value> = servers
+                .iter()
+                .map(|server| {
+                    serde_json::json!({
+                        "id": server.id,
+                        "hostname": server.hostname,
+                        "ip_address": server.ip_address,
+                        "ipv6_address": server.ipv6_address,
+                        "public_ip_address": server.public_record().map(|(_, ip)| ip),
+                        "public_fqdn": server.public_fqdn(),
+                        "fqdn": server.fqdn(),
+                        "zone": server.zone,
+                        "ttl": server.ttl,
+                        "cname": server.cname,
+                        "project": server.project,
+                    })
+                })
+                .collect();
+            output = serde_json::to_string_pretty(&servers)
+                .map_err(|e| Error::State(format!("unable to serialise the servers. {}", e)))?;
+            output.push('\n');
+        }
+        ExportFormat::Csv => {
+            output.push_str("id,hostname,ip_address,ipv6_address,public_ip_address,public_fqdn,fqdn,zone,ttl,cname,project\n");
+            for server in &servers {
+                output.push_str(&format!(
+                    "{},{},{},{},{},{},{},{},{},{},{}\n",
+                    server.id,
+                    csv_field(&server.hostname),
+                    csv_field(&server.ip_address),
+                    csv_field(server.ipv6_address.as_deref().unwrap_or_default()),
+                    csv_field(server.public_record().map(|(_, ip)| ip).unwrap_or_default()),
+                    csv_field(&server.public_fqdn().unwrap_or_default()),
+                    csv_field(&server.fqdn()),
+                    csv_field(&server.zone),
+                    server.ttl,
+                    csv_field(server.cname.as_deref().unwrap_or_default()),
+                    csv_field(server.project.as_deref().unwrap_or_default())
+                ));
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+/// Replaces the current state with the one in the given file, which has to be in the same form as `dump_state()` returns.
+pub fn load_state(args: &Args, path: &std::path::Path) -> Result<()> {
+    if args.no_state {
+        return Err(Error::Config(
+            "there's no state to load into with --no-state.".to_string(),
+        ));
+    }
+
+    let contents = std::fs::read(path).map_err(|e| {
+        Error::State(format!(
+            "unable to read the state to load at {}. {}",
+            path.display(),
+            e
+        ))
+    })?;
+    let loaded_state: State = serde_json::from_slice(&contents)
+        .map_err(|e| Error::State(format!("unable to parse the state to load. {}", e)))?;
+    tracing::info!(
+        private_network_name = loaded_state.private_network_name,
+        servers_synced = loaded_state.servers_synced.len(),
+        "Loaded a valid state, will replace the current one with it."
+    );
+
+    let mut current_state = open_state(args, false)?;
+    *current_state = loaded_state;
+    current_state.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+    use crate::testing;
+
+    fn state() -> State {
+        State {
+            private_network_name: "test".to_string(),
+            private_network_id: Some(1),
+            servers_synced: vec![Server {
+                id: 1,
+                ip_address: "10.0.0.2".to_string(),
+                hostname: "web".to_string(),
+                zone: "example.com".to_string(),
+                ..Default::default()
+            }],
+            servers_pending_removal: BTreeMap::from([(2, 1_700_000_000)]),
+            last_synced_at: Some(1_700_000_100),
+        }
+    }
+
+    /// The checksum in the file, and the one we'd write for what's read back from it.
+    fn checksums(file: &str) -> (Option<String>, Option<String>) {
+        let file: StateFile = serde_json::from_str(file).unwrap();
+        let checksum = file.checksum.clone();
+        let state = State::try_from(file).unwrap();
+
+        (checksum, StateFile::from(state).checksum)
+    }
+
+    #[test]
+    fn checksum_covers_the_whole_state_file() {
+        let file = serde_json::to_string(&state()).unwrap();
+        let (written, read) = checksums(&file);
+        assert!(written.is_some());
+        assert_eq!(written, read);
+
+        for (from, to) in [
+            ("\"10.0.0.2\"", "\"10.0.0.3\""),
+            (
+                "\"private_network_name\":\"test\"",
+                "\"private_network_name\":\"other\"",
+            ),
+            ("1700000000", "1700000001"),
+            ("1700000100", "1700000101"),
+        ] {
+            assert!(file.contains(from));
+            let (written, read) = checksums(&file.replace(from, to));
+            assert_ne!(written, read, "changing {} to {} went unnoticed", from, to);
+        }
+    }
+
+    #[test]
+    fn batched_changes_are_written_once() {
+        let state_directory = testing::temp_dir("batched-changes");
+        let state_path = state_directory.join("state.json");
+        let mut state = StateWrapper::from_directory(state_directory.clone()).unwrap();
+        state.set_save_mode(StateSaveMode::Batch, 3);
+        // Every write is a new file renamed over the state file.
+        let inode = || std::fs::metadata(&state_path).unwrap().ino();
+        let initial_inode = inode();
+
+        for id in 1..=2 {
+            state.servers_synced.push(Server {
+                id,
+                ..Default::default()
+            });
+            state.checkpoint().unwrap();
+        }
+        assert_eq!(inode(), initial_inode);
+
+        state.servers_synced.push(Server {
+            id: 3,
+            ..Default::default()
+        });
+        state.checkpoint().unwrap();
+        let written_inode = inode();
+        assert_ne!(written_inode, initial_inode);
+        assert!(!state_directory.join("state.json.tmp").exists());
+        let written: State = serde_json::from_slice(&std::fs::read(&state_path).unwrap()).unwrap();
+        assert_eq!(written.servers_synced.len(), 3);
+        assert_eq!(
+            std::fs::metadata(&state_path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        drop(state);
+        let reopened = StateWrapper::from_directory(state_directory.clone()).unwrap();
+        assert_eq!(reopened.servers_synced.len(), 3);
+
+        drop(reopened);
+        std::fs::remove_dir_all(state_directory).unwrap();
+    }
+
+    #[test]
+    fn a_missing_state_directory_is_created() {
+        let temp_dir = testing::temp_dir("missing-state-directory");
+        let state_directory = temp_dir.join("nested").join("state");
+
+        let mut state = StateWrapper::from_directory(state_directory.clone()).unwrap();
+        assert!(state.servers_synced.is_empty());
+        state.private_network_name = "test".to_string();
+        state.save().unwrap();
+        assert_eq!(
+            std::fs::metadata(&state_directory)
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o700
+        );
+
+        drop(state);
+        let reopened = StateWrapper::from_directory(state_directory).unwrap();
+        assert_eq!(reopened.private_network_name, "test");
+
+        drop(reopened);
+        std::fs::remove_dir_all(temp_dir).unwrap();
+    }
+
+    #[test]
+    fn the_state_file_is_only_readable_by_its_owner() {
+        let state_directory = testing::temp_dir("state-permissions");
+        let state_path = state_directory.join("state.json");
+        let mode = || std::fs::metadata(&state_path).unwrap().permissions().mode() & 0o777;
+
+        drop(StateWrapper::from_directory(state_directory.clone()).unwrap());
+        assert_eq!(mode(), 0o600);
+
+        // A file others could read before is restricted when it's opened.
+        std::fs::set_permissions(&state_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        drop(StateWrapper::from_directory(state_directory.clone()).unwrap());
+        assert_eq!(mode(), 0o600);
+
+        std::fs::remove_dir_all(state_directory).unwrap();
+    }
+
+    #[test]
+    fn states_with_a_single_list_of_servers_are_migrated() {
+        let state_directory = testing::temp_dir("state-migration");
+        std::fs::write(
+            state_directory.join("state.json"),
+            r#"{
+                "private_network_name": "test",
+                "private_network_id": 1,
+                "servers_synced": [
+                    {"id": 1, "ip_address": "10.0.0.2", "hostname": "web"},
+                    {"id": 2, "ip_address": "10.0.0.3", "hostname": "db", "zone": "acme.example.com"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let mut args = testing::args(&[]);
+        args.state_directory = Some(state_directory.clone());
+
+        // Servers without a zone were published before zones could be picked, in --zone-name.
+        let mut state = open_state(&args, false).unwrap();
+        let zones: Vec<(i64, &str)> = state
+            .servers_synced
+            .iter()
+            .map(|s| (s.id, s.zone.as_str()))
+            .collect();
+        assert_eq!(zones, [(1, "example.com"), (2, "acme.example.com")]);
+
+        state.save().unwrap();
+        drop(state);
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(state_directory.join("state.json")).unwrap())
+                .unwrap();
+        assert_eq!(written["version"], STATE_VERSION);
+        assert!(written.get("servers_synced").is_none());
+        let scopes: Vec<(&str, usize)> = written["scopes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| {
+                (
+                    s["zone"].as_str().unwrap(),
+                    s["records"].as_array().unwrap().len(),
+                )
+            })
+            .collect();
+        assert_eq!(scopes, [("acme.example.com", 1), ("example.com", 1)]);
+        assert_eq!(
+            written["scopes"][1]["records"][0]["fqdn"],
+            "web.example.com"
+        );
+
+        let reopened = open_state(&args, true).unwrap();
+        assert_eq!(reopened.servers_synced.len(), 2);
+
+        // A layout from the future isn't something we can make sense of.
+        std::fs::write(
+            state_directory.join("state.json"),
+            r#"{"version": 99, "private_network_name": "test"}"#,
+        )
+        .unwrap();
+        assert!(matches!(open_state(&args, true), Err(Error::State(_))));
+
+        std::fs::remove_dir_all(state_directory).unwrap();
+    }
+
+    #[test]
+    fn a_dumped_state_loads_back_the_same() {
+        let temp_dir = testing::temp_dir("dump-and-load");
+        let args_in = |name: &str| {
+            let mut args = testing::args(&[]);
+            args.state_directory = Some(temp_dir.join(name));
+            args
+        };
+        let (old_host, new_host) = (args_in("old-host"), args_in("new-host"));
+
+        let mut current_state = open_state(&old_host, false).unwrap();
+        *current_state = state();
+        current_state.save().unwrap();
+        drop(current_state);
+
+        let dump = dump_state(&old_host).unwrap();
+        let dump_path = temp_dir.join("dump.json");
+        std::fs::write(&dump_path, &dump).unwrap();
+        load_state(&new_host, &dump_path).unwrap();
+        assert_eq!(dump_state(&new_host).unwrap(), dump);
+        let loaded = open_state(&new_host, true).unwrap();
+        assert_eq!(loaded.private_network_id, Some(1));
+        assert_eq!(loaded.servers_synced[0].fqdn(), "web.example.com");
+        assert_eq!(
+            loaded.servers_pending_removal,
+            state().servers_pending_removal
+        );
+        assert_eq!(loaded.last_synced_at, state().last_synced_at);
+
+        // A state of a version we don't know leaves the current one as it was.
+        std::fs::write(
+            &dump_path,
+            dump.replace(
+                &format!("\"version\": {}", STATE_VERSION),
+                "\"version\": 99",
+            ),
+        )
+        .unwrap();
+        assert!(matches!(
+            load_state(&new_host, &dump_path),
+            Err(Error::State(_))
+        ));
+        assert_eq!(dump_state(&new_host).unwrap(), dump);
+
+        std::fs::remove_dir_all(temp_dir).unwrap();
+    }
+
+    #[test]
+    fn records_of_two_networks_in_one_zone_are_scoped_apart() {
+        let state_directory = testing::temp_dir("state-network-scopes");
+        let mut args = testing::args(&[]);
+        args.state_directory = Some(state_directory.clone());
+        let db = Server {
+            id: 2,
+            ip_address: "10.1.0.3".to_string(),
+            hostname: "db".to_string(),
+            zone: "example.com".to_string(),
+            project: Some("other".to_string()),
+            network_id: Some(2),
+            ..Default::default()
+        };
+
+        let mut current_state = open_state(&args, false).unwrap();
+        *current_state = state();
+        current_state.servers_synced[0].network_id = Some(1);
+        current_state.servers_synced.push(db);
+        current_state.save().unwrap();
+        drop(current_state);
+
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(state_directory.join("state.json")).unwrap())
+                .unwrap();
+        let scopes: Vec<(&str, i64, i64)> = written["scopes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| {
+                (
+                    s["zone"].as_str().unwrap(),
+                    s["network_id"].as_i64().unwrap(),
+                    s["records"][0]["server_id"].as_i64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(scopes, [("example.com", 1, 1), ("example.com", 2, 2)]);
+
+        let network_ids = |state: &State| -> Vec<(i64, Option<i64>)> {
+            state
+                .servers_synced
+                .iter()
+                .map(|s| (s.id, s.network_id))
+                .collect()
+        };
+        let reopened = open_state(&args, true).unwrap();
+        assert_eq!(network_ids(&reopened), [(1, Some(1)), (2, Some(2))]);
+        drop(reopened);
+
+        // Version 2 put the servers of every project under the network of --hcloud-api-token.
+        std::fs::write(
+            state_directory.join("state.json"),
+            r#"{
+                "version": 2,
+                "private_network_name": "test",
+                "private_network_id": 1,
+                "scopes": [{"zone": "example.com", "network_id": 1, "records": [
+                    {"server_id": 1, "hostname": "web", "ip_address": "10.0.0.2"},
+                    {"server_id": 2, "hostname": "db", "ip_address": "10.1.0.3", "project": "other"}
+                ]}]
+            }"#,
+        )
+        .unwrap();
+        let migrated = open_state(&args, true).unwrap();
+        assert_eq!(network_ids(&migrated), [(1, Some(1)), (2, None)]);
+        drop(migrated);
+
+        std::fs::remove_dir_all(state_directory).unwrap();
+    }
+
+    #[test]
+    fn the_state_is_written_as_often_as_its_save_mode_says() {
+        for (save_mode, expected_writes) in [
+            (StateSaveMode::Each, 5),
+            (StateSaveMode::Batch, 1),
+            (StateSaveMode::End, 0),
+        ] {
+            let state_directory = testing::temp_dir(&format!("save-mode-{:?}", save_mode));
+            let mut state = StateWrapper::from_directory(state_directory.clone()).unwrap();
+            state.set_save_mode(save_mode, 3);
+
+            for id in 1..=5 {
+                state.servers_synced.push(Server {
+                    id,
+                    ..Default::default()
+                });
+                state.checkpoint().unwrap();
+            }
+            assert_eq!(state.writes, expected_writes, "{:?}", save_mode);
+
+            // Whatever wasn't written yet is when the state is dropped.
+            drop(state);
+            let reopened = StateWrapper::from_directory(state_directory.clone()).unwrap();
+            assert_eq!(reopened.servers_synced.len(), 5, "{:?}", save_mode);
+
+            drop(reopened);
+            std::fs::remove_dir_all(state_directory).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_state_that_cant_be_written_when_dropped_is_flagged_instead_of_panicking() {
+        let state_directory = testing::temp_dir("unwritable-state");
+        let mut state = StateWrapper::from_directory(state_directory.clone()).unwrap();
+        state.path = Some(state_directory.join("gone").join("state.json"));
+
+        drop(state);
+        assert!(state_save_failed());
+
+        std::fs::remove_dir_all(state_directory).unwrap();
+    }
+
+    #[test]
+    fn a_state_file_changed_by_hand_is_logged() {
+        const CHANGED: &str = "The state file changed since it was last written by us";
+        let file = serde_json::to_string(&StateFile::from(state())).unwrap();
+
+        let (logs, guard) = testing::captured_logs();
+        serde_json::from_str::<State>(&file).unwrap();
+        serde_json::from_str::<State>(&file.replace("10.0.0.2", "10.0.0.3")).unwrap();
+        drop(guard);
+
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = logs.lines().filter(|l| l.contains(CHANGED)).collect();
+        assert_eq!(lines.len(), 1, "{}", logs);
+        assert!(lines[0].contains(" INFO "), "{}", lines[0]);
+    }
+}
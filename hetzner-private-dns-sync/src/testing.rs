@@ -0,0 +1,273 @@
+//! Fakes of the DNS servers and the Hetzner API for the tests, recording what a sync asks of them instead of sending anything.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use clap::Parser;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    cloud::{CloudBackend, HCloudServer},
+    dns::{DnsBackend, ZoneRecord},
+    Args, Error, Result, Server,
+};
+
+/// Keeps every change it's asked for, in order, as "add <fqdn> <records>", "remove <fqdn> <records>" and "remove-name <fqdn>".
+#[derive(Debug, Default)]
+pub(crate) struct FakeDns {
+    calls: Mutex<Vec<String>>,
+    /// What `zone_records` answers with, by zone.
+    pub(crate) zones: HashMap<String, Vec<ZoneRecord>>,
+    /// Names whose changes never finish, like on a DNS server that stopped answering.
+    pub(crate) hanging: HashSet<String>,
+    /// Names whose changes the DNS server rejects.
+    pub(crate) failing: HashSet<String>,
+}
+
+impl FakeDns {
+    /// The changes asked for since the last time this was called.
+    pub(crate) fn take_calls(&self) -> Vec<String> {
+        std::mem::take(&mut *self.calls.lock().unwrap())
+    }
+
+    async fn change(&self, fqdn: &str, call: String) -> Result<()> {
+        if self.hanging.contains(fqdn) {
+            std::future::pending::<()>().await;
+        }
+
+        self.calls.lock().unwrap().push(call);
+        if self.failing.contains(fqdn) {
+            return Err(Error::Dns(format!(
+                "the DNS server rejected the change to {}.",
+                fqdn
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl DnsBackend for FakeDns {
+    async fn zone_records(&self, zone_name: &str) -> Result<Vec<ZoneRecord>> {
+        Ok(self.zones.get(zone_name).cloned().unwrap_or_default())
+    }
+
+    async fn add_server(&self, server: &Server) -> Result<()> {
+        self.change(
+            &server.fqdn(),
+            format!("add {} {}", server.fqdn(), server.record_description()),
+        )
+        .await
+    }
+
+    async fn remove_server(&self, server: &Server) -> Result<()> {
+        self.change(
+            &server.fqdn(),
+            format!("remove {} {}", server.fqdn(), server.record_description()),
+        )
+        .await
+    }
+
+    async fn remove_name(&self, fqdn: &str, _zone_name: &str) -> Result<()> {
+        self.change(fqdn, format!("remove-name {}", fqdn)).await
+    }
+
+    async fn verify_propagation(
+        &self,
+        _server: &Server,
+        _resolvers: &[SocketAddr],
+        _timeout: Duration,
+    ) {
+    }
+}
+
+/// Answers with the servers it's given, as if they were all attached to the network with ID 1.
+#[derive(Debug, Default)]
+pub(crate) struct FakeCloud {
+    pub(crate) servers: Vec<HCloudServer>,
+}
+
+impl CloudBackend for FakeCloud {
+    fn forget_network(&mut self) {}
+
+    async fn network_id(&mut self) -> Result<i64> {
+        Ok(1)
+    }
+
+    async fn server_ids(&mut self) -> Result<Vec<i64>> {
+        Ok(self.servers.iter().map(|s| s.id).collect())
+    }
+
+    async fn hydrate_server_list(&mut self, server_ids: Vec<i64>) -> Result<Vec<HCloudServer>> {
+        Ok(self
+            .servers
+            .iter()
+            .filter(|s| server_ids.contains(&s.id))
+            .cloned()
+            .collect())
+    }
+}
+
+/// A server attached to the network with the given address in it, and nothing else.
+pub(crate) fn hcloud_server(id: i64, name: &str, ip_address: &str) -> HCloudServer {
+    HCloudServer {
+        id,
+        name: name.to_string(),
+        ip_address: Some(ip_address.to_string()),
+        alias_ips: Vec::new(),
+        public_ipv6_network: None,
+        public_ip_address: None,
+        datacenter: "fsn1-dc14".to_string(),
+        location: "fsn1".to_string(),
+        labels: HashMap::new(),
+        project: None,
+        network_id: 1,
+    }
+}
+
+/// An HTTP server standing in for an API, answering each request with the status and JSON body `respond` gives for it. `respond` gets the method and path of the request, e.g. "GET /networks/7", and its body. Returns the URL of the server and the requests it got so far, as the method and path.
+pub(crate) async fn http_server(
+    respond: impl Fn(&str, &str) -> (u16, String) + Send + Sync + 'static,
+) -> (String, Arc<Mutex<Vec<String>>>) {
+    http_server_with_headers(move |request, body| {
+        let (status, response_body) = respond(request, body);
+        (status, Vec::new(), response_body)
+    })
+    .await
+}
+
+/// Like `http_server`, but `respond` also gives the headers to answer with, on top of the content type and length.
+pub(crate) async fn http_server_with_headers(
+    respond: impl Fn(&str, &str) -> (u16, Vec<(&'static str, String)>, String) + Send + Sync + 'static,
+) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let respond = Arc::new(respond);
+
+    let seen = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (respond, seen) = (respond.clone(), seen.clone());
+            tokio::spawn(async move {
+                let mut stream = tokio::io::BufReader::new(stream);
+                // Clients keep the connection around for their next requests.
+                loop {
+                    let mut request_line = String::new();
+                    if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let mut content_length = 0;
+                    loop {
+                        let mut header = String::new();
+                        stream.read_line(&mut header).await.unwrap();
+                        let Some((name, value)) = header.trim_end().split_once(':') else {
+                            break;
+                        };
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                    let mut body = vec![0; content_length];
+                    stream.read_exact(&mut body).await.unwrap();
+
+                    let request = request_line
+                        .split_whitespace()
+                        .take(2)
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    let (status, headers, response_body) =
+                        respond(&request, &String::from_utf8_lossy(&body));
+                    seen.lock().unwrap().push(request);
+                    let headers: String = headers
+                        .iter()
+                        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+                        .collect();
+                    let response = format!(
+                        "HTTP/1.1 {} -\r\ncontent-type: application/json\r\ncontent-length: {}\r\n{}\r\n{}",
+                        status,
+                        response_body.len(),
+                        headers,
+                        response_body
+                    );
+                    stream
+                        .get_mut()
+                        .write_all(response.as_bytes())
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+
+    (url, requests)
+}
+
+/// An empty directory for the test with the given name, which nothing else uses.
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "hetzner-private-dns-sync-{}-{}",
+        std::process::id(),
+        name
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+/// The arguments of a sync of the "test" network into "example.com", plus `extra`.
+pub(crate) fn args(extra: &[&str]) -> Args {
+    Args::parse_from(
+        [
+            "hetzner-private-dns-sync",
+            "--tsig-key-name",
+            "test",
+            "--tsig-key-path",
+            "/dev/null",
+            "--server-address",
+            "udp://127.0.0.1:53",
+            "--hcloud-api-token",
+            "test",
+            "--private-network-name",
+            "test",
+            "--zone-name",
+            "example.com",
+            "--state-directory",
+            "/nonexistent",
+        ]
+        .iter()
+        .chain(extra),
+    )
+}
+
+/// Keeps what's logged, for as long as the guard next to it is around.
+pub(crate) fn captured_logs() -> (Arc<Mutex<Vec<u8>>>, tracing::subscriber::DefaultGuard) {
+    struct Writer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Writer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    let writer_logs = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || Writer(writer_logs.clone()))
+        .finish();
+
+    (logs, tracing::subscriber::set_default(subscriber))
+}